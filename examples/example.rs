@@ -1,6 +1,8 @@
 use really_notify::FileWatcherConfig;
 
-#[tokio::main]
+#[cfg_attr(not(target_family = "wasm"), tokio::main)]
+// tokio has no multi-threaded runtime on wasm
+#[cfg_attr(target_family = "wasm", tokio::main(flavor = "current_thread"))]
 async fn main() {
     env_logger::Builder::new()
         .parse_env(env_logger::Env::default().default_filter_or("info"))
@@ -12,7 +14,7 @@ async fn main() {
     // if the file doesn't exist, isn't readable, can't be parsed, etc, then `really-notify` will enter a 1-second loop to reattempt and print errors.
     // this helps recover against not having read permissions, which prevents us from watching the file for changes as well.
     let mut receiver = FileWatcherConfig::new("./examples/config.yaml", "config")
-        .with_parser(|data| String::from_utf8(data))
+        .with_parser(String::from_utf8)
         .start();
     while let Some(config) = receiver.recv().await {
         // so, everytime we get here, we have a new valid config to throw in an `ArcSwap`/`tokio::sync::watch`/etc. No further validation needed.
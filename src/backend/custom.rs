@@ -0,0 +1,92 @@
+use std::{fmt::Display, io, sync::Arc};
+
+use futures::future::BoxFuture;
+
+use crate::{
+    rt::{self, AbortHandle},
+    ErrorHook, WatcherContext,
+};
+
+/// A change detection mechanism of your own, i.e. a cluster notification bus, or a test double.
+/// See [`crate::FileWatcherConfig::with_custom_backend`]. Reads are retried, parsed, and emitted the same as with the built-in backends.
+pub trait WatchBackend: Send + Sync + 'static {
+    /// Watch the target, calling [`WatcherContext::changed`] whenever it may have changed.
+    /// Returning `Ok` starts watching afresh, i.e. after the path to the target was replaced.
+    /// Errors are logged, reported to error hooks, and retried after the retry interval.
+    /// The future is dropped once the watcher stops.
+    fn watch<'a>(&'a self, context: &'a WatcherContext) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// Lets you keep a handle to the backend, i.e. to trigger changes from a test.
+impl<B: WatchBackend> WatchBackend for Arc<B> {
+    fn watch<'a>(&'a self, context: &'a WatcherContext) -> BoxFuture<'a, io::Result<()>> {
+        (**self).watch(context)
+    }
+}
+
+/// What kind of filesystem object a [`WatcherContext`] is pointed at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum TargetKind {
+    /// A file, whose contents matter.
+    File,
+    /// A directory, whose direct children matter.
+    Directory,
+    /// A directory, and everything below it.
+    Tree,
+    /// Only whether the path exists matters.
+    Sentinel,
+}
+
+/// What happened to a path, as far as the backend can tell, handed to the filter set with
+/// [`crate::FileWatcherConfig::with_event_filter`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ChangeKind {
+    /// Contents were written to, possibly with more writes to come.
+    Modified,
+    /// A file opened for writing was closed. Only reported by the inotify, fanotify, and notify backends, on Linux.
+    ClosedWrite,
+    /// Created, including directories on the way to the target.
+    Created,
+    /// Deleted, including directories on the way to the target.
+    Removed,
+    /// Renamed, i.e. moved into place, or away.
+    Renamed,
+    /// Permissions, ownership, or other attributes changed. See [`crate::FileWatcherConfig::with_attribute_changes`].
+    /// The windows backend reports these as [`ChangeKind::Modified`].
+    Attributes,
+    /// Anything that can't be told apart, i.e. a difference found by polling, or a symlink on the way being replaced.
+    Other,
+}
+
+/// Stops the backend when dropped.
+pub(crate) struct BackendHandle(AbortHandle);
+
+impl Drop for BackendHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub(crate) fn start_backend<E: Display + Send + 'static>(
+    backend: Arc<dyn WatchBackend>,
+    watcher_context: WatcherContext,
+    on_error: ErrorHook<E>,
+) -> BackendHandle {
+    let task = rt::spawn(async move {
+        let mut failures = 0;
+        loop {
+            match backend.watch(&watcher_context).await {
+                Ok(()) => failures = 0,
+                Err(e) => {
+                    failures += 1;
+                    watcher_context.watch_error(&e, failures);
+                    on_error(&e.into());
+                    rt::sleep(watcher_context.retry_interval).await;
+                }
+            }
+        }
+    });
+    BackendHandle(task.abort_handle())
+}
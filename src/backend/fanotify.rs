@@ -0,0 +1,411 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    ffi::{CStr, OsStr, OsString},
+    fmt::Display,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::Level;
+
+use super::Links;
+use crate::{
+    directory::{walk, WatchMode},
+    fs,
+    k8s::DATA_LINK,
+    normalize,
+    rt::{self, AbortHandle},
+    ChangeKind, ErrorHook, FileWatcherError, WatcherContext,
+};
+
+/// Stops the backend when dropped.
+pub(crate) struct BackendHandle(AbortHandle);
+
+impl Drop for BackendHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Creates a fanotify group reporting directory handles and names, which needs `CAP_SYS_ADMIN` and Linux 5.9.
+pub(crate) fn init() -> io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::fanotify_init(
+            libc::FAN_CLASS_NOTIF
+                | libc::FAN_CLOEXEC
+                | libc::FAN_NONBLOCK
+                | libc::FAN_REPORT_DFID_NAME,
+            (libc::O_RDONLY | libc::O_CLOEXEC) as _,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+pub(crate) async fn start_backend<E: Display + Send + 'static>(
+    watcher_context: WatcherContext,
+    fanotify: OwnedFd,
+    on_error: ErrorHook<E>,
+) -> BackendHandle {
+    let task = rt::spawn(async move {
+        let watcher_context = Arc::new(watcher_context);
+        // marks stay on the filesystem across rebuilds, so recreated directories are covered from the moment they exist
+        let fanotify = match rt::AsyncFd::new(fanotify) {
+            Ok(x) => x,
+            Err(e) => {
+                watcher_context.watch_error(&e, 1);
+                on_error(&e.into());
+                return;
+            }
+        };
+        let mut failures = 0;
+        loop {
+            match load_config::<E>(watcher_context.clone(), &fanotify).await {
+                Ok(()) => failures = 0,
+                Err(e) => {
+                    failures += 1;
+                    watcher_context.watch_error(&e, failures);
+                    on_error(&e);
+                    rt::sleep(watcher_context.retry_interval).await;
+                }
+            }
+        }
+    });
+    BackendHandle(task.abort_handle())
+}
+
+const MASK: u64 = libc::FAN_MODIFY
+    | libc::FAN_CLOSE_WRITE
+    | libc::FAN_CREATE
+    | libc::FAN_DELETE
+    | libc::FAN_MOVE
+    | libc::FAN_ONDIR;
+
+/// What an event was, for the event filter. Events can be merged, in which case the close after a write wins.
+fn kind(mask: u64) -> ChangeKind {
+    if mask & libc::FAN_CREATE != 0 {
+        ChangeKind::Created
+    } else if mask & libc::FAN_DELETE != 0 {
+        ChangeKind::Removed
+    } else if mask & libc::FAN_MOVE != 0 {
+        ChangeKind::Renamed
+    } else if mask & libc::FAN_CLOSE_WRITE != 0 {
+        ChangeKind::ClosedWrite
+    } else if mask & libc::FAN_ATTRIB != 0 {
+        ChangeKind::Attributes
+    } else if mask & libc::FAN_MODIFY != 0 {
+        ChangeKind::Modified
+    } else {
+        ChangeKind::Other
+    }
+}
+
+/// A watched directory.
+#[derive(Default)]
+struct Node {
+    /// Any change within is a change to the target.
+    target: bool,
+    /// Entries we care about, and whether modifying them (rather than only replacing them) is a change.
+    children: Vec<(OsString, bool)>,
+}
+
+impl Node {
+    fn child(&mut self, name: &OsStr, contents: bool) {
+        match self.children.iter_mut().find(|(x, _)| x == name) {
+            Some((_, x)) => *x |= contents,
+            None => self.children.push((name.to_os_string(), contents)),
+        }
+    }
+
+    fn merge(&mut self, other: Node) {
+        self.target |= other.target;
+        for (name, contents) in other.children {
+            self.child(&name, contents);
+        }
+    }
+}
+
+/// Watch every ancestor of `path` for `path` (or the path to it) being replaced, following symlinked ancestors to where they point.
+/// Symlinked ancestors are followed at most `links` deep.
+fn watch_ancestors(nodes: &mut HashMap<PathBuf, Node>, path: &Path, links: usize) {
+    let mut child = path;
+    while let Some(parent) = child.parent() {
+        if let Some(name) = child.file_name() {
+            nodes
+                .entry(parent.to_path_buf())
+                .or_default()
+                .child(name, false);
+        }
+        if links > 0 && std::fs::symlink_metadata(parent).is_ok_and(|x| x.is_symlink()) {
+            if let Ok(real) = std::fs::canonicalize(parent) {
+                watch_ancestors(nodes, &real, links - 1);
+            }
+        }
+        child = parent;
+    }
+}
+
+async fn load_config<E: Display + Send + 'static>(
+    context: Arc<WatcherContext>,
+    fanotify: &rt::AsyncFd<OwnedFd>,
+) -> Result<(), FileWatcherError<E>> {
+    let mut nodes: HashMap<PathBuf, Node> = HashMap::new();
+    let mut current_main_file = normalize(&context.file)?;
+    let mut links = Links::new(context.max_symlink_depth);
+    loop {
+        let missing = match context.mode {
+            WatchMode::Sentinel => true,
+            _ => {
+                context.optional
+                    && matches!(
+                        std::fs::symlink_metadata(&current_main_file),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound
+                    )
+            }
+        };
+        watch_ancestors(&mut nodes, &current_main_file, context.max_symlink_depth);
+        if missing {
+            break;
+        }
+        let metadata = std::fs::symlink_metadata(&current_main_file)?;
+        if metadata.is_symlink() {
+            links.follow(&current_main_file)?;
+            let link = std::fs::read_link(&current_main_file)?;
+            current_main_file = normalize(&match current_main_file.parent() {
+                Some(parent) if link.is_relative() => parent.join(link),
+                _ => link,
+            })?;
+            continue;
+        }
+        context.logger.log(
+            Level::Debug,
+            0,
+            format_args!("watching main target {}", current_main_file.display()),
+        );
+        match &context.mode {
+            // only the atomic swap matters, everything else is kubelet staging the next version
+            WatchMode::ConfigMap => nodes
+                .entry(current_main_file.clone())
+                .or_default()
+                .child(OsStr::new(DATA_LINK), false),
+            WatchMode::Directory { .. } => {
+                nodes.entry(current_main_file.clone()).or_default().target = true
+            }
+            WatchMode::Tree(options) => {
+                let (dirs, _) = walk(&current_main_file, options).await?;
+                for dir in dirs {
+                    nodes.entry(dir).or_default().target = true;
+                }
+            }
+            _ => {
+                if let (Some(parent), Some(name)) =
+                    (current_main_file.parent(), current_main_file.file_name())
+                {
+                    nodes
+                        .entry(parent.to_path_buf())
+                        .or_default()
+                        .child(name, true);
+                }
+            }
+        }
+        break;
+    }
+
+    // events identify directories by handle rather than path
+    let mut watched: HashMap<Vec<u8>, (PathBuf, Node)> = HashMap::new();
+    let mut filesystems = vec![];
+    for (path, node) in nodes {
+        let (fsid, handle) = match handle(&path) {
+            Ok(x) => x,
+            // an ancestor of a missing target
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !node.target => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if !filesystems.contains(&fsid) {
+            mark(
+                fanotify.get_ref().as_raw_fd(),
+                &path,
+                context.attribute_changes,
+            )?;
+            filesystems.push(fsid);
+        }
+        // `a/b/..` and `a` are the same directory, so both want its events
+        match watched.entry(handle) {
+            Entry::Occupied(mut x) => x.get_mut().1.merge(node),
+            Entry::Vacant(x) => {
+                x.insert((path, node));
+            }
+        }
+    }
+    // the marks are per filesystem, but only events in these directories are reported
+    let mask = format!("{:#x}", mask(context.attribute_changes));
+    context.established(
+        watched
+            .values()
+            .map(|(path, _)| (path.clone(), mask.clone())),
+    );
+
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = fanotify
+            .read_with(|fd| {
+                let read =
+                    unsafe { libc::read(fd.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len()) };
+                if read < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(read as usize)
+            })
+            .await?;
+        let mut rebuild = false;
+        for event in events(&buffer[..read]) {
+            let Some((mask, key, name)) = event else {
+                context
+                    .logger
+                    .log(Level::Debug, 0, format_args!("fanotify queue overflowed"));
+                context.notify.notify_one();
+                rebuild = true;
+                continue;
+            };
+            let Some((path, node)) = watched.get(&key) else {
+                continue;
+            };
+            let changed = path.join(&name);
+            context.logger.log(
+                Level::Debug,
+                0,
+                format_args!("received event {mask:#x} for {}", changed.display()),
+            );
+            let replaced = mask & (libc::FAN_CREATE | libc::FAN_DELETE | libc::FAN_MOVE) != 0;
+            if let Some((_, contents)) = node.children.iter().find(|(x, _)| *x == name) {
+                if replaced {
+                    // something along the way was replaced, and needs a full refresh
+                    context.report(kind(mask), &changed);
+                    rebuild = true;
+                } else if *contents {
+                    context.report(kind(mask), &changed);
+                }
+            } else if node.target {
+                context.report(kind(mask), &changed);
+                // subdirectories may have appeared or disappeared
+                if replaced
+                    && mask & libc::FAN_ONDIR != 0
+                    && matches!(context.mode, WatchMode::Tree(_))
+                {
+                    rebuild = true;
+                }
+            }
+        }
+        if rebuild {
+            return Ok(());
+        }
+    }
+}
+
+fn mask(attribute_changes: bool) -> u64 {
+    if attribute_changes {
+        MASK | libc::FAN_ATTRIB
+    } else {
+        MASK
+    }
+}
+
+fn mark(fanotify: RawFd, path: &Path, attribute_changes: bool) -> io::Result<()> {
+    let path = fs::c_path(path)?;
+    let mask = mask(attribute_changes);
+    let result = unsafe {
+        libc::fanotify_mark(
+            fanotify,
+            libc::FAN_MARK_ADD | libc::FAN_MARK_FILESYSTEM,
+            mask,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+const MAX_HANDLE_SZ: usize = 128;
+
+/// The filesystem id, and the filesystem id with the handle of `path`, as it is reported in events.
+fn handle(path: &Path) -> io::Result<([u8; 8], Vec<u8>)> {
+    let c_path = fs::c_path(path)?;
+    let mut statfs: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut statfs) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fsid: [u8; 8] = unsafe { std::mem::transmute(statfs.f_fsid) };
+    let mut raw = [0u8; std::mem::size_of::<libc::file_handle>() + MAX_HANDLE_SZ];
+    let file_handle = raw.as_mut_ptr().cast::<libc::file_handle>();
+    unsafe { (*file_handle).handle_bytes = MAX_HANDLE_SZ as _ };
+    let mut mount_id = 0;
+    // events within a symlinked directory are reported for the directory it points to
+    if unsafe {
+        libc::name_to_handle_at(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            file_handle,
+            &mut mount_id,
+            libc::AT_SYMLINK_FOLLOW,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    let length =
+        std::mem::size_of::<libc::file_handle>() + unsafe { (*file_handle).handle_bytes } as usize;
+    let mut key = fsid.to_vec();
+    key.extend_from_slice(&raw[..length]);
+    Ok((fsid, key))
+}
+
+/// Parses events as (mask, directory key, name), or `None` if events were lost.
+fn events(mut buffer: &[u8]) -> impl Iterator<Item = Option<(u64, Vec<u8>, OsString)>> + '_ {
+    std::iter::from_fn(move || {
+        let header = std::mem::size_of::<libc::fanotify_event_metadata>();
+        if buffer.len() < header {
+            return None;
+        }
+        let metadata: libc::fanotify_event_metadata =
+            unsafe { std::ptr::read_unaligned(buffer.as_ptr().cast()) };
+        let event_len = (metadata.event_len as usize).min(buffer.len());
+        let mut info = &buffer[metadata.metadata_len as usize..event_len];
+        buffer = &buffer[event_len..];
+        if metadata.vers != libc::FANOTIFY_METADATA_VERSION
+            || metadata.mask & libc::FAN_Q_OVERFLOW != 0
+        {
+            return Some(None);
+        }
+        let header = std::mem::size_of::<libc::fanotify_event_info_header>();
+        while info.len() >= header {
+            let record: libc::fanotify_event_info_header =
+                unsafe { std::ptr::read_unaligned(info.as_ptr().cast()) };
+            let len = (record.len as usize).min(info.len());
+            if record.info_type == libc::FAN_EVENT_INFO_TYPE_DFID_NAME {
+                // header, fsid, then the directory handle followed by the name
+                let fid = &info[header..len];
+                let fsid = &fid[..8];
+                let handle_bytes = u32::from_ne_bytes(fid[8..12].try_into().unwrap()) as usize;
+                let handle_end = 8 + std::mem::size_of::<libc::file_handle>() + handle_bytes;
+                let mut key = fsid.to_vec();
+                key.extend_from_slice(&fid[8..handle_end]);
+                let name = CStr::from_bytes_until_nul(&fid[handle_end..])
+                    .map(|x| OsStr::from_bytes(x.to_bytes()).to_os_string())
+                    .unwrap_or_default();
+                return Some(Some((metadata.mask, key, name)));
+            }
+            info = &info[len..];
+        }
+        Some(Some((metadata.mask, vec![], OsString::new())))
+    })
+}
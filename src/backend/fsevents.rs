@@ -0,0 +1,331 @@
+use std::{
+    ffi::{c_void, CStr},
+    fmt::Display,
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use fsevent_sys::{self as fs, core_foundation as cf};
+use log::Level;
+use tokio::sync::mpsc;
+
+use super::Links;
+use crate::{
+    directory::WatchMode,
+    k8s::DATA_LINK,
+    normalize,
+    rt::{self, AbortHandle},
+    ChangeKind, ErrorHook, FileWatcherError, WatcherContext,
+};
+
+/// `/dev/fd` paths don't generate FSEvents, so open files are polled.
+pub(crate) const WATCHES_DESCRIPTORS: bool = false;
+
+/// Stops the backend when dropped.
+pub(crate) struct BackendHandle(AbortHandle);
+
+impl Drop for BackendHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub(crate) async fn start_backend<E: Display + Send + 'static>(
+    watcher_context: WatcherContext,
+    on_error: ErrorHook<E>,
+) -> BackendHandle {
+    let task = rt::spawn(async move {
+        let watcher_context = Arc::new(watcher_context);
+        let mut failures = 0;
+        loop {
+            match load_config::<E>(watcher_context.clone()).await {
+                Ok(()) => failures = 0,
+                Err(e) => {
+                    failures += 1;
+                    watcher_context.watch_error(&e, failures);
+                    on_error(&e);
+                    rt::sleep(watcher_context.retry_interval).await;
+                }
+            }
+        }
+    });
+    BackendHandle(task.abort_handle())
+}
+
+/// How long FSEvents may hold on to events to coalesce them.
+const LATENCY: f64 = 0.05;
+
+/// Flags meaning the events we would compare against are unreliable, or the watched roots moved.
+const RESCAN: u32 = fs::kFSEventStreamEventFlagRootChanged
+    | fs::kFSEventStreamEventFlagMustScanSubDirs
+    | fs::kFSEventStreamEventFlagUserDropped
+    | fs::kFSEventStreamEventFlagKernelDropped
+    | fs::kFSEventStreamEventFlagUnmount;
+
+/// Flags meaning an entry was replaced rather than modified in place.
+const REPLACED: u32 = fs::kFSEventStreamEventFlagItemCreated
+    | fs::kFSEventStreamEventFlagItemRemoved
+    | fs::kFSEventStreamEventFlagItemRenamed;
+
+/// Flags meaning an entry's permissions, ownership, or other attributes changed.
+const ATTRIBUTES: u32 = fs::kFSEventStreamEventFlagItemInodeMetaMod
+    | fs::kFSEventStreamEventFlagItemChangeOwner
+    | fs::kFSEventStreamEventFlagItemXattrMod
+    | fs::kFSEventStreamEventFlagItemFinderInfoMod;
+
+/// What an event was, for the event filter. Flags of events close together are merged.
+fn kind(flags: u32) -> ChangeKind {
+    if flags & fs::kFSEventStreamEventFlagItemCreated != 0 {
+        ChangeKind::Created
+    } else if flags & fs::kFSEventStreamEventFlagItemRemoved != 0 {
+        ChangeKind::Removed
+    } else if flags & fs::kFSEventStreamEventFlagItemRenamed != 0 {
+        ChangeKind::Renamed
+    } else if flags & fs::kFSEventStreamEventFlagItemModified != 0 {
+        ChangeKind::Modified
+    } else if flags & ATTRIBUTES != 0 {
+        ChangeKind::Attributes
+    } else {
+        ChangeKind::Other
+    }
+}
+
+/// FSEvents reports resolved paths, so paths are compared with their parent directory resolved.
+fn real_path(path: &Path) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => std::fs::canonicalize(parent)
+            .map(|x| x.join(name))
+            .unwrap_or_else(|_| path.to_path_buf()),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// The closest ancestor of `path` that exists, FSEvents can't watch missing paths.
+fn existing_ancestor(path: &Path) -> PathBuf {
+    path.ancestors()
+        .skip(1)
+        .find(|x| x.exists())
+        .unwrap_or(Path::new("/"))
+        .to_path_buf()
+}
+
+pub(crate) async fn load_config<E: Display + Send + 'static>(
+    context: Arc<WatcherContext>,
+) -> Result<(), FileWatcherError<E>> {
+    // every path along the chain of symlinks to the target, where replacing any of them is a change
+    let mut hops = vec![];
+    // directories where any change within is a change to the target
+    let mut contents = None;
+    let mut current_main_file = context.file.clone();
+    let mut links = Links::new(context.max_symlink_depth);
+    loop {
+        hops.push(current_main_file.clone());
+        let missing = match context.mode {
+            WatchMode::Sentinel => true,
+            _ => {
+                context.optional
+                    && matches!(
+                        std::fs::symlink_metadata(&current_main_file),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound
+                    )
+            }
+        };
+        if missing {
+            break;
+        }
+        let metadata = std::fs::symlink_metadata(&current_main_file)?;
+        if metadata.is_symlink() {
+            links.follow(&current_main_file)?;
+            let link = std::fs::read_link(&current_main_file)?;
+            current_main_file = normalize(&match current_main_file.parent() {
+                Some(parent) if link.is_relative() => parent.join(link),
+                _ => link,
+            })?;
+            continue;
+        }
+        context.logger.log(
+            Level::Debug,
+            0,
+            format_args!("watching main target {}", current_main_file.display()),
+        );
+        match &context.mode {
+            // only the atomic swap matters, everything else is kubelet staging the next version
+            WatchMode::ConfigMap => hops.push(current_main_file.join(DATA_LINK)),
+            WatchMode::Directory { .. } | WatchMode::Tree(_) => {
+                contents = Some((
+                    std::fs::canonicalize(&current_main_file)?,
+                    matches!(context.mode, WatchMode::Tree(_)),
+                ))
+            }
+            _ => (),
+        }
+        break;
+    }
+
+    // a single stream covers the whole chain, no matter how deep, rather than a descriptor per directory
+    let mut roots: Vec<PathBuf> = hops.iter().map(|x| existing_ancestor(x)).collect();
+    roots.sort();
+    roots.dedup();
+    let hops: Vec<PathBuf> = hops.iter().map(|x| real_path(x)).collect();
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    context.established(
+        roots
+            .iter()
+            .map(|root| (root.clone(), "FileEvents | WatchRoot, subtree".to_string())),
+    );
+    start_stream(roots, sender)?;
+
+    while let Some((path, flags)) = receiver.recv().await {
+        context.logger.log(
+            Level::Debug,
+            0,
+            format_args!("received event {flags:#x} for {}", path.display()),
+        );
+        if flags & RESCAN != 0 {
+            context.notify.notify_one();
+            return Ok(());
+        }
+        if !context.attribute_changes
+            && flags & ATTRIBUTES != 0
+            && flags & (REPLACED | fs::kFSEventStreamEventFlagItemModified) == 0
+        {
+            // only attributes changed, which we weren't asked to reload for
+            continue;
+        }
+        if hops.contains(&path) {
+            context.report(kind(flags), &path);
+            // the chain itself changed, rather than the contents at the end of it
+            if flags & (REPLACED | fs::kFSEventStreamEventFlagItemIsSymlink) != 0 {
+                return Ok(());
+            }
+            continue;
+        }
+        if flags & REPLACED != 0 && hops.iter().any(|x| x.starts_with(&path)) {
+            // a directory along the way was replaced, or created for a missing target
+            context.report(kind(flags), &path);
+            return Ok(());
+        }
+        if let Some((dir, recursive)) = &contents {
+            let within = match path.strip_prefix(dir) {
+                Ok(relative) => *recursive || relative.components().count() == 1,
+                Err(_) => false,
+            };
+            if within {
+                context.report(kind(flags), &path);
+            }
+        }
+    }
+    Err(io::Error::other("fsevents stream stopped").into())
+}
+
+type Sender = mpsc::UnboundedSender<(PathBuf, u32)>;
+
+/// Runs an FSEvents stream over `roots` on its own thread, which exits once `sender` is closed.
+fn start_stream(roots: Vec<PathBuf>, sender: Sender) -> io::Result<()> {
+    let roots = roots
+        .into_iter()
+        .map(|x| crate::fs::c_path(&x))
+        .collect::<io::Result<Vec<_>>>()?;
+    let (started, result) = std::sync::mpsc::sync_channel(1);
+    crate::thread::spawn("fsevents", move || {
+        let sender = Box::new(sender);
+        let paths = unsafe {
+            let paths =
+                cf::CFArrayCreateMutable(cf::kCFAllocatorDefault, 0, &cf::kCFTypeArrayCallBacks);
+            for root in &roots {
+                let path = cf::CFStringCreateWithCString(
+                    cf::kCFAllocatorDefault,
+                    root.as_ptr(),
+                    cf::kCFStringEncodingUTF8,
+                );
+                cf::CFArrayAppendValue(paths, path);
+                cf::CFRelease(path);
+            }
+            paths
+        };
+        let stream_context = fs::FSEventStreamContext {
+            version: 0,
+            info: &*sender as *const Sender as *mut c_void,
+            retain: None,
+            release: None,
+            copy_description: None,
+        };
+        let stream = unsafe {
+            let stream = fs::FSEventStreamCreate(
+                cf::kCFAllocatorDefault,
+                callback,
+                &stream_context,
+                paths,
+                fs::kFSEventStreamEventIdSinceNow,
+                LATENCY,
+                fs::kFSEventStreamCreateFlagFileEvents
+                    | fs::kFSEventStreamCreateFlagWatchRoot
+                    | fs::kFSEventStreamCreateFlagNoDefer,
+            );
+            cf::CFRelease(paths);
+            stream
+        };
+        if stream.is_null() {
+            started
+                .send(Err(io::Error::other("failed to create fsevents stream")))
+                .ok();
+            return;
+        }
+        unsafe {
+            fs::FSEventStreamScheduleWithRunLoop(
+                stream,
+                cf::CFRunLoopGetCurrent(),
+                cf::kCFRunLoopDefaultMode,
+            );
+            if fs::FSEventStreamStart(stream) == 0 {
+                fs::FSEventStreamInvalidate(stream);
+                fs::FSEventStreamRelease(stream);
+                started
+                    .send(Err(io::Error::other("failed to start fsevents stream")))
+                    .ok();
+                return;
+            }
+        }
+        started.send(Ok(())).ok();
+        // the watcher going away closes the channel, which is checked between short runs of the loop
+        while !sender.is_closed() {
+            unsafe { CFRunLoopRunInMode(cf::kCFRunLoopDefaultMode, 0.5, 0) };
+        }
+        unsafe {
+            fs::FSEventStreamStop(stream);
+            fs::FSEventStreamInvalidate(stream);
+            fs::FSEventStreamRelease(stream);
+        }
+    })?;
+    result
+        .recv()
+        .map_err(|_| io::Error::other("fsevents thread exited"))?
+}
+
+extern "C" fn callback(
+    _stream: fs::FSEventStreamRef,
+    info: *mut c_void,
+    count: usize,
+    paths: *mut c_void,
+    flags: *const fs::FSEventStreamEventFlags,
+    _ids: *const fs::FSEventStreamEventId,
+) {
+    let sender = unsafe { &*(info as *const Sender) };
+    let paths = paths as *const *const std::ffi::c_char;
+    for i in 0..count {
+        let (path, flags) = unsafe { (CStr::from_ptr(*paths.add(i)), *flags.add(i)) };
+        let path = PathBuf::from(std::ffi::OsStr::from_bytes(path.to_bytes()));
+        sender.send((path, flags)).ok();
+    }
+}
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    fn CFRunLoopRunInMode(
+        mode: cf::CFStringRef,
+        seconds: f64,
+        return_after_source_handled: cf::Boolean,
+    ) -> i32;
+}
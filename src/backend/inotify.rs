@@ -2,168 +2,496 @@ use std::{
     collections::{HashMap, HashSet},
     ffi::OsString,
     fmt::Display,
+    fs::Metadata,
+    io,
+    os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
-use futures::{pin_mut, StreamExt};
-use log::{debug, error};
+use crate::logging::Logger;
+use async_stream::stream;
+use futures::{future::Either, pin_mut, StreamExt};
+use log::Level;
+use tokio::sync::watch;
 
+use super::{
+    mounts, poll,
+    shared_inotify::{SharedINotify, Subscription},
+    Links,
+};
 use crate::{
-    inotify::{normalize, INotify, INotifyMask, WatchHandle},
-    FileWatcherError, WatcherContext,
+    directory::{walk_within, TreeOptions, WatchMode},
+    fs,
+    inotify::{INotify, INotifyEvent, INotifyMask, WatchHandle},
+    k8s::DATA_LINK,
+    normalize,
+    rt::{self, AbortHandle},
+    Backend, ChangeKind, ErrorHook, FileWatcherError, LifecycleEvent, SymlinkError, WatcherContext,
 };
 
+/// Whether open files can be watched through their `/proc/self/fd` path, otherwise they are polled.
+pub(crate) const WATCHES_DESCRIPTORS: bool = true;
+
+/// Stops the backend when dropped.
+pub(crate) struct BackendHandle(AbortHandle);
+
+impl Drop for BackendHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
 pub(crate) async fn start_backend<E: Display + Send + 'static>(
     mut watcher_context: WatcherContext,
-) {
-    tokio::spawn(async move {
-        watcher_context.file = normalize(&watcher_context.file);
+    on_error: ErrorHook<E>,
+) -> BackendHandle {
+    let task = rt::spawn(async move {
+        watcher_context.file = match normalize(&watcher_context.file) {
+            Ok(file) => file,
+            Err(e) => {
+                let e = e.into();
+                watcher_context.watch_error(&e, 1);
+                on_error(&e);
+                return;
+            }
+        };
         let watcher_context = Arc::new(watcher_context);
-        loop {
-            if let Err(e) = load_config::<E>(watcher_context.clone()).await {
-                error!(
-                    "{} watch error: {e} @ '{}'",
+        if let Some(timeout) = watcher_context.self_test {
+            if !passes_self_test(&watcher_context, timeout).await {
+                let interval = watcher_context
+                    .poll_interval
+                    .unwrap_or(watcher_context.retry_interval);
+                watcher_context.logger.log(Level::Warn, 0, format_args!(
+                    "{} inotify events are not delivered for '{}' within {:.1} second(s), polling every {:.1} second(s) instead",
                     watcher_context.log_name,
-                    watcher_context.file.display()
-                );
-                tokio::time::sleep(watcher_context.retry_interval).await;
+                    watcher_context.file.display(),
+                    timeout.as_secs_f64(),
+                    interval.as_secs_f64()
+                ));
+                watcher_context.lifecycle(LifecycleEvent::BackendStarted {
+                    path: watcher_context.file.clone(),
+                    backend: Backend::Poll,
+                });
+                return poll::poll(&watcher_context, interval).await;
+            }
+        }
+        let mut failures = 0;
+        loop {
+            match load_config::<E>(watcher_context.clone()).await {
+                Ok(()) => failures = 0,
+                Err(FileWatcherError::Io(e)) if e.raw_os_error() == Some(libc::ENOSPC) => {
+                    // retrying won't free up watches, and other watchers may need them more
+                    let interval = watcher_context
+                        .poll_interval
+                        .unwrap_or(watcher_context.retry_interval);
+                    watcher_context.logger.log(Level::Warn, 0, format_args!(
+                        "{} inotify watch limit reached, raise fs.inotify.max_user_watches. Polling every {:.1} second(s) instead @ '{}'",
+                        watcher_context.log_name,
+                        interval.as_secs_f64(),
+                        watcher_context.file.display()
+                    ));
+                    on_error(&FileWatcherError::WatchLimit(e));
+                    watcher_context.lifecycle(LifecycleEvent::BackendStarted {
+                        path: watcher_context.file.clone(),
+                        backend: Backend::Poll,
+                    });
+                    return poll::poll(&watcher_context, interval).await;
+                }
+                Err(e) => {
+                    failures += 1;
+                    watcher_context.watch_error(&e, failures);
+                    on_error(&e);
+                    rt::sleep(watcher_context.retry_interval).await;
+                }
             }
         }
     });
+    BackendHandle(task.abort_handle())
 }
 
-const MAX_ITER: usize = 16;
+/// Whether events are delivered for the directory holding the target, or the target directory. Passes if that can't be told,
+/// i.e. the directory is read-only or missing, since the regular watches will report any errors.
+async fn passes_self_test(context: &WatcherContext, timeout: Duration) -> bool {
+    let dir = match context.mode {
+        WatchMode::Descriptor => return true,
+        WatchMode::Directory { .. } | WatchMode::Tree(_) | WatchMode::ConfigMap => {
+            fs::canonicalize(&context.file).await
+        }
+        _ => match context.file.parent() {
+            Some(parent) => fs::canonicalize(parent).await,
+            None => return true,
+        },
+    };
+    let Ok(dir) = dir else {
+        return true;
+    };
+    match self_test(&dir, timeout).await {
+        Ok(passed) => passed,
+        Err(e) => {
+            context.logger.log(
+                Level::Debug,
+                0,
+                format_args!("skipping self test of '{}': {e}", dir.display()),
+            );
+            true
+        }
+    }
+}
 
-pub(crate) async fn load_config<E: Display + Send + 'static>(
-    context: Arc<WatcherContext>,
-) -> Result<(), FileWatcherError<E>> {
-    let mut notify = INotify::new()?;
-    let mut watch_handles = vec![];
-    let mut interesting_children: HashMap<WatchHandle, OsString> = HashMap::new();
-    let mut symlinks: HashSet<WatchHandle> = HashSet::new();
+/// Creates and removes a hidden file in `dir`, waiting up to `timeout` for inotify to report it. Runs before the regular watches
+/// are set up, which therefore never see the file.
+async fn self_test(dir: &Path, timeout: Duration) -> io::Result<bool> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let notify = INotify::new()?;
+    notify.add_watch(dir, INotifyMask::Create | INotifyMask::OnlyDir)?;
+    let name = OsString::from(format!(
+        ".really-notify-self-test-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let probe = dir.join(&name);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)?;
+    std::fs::remove_file(&probe).ok();
+    let stream = notify.stream();
+    pin_mut!(stream);
+    let delivered = rt::timeout(timeout, async {
+        while let Some(event) = stream.next().await {
+            if event?.name == name {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    });
+    delivered.await.unwrap_or(Ok(false))
+}
+
+/// What events on a watch mean.
+#[derive(Clone, Debug, PartialEq)]
+enum Role {
+    /// Any event is a change.
+    Notify,
+    /// A symlink on the way to the target, any event is a change and the path to the target needs resolving again.
+    Link,
+    /// A directory on the way to the target, where only events for the named child matter, and need the path resolved again.
+    Child(OsString),
+    /// A directory in a watched tree, relative to the tree root.
+    Tree(PathBuf),
+}
+
+impl Role {
+    /// Which role wins if the same inode is watched through different paths.
+    fn rank(&self) -> u8 {
+        match self {
+            Role::Child(_) => 3,
+            Role::Link => 2,
+            Role::Tree(_) => 1,
+            Role::Notify => 0,
+        }
+    }
+}
+
+/// What is at a path, so a watch is only registered again if it was replaced.
+type Identity = (u64, u64);
+
+fn identity(metadata: &Metadata) -> Identity {
+    (metadata.dev(), metadata.ino())
+}
+
+/// Where a watcher's watches are registered, either an instance of its own, or one shared with other watchers.
+#[derive(Clone, Copy)]
+enum Instance<'a> {
+    Own(&'a INotify),
+    Shared(&'a Subscription),
+}
+
+impl Instance<'_> {
+    fn add_watch(self, path: &Path, mask: INotifyMask) -> io::Result<WatchHandle> {
+        match self {
+            Instance::Own(notify) => notify.add_watch(path, mask),
+            Instance::Shared(subscription) => subscription.add_watch(path, mask),
+        }
+    }
+
+    fn rm_watch(self, handle: WatchHandle) -> io::Result<()> {
+        match self {
+            Instance::Own(notify) => notify.rm_watch(handle),
+            Instance::Shared(subscription) => subscription.rm_watch(handle),
+        }
+    }
+}
+
+/// A watch the target needs.
+struct Planned {
+    path: PathBuf,
+    mask: INotifyMask,
+    role: Role,
+    identity: Identity,
+}
+
+/// Every watch needed along the path to the target, and the resolved root of a watched tree, whose subdirectories are
+/// watched separately.
+struct Plan {
+    watches: Vec<Planned>,
+    tree_root: Option<PathBuf>,
+}
+
+const TREE_MASK: INotifyMask = INotifyMask::CloseWrite
+    .or(INotifyMask::Create)
+    .or(INotifyMask::Delete)
+    .or(INotifyMask::DeleteSelf)
+    .or(INotifyMask::Modify)
+    .or(INotifyMask::MoveSelf)
+    .or(INotifyMask::MovedFrom)
+    .or(INotifyMask::MovedTo);
+
+const LINK_MASK: INotifyMask = INotifyMask::CloseWrite
+    .or(INotifyMask::DeleteSelf)
+    .or(INotifyMask::Modify)
+    .or(INotifyMask::MoveSelf)
+    .or(INotifyMask::DontFollow);
+
+/// Extra events for the target (or within a watched directory) if attribute changes count.
+fn attribute_mask(context: &WatcherContext) -> INotifyMask {
+    if context.attribute_changes {
+        INotifyMask::AttributeChanged
+    } else {
+        INotifyMask::none()
+    }
+}
+
+/// What an event was, for the event filter.
+fn kind(mask: INotifyMask) -> ChangeKind {
+    if mask.contains(INotifyMask::Create) {
+        ChangeKind::Created
+    } else if mask.intersects(INotifyMask::Delete | INotifyMask::DeleteSelf | INotifyMask::Ignored)
+    {
+        ChangeKind::Removed
+    } else if mask.intersects(INotifyMask::MovedFrom | INotifyMask::MovedTo | INotifyMask::MoveSelf)
+    {
+        ChangeKind::Renamed
+    } else if mask.contains(INotifyMask::CloseWrite) {
+        ChangeKind::ClosedWrite
+    } else if mask.contains(INotifyMask::AttributeChanged) {
+        ChangeKind::Attributes
+    } else if mask.contains(INotifyMask::Modify) {
+        ChangeKind::Modified
+    } else {
+        ChangeKind::Other
+    }
+}
+
+/// Reports `event` as a change, only looking up the path it happened to if there is a filter to hand it to, or a history
+/// to record it in.
+fn report(context: &WatcherContext, watches: &Watches, event: &INotifyEvent) {
+    if context.event_filter.is_none()
+        && !context.mode.ignores_editor_artifacts()
+        && context.history.is_none()
+    {
+        context.notify.notify_one();
+        return;
+    }
+    let Some(path) = watches.path(event.watch_descriptor) else {
+        return;
+    };
+    let path = if event.name.is_empty() {
+        path
+    } else {
+        path.join(&event.name)
+    };
+    context.report(kind(event.mask), &path);
+}
+
+/// Resolves the path to the target, listing the watches it needs.
+async fn plan(context: &WatcherContext) -> io::Result<Plan> {
+    let mut watches = vec![];
+    let mut tree_root = None;
     let mut current_main_file = context.file.clone();
     let mut hanging_dirs = vec![];
     let mut seen_dirs: HashSet<PathBuf> = HashSet::new();
+    // whether the target may be missing, and we need to watch its parent for it to be created
+    let mut watch_creation = false;
+    let attributes = attribute_mask(context);
+    let mut links = Links::new(context.max_symlink_depth);
     loop {
-        debug!(
-            "watching main target or link {}",
-            current_main_file.display()
-        );
-        let main_notify = notify.add_watch(
-            &current_main_file,
-            INotifyMask::CloseWrite
-                | INotifyMask::DeleteSelf
-                | INotifyMask::Modify
-                | INotifyMask::MoveSelf
-                | INotifyMask::DontFollow,
-        )?;
-        watch_handles.push(main_notify);
+        if let WatchMode::Descriptor = context.mode {
+            // follows the magic link to the open file, wherever it is linked now
+            watches.push(Planned {
+                identity: identity(&fs::metadata(&current_main_file).await?),
+                path: current_main_file,
+                mask: INotifyMask::CloseWrite
+                    | INotifyMask::DeleteSelf
+                    | INotifyMask::Modify
+                    | INotifyMask::MoveSelf
+                    | attributes,
+                role: Role::Notify,
+            });
+            break;
+        }
+        let missing = match context.mode {
+            WatchMode::Sentinel => true,
+            _ => {
+                context.optional
+                    && matches!(
+                        fs::symlink_metadata(&current_main_file).await,
+                        Err(e) if e.kind() == io::ErrorKind::NotFound
+                    )
+            }
+        };
+        if missing {
+            // the target may not exist, so we only watch its nearest existing ancestor for it (or the path to it) coming and going
+            watch_creation = true;
+            let mut child: &Path = &current_main_file;
+            while let Some(parent) = child.parent() {
+                if fs::symlink_metadata(parent).await.is_ok() {
+                    hanging_dirs.push((
+                        parent.to_path_buf(),
+                        child.file_name().map(|x| x.to_os_string()),
+                    ));
+                    break;
+                }
+                child = parent;
+            }
+            break;
+        }
+        let mut main_mask = INotifyMask::CloseWrite
+            | INotifyMask::DeleteSelf
+            | INotifyMask::Modify
+            | INotifyMask::MoveSelf
+            | INotifyMask::DontFollow
+            | attributes;
+        if context.mode.is_directory() {
+            // only meaningful on the final directory, harmless on symlinks along the way
+            main_mask |= INotifyMask::Create
+                | INotifyMask::Delete
+                | INotifyMask::MovedFrom
+                | INotifyMask::MovedTo;
+        }
         if let Some(parent) = current_main_file.parent() {
             hanging_dirs.push((
                 parent.to_path_buf(),
-                Some(current_main_file.file_name().unwrap().to_os_string()),
+                current_main_file.file_name().map(|x| x.to_os_string()),
             ));
         }
-        let main_file_metadata = tokio::fs::symlink_metadata(&current_main_file).await?;
+        let main_file_metadata = fs::symlink_metadata(&current_main_file).await?;
+        let role = if main_file_metadata.is_symlink() {
+            Role::Link
+        } else if let WatchMode::Tree(_) = context.mode {
+            tree_root = Some(current_main_file.clone());
+            Role::Tree(PathBuf::new())
+        } else {
+            Role::Notify
+        };
+        watches.push(Planned {
+            path: current_main_file.clone(),
+            mask: main_mask,
+            role,
+            identity: identity(&main_file_metadata),
+        });
         if main_file_metadata.is_symlink() {
-            symlinks.insert(main_notify);
-            let link = tokio::fs::read_link(&current_main_file).await?;
+            links.follow(&current_main_file)?;
+            let link = fs::read_link(&current_main_file).await?;
             current_main_file = if link.is_relative() {
                 current_main_file.parent().unwrap().join(link)
             } else {
                 link
             };
-            current_main_file = normalize(&current_main_file);
+            current_main_file = normalize(&current_main_file)?;
         } else {
             break;
         }
     }
+    let mut ancestor_mask = INotifyMask::Delete
+        | INotifyMask::DeleteSelf
+        | INotifyMask::Modify
+        | INotifyMask::MoveSelf
+        | INotifyMask::MovedFrom
+        | INotifyMask::MovedTo
+        | INotifyMask::DontFollow;
+    if watch_creation {
+        ancestor_mask |= INotifyMask::Create;
+    }
     let mut next_round = hanging_dirs;
     let mut round_count = 0usize;
     loop {
         let mut round = std::mem::take(&mut next_round);
-        if round.is_empty() || round_count > MAX_ITER {
+        let Some((dir, _)) = round.first() else {
             break;
+        };
+        // the first round is the path to the target itself, the rest through symlinked ancestors
+        if round_count > context.max_symlink_depth {
+            return Err(SymlinkError::TooDeep {
+                path: dir.clone(),
+                depth: context.max_symlink_depth,
+            }
+            .into());
         }
         round_count += 1;
         while let Some((dir, child)) = round.pop() {
             if seen_dirs.contains(&dir) {
                 continue;
             }
-            let dir_metadata = tokio::fs::symlink_metadata(&dir).await?;
-            debug!("watching ancestor {}", dir.display());
+            let dir_metadata = fs::symlink_metadata(&dir).await?;
             if dir_metadata.is_symlink() {
-                let mut link = tokio::fs::read_link(&dir).await?;
+                let mut link = fs::read_link(&dir).await?;
                 if link.is_relative() {
                     link = dir.parent().unwrap().join(link);
                 }
-                link = normalize(&link);
+                link = normalize(&link)?;
                 next_round.push((link, child));
-                watch_handles.push(notify.add_watch(
-                    &dir,
-                    INotifyMask::CloseWrite
-                        | INotifyMask::DeleteSelf
-                        | INotifyMask::Modify
-                        | INotifyMask::MoveSelf
-                        | INotifyMask::DontFollow,
-                )?);
-            } else {
-                let watcher = notify.add_watch(
-                    &dir,
-                    INotifyMask::Delete
-                        | INotifyMask::DeleteSelf
-                        | INotifyMask::Modify
-                        | INotifyMask::MoveSelf
-                        | INotifyMask::MovedFrom
-                        | INotifyMask::MovedTo
-                        | INotifyMask::DontFollow,
-                )?;
-                watch_handles.push(watcher);
-                interesting_children
-                    .insert(watcher, child.expect("missing child for non-symlink root"));
+                watches.push(Planned {
+                    path: dir.clone(),
+                    mask: LINK_MASK,
+                    role: Role::Notify,
+                    identity: identity(&dir_metadata),
+                });
+            } else if let Some(child) = child {
+                watches.push(Planned {
+                    path: dir.clone(),
+                    mask: ancestor_mask,
+                    role: Role::Child(child),
+                    identity: identity(&dir_metadata),
+                });
             }
             seen_dirs.insert(dir.clone());
             let mut current_child: &Path = &dir;
             let mut current_parent = current_child.parent();
             while let Some(parent) = current_parent {
+                // lexically above a `..`, which isn't on the way to the target
+                let Some(name) = current_child.file_name() else {
+                    break;
+                };
                 if seen_dirs.contains(parent) {
                     break;
                 }
-                debug!("watching ancestor {}", parent.display());
-                let metadata = tokio::fs::symlink_metadata(parent).await?;
+                let metadata = fs::symlink_metadata(parent).await?;
                 if metadata.is_symlink() {
-                    let mut link = tokio::fs::read_link(parent).await?;
+                    let mut link = fs::read_link(parent).await?;
                     if link.is_relative() {
                         link = dir.parent().unwrap().join(link);
                     }
-                    link = normalize(&link);
-                    next_round.push((
-                        link,
-                        Some(current_child.file_name().unwrap().to_os_string()),
-                    ));
-                    watch_handles.push(notify.add_watch(
-                        parent,
-                        INotifyMask::CloseWrite
-                            | INotifyMask::DeleteSelf
-                            | INotifyMask::Modify
-                            | INotifyMask::MoveSelf
-                            | INotifyMask::DontFollow,
-                    )?);
+                    link = normalize(&link)?;
+                    next_round.push((link, Some(name.to_os_string())));
+                    watches.push(Planned {
+                        path: parent.to_path_buf(),
+                        mask: LINK_MASK,
+                        role: Role::Notify,
+                        identity: identity(&metadata),
+                    });
                 } else {
-                    let watcher = notify.add_watch(
-                        parent,
-                        INotifyMask::Delete
-                            | INotifyMask::DeleteSelf
-                            | INotifyMask::Modify
-                            | INotifyMask::MoveSelf
-                            | INotifyMask::MovedFrom
-                            | INotifyMask::MovedTo
-                            | INotifyMask::DontFollow,
-                    )?;
-                    watch_handles.push(watcher);
-                    interesting_children
-                        .insert(watcher, current_child.file_name().unwrap().to_os_string());
+                    watches.push(Planned {
+                        path: parent.to_path_buf(),
+                        mask: ancestor_mask,
+                        role: Role::Child(name.to_os_string()),
+                        identity: identity(&metadata),
+                    });
                 }
                 seen_dirs.insert(parent.to_path_buf());
 
@@ -172,33 +500,547 @@ pub(crate) async fn load_config<E: Display + Send + 'static>(
             }
         }
     }
+    Ok(Plan { watches, tree_root })
+}
 
-    let stream = notify.stream();
+/// The watches currently registered for a target.
+#[derive(Default)]
+struct Watches {
+    /// By path, with what they were registered with.
+    paths: HashMap<PathBuf, (WatchHandle, INotifyMask, Identity)>,
+    roles: HashMap<WatchHandle, Role>,
+    tree_root: Option<PathBuf>,
+    /// Whether attribute changes within the tree count.
+    attribute_changes: bool,
+    logger: Logger,
+}
+
+impl Watches {
+    /// Registers what changed since the last plan, and removes watches no longer needed.
+    /// Paths where the same inode is still watched with the same mask are left alone, so there is no window where their events are missed.
+    async fn apply(
+        &mut self,
+        notify: Instance<'_>,
+        plan: Plan,
+        mode: &WatchMode,
+    ) -> io::Result<()> {
+        let mut paths = HashMap::new();
+        let mut roles = HashMap::new();
+        for planned in plan.watches {
+            let handle = match self.paths.get(&planned.path) {
+                Some((handle, mask, identity))
+                    if *mask == planned.mask
+                        && *identity == planned.identity
+                        && self.roles.contains_key(handle) =>
+                {
+                    *handle
+                }
+                _ => {
+                    self.logger.log(
+                        Level::Debug,
+                        0,
+                        format_args!("watching {:?} {}", planned.role, planned.path.display()),
+                    );
+                    notify.add_watch(&planned.path, planned.mask)?
+                }
+            };
+            paths.insert(planned.path, (handle, planned.mask, planned.identity));
+            insert_role(&mut roles, handle, planned.role);
+        }
+        let old_roles = std::mem::replace(&mut self.roles, roles);
+        let old_paths = std::mem::replace(&mut self.paths, paths);
+        match (&plan.tree_root, mode) {
+            (Some(root), WatchMode::Tree(_)) if self.tree_root == plan.tree_root => {
+                // subdirectories of the same tree are kept up to date as they come and go
+                for (path, (handle, mask, identity)) in old_paths {
+                    if let Some(role @ Role::Tree(relative)) = old_roles.get(&handle) {
+                        if !relative.as_os_str().is_empty() {
+                            self.paths.insert(path, (handle, mask, identity));
+                            insert_role(&mut self.roles, handle, role.clone());
+                        }
+                    }
+                }
+                self.tree_root = Some(root.clone());
+            }
+            (Some(root), WatchMode::Tree(options)) => {
+                self.tree_root = Some(root.clone());
+                self.add_tree(notify, root, options).await?;
+            }
+            _ => self.tree_root = None,
+        }
+        for handle in old_roles.keys() {
+            if !self.roles.contains_key(handle) {
+                // already gone if what it watched was deleted
+                notify.rm_watch(*handle).ok();
+            }
+        }
+        Ok(())
+    }
+
+    /// Watches `dir` and every directory below it, for a directory that appeared in the tree.
+    async fn add_tree(
+        &mut self,
+        notify: Instance<'_>,
+        dir: &Path,
+        options: &TreeOptions,
+    ) -> io::Result<()> {
+        let Some(root) = self.tree_root.clone() else {
+            return Ok(());
+        };
+        let Ok(relative) = dir.strip_prefix(&root) else {
+            return Ok(());
+        };
+        if !relative.as_os_str().is_empty() && options.is_ignored(relative) {
+            return Ok(());
+        }
+        let (dirs, _) = walk_within(&root, dir, options).await?;
+        for dir in dirs {
+            let Ok(relative) = dir.strip_prefix(&root) else {
+                continue;
+            };
+            if relative.as_os_str().is_empty() {
+                continue;
+            }
+            let metadata = match fs::symlink_metadata(&dir).await {
+                Ok(x) => x,
+                // raced deletion, which the parent will tell us about
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            self.logger.log(
+                Level::Debug,
+                0,
+                format_args!("watching subdirectory {}", dir.display()),
+            );
+            let mask = if self.attribute_changes {
+                TREE_MASK | INotifyMask::AttributeChanged
+            } else {
+                TREE_MASK
+            };
+            let handle = notify.add_watch(&dir, mask)?;
+            insert_role(&mut self.roles, handle, Role::Tree(relative.to_path_buf()));
+            self.paths.insert(dir, (handle, mask, identity(&metadata)));
+        }
+        Ok(())
+    }
+
+    /// Stops watching `relative` and every directory below it, for a directory that left the tree.
+    fn remove_tree(&mut self, notify: Instance<'_>, relative: &Path) {
+        for (handle, ..) in self.detach_tree(relative) {
+            // already gone if it was deleted rather than moved
+            notify.rm_watch(handle).ok();
+        }
+    }
+
+    /// Stops watching `entry` of `dir`, on the way to the target, and everything watched below it, for an entry renamed
+    /// away, and watches `dir` for it being created again. Returns whether a symlink was among them, which may have led
+    /// to watches elsewhere.
+    fn remove_entry(&mut self, notify: Instance<'_>, dir: &Path, entry: &Path) -> io::Result<bool> {
+        let handles: Vec<(WatchHandle, INotifyMask)> = self
+            .paths
+            .iter()
+            .filter(|(path, _)| path.starts_with(entry))
+            .map(|(_, (handle, mask, _))| (*handle, *mask))
+            .collect();
+        let mut link = false;
+        for (handle, mask) in handles {
+            link |= matches!(self.forget(handle), Some(Role::Link)) || mask == LINK_MASK;
+            notify.rm_watch(handle).ok();
+        }
+        if let Some((_, mask, _)) = self.paths.get_mut(dir) {
+            if !mask.contains(INotifyMask::Create) {
+                *mask |= INotifyMask::Create;
+                notify.add_watch(dir, *mask)?;
+            }
+        }
+        Ok(link)
+    }
+
+    /// Forgets the watches of `relative` and every directory below it, without removing them.
+    fn detach_tree(&mut self, relative: &Path) -> Vec<Detached> {
+        let handles: Vec<(WatchHandle, PathBuf)> = self
+            .roles
+            .iter()
+            .filter_map(|(handle, role)| match role {
+                Role::Tree(x) if x.starts_with(relative) => Some((*handle, x.clone())),
+                _ => None,
+            })
+            .collect();
+        let mut out = vec![];
+        for (handle, relative) in handles {
+            if let Some((_, (_, mask, identity))) =
+                self.paths.iter().find(|(_, (x, _, _))| *x == handle)
+            {
+                out.push((handle, relative, *mask, *identity));
+            }
+            self.forget(handle);
+        }
+        out
+    }
+
+    /// Takes back the watches of a directory moved to `relative` within the tree.
+    fn attach_tree(&mut self, notify: Instance<'_>, moved: MovedFrom, relative: &Path) {
+        let Some(root) = self.tree_root.clone() else {
+            moved.remove(notify);
+            return;
+        };
+        for (handle, old, mask, identity) in moved.watches {
+            let Ok(within) = old.strip_prefix(&moved.relative) else {
+                continue;
+            };
+            let new = relative.join(within);
+            self.paths.insert(root.join(&new), (handle, mask, identity));
+            insert_role(&mut self.roles, handle, Role::Tree(new));
+        }
+    }
+
+    /// The paths watched, and the masks they are watched with.
+    fn established(&self) -> impl Iterator<Item = (PathBuf, String)> + '_ {
+        self.paths
+            .iter()
+            .map(|(path, (_, mask, _))| (path.clone(), format!("{mask:?}")))
+    }
+
+    fn path(&self, handle: WatchHandle) -> Option<PathBuf> {
+        self.paths
+            .iter()
+            .find(|(_, (x, _, _))| *x == handle)
+            .map(|(path, _)| path.clone())
+    }
+
+    /// Whether `plan` watches anything else than what is watched, or what is at a path was replaced.
+    fn differs(&self, plan: &Plan) -> bool {
+        plan.watches.iter().any(|planned| {
+            self.paths
+                .get(&planned.path)
+                .is_none_or(|(_, _, identity)| *identity != planned.identity)
+        })
+    }
+
+    /// Drops a watch the kernel removed, or we are about to.
+    fn forget(&mut self, handle: WatchHandle) -> Option<Role> {
+        self.paths.retain(|_, (x, _, _)| *x != handle);
+        self.roles.remove(&handle)
+    }
+}
+
+/// A watch forgotten but not removed, with the path it watches relative to the tree root.
+type Detached = (WatchHandle, PathBuf, INotifyMask, Identity);
+
+/// A directory moved out of its place in the tree, whose watches are kept in case it was only renamed within it.
+struct MovedFrom {
+    cookie: u32,
+    relative: PathBuf,
+    watches: Vec<Detached>,
+}
+
+impl MovedFrom {
+    /// It left the tree, so its watches are no longer needed.
+    fn remove(self, notify: Instance) {
+        for (handle, ..) in self.watches {
+            notify.rm_watch(handle).ok();
+        }
+    }
+}
+
+/// Resolves when the mount table changed, and never without a subscription.
+async fn mounts_changed(mounts: &mut Option<watch::Receiver<u64>>) {
+    if let Some(mounts) = mounts {
+        if mounts.changed().await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Polls `path` every `interval` until something is mounted there again, i.e. it reappears, or a mount point is back on its own device.
+async fn wait_for_remount(path: &Path, interval: Duration) {
+    async fn device(path: &Path) -> Option<u64> {
+        fs::metadata(path).await.ok().map(|x| x.dev())
+    }
+    let unmounted = device(path).await;
+    loop {
+        rt::sleep(interval).await;
+        let current = device(path).await;
+        if current.is_some() && current != unmounted {
+            return;
+        }
+    }
+}
+
+fn insert_role(roles: &mut HashMap<WatchHandle, Role>, handle: WatchHandle, role: Role) {
+    match roles.get(&handle) {
+        Some(existing) if existing.rank() >= role.rank() => (),
+        _ => {
+            roles.insert(handle, role);
+        }
+    }
+}
+
+pub(crate) async fn load_config<E: Display + Send + 'static>(
+    context: Arc<WatcherContext>,
+) -> Result<(), FileWatcherError<E>> {
+    let own;
+    let subscription;
+    let (notify, stream) = if context.shared_inotify {
+        let (x, mut receiver) = SharedINotify::subscribe()?;
+        subscription = x;
+        let events = stream! {
+            while let Some(event) = receiver.recv().await {
+                yield Ok(event);
+            }
+        };
+        (Instance::Shared(&subscription), Either::Right(events))
+    } else {
+        own = INotify::new()?;
+        (Instance::Own(&own), Either::Left(own.stream()))
+    };
+    // before planning, so no change after it is missed
+    let mut mounts = match context.mount_changes {
+        true => Some(mounts::subscribe()?),
+        false => None,
+    };
+    let mut watches = Watches {
+        attribute_changes: context.attribute_changes,
+        logger: context.logger.clone(),
+        ..Default::default()
+    };
+    watches
+        .apply(notify, plan(&context).await?, &context.mode)
+        .await?;
+    context.established(watches.established());
+
+    let mut moved_from: Option<MovedFrom> = None;
+    // the cookie and name of an entry renamed within a directory on the way to the target, in case it was renamed over it
+    let mut moved_child: Option<(u32, OsString)> = None;
     pin_mut!(stream);
-    while let Some(event) = stream.next().await {
+    loop {
+        let event = tokio::select! {
+            event = stream.next() => event,
+            () = mounts_changed(&mut mounts) => {
+                if watches.differs(&plan(&context).await?) {
+                    // what we watch is covered by another mount now, or uncovered, without an event on the old inodes
+                    context.logger.log(Level::Info, 0, format_args!(
+                        "{} mount along the path changed, re-reading and rebuilding watches @ '{}'",
+                        context.log_name,
+                        context.file.display()
+                    ));
+                    context.report(ChangeKind::Other, &context.file);
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+        let Some(event) = event else {
+            break;
+        };
         let event = match event {
             Err(e) => {
                 return Err(e.into());
             }
             Ok(x) => x,
         };
-        debug!("received event {event:?}");
-        if let Some(interest) = interesting_children.get(&event.watch_descriptor) {
-            // a directory event we need to filter, and if applicable, always full refresh
-            if &event.name != interest {
-                continue;
-            }
+        context
+            .logger
+            .log(Level::Debug, 0, format_args!("received event {event:?}"));
+        if event.mask.contains(INotifyMask::QueueOverflow) {
+            // events were dropped, possibly the one we cared about, so assume a change and start over
+            context.logger.log(
+                Level::Warn,
+                0,
+                format_args!(
+                    "{} inotify queue overflowed, re-reading and rebuilding watches @ '{}'",
+                    context.log_name,
+                    context.file.display()
+                ),
+            );
             context.notify.notify_one();
-
             return Ok(());
-        } else if symlinks.contains(&event.watch_descriptor) {
-            // a symlink changed, we always reload and need a full refresh
+        }
+        if event.mask.contains(INotifyMask::Unmount) {
+            // every watch on the filesystem is gone, and reads would only fail until it is back
+            let Some(path) = watches.path(event.watch_descriptor) else {
+                continue;
+            };
+            context.logger.log(
+                Level::Warn,
+                0,
+                format_args!(
+                    "{} filesystem of '{}' was unmounted, waiting for it to be remounted @ '{}'",
+                    context.log_name,
+                    path.display(),
+                    context.file.display()
+                ),
+            );
+            wait_for_remount(&path, context.retry_interval).await;
+            context.logger.log(
+                Level::Info,
+                0,
+                format_args!(
+                    "{} filesystem of '{}' was remounted @ '{}'",
+                    context.log_name,
+                    path.display(),
+                    context.file.display()
+                ),
+            );
             context.notify.notify_one();
             return Ok(());
-        } else {
-            // the underlying file was modified, we don't need to full refresh
-            context.notify.notify_one();
         }
+        // a directory moved within the tree is reported as a pair of events, anything else in between means it left
+        let mut renamed = None;
+        if let Some(moved) = moved_from.take() {
+            if moved.cookie == event.cookie {
+                renamed = Some(moved);
+            } else {
+                moved.remove(notify);
+            }
+        }
+        let renamed_child = moved_child
+            .take()
+            .filter(|(cookie, _)| *cookie == event.cookie);
+        'event: {
+            if event.mask.contains(INotifyMask::Ignored) {
+                // the kernel removed the watch, i.e. what it watched was deleted
+                let removed = watches.path(event.watch_descriptor);
+                match watches.forget(event.watch_descriptor) {
+                    // the parent reports the directory leaving the tree
+                    Some(Role::Tree(relative)) if !relative.as_os_str().is_empty() => (),
+                    Some(_) => {
+                        let removed = removed.unwrap_or_else(|| context.file.clone());
+                        context.report(ChangeKind::Removed, &removed);
+                        watches
+                            .apply(notify, plan(&context).await?, &context.mode)
+                            .await?;
+                    }
+                    None => (),
+                }
+                break 'event;
+            }
+            let Some(role) = watches.roles.get(&event.watch_descriptor).cloned() else {
+                // left over from a watch we replaced
+                break 'event;
+            };
+            match role {
+                Role::Child(interest) => {
+                    // a directory event we need to filter, and if applicable, resolve the path again
+                    if event.name != interest {
+                        if event.mask.contains(INotifyMask::MovedFrom) {
+                            moved_child = Some((event.cookie, event.name.clone()));
+                        }
+                        break 'event;
+                    }
+                    if event.mask.contains(INotifyMask::MovedFrom) {
+                        let Some(dir) = watches.path(event.watch_descriptor) else {
+                            break 'event;
+                        };
+                        let entry = dir.join(&interest);
+                        context.logger.log(
+                            Level::Debug,
+                            0,
+                            format_args!("'{}' was renamed away", entry.display()),
+                        );
+                        context.report(ChangeKind::Removed, &entry);
+                        // what is left watched is unaffected, and watches the entry being created again
+                        if !watches.remove_entry(notify, &dir, &entry)? {
+                            break 'event;
+                        }
+                    } else if event.mask.contains(INotifyMask::MovedTo) {
+                        match &renamed_child {
+                            Some((_, from)) => context.logger.log(
+                                Level::Debug,
+                                0,
+                                format_args!(
+                                    "'{}' was replaced by renaming '{}' over it",
+                                    interest.to_string_lossy(),
+                                    from.to_string_lossy()
+                                ),
+                            ),
+                            None => context.logger.log(
+                                Level::Debug,
+                                0,
+                                format_args!(
+                                    "'{}' was replaced by a rename from another directory",
+                                    interest.to_string_lossy()
+                                ),
+                            ),
+                        }
+                        report(&context, &watches, &event);
+                    } else {
+                        report(&context, &watches, &event);
+                    }
+                    // watches of what is unchanged along the way are kept, only the replaced entry's are set up again
+                    watches
+                        .apply(notify, plan(&context).await?, &context.mode)
+                        .await?;
+                }
+                Role::Link => {
+                    // a symlink changed, we always resolve the path again
+                    report(&context, &watches, &event);
+                    watches
+                        .apply(notify, plan(&context).await?, &context.mode)
+                        .await?;
+                }
+                Role::Tree(relative) => {
+                    let WatchMode::Tree(options) = &context.mode else {
+                        break 'event;
+                    };
+                    let path = relative.join(&event.name);
+                    if options.is_ignored(&path) {
+                        break 'event;
+                    }
+                    report(&context, &watches, &event);
+                    if !event.mask.contains(INotifyMask::IsDir) {
+                        break 'event;
+                    }
+                    // directories appeared or disappeared, only those need watches added or removed
+                    if event.mask.contains(INotifyMask::MovedTo) && renamed.is_some() {
+                        // renamed within the tree, where the existing watches follow it
+                        context.logger.log(
+                            Level::Debug,
+                            0,
+                            format_args!(
+                                "'{}' was renamed to '{}'",
+                                renamed.as_ref().unwrap().relative.display(),
+                                path.display()
+                            ),
+                        );
+                        watches.attach_tree(notify, renamed.take().unwrap(), &path);
+                    } else if event
+                        .mask
+                        .intersects(INotifyMask::Create | INotifyMask::MovedTo)
+                    {
+                        if let Some(root) = watches.tree_root.clone() {
+                            watches.add_tree(notify, &root.join(&path), options).await?;
+                        }
+                    } else if event.mask.contains(INotifyMask::MovedFrom) {
+                        // kept until we know whether it was moved within the tree
+                        moved_from = Some(MovedFrom {
+                            cookie: event.cookie,
+                            watches: watches.detach_tree(&path),
+                            relative: path,
+                        });
+                    } else if event.mask.contains(INotifyMask::Delete) {
+                        watches.remove_tree(notify, &path);
+                    }
+                }
+                Role::Notify => {
+                    if let WatchMode::ConfigMap = context.mode {
+                        // only the atomic swap matters, everything else is kubelet staging the next version
+                        if event.name != DATA_LINK {
+                            break 'event;
+                        }
+                    }
+                    // the underlying file was modified, we don't need to resolve the path again
+                    report(&context, &watches, &event);
+                }
+            }
+        }
+        if let Some(moved) = renamed {
+            moved.remove(notify);
+        }
+        context.established(watches.established());
     }
+    // the shared instance stopped, i.e. the runtime it was started on shut down, so assume a change and start over on a new one
+    context.notify.notify_one();
     Ok(())
 }
@@ -0,0 +1,349 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt::Display,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::fs::MetadataExt,
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::Level;
+
+use super::Links;
+use crate::{
+    directory::{walk, WatchMode},
+    fs,
+    k8s::DATA_LINK,
+    normalize,
+    rt::{self, AbortHandle},
+    ChangeKind, ErrorHook, FileWatcherError, WatcherContext,
+};
+
+/// Whether open files can be watched through their `/dev/fd` path, otherwise they are polled.
+pub(crate) const WATCHES_DESCRIPTORS: bool = cfg!(any(target_os = "macos", target_os = "ios"));
+
+/// Stops the backend when dropped.
+pub(crate) struct BackendHandle(AbortHandle);
+
+impl Drop for BackendHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub(crate) async fn start_backend<E: Display + Send + 'static>(
+    watcher_context: WatcherContext,
+    on_error: ErrorHook<E>,
+) -> BackendHandle {
+    let task = rt::spawn(async move {
+        let watcher_context = Arc::new(watcher_context);
+        let mut failures = 0;
+        loop {
+            match load_config::<E>(watcher_context.clone()).await {
+                Ok(()) => failures = 0,
+                Err(e) => {
+                    failures += 1;
+                    watcher_context.watch_error(&e, failures);
+                    on_error(&e);
+                    rt::sleep(watcher_context.retry_interval).await;
+                }
+            }
+        }
+    });
+    BackendHandle(task.abort_handle())
+}
+
+/// Identifies what is at a path, so a directory event can be narrowed down to the children we care about.
+type Identity = Option<(u64, u64)>;
+
+/// What became of a child of a watched directory since it was recorded, for the event filter.
+fn replacement(previous: Identity, current: Identity) -> ChangeKind {
+    match (previous, current) {
+        (None, _) => ChangeKind::Created,
+        (_, None) => ChangeKind::Removed,
+        _ => ChangeKind::Renamed,
+    }
+}
+
+fn identity(path: &Path) -> Identity {
+    std::fs::symlink_metadata(path)
+        .ok()
+        .map(|x| (x.dev(), x.ino()))
+}
+
+/// A watched path. kqueue events don't carry names, so for directories we record the children we care about
+/// and compare them when the directory changes.
+#[derive(Default)]
+struct Node {
+    /// Any change is a change to the target.
+    target: bool,
+    children: Vec<(OsString, Identity)>,
+}
+
+/// Watch every ancestor of `path` for `path` (or the path to it) being replaced, following symlinked ancestors to where they point.
+/// Symlinked ancestors are followed at most `links` deep.
+fn watch_ancestors(nodes: &mut HashMap<PathBuf, Node>, path: &Path, links: usize) {
+    let mut child = path;
+    while let Some(parent) = child.parent() {
+        if let Some(name) = child.file_name() {
+            let node = nodes.entry(parent.to_path_buf()).or_default();
+            if !node.children.iter().any(|(x, _)| x == name) {
+                node.children.push((name.to_os_string(), identity(child)));
+            }
+        }
+        if links > 0 && std::fs::symlink_metadata(parent).is_ok_and(|x| x.is_symlink()) {
+            // the watch on `parent` follows the link, but the real directory can be replaced on its own
+            if let Ok(real) = std::fs::canonicalize(parent) {
+                watch_ancestors(nodes, &real, links - 1);
+            }
+        }
+        child = parent;
+    }
+}
+
+pub(crate) async fn load_config<E: Display + Send + 'static>(
+    context: Arc<WatcherContext>,
+) -> Result<(), FileWatcherError<E>> {
+    let mut nodes: HashMap<PathBuf, Node> = HashMap::new();
+    let mut current_main_file = context.file.clone();
+    let mut links = Links::new(context.max_symlink_depth);
+    loop {
+        if let WatchMode::Descriptor = context.mode {
+            // opening the `/dev/fd` path duplicates the descriptor, so the open file is watched wherever it is linked
+            nodes.entry(current_main_file.clone()).or_default().target = true;
+            break;
+        }
+        let missing = match context.mode {
+            WatchMode::Sentinel => true,
+            _ => {
+                context.optional
+                    && matches!(
+                        std::fs::symlink_metadata(&current_main_file),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound
+                    )
+            }
+        };
+        watch_ancestors(&mut nodes, &current_main_file, context.max_symlink_depth);
+        if missing {
+            // the ancestors are watched for the target (or the path to it) coming and going
+            break;
+        }
+        let metadata = std::fs::symlink_metadata(&current_main_file)?;
+        if metadata.is_symlink() {
+            // symlinks are replaced rather than modified, which the parent directory sees
+            links.follow(&current_main_file)?;
+            let link = std::fs::read_link(&current_main_file)?;
+            current_main_file = normalize(&match current_main_file.parent() {
+                Some(parent) if link.is_relative() => parent.join(link),
+                _ => link,
+            })?;
+            continue;
+        }
+        context.logger.log(
+            Level::Debug,
+            0,
+            format_args!("watching main target {}", current_main_file.display()),
+        );
+        match &context.mode {
+            WatchMode::ConfigMap => {
+                // only the atomic swap matters, everything else is kubelet staging the next version
+                let node = nodes.entry(current_main_file.clone()).or_default();
+                node.children.push((
+                    DATA_LINK.into(),
+                    identity(&current_main_file.join(DATA_LINK)),
+                ));
+            }
+            WatchMode::Tree(options) => {
+                let (dirs, _) = walk(&current_main_file, options).await?;
+                for dir in dirs {
+                    context.logger.log(
+                        Level::Debug,
+                        0,
+                        format_args!("watching subdirectory {}", dir.display()),
+                    );
+                    nodes.entry(dir).or_default().target = true;
+                }
+            }
+            _ => nodes.entry(current_main_file.clone()).or_default().target = true,
+        }
+        break;
+    }
+
+    let kqueue = unsafe { libc::kqueue() };
+    if kqueue < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let kqueue = rt::AsyncFd::new(unsafe { OwnedFd::from_raw_fd(kqueue) })?;
+    let mut watches: HashMap<RawFd, (PathBuf, Node, OwnedFd)> = HashMap::new();
+    for (path, node) in nodes {
+        let fd = match open(&path) {
+            Ok(x) => x,
+            // an ancestor of a missing target
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !node.target => continue,
+            Err(e) => return Err(e.into()),
+        };
+        register(
+            kqueue.get_ref().as_raw_fd(),
+            fd.as_raw_fd(),
+            context.attribute_changes,
+        )?;
+        watches.insert(fd.as_raw_fd(), (path, node, fd));
+    }
+    let mask = if context.attribute_changes {
+        "NOTE_WRITE | NOTE_EXTEND | NOTE_DELETE | NOTE_RENAME | NOTE_REVOKE | NOTE_ATTRIB"
+    } else {
+        "NOTE_WRITE | NOTE_EXTEND | NOTE_DELETE | NOTE_RENAME | NOTE_REVOKE"
+    };
+    context.established(
+        watches
+            .values()
+            .map(|(path, _, _)| (path.clone(), mask.to_string())),
+    );
+
+    loop {
+        let events = kqueue
+            .read_with(|fd| match read_events(fd.as_raw_fd())? {
+                events if events.is_empty() => Err(io::ErrorKind::WouldBlock.into()),
+                events => Ok(events),
+            })
+            .await?;
+        let mut rebuild = false;
+        for (fd, fflags) in events {
+            let Some((path, node, _)) = watches.get(&fd) else {
+                continue;
+            };
+            context.logger.log(
+                Level::Debug,
+                0,
+                format_args!("received event {fflags:#x} for {}", path.display()),
+            );
+            if fflags & (libc::NOTE_DELETE | libc::NOTE_RENAME | libc::NOTE_REVOKE) != 0 {
+                // the watched node itself went away or moved, the watch set needs a full refresh
+                context.report(kind(fflags), path);
+                rebuild = true;
+            } else if let Some((child, previous, current)) =
+                node.children.iter().find_map(|(name, previous)| {
+                    let child = path.join(name);
+                    let current = identity(&child);
+                    (current != *previous).then_some((child, *previous, current))
+                })
+            {
+                // something we care about within a directory was replaced, and needs a full refresh
+                context.report(replacement(previous, current), &child);
+                rebuild = true;
+            } else if node.target {
+                context.report(kind(fflags), path);
+                // directories may have appeared or disappeared
+                if let WatchMode::Tree(_) = context.mode {
+                    rebuild = true;
+                }
+            }
+        }
+        if rebuild {
+            return Ok(());
+        }
+    }
+}
+
+/// What an event was, for the event filter.
+fn kind(fflags: u32) -> ChangeKind {
+    if fflags & libc::NOTE_DELETE != 0 {
+        ChangeKind::Removed
+    } else if fflags & libc::NOTE_RENAME != 0 {
+        ChangeKind::Renamed
+    } else if fflags & (libc::NOTE_WRITE | libc::NOTE_EXTEND) != 0 {
+        ChangeKind::Modified
+    } else if fflags & libc::NOTE_ATTRIB != 0 {
+        ChangeKind::Attributes
+    } else {
+        ChangeKind::Other
+    }
+}
+
+/// Drains pending events without blocking, as (watched fd, fflags).
+fn read_events(kqueue: RawFd) -> io::Result<Vec<(RawFd, u32)>> {
+    let mut events: [libc::kevent; 32] = unsafe { std::mem::zeroed() };
+    let timeout = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    loop {
+        let count = unsafe {
+            libc::kevent(
+                kqueue,
+                std::ptr::null(),
+                0,
+                events.as_mut_ptr(),
+                events.len() as _,
+                &timeout,
+            )
+        };
+        if count >= 0 {
+            return Ok(events[..count as usize]
+                .iter()
+                .map(|x| (x.ident as RawFd, x.fflags))
+                .collect());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+fn open(path: &Path) -> io::Result<OwnedFd> {
+    let path = fs::c_path(path)?;
+    // doesn't prevent unmounting the volume
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    let flags = libc::O_EVTONLY;
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    let flags = libc::O_RDONLY;
+    let fd = unsafe { libc::open(path.as_ptr(), flags | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Whether a kqueue can be created at all.
+pub(crate) fn probe() -> io::Result<()> {
+    let kqueue = unsafe { libc::kqueue() };
+    if kqueue < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    drop(unsafe { OwnedFd::from_raw_fd(kqueue) });
+    Ok(())
+}
+
+fn register(kqueue: RawFd, fd: RawFd, attribute_changes: bool) -> io::Result<()> {
+    let mut change: libc::kevent = unsafe { std::mem::zeroed() };
+    change.ident = fd as _;
+    change.filter = libc::EVFILT_VNODE as _;
+    change.flags = (libc::EV_ADD | libc::EV_CLEAR) as _;
+    change.fflags = (libc::NOTE_WRITE
+        | libc::NOTE_EXTEND
+        | libc::NOTE_DELETE
+        | libc::NOTE_RENAME
+        | libc::NOTE_REVOKE) as _;
+    if attribute_changes {
+        change.fflags |= libc::NOTE_ATTRIB as libc::c_uint;
+    }
+    let result = unsafe {
+        libc::kevent(
+            kqueue,
+            &change,
+            1,
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null(),
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
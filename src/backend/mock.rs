@@ -0,0 +1,54 @@
+use std::{io, sync::Arc};
+
+use futures::future::BoxFuture;
+use tokio::sync::watch;
+
+use super::WatchBackend;
+use crate::WatcherContext;
+
+/// A backend for tests, where changes are only ever reported by calling [`MockBackend::trigger`].
+/// Set it with [`crate::FileWatcherConfig::with_custom_backend`], keeping a clone to trigger changes from the test.
+/// Triggers before a watcher starts watching (after its initial read) are delivered once it does, so no sleeps are needed.
+#[derive(Clone)]
+pub struct MockBackend(Arc<Triggers>);
+
+struct Triggers {
+    sender: watch::Sender<u64>,
+    /// Never updated, so clones of it see every trigger since the backend was created.
+    baseline: watch::Receiver<u64>,
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        let (sender, baseline) = watch::channel(0);
+        Self(Arc::new(Triggers { sender, baseline }))
+    }
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report a change to every watcher using this backend.
+    pub fn trigger(&self) {
+        self.0.sender.send_modify(|x| *x += 1);
+    }
+
+    /// How many targets are currently watched through this backend.
+    pub fn watching(&self) -> usize {
+        self.0.sender.receiver_count() - 1
+    }
+}
+
+impl WatchBackend for MockBackend {
+    fn watch<'a>(&'a self, context: &'a WatcherContext) -> BoxFuture<'a, io::Result<()>> {
+        let mut receiver = self.0.baseline.clone();
+        Box::pin(async move {
+            while receiver.changed().await.is_ok() {
+                context.changed();
+            }
+            Ok(())
+        })
+    }
+}
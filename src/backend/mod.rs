@@ -1,15 +1,603 @@
+use std::{
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+#[cfg(all(feature = "fanotify", any(target_os = "linux", target_os = "android")))]
+use crate::logging::debug;
+use log::Level;
+use tokio::{
+    select,
+    sync::{mpsc, watch},
+};
+
+use crate::{
+    directory::WatchMode,
+    fs::Stat,
+    is_missing, restart_delay,
+    rt::{self, AbortHandle, Instant},
+    stats::WatchSet,
+    ErrorHook, FileWatcherError, LifecycleEvent, SymlinkError, WatcherContext,
+};
+
+#[cfg(all(feature = "inotify", any(target_os = "linux", target_os = "android")))]
+mod inotify;
+
+#[cfg(all(feature = "inotify", any(target_os = "linux", target_os = "android")))]
+mod shared_inotify;
+
+#[cfg(all(feature = "inotify", any(target_os = "linux", target_os = "android")))]
+mod mounts;
+
+#[cfg(all(feature = "fanotify", any(target_os = "linux", target_os = "android")))]
+mod fanotify;
+
 #[cfg(all(
-    feature = "notify",
-    not(all(feature = "inotify", target_family = "unix"))
+    feature = "kqueue",
+    any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    )
 ))]
+mod kqueue;
+
+#[cfg(all(feature = "fsevents", target_os = "macos"))]
+mod fsevents;
+
+#[cfg(all(feature = "ports", any(target_os = "illumos", target_os = "solaris")))]
+mod ports;
+
+#[cfg(all(feature = "windows", windows))]
+mod windows;
+
+#[cfg(feature = "notify")]
 mod notify;
-#[cfg(all(
-    feature = "notify",
-    not(all(feature = "inotify", target_family = "unix"))
-))]
-pub(crate) use self::notify::*;
 
-#[cfg(all(feature = "inotify", target_family = "unix"))]
-mod inotify;
-#[cfg(all(feature = "inotify", target_family = "unix"))]
-pub(crate) use inotify::*;
+mod poll;
+
+mod custom;
+
+#[cfg(feature = "mock")]
+mod mock;
+
+pub use custom::{ChangeKind, TargetKind, WatchBackend};
+#[cfg(feature = "mock")]
+pub use mock::MockBackend;
+
+/// Symlinks followed so far on the way to a target, to catch cycles and chains longer than the watcher allows.
+#[allow(dead_code)] // unused by the notify and poll backends
+pub(crate) struct Links {
+    followed: Vec<PathBuf>,
+    max_depth: usize,
+}
+
+#[allow(dead_code)]
+impl Links {
+    pub(crate) fn new(max_depth: usize) -> Self {
+        Links {
+            followed: vec![],
+            max_depth,
+        }
+    }
+
+    /// Records following the symlink at `path`.
+    pub(crate) fn follow(&mut self, path: &Path) -> Result<(), SymlinkError> {
+        if self.followed.iter().any(|x| x == path) {
+            return Err(SymlinkError::Cycle(path.to_path_buf()));
+        }
+        if self.followed.len() >= self.max_depth {
+            return Err(SymlinkError::TooDeep {
+                path: path.to_path_buf(),
+                depth: self.max_depth,
+            });
+        }
+        self.followed.push(path.to_path_buf());
+        Ok(())
+    }
+}
+
+/// How changes to a target are detected, chosen per watcher with [`crate::FileWatcherConfig::with_backend`].
+/// Any number of backends can be compiled in, each behind the cargo feature of the same name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Backend {
+    /// Polls targets under `/proc` and `/sys`, on network filesystems, or with a poll interval set.
+    /// Otherwise the first available of fanotify (if privileged), inotify, FSEvents, kqueue, event ports, Windows, notify, and polling.
+    /// Any backend that can't be set up on the host falls back to those set with
+    /// [`crate::FileWatcherConfig::with_fallback_backends`], then polling.
+    #[default]
+    Auto,
+    /// Linux and Android.
+    Inotify,
+    /// Linux and Android, with `CAP_SYS_ADMIN`. Falls back to [`Backend::Auto`] if unprivileged.
+    Fanotify,
+    /// macOS, iOS, and the BSDs.
+    Kqueue,
+    /// macOS.
+    FsEvents,
+    /// illumos and Solaris.
+    EventPorts,
+    /// Windows.
+    Windows,
+    /// The `notify` crate, on any platform it supports. With a poll interval set, its `PollWatcher` polls at that interval instead.
+    Notify,
+    /// Compare contents every poll interval, or every retry interval if not set. Always available.
+    Poll,
+}
+
+impl Backend {
+    /// Whether the backend is compiled in and supported on the current target.
+    /// Watchers configured with an unavailable backend report an error and fall back to [`Backend::Auto`].
+    pub fn is_available(self) -> bool {
+        match self {
+            Backend::Auto | Backend::Poll => true,
+            Backend::Inotify => cfg!(all(
+                feature = "inotify",
+                any(target_os = "linux", target_os = "android")
+            )),
+            Backend::Fanotify => cfg!(all(
+                feature = "fanotify",
+                any(target_os = "linux", target_os = "android")
+            )),
+            Backend::Kqueue => cfg!(all(
+                feature = "kqueue",
+                any(
+                    target_os = "macos",
+                    target_os = "ios",
+                    target_os = "freebsd",
+                    target_os = "netbsd",
+                    target_os = "openbsd",
+                    target_os = "dragonfly"
+                )
+            )),
+            Backend::FsEvents => cfg!(all(feature = "fsevents", target_os = "macos")),
+            Backend::EventPorts => cfg!(all(
+                feature = "ports",
+                any(target_os = "illumos", target_os = "solaris")
+            )),
+            Backend::Windows => cfg!(all(feature = "windows", windows)),
+            Backend::Notify => cfg!(feature = "notify"),
+        }
+    }
+
+    /// Whether open files can be watched through their `/proc/self/fd` or `/dev/fd` path, otherwise they are polled.
+    fn watches_descriptors(self) -> bool {
+        match self {
+            #[cfg(all(feature = "inotify", any(target_os = "linux", target_os = "android")))]
+            Backend::Inotify => inotify::WATCHES_DESCRIPTORS,
+            #[cfg(all(
+                feature = "kqueue",
+                any(
+                    target_os = "macos",
+                    target_os = "ios",
+                    target_os = "freebsd",
+                    target_os = "netbsd",
+                    target_os = "openbsd",
+                    target_os = "dragonfly"
+                )
+            ))]
+            Backend::Kqueue => kqueue::WATCHES_DESCRIPTORS,
+            #[cfg(all(feature = "fsevents", target_os = "macos"))]
+            Backend::FsEvents => fsevents::WATCHES_DESCRIPTORS,
+            #[cfg(all(feature = "ports", any(target_os = "illumos", target_os = "solaris")))]
+            Backend::EventPorts => ports::WATCHES_DESCRIPTORS,
+            #[cfg(all(feature = "windows", windows))]
+            Backend::Windows => windows::WATCHES_DESCRIPTORS,
+            #[cfg(feature = "notify")]
+            Backend::Notify => notify::WATCHES_DESCRIPTORS,
+            _ => false,
+        }
+    }
+
+    /// The first available native backend, preferring the one written for the platform.
+    fn native() -> Backend {
+        [
+            Backend::Inotify,
+            Backend::FsEvents,
+            Backend::Kqueue,
+            Backend::EventPorts,
+            Backend::Windows,
+            Backend::Notify,
+        ]
+        .into_iter()
+        .find(|x| x.is_available())
+        .unwrap_or(Backend::Poll)
+    }
+}
+
+/// Stops the backend when dropped.
+#[allow(dead_code)]
+pub(crate) enum BackendHandle {
+    #[cfg(all(feature = "inotify", any(target_os = "linux", target_os = "android")))]
+    Inotify(inotify::BackendHandle),
+    #[cfg(all(feature = "fanotify", any(target_os = "linux", target_os = "android")))]
+    Fanotify(fanotify::BackendHandle),
+    #[cfg(all(
+        feature = "kqueue",
+        any(
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd",
+            target_os = "dragonfly"
+        )
+    ))]
+    Kqueue(kqueue::BackendHandle),
+    #[cfg(all(feature = "fsevents", target_os = "macos"))]
+    FsEvents(fsevents::BackendHandle),
+    #[cfg(all(feature = "ports", any(target_os = "illumos", target_os = "solaris")))]
+    EventPorts(ports::BackendHandle),
+    #[cfg(all(feature = "windows", windows))]
+    Windows(windows::BackendHandle),
+    #[cfg(feature = "notify")]
+    Notify(notify::BackendHandle),
+    Poll(poll::BackendHandle),
+    Custom(custom::BackendHandle),
+    Supervised(Supervisor),
+}
+
+/// Stops the supervisor, and with it the backend it runs, when dropped.
+pub(crate) struct Supervisor(AbortHandle);
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Resolves [`Backend::Auto`] and unavailable choices to the backend that will watch the target.
+fn choose<E>(context: &WatcherContext, on_error: &ErrorHook<E>) -> Backend {
+    let mut backend = context.backend;
+    if !backend.is_available() {
+        let e = io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{backend:?} backend is not available on this target, or its feature is not enabled"),
+        );
+        context.watch_error(&e, 1);
+        on_error(&e.into());
+        backend = Backend::Auto;
+    }
+    if backend == Backend::Auto {
+        backend = if context.poll_interval.is_some() {
+            Backend::Poll
+        } else if poll::is_pseudo_fs(&context.file)
+            && !matches!(context.mode, WatchMode::Descriptor)
+        {
+            // a magic link under /proc, but the native backend may be able to watch the file behind it
+            Backend::Poll
+        } else if let Some(network_fs) = poll::network_fs(&context.file) {
+            context.logger.log(Level::Info, 0, format_args!(
+                "{} '{}' is on {network_fs}, which doesn't report changes made by other hosts, polling every {:.1} second(s)",
+                context.log_name,
+                context.file.display(),
+                context.retry_interval.as_secs_f64()
+            ));
+            Backend::Poll
+        } else if Backend::Fanotify.is_available()
+            && !matches!(context.mode, WatchMode::Descriptor)
+            && fanotify_permitted()
+        {
+            // a single filesystem mark rather than a watch per directory, if we're privileged enough
+            Backend::Fanotify
+        } else {
+            Backend::native()
+        };
+    }
+    if matches!(context.mode, WatchMode::Descriptor) && !backend.watches_descriptors() {
+        backend = Backend::Poll;
+    }
+    backend
+}
+
+/// Sets up `watcher_context` to list the watches of `backend`, for watchers with statistics.
+fn watch_set(watcher_context: WatcherContext, backend: Option<Backend>) -> WatcherContext {
+    WatcherContext {
+        watch_set: watcher_context
+            .stats
+            .as_ref()
+            .map(|stats| Arc::new(WatchSet::new(stats, backend))),
+        ..watcher_context
+    }
+}
+
+/// The backend chosen for the watcher followed by its fallbacks, ending with polling, which always works.
+fn candidates<E>(context: &WatcherContext, on_error: &ErrorHook<E>) -> Vec<Backend> {
+    let mut out = vec![choose(context, on_error)];
+    for backend in context
+        .fallback_backends
+        .iter()
+        .copied()
+        .chain([Backend::Poll])
+    {
+        if backend == Backend::Auto || !backend.is_available() || out.contains(&backend) {
+            continue;
+        }
+        if matches!(context.mode, WatchMode::Descriptor)
+            && backend != Backend::Poll
+            && !backend.watches_descriptors()
+        {
+            continue;
+        }
+        out.push(backend);
+    }
+    out
+}
+
+/// Whether the backend can be set up on this host at all, before relying on it. i.e. inotify blocked by seccomp, or out of instances.
+#[cfg_attr(not(feature = "notify"), allow(unused_variables))]
+fn probe<E>(backend: Backend, context: &WatcherContext) -> Result<(), FileWatcherError<E>> {
+    match backend {
+        #[cfg(all(feature = "inotify", any(target_os = "linux", target_os = "android")))]
+        Backend::Inotify => crate::inotify::INotify::new().map(drop)?,
+        #[cfg(all(feature = "fanotify", any(target_os = "linux", target_os = "android")))]
+        Backend::Fanotify => fanotify::init().map(drop)?,
+        #[cfg(all(
+            feature = "kqueue",
+            any(
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "openbsd",
+                target_os = "dragonfly"
+            )
+        ))]
+        Backend::Kqueue => kqueue::probe()?,
+        #[cfg(all(feature = "ports", any(target_os = "illumos", target_os = "solaris")))]
+        Backend::EventPorts => ports::probe()?,
+        #[cfg(feature = "notify")]
+        Backend::Notify => notify::probe(context)?,
+        _ => (),
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "fanotify", any(target_os = "linux", target_os = "android")))]
+fn fanotify_permitted() -> bool {
+    match fanotify::init() {
+        Ok(_) => true,
+        Err(e) => {
+            debug!("fanotify unavailable, using the native backend: {e}");
+            false
+        }
+    }
+}
+
+#[cfg(not(all(feature = "fanotify", any(target_os = "linux", target_os = "android"))))]
+fn fanotify_permitted() -> bool {
+    false
+}
+
+pub(crate) use poll::stat;
+
+/// Stats the target every `interval` on top of the backend watching it, to catch changes it missed.
+pub(crate) fn start_stat_check(
+    context: WatcherContext,
+    interval: Duration,
+    read: Arc<Mutex<Option<Stat>>>,
+) -> BackendHandle {
+    BackendHandle::Poll(poll::start_stat_check(context, interval, read))
+}
+
+/// Bumped to have every backend start over, see [`restart_all`].
+fn generation() -> &'static watch::Sender<u64> {
+    static GENERATION: OnceLock<watch::Sender<u64>> = OnceLock::new();
+    GENERATION.get_or_init(|| watch::channel(0).0)
+}
+
+/// Has every backend in the process start over with new descriptors and tasks, after forgetting the process wide state
+/// they share, i.e. the shared inotify instance.
+pub(crate) fn restart_all() {
+    #[cfg(all(feature = "inotify", any(target_os = "linux", target_os = "android")))]
+    {
+        shared_inotify::reset();
+        mounts::reset();
+    }
+    generation().send_modify(|x| *x += 1);
+}
+
+/// Starts the backend configured for the watcher, or the best one for the target, and starts it again after a backoff
+/// whenever it stops, i.e. because it panicked, or right away after [`restart_all`]. Either way the target is read again,
+/// for changes that were missed in between.
+pub(crate) async fn start_backend<E: Display + Send + 'static>(
+    watcher_context: WatcherContext,
+    on_error: ErrorHook<E>,
+) -> BackendHandle {
+    let mut restarts = generation().subscribe();
+    let mut started = Instant::now();
+    let (running, mut stopped) = mpsc::channel(1);
+    let mut backend = start(
+        WatcherContext {
+            _running: Some(running),
+            ..watcher_context.clone()
+        },
+        on_error.clone(),
+    )
+    .await;
+    let task = rt::spawn(async move {
+        let mut failures = 0;
+        loop {
+            select! {
+                // nothing is ever sent, this returns once the backend dropped its context
+                _ = stopped.recv() => {
+                    drop(backend);
+                    let delay = restart_delay(watcher_context.retry_interval, &mut failures, started);
+                    let e = FileWatcherError::Crashed("backend stopped unexpectedly".to_string());
+                    watcher_context.logger.log(
+                        Level::Error,
+                        failures,
+                        format_args!(
+                            "{} {e} in {:.1} second(s) @ '{}'",
+                            watcher_context.log_name,
+                            delay.as_secs_f64(),
+                            watcher_context.file.display()
+                        ),
+                    );
+                    on_error(&e);
+                    rt::sleep(delay).await;
+                }
+                _ = restarts.changed() => {
+                    watcher_context.logger.log(Level::Info, 0, format_args!(
+                        "{} restarting backend @ '{}'",
+                        watcher_context.log_name,
+                        watcher_context.file.display()
+                    ));
+                    drop(backend);
+                }
+            }
+            started = Instant::now();
+            let running;
+            (running, stopped) = mpsc::channel(1);
+            backend = start(
+                WatcherContext {
+                    _running: Some(running),
+                    ..watcher_context.clone()
+                },
+                on_error.clone(),
+            )
+            .await;
+            watcher_context.changed();
+        }
+    });
+    BackendHandle::Supervised(Supervisor(task.abort_handle()))
+}
+
+/// Starts the backend configured for the watcher, or the best one for the target.
+async fn start<E: Display + Send + 'static>(
+    watcher_context: WatcherContext,
+    on_error: ErrorHook<E>,
+) -> BackendHandle {
+    // a missing target is left to the read loop, which only reports it once the grace period to recreate it in is over
+    let on_error: ErrorHook<E> = match watcher_context.deletion_grace {
+        Some(_) => Arc::new(move |e| {
+            if !is_missing(e) {
+                on_error(e)
+            }
+        }),
+        None => on_error,
+    };
+    if let Some(custom) = watcher_context.custom.clone() {
+        let watcher_context = watch_set(watcher_context, None);
+        watcher_context.logger.log(
+            Level::Debug,
+            0,
+            format_args!(
+                "watching {} '{}' with a custom backend",
+                watcher_context.log_name,
+                watcher_context.file.display()
+            ),
+        );
+        return BackendHandle::Custom(custom::start_backend(custom, watcher_context, on_error));
+    }
+    let mut candidates = candidates(&watcher_context, &on_error)
+        .into_iter()
+        .peekable();
+    let backend = loop {
+        let backend = candidates.next().unwrap_or(Backend::Poll);
+        let Some(next) = candidates.peek() else {
+            break backend;
+        };
+        match probe(backend, &watcher_context) {
+            Ok(()) => break backend,
+            Err(e) => {
+                watcher_context.logger.log(
+                    Level::Warn,
+                    1,
+                    format_args!(
+                        "{} {backend:?} backend can't be used: {e} @ '{}', falling back to {next:?}",
+                        watcher_context.log_name,
+                        watcher_context.file.display()
+                    ),
+                );
+                on_error(&e);
+            }
+        }
+    };
+    watcher_context.lifecycle(LifecycleEvent::BackendStarted {
+        path: watcher_context.file.clone(),
+        backend,
+    });
+    let watcher_context = watch_set(watcher_context, Some(backend));
+    watcher_context.logger.log(
+        Level::Debug,
+        0,
+        format_args!(
+            "watching {} '{}' with the {backend:?} backend",
+            watcher_context.log_name,
+            watcher_context.file.display()
+        ),
+    );
+    match backend {
+        #[cfg(all(feature = "inotify", any(target_os = "linux", target_os = "android")))]
+        Backend::Inotify => {
+            BackendHandle::Inotify(inotify::start_backend(watcher_context, on_error).await)
+        }
+        #[cfg(all(feature = "fanotify", any(target_os = "linux", target_os = "android")))]
+        Backend::Fanotify => match fanotify::init() {
+            Ok(fanotify) => BackendHandle::Fanotify(
+                fanotify::start_backend(watcher_context, fanotify, on_error).await,
+            ),
+            Err(e) => {
+                watcher_context.logger.log(
+                    Level::Error,
+                    1,
+                    format_args!(
+                        "{} watch error: {e} @ '{}', falling back to the native backend",
+                        watcher_context.log_name,
+                        watcher_context.file.display()
+                    ),
+                );
+                on_error(&e.into());
+                let watcher_context = WatcherContext {
+                    backend: Backend::native(),
+                    ..watcher_context
+                };
+                Box::pin(start(watcher_context, on_error)).await
+            }
+        },
+        #[cfg(all(
+            feature = "kqueue",
+            any(
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "freebsd",
+                target_os = "netbsd",
+                target_os = "openbsd",
+                target_os = "dragonfly"
+            )
+        ))]
+        Backend::Kqueue => {
+            BackendHandle::Kqueue(kqueue::start_backend(watcher_context, on_error).await)
+        }
+        #[cfg(all(feature = "fsevents", target_os = "macos"))]
+        Backend::FsEvents => {
+            BackendHandle::FsEvents(fsevents::start_backend(watcher_context, on_error).await)
+        }
+        #[cfg(all(feature = "ports", any(target_os = "illumos", target_os = "solaris")))]
+        Backend::EventPorts => {
+            BackendHandle::EventPorts(ports::start_backend(watcher_context, on_error).await)
+        }
+        #[cfg(all(feature = "windows", windows))]
+        Backend::Windows => {
+            BackendHandle::Windows(windows::start_backend(watcher_context, on_error).await)
+        }
+        #[cfg(feature = "notify")]
+        Backend::Notify => {
+            BackendHandle::Notify(notify::start_backend(watcher_context, on_error).await)
+        }
+        _ => {
+            let interval = watcher_context
+                .poll_interval
+                .unwrap_or(watcher_context.retry_interval);
+            BackendHandle::Poll(poll::start_backend(watcher_context, interval))
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::{
+    ffi::CStr,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::{Arc, Mutex},
+};
+
+use crate::logging::error;
+use tokio::sync::watch;
+
+/// Subscribes to changes of the mount table of the process, which cover paths without generating inotify events on them.
+/// The value counts the changes, and only its changing matters.
+pub(crate) fn subscribe() -> io::Result<watch::Receiver<u64>> {
+    let mut changes = CHANGES.lock().unwrap();
+    if let Some(sender) = &*changes {
+        return Ok(sender.subscribe());
+    }
+    let mountinfo = open()?;
+    let sender = Arc::new(watch::channel(0).0);
+    let thread_changes = sender.clone();
+    // parked in poll(2) between changes, for the rest of the process
+    crate::thread::spawn("mounts", move || run(mountinfo, thread_changes))
+        .map_err(|e| error!("failed to watch the mount table: {e}"))
+        .ok();
+    *changes = Some(sender.clone());
+    Ok(sender.subscribe())
+}
+
+static CHANGES: Mutex<Option<Arc<watch::Sender<u64>>>> = Mutex::new(None);
+
+/// Forgets the thread watching the mount table, which doesn't survive a fork, so the next subscriber starts another.
+pub(crate) fn reset() {
+    *CHANGES.lock().unwrap() = None;
+}
+
+fn open() -> io::Result<OwnedFd> {
+    const PATH: &CStr = c"/proc/self/mountinfo";
+    let fd = unsafe { libc::open(PATH.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// The kernel reports mount table changes as an exceptional condition on the open file.
+fn run(mountinfo: OwnedFd, changes: Arc<watch::Sender<u64>>) {
+    loop {
+        let mut pollfd = libc::pollfd {
+            fd: mountinfo.as_raw_fd(),
+            events: libc::POLLPRI,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            error!("failed to watch the mount table: {e}");
+            return;
+        }
+        if pollfd.revents & (libc::POLLPRI | libc::POLLERR) != 0 {
+            changes.send_modify(|x| *x += 1);
+        }
+    }
+}
@@ -1,102 +1,400 @@
-use std::{fmt::Display, sync::Arc};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
 
-use log::{debug, error, info};
+use crate::logging::error;
+use log::Level;
 use notify::{
-    event::{AccessKind, AccessMode},
-    EventKind, RecursiveMode, Watcher,
+    event::{AccessKind, AccessMode, MetadataKind, ModifyKind},
+    Config, EventKind, PollWatcher, RecursiveMode, Watcher,
 };
 use tokio::sync::oneshot;
 
-use crate::{FileWatcherError, WatcherContext};
-
-pub(crate) async fn start_backend<E: Display + Send + 'static>(watcher_context: WatcherContext) {
-    tokio::task::spawn_blocking(move || {
-        let watcher_context = Arc::new(watcher_context);
-        loop {
-            match load_config::<E>(watcher_context.clone()) {
-                Ok(()) => break,
-                Err(e) => {
-                    error!(
-                        "failed to setup {} watcher: {e} @ '{}', retrying in {:.1} second(s)",
-                        watcher_context.log_name,
-                        watcher_context.file.display(),
-                        watcher_context.retry_interval.as_secs_f64()
-                    );
-                    std::thread::sleep(watcher_context.retry_interval);
-                }
-            }
-        }
-    })
-    .await
-    .unwrap();
+use crate::{
+    directory::WatchMode, normalize, ChangeKind, ErrorHook, FileWatcherError, WatcherContext,
+};
+
+/// Whether open files can be watched through their `/proc/self/fd` path, otherwise they are polled.
+pub(crate) const WATCHES_DESCRIPTORS: bool = false;
+
+/// How long events have to stop arriving for before a burst of them is a single reload.
+/// Editors emit dozens of events per save (backup, rename, write, chmod), which inotify would coalesce while the read is pending.
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// What the watcher callback reports to the backend thread.
+enum Signal {
+    /// With the paths on the way to the target that were created, removed, or renamed, and need resolving and watching again,
+    /// and whether the event filter accepted the change.
+    Changed(Vec<PathBuf>, bool),
+    Error(notify::Error),
+    Stop,
 }
 
-fn load_config<E: Display + Send + 'static>(
-    context: Arc<WatcherContext>,
-) -> Result<(), FileWatcherError<E>> {
-    let (watcher_sender, watcher_receiver) = oneshot::channel();
-    let mut watcher_receiver = Some(watcher_receiver);
+/// Stops the backend when dropped.
+pub(crate) struct BackendHandle(mpsc::Sender<Signal>);
 
-    let context2 = context.clone();
-    let realpath = std::fs::canonicalize(&context2.file)?;
-    let realpath2 = realpath.clone();
+impl Drop for BackendHandle {
+    fn drop(&mut self) {
+        self.0.send(Signal::Stop).ok();
+    }
+}
 
-    let mut watcher = notify::recommended_watcher(
-        move |res: Result<notify::Event, notify::Error>| {
-            if watcher_receiver.is_none() {
-                return;
+pub(crate) async fn start_backend<E: Display + Send + 'static>(
+    watcher_context: WatcherContext,
+    on_error: ErrorHook<E>,
+) -> BackendHandle {
+    let (sender, receiver) = mpsc::channel();
+    let (ready_sender, ready) = oneshot::channel();
+    let signals = sender.clone();
+    // runs until stopped, without anything waiting for it
+    if let Err(e) = crate::thread::spawn("notify", move || {
+        run(
+            Arc::new(watcher_context),
+            on_error,
+            signals,
+            receiver,
+            ready_sender,
+        )
+    }) {
+        error!("failed to spawn notify thread: {e}");
+    }
+    // held while waiting, so the thread is stopped even if we're dropped before it's ready
+    let handle = BackendHandle(sender);
+    ready.await.ok();
+    handle
+}
+
+/// Whether a watcher can be created at all, i.e. the platform's mechanism isn't blocked or out of instances.
+pub(crate) fn probe(context: &WatcherContext) -> notify::Result<()> {
+    if context.poll_interval.is_some() {
+        return Ok(());
+    }
+    notify::recommended_watcher(|_: notify::Result<notify::Event>| ()).map(drop)
+}
+
+/// Watches until stopped, reloading once per burst of changes. A single watcher is kept throughout,
+/// and only the watches along the path to the target that changed are replaced.
+fn run<E: Display + Send + 'static>(
+    context: Arc<WatcherContext>,
+    on_error: ErrorHook<E>,
+    sender: mpsc::Sender<Signal>,
+    signals: mpsc::Receiver<Signal>,
+    ready: oneshot::Sender<()>,
+) {
+    // in a row, until the watches are in place
+    let failures = Cell::new(0);
+    let retry = |e: &FileWatcherError<E>| {
+        failures.set(failures.get() + 1);
+        context.logger.log(
+            Level::Error,
+            failures.get(),
+            format_args!(
+                "failed to setup {} watcher: {e} @ '{}', retrying in {:.1} second(s)",
+                context.log_name,
+                context.file.display(),
+                context.retry_interval.as_secs_f64()
+            ),
+        );
+        on_error(e);
+        let mut replaced = vec![];
+        let mut reload = false;
+        wait(
+            &context,
+            &on_error,
+            &signals,
+            Some(context.retry_interval),
+            &mut replaced,
+            &mut reload,
+        )
+    };
+    let target = Arc::new(Mutex::new(Target::default()));
+    let mut watcher = loop {
+        match watcher(context.clone(), target.clone(), sender.clone()) {
+            Ok(x) => break x,
+            Err(e) => {
+                if !retry(&e) {
+                    return;
+                }
             }
-            match res {
-                Ok(event) => {
-                    match event.kind {
-                        EventKind::Access(AccessKind::Close(AccessMode::Write))
-                        | EventKind::Modify(_)
-                        | EventKind::Remove(_) => (),
-                        _ => return,
-                    }
-                    let mut found_path = false;
-                    for path in &event.paths {
-                        if context
-                            .file
-                            .ancestors()
-                            .chain(realpath2.ancestors())
-                            .any(|x| x == path)
-                        {
-                            found_path = true;
-                            break;
-                        }
-                    }
-                    if !found_path {
-                        return;
-                    }
-                    debug!("file updated: {:?}", event.paths);
+        }
+    };
+    let mut ready = Some(ready);
+    let mut watches = HashMap::new();
+    // resolved on the first pass, and after anything on the way to the target is replaced
+    let mut resolve = true;
+    let mut changed = false;
+    let mut replaced = vec![];
+    let mut reload = false;
+    loop {
+        if resolve {
+            if let Err(e) = update(&context, &mut watcher, &target, &mut watches, &replaced) {
+                if changed {
+                    // i.e. the target is gone, which the read reports
                     context.notify.notify_one();
-                    watcher_receiver.take().unwrap().blocking_recv().ok();
-                    while let Err(e) = load_config::<E>(context.clone()) {
-                        error!("failed to reload {} watcher: {e} @ '{}', retrying in {:.1} second(s)...", context.log_name, context.file.display(), context.retry_interval.as_secs_f64());
-                        std::thread::sleep(context.retry_interval);
-                        context.notify.notify_one();
-                    }
                 }
-                Err(e) => {
-                    error!(
-                        "{} watch error: {e} @ '{}'",
-                        context.log_name,
-                        context.file.display()
-                    );
+                if !retry(&e) {
+                    return;
                 }
+                // we may have missed changes while we couldn't watch
+                changed = ready.is_none();
+                continue;
             }
-        },
-    )?;
-    for ancestor in context2.file.ancestors() {
-        debug!("watching {}", ancestor.display());
-        watcher.watch(&ancestor, RecursiveMode::NonRecursive)?;
+            replaced.clear();
+            failures.set(0);
+            if let Some(ready) = ready.take() {
+                ready.send(()).ok();
+            }
+        }
+        if changed {
+            // only once the watches are in place, so nothing after the read is missed
+            context.notify.notify_one();
+        }
+        if !wait(
+            &context,
+            &on_error,
+            &signals,
+            None,
+            &mut replaced,
+            &mut reload,
+        ) {
+            return;
+        }
+        // editors emit bursts of events per save, which we let settle into a single reload
+        if !wait(
+            &context,
+            &on_error,
+            &signals,
+            Some(DEBOUNCE),
+            &mut replaced,
+            &mut reload,
+        ) {
+            return;
+        }
+        resolve = !replaced.is_empty();
+        changed = std::mem::take(&mut reload);
     }
-    for ancestor in realpath.ancestors() {
-        debug!("watching {}", ancestor.display());
-        watcher.watch(&ancestor, RecursiveMode::NonRecursive)?;
+}
+
+/// Waits for a change, or without one for `quiet` to elapse, collecting paths that were replaced along the way,
+/// and whether any of the changes passed the event filter. Errors are reported as they arrive. Returns false once stopped.
+fn wait<E>(
+    context: &WatcherContext,
+    on_error: &ErrorHook<E>,
+    signals: &mpsc::Receiver<Signal>,
+    quiet: Option<Duration>,
+    replaced: &mut Vec<PathBuf>,
+    reload: &mut bool,
+) -> bool {
+    loop {
+        let signal = match quiet {
+            Some(quiet) => match signals.recv_timeout(quiet) {
+                Ok(x) => x,
+                Err(mpsc::RecvTimeoutError::Timeout) => return true,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return false,
+            },
+            None => match signals.recv() {
+                Ok(x) => x,
+                Err(_) => return false,
+            },
+        };
+        match signal {
+            Signal::Changed(paths, accepted) => {
+                replaced.extend(paths);
+                *reload |= accepted;
+                if quiet.is_none() {
+                    return true;
+                }
+            }
+            Signal::Error(e) => {
+                context.watch_error(&e, 1);
+                on_error(&FileWatcherError::Notify(e));
+            }
+            Signal::Stop => return false,
+        }
+    }
+}
+
+/// The resolved target, which the watcher callback filters events by.
+#[derive(Default)]
+struct Target {
+    realpath: PathBuf,
+    /// Whether the target may be missing, and we need to watch its parent for it to be created.
+    watch_creation: bool,
+}
+
+/// Resolves the path to the target, listing the paths to watch.
+fn plan(context: &WatcherContext) -> io::Result<(Target, HashMap<PathBuf, RecursiveMode>)> {
+    let watch_creation = matches!(context.mode, WatchMode::Sentinel)
+        || (context.optional && std::fs::symlink_metadata(&context.file).is_err());
+    let realpath = if watch_creation {
+        // resolve through the nearest existing ancestor
+        context
+            .file
+            .ancestors()
+            .skip(1)
+            .find_map(|ancestor| {
+                let real = std::fs::canonicalize(ancestor).ok()?;
+                normalize(&real.join(context.file.strip_prefix(ancestor).ok()?)).ok()
+            })
+            .unwrap_or_else(|| context.file.clone())
+    } else {
+        std::fs::canonicalize(&context.file)?
+    };
+    let mut watches = HashMap::new();
+    for ancestor in context.file.ancestors().chain(realpath.ancestors()) {
+        if watch_creation && std::fs::metadata(ancestor).is_err() {
+            continue;
+        }
+        let mode = match context.mode {
+            WatchMode::Tree(_) if ancestor == context.file || ancestor == realpath => {
+                RecursiveMode::Recursive
+            }
+            _ => RecursiveMode::NonRecursive,
+        };
+        watches.insert(ancestor.to_path_buf(), mode);
     }
-    watcher_sender.send(watcher).ok();
+    Ok((
+        Target {
+            realpath,
+            watch_creation,
+        },
+        watches,
+    ))
+}
 
+/// Resolves the path to the target again, watching what is new or was `replaced`, and unwatching what is no longer on the way.
+fn update<E>(
+    context: &WatcherContext,
+    watcher: &mut Box<dyn Watcher + Send>,
+    target: &Mutex<Target>,
+    watches: &mut HashMap<PathBuf, RecursiveMode>,
+    replaced: &[PathBuf],
+) -> Result<(), FileWatcherError<E>> {
+    let (resolved, planned) = plan(context)?;
+    *target.lock().unwrap() = resolved;
+    watches.retain(|path, mode| {
+        if planned.get(path) == Some(mode) && !replaced.contains(path) {
+            return true;
+        }
+        // already gone if it was removed
+        watcher.unwatch(path).ok();
+        false
+    });
+    for (path, mode) in planned {
+        if watches.contains_key(&path) {
+            continue;
+        }
+        context
+            .logger
+            .log(Level::Debug, 0, format_args!("watching {}", path.display()));
+        watcher.watch(&path, mode)?;
+        watches.insert(path, mode);
+    }
+    context.established(
+        watches
+            .iter()
+            .map(|(path, mode)| (path.clone(), format!("{mode:?}"))),
+    );
     Ok(())
 }
+
+/// Whether `path` is the target or on the way to it, rather than i.e. a file in a watched directory.
+fn on_the_way(context: &WatcherContext, target: &Target, path: &Path) -> bool {
+    context
+        .file
+        .ancestors()
+        .chain(target.realpath.ancestors())
+        .any(|x| x == path)
+}
+
+/// What an event was, for the event filter.
+fn kind(kind: EventKind) -> ChangeKind {
+    match kind {
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => ChangeKind::ClosedWrite,
+        EventKind::Create(_) => ChangeKind::Created,
+        EventKind::Remove(_) => ChangeKind::Removed,
+        EventKind::Modify(ModifyKind::Name(_)) => ChangeKind::Renamed,
+        // the poll watcher reports changed contents as a changed modification time
+        EventKind::Modify(ModifyKind::Metadata(MetadataKind::WriteTime)) => ChangeKind::Modified,
+        EventKind::Modify(ModifyKind::Metadata(_)) => ChangeKind::Attributes,
+        EventKind::Modify(_) => ChangeKind::Modified,
+        _ => ChangeKind::Other,
+    }
+}
+
+/// A watcher reporting changes relevant to the target, as it was last resolved.
+/// Polls with notify's own `PollWatcher` if a poll interval is set, i.e. where inotify is broken inside containers.
+fn watcher<E>(
+    context: Arc<WatcherContext>,
+    target: Arc<Mutex<Target>>,
+    sender: mpsc::Sender<Signal>,
+) -> Result<Box<dyn Watcher + Send>, FileWatcherError<E>> {
+    let poll_interval = context.poll_interval;
+    let handler = move |res: Result<notify::Event, notify::Error>| match res {
+        Ok(event) => {
+            let target = target.lock().unwrap();
+            match event.kind {
+                // the poll watcher reports changed contents as a changed modification time
+                EventKind::Modify(ModifyKind::Metadata(kind))
+                    if !context.attribute_changes && kind != MetadataKind::WriteTime =>
+                {
+                    return
+                }
+                EventKind::Access(AccessKind::Close(AccessMode::Write))
+                | EventKind::Modify(_)
+                | EventKind::Remove(_) => (),
+                EventKind::Create(_) if context.mode.is_directory() || target.watch_creation => {}
+                _ => return,
+            }
+            let relevant = |path: &PathBuf| {
+                on_the_way(&context, &target, path)
+                    || context.mode.is_relevant_child(&context.file, path)
+                    || context.mode.is_relevant_child(&target.realpath, path)
+            };
+            let Some(path) = event.paths.iter().find(|x| relevant(x)) else {
+                return;
+            };
+            let accepted = context.accepts(kind(event.kind), path);
+            context.logger.log(
+                Level::Debug,
+                0,
+                format_args!("file updated: {:?}", event.paths),
+            );
+            let mut replaced: Vec<PathBuf> = match event.kind {
+                EventKind::Create(_)
+                | EventKind::Remove(_)
+                | EventKind::Modify(ModifyKind::Name(_)) => event
+                    .paths
+                    .iter()
+                    .filter(|x| on_the_way(&context, &target, x))
+                    .cloned()
+                    .collect(),
+                _ => vec![],
+            };
+            if let (WatchMode::Tree(_), EventKind::Modify(ModifyKind::Name(_))) =
+                (&context.mode, event.kind)
+            {
+                // recursive watches don't follow directories renamed within the tree
+                replaced.push(context.file.clone());
+                replaced.push(target.realpath.clone());
+            }
+            sender.send(Signal::Changed(replaced, accepted)).ok();
+        }
+        Err(e) => {
+            sender.send(Signal::Error(e)).ok();
+        }
+    };
+    Ok(match poll_interval {
+        Some(interval) => Box::new(PollWatcher::new(
+            handler,
+            Config::default().with_poll_interval(interval),
+        )?),
+        None => Box::new(notify::recommended_watcher(handler)?),
+    })
+}
@@ -0,0 +1,256 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::Level;
+
+use crate::{
+    directory::{walk, TreeOptions, WatchMode},
+    fs::{self, Stat},
+    rt::{self, AbortHandle},
+    ChangeKind, WatcherContext,
+};
+
+/// Stops the backend when dropped.
+pub(crate) struct BackendHandle(AbortHandle);
+
+impl Drop for BackendHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// procfs and sysfs don't generate filesystem events, and report a size of zero, so only their contents can be compared.
+pub(crate) fn is_pseudo_fs(file: &Path) -> bool {
+    let is_pseudo = |path: &Path| path.starts_with("/proc") || path.starts_with("/sys");
+    is_pseudo(file) || std::fs::canonicalize(file).is_ok_and(|x| is_pseudo(&x))
+}
+
+/// Filesystem types (`statfs(2)` magic numbers) where writes from other hosts don't generate events.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const NETWORK_FS: &[(u32, &str)] = &[
+    (0x6969, "nfs"),
+    (0x517b, "smb"),
+    (0xfe534d42, "smb2"),
+    (0xff534d42, "cifs"),
+    (0x73757245, "coda"),
+    (0x5346414f, "afs"),
+    (0x6b414653, "afs"),
+    (0x01021997, "9p"),
+    (0x00c36400, "ceph"),
+    (0x0bd00bd0, "lustre"),
+    (0x47504653, "gpfs"),
+];
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+const NETWORK_FS: &[&str] = &["nfs", "smbfs", "cifs", "afpfs", "webdav"];
+
+/// The type of network filesystem `file` is on, if any. Missing targets are checked by their closest existing ancestor.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+pub(crate) fn network_fs(file: &Path) -> Option<&'static str> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let existing = file.ancestors().find(|x| x.exists())?;
+    let path = CString::new(existing.as_os_str().as_bytes()).ok()?;
+    let mut statfs: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut statfs) } < 0 {
+        return None;
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let found = NETWORK_FS
+        .iter()
+        .find(|(magic, _)| *magic == statfs.f_type as u32)
+        .map(|(_, name)| *name);
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let found = {
+        let name = unsafe { std::ffi::CStr::from_ptr(statfs.f_fstypename.as_ptr()) };
+        NETWORK_FS
+            .iter()
+            .find(|x| x.as_bytes() == name.to_bytes())
+            .copied()
+    };
+    found
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+)))]
+pub(crate) fn network_fs(_file: &Path) -> Option<&'static str> {
+    None
+}
+
+/// Compares the contents of the target every `interval`, notifying when they change.
+pub(crate) fn start_backend(context: WatcherContext, interval: Duration) -> BackendHandle {
+    context.established([(
+        context.file.clone(),
+        format!("polled every {:.1} second(s)", interval.as_secs_f64()),
+    )]);
+    let task = rt::spawn(async move { poll(&context, interval).await });
+    BackendHandle(task.abort_handle())
+}
+
+/// Stats the target every `interval`, and signals a change if it stayed different from when it was last `read` for a
+/// whole interval, i.e. the change was missed by the backend watching it.
+pub(crate) fn start_stat_check(
+    context: WatcherContext,
+    interval: Duration,
+    read: Arc<Mutex<Option<Stat>>>,
+) -> BackendHandle {
+    let task = rt::spawn(async move {
+        let mut unread = None;
+        loop {
+            rt::sleep(interval).await;
+            let current = stat(&context.file, context.attribute_changes).await;
+            if current == *read.lock().unwrap() {
+                unread = None;
+                continue;
+            }
+            if unread.as_ref() == Some(&current) {
+                context.logger.log(
+                    Level::Warn,
+                    0,
+                    format_args!(
+                        "{} changed without an event being reported, reloading @ '{}'",
+                        context.log_name,
+                        context.file.display()
+                    ),
+                );
+                context.changed();
+            }
+            unread = Some(current);
+        }
+    });
+    BackendHandle(task.abort_handle())
+}
+
+/// The metadata of `file` as the stat check compares it, `None` if it is missing.
+pub(crate) async fn stat(file: &Path, attribute_changes: bool) -> Option<Stat> {
+    let mut stat = Stat::new(&fs::metadata(file).await.ok()?);
+    if !attribute_changes {
+        stat.attributes = Default::default();
+    }
+    Some(stat)
+}
+
+/// Polls forever, for backends that give up on events for a target.
+pub(crate) async fn poll(context: &WatcherContext, interval: Duration) {
+    context.logger.log(
+        Level::Debug,
+        0,
+        format_args!(
+            "polling {} '{}' every {:.1} second(s)",
+            context.log_name,
+            context.file.display(),
+            interval.as_secs_f64()
+        ),
+    );
+    let mut poller = Poller {
+        pseudo: is_pseudo_fs(&context.file),
+        attribute_changes: context.attribute_changes,
+        hashes: HashMap::new(),
+    };
+    let mut previous = poller.fingerprint(&context.file, &context.mode).await;
+    loop {
+        rt::sleep(interval).await;
+        let current = poller.fingerprint(&context.file, &context.mode).await;
+        if current != previous {
+            context.logger.log(
+                Level::Debug,
+                0,
+                format_args!("polled change in '{}'", context.file.display()),
+            );
+            context.report(ChangeKind::Other, &context.file);
+            previous = current;
+        }
+    }
+}
+
+struct Poller {
+    /// Metadata can't be trusted, so everything is read every time.
+    pseudo: bool,
+    /// Attribute changes count as changes to the contents.
+    attribute_changes: bool,
+    /// Hashes of the files seen in the last round, only read again if their metadata changed.
+    hashes: HashMap<PathBuf, (Stat, u64)>,
+}
+
+impl Poller {
+    /// Hash of the contents of the target, or every file within it for directories. `None` if it couldn't be read.
+    async fn fingerprint(&mut self, file: &Path, mode: &WatchMode) -> Option<u64> {
+        let mut previous = std::mem::take(&mut self.hashes);
+        let mut hasher = DefaultHasher::new();
+        match mode {
+            WatchMode::Directory { .. } | WatchMode::Tree(_) | WatchMode::ConfigMap => {
+                let options = match mode {
+                    WatchMode::Tree(options) => options.clone(),
+                    WatchMode::Directory { editor_artifacts } => {
+                        TreeOptions::flat(*editor_artifacts)
+                    }
+                    // only the `..data` swap matters, which anything in the directory changing covers
+                    _ => TreeOptions::flat(true),
+                };
+                let (_, files) = walk(file, &options).await.ok()?;
+                for file in files {
+                    match self.hash(&mut previous, &file).await {
+                        Ok(hash) => (file, hash).hash(&mut hasher),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                        Err(_) => return None,
+                    }
+                }
+            }
+            WatchMode::Sentinel => fs::try_exists(file).await.ok()?.hash(&mut hasher),
+            WatchMode::File | WatchMode::Tail(_) | WatchMode::Descriptor => {
+                self.hash(&mut previous, file).await.ok()?.hash(&mut hasher)
+            }
+        }
+        Some(hasher.finish())
+    }
+
+    /// Hashes the contents of `file`, skipping the read if its metadata is the same as last round.
+    /// Includes its attributes if they count as changes.
+    async fn hash(
+        &mut self,
+        previous: &mut HashMap<PathBuf, (Stat, u64)>,
+        file: &Path,
+    ) -> io::Result<u64> {
+        let stat = Stat::new(&fs::metadata(file).await?);
+        let attributes = self.attribute_changes.then_some(stat.attributes);
+        let hash = match previous.remove(file) {
+            Some((old, hash)) if !self.pseudo && old == stat => hash,
+            _ => {
+                let mut hasher = DefaultHasher::new();
+                fs::read(file).await?.hash(&mut hasher);
+                hasher.finish()
+            }
+        };
+        self.hashes.insert(file.to_path_buf(), (stat, hash));
+        let mut hasher = DefaultHasher::new();
+        (hash, attributes).hash(&mut hasher);
+        Ok(hasher.finish())
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString, OsString},
+    fmt::Display,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::fs::MetadataExt,
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::Level;
+use tokio::sync::mpsc;
+
+use super::Links;
+use crate::{
+    directory::{walk, WatchMode},
+    fs,
+    k8s::DATA_LINK,
+    normalize,
+    rt::{self, AbortHandle},
+    ChangeKind, ErrorHook, FileWatcherError, WatcherContext,
+};
+
+/// `/proc/self/fd` paths can't be associated with a port, so open files are polled.
+pub(crate) const WATCHES_DESCRIPTORS: bool = false;
+
+/// Stops the backend when dropped.
+pub(crate) struct BackendHandle(AbortHandle);
+
+impl Drop for BackendHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub(crate) async fn start_backend<E: Display + Send + 'static>(
+    watcher_context: WatcherContext,
+    on_error: ErrorHook<E>,
+) -> BackendHandle {
+    let task = rt::spawn(async move {
+        let watcher_context = Arc::new(watcher_context);
+        let mut failures = 0;
+        loop {
+            match load_config::<E>(watcher_context.clone()).await {
+                Ok(()) => failures = 0,
+                Err(e) => {
+                    failures += 1;
+                    watcher_context.watch_error(&e, failures);
+                    on_error(&e);
+                    rt::sleep(watcher_context.retry_interval).await;
+                }
+            }
+        }
+    });
+    BackendHandle(task.abort_handle())
+}
+
+// from sys/port.h, which libc doesn't carry
+const FILE_MODIFIED: i32 = 0x2;
+const FILE_ATTRIB: i32 = 0x4;
+const FILE_TRUNC: i32 = 0x100000;
+const FILE_DELETE: i32 = 0x10;
+const FILE_RENAME_TO: i32 = 0x20;
+const FILE_RENAME_FROM: i32 = 0x40;
+const UNMOUNTED: i32 = 0x20000000;
+const MOUNTEDOVER: i32 = 0x40000000;
+
+const MASK: i32 = FILE_MODIFIED | FILE_TRUNC;
+
+/// Always reported, and end the association.
+const EXCEPTION: i32 = FILE_DELETE | FILE_RENAME_TO | FILE_RENAME_FROM | UNMOUNTED | MOUNTEDOVER;
+
+#[repr(C)]
+struct FileObj {
+    fo_atime: libc::timespec,
+    fo_mtime: libc::timespec,
+    fo_ctime: libc::timespec,
+    fo_pad: [libc::uintptr_t; 3],
+    fo_name: *const libc::c_char,
+}
+
+/// Identifies what is at a path, so a directory event can be narrowed down to the children we care about.
+type Identity = Option<(u64, u64)>;
+
+/// What became of a child of a watched directory since it was recorded, for the event filter.
+fn replacement(previous: Identity, current: Identity) -> ChangeKind {
+    match (previous, current) {
+        (None, _) => ChangeKind::Created,
+        (_, None) => ChangeKind::Removed,
+        _ => ChangeKind::Renamed,
+    }
+}
+
+fn identity(path: &Path) -> Identity {
+    std::fs::symlink_metadata(path)
+        .ok()
+        .map(|x| (x.dev(), x.ino()))
+}
+
+/// A watched path. Port events don't carry names, so for directories we record the children we care about
+/// and compare them when the directory changes.
+#[derive(Default)]
+struct Node {
+    /// Any change is a change to the target.
+    target: bool,
+    children: Vec<(OsString, Identity)>,
+}
+
+/// Watch every ancestor of `path` for `path` (or the path to it) being replaced, following symlinked ancestors to where they point.
+/// Symlinked ancestors are followed at most `links` deep.
+fn watch_ancestors(nodes: &mut HashMap<PathBuf, Node>, path: &Path, links: usize) {
+    let mut child = path;
+    while let Some(parent) = child.parent() {
+        if let Some(name) = child.file_name() {
+            let node = nodes.entry(parent.to_path_buf()).or_default();
+            if !node.children.iter().any(|(x, _)| x == name) {
+                node.children.push((name.to_os_string(), identity(child)));
+            }
+        }
+        if links > 0 && std::fs::symlink_metadata(parent).is_ok_and(|x| x.is_symlink()) {
+            // the association with `parent` follows the link, but the real directory can be replaced on its own
+            if let Ok(real) = std::fs::canonicalize(parent) {
+                watch_ancestors(nodes, &real, links - 1);
+            }
+        }
+        child = parent;
+    }
+}
+
+pub(crate) async fn load_config<E: Display + Send + 'static>(
+    context: Arc<WatcherContext>,
+) -> Result<(), FileWatcherError<E>> {
+    let mut nodes: HashMap<PathBuf, Node> = HashMap::new();
+    let mut current_main_file = context.file.clone();
+    let mut links = Links::new(context.max_symlink_depth);
+    loop {
+        let missing = match context.mode {
+            WatchMode::Sentinel => true,
+            _ => {
+                context.optional
+                    && matches!(
+                        std::fs::symlink_metadata(&current_main_file),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound
+                    )
+            }
+        };
+        watch_ancestors(&mut nodes, &current_main_file, context.max_symlink_depth);
+        if missing {
+            // the ancestors are watched for the target (or the path to it) coming and going
+            break;
+        }
+        let metadata = std::fs::symlink_metadata(&current_main_file)?;
+        if metadata.is_symlink() {
+            // symlinks are replaced rather than modified, which the parent directory sees
+            links.follow(&current_main_file)?;
+            let link = std::fs::read_link(&current_main_file)?;
+            current_main_file = normalize(&match current_main_file.parent() {
+                Some(parent) if link.is_relative() => parent.join(link),
+                _ => link,
+            })?;
+            continue;
+        }
+        context.logger.log(
+            Level::Debug,
+            0,
+            format_args!("watching main target {}", current_main_file.display()),
+        );
+        match &context.mode {
+            WatchMode::ConfigMap => {
+                // only the atomic swap matters, everything else is kubelet staging the next version
+                let node = nodes.entry(current_main_file.clone()).or_default();
+                node.children.push((
+                    DATA_LINK.into(),
+                    identity(&current_main_file.join(DATA_LINK)),
+                ));
+            }
+            WatchMode::Tree(options) => {
+                let (dirs, _) = walk(&current_main_file, options).await?;
+                for dir in dirs {
+                    context.logger.log(
+                        Level::Debug,
+                        0,
+                        format_args!("watching subdirectory {}", dir.display()),
+                    );
+                    nodes.entry(dir).or_default().target = true;
+                }
+            }
+            _ => nodes.entry(current_main_file.clone()).or_default().target = true,
+        }
+        break;
+    }
+
+    let port = unsafe { libc::port_create() };
+    if port < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let port = Port(Arc::new(unsafe { OwnedFd::from_raw_fd(port) }));
+    let mut watches: Vec<(PathBuf, Node)> = vec![];
+    let mut names = vec![];
+    let mask = if context.attribute_changes {
+        MASK | FILE_ATTRIB
+    } else {
+        MASK
+    };
+    for (path, node) in nodes {
+        let name = fs::c_path(&path)?;
+        match associate(port.0.as_raw_fd(), &name, names.len(), mask) {
+            Ok(()) => (),
+            // an ancestor of a missing target
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !node.target => continue,
+            Err(e) => return Err(e.into()),
+        }
+        names.push(name);
+        watches.push((path, node));
+    }
+    context.established(
+        watches
+            .iter()
+            .map(|(path, _)| (path.clone(), format!("{mask:#x}"))),
+    );
+    let mut receiver = port.start(names, mask)?;
+
+    while let Some(event) = receiver.recv().await {
+        let (index, events) = event?;
+        let Some((path, node)) = watches.get(index) else {
+            continue;
+        };
+        context.logger.log(
+            Level::Debug,
+            0,
+            format_args!("received event {events:#x} for {}", path.display()),
+        );
+        if events & EXCEPTION != 0 {
+            // the watched node itself went away or moved, the watch set needs a full refresh
+            context.report(kind(events), path);
+            return Ok(());
+        } else if let Some((child, previous, current)) =
+            node.children.iter().find_map(|(name, previous)| {
+                let child = path.join(name);
+                let current = identity(&child);
+                (current != *previous).then_some((child, *previous, current))
+            })
+        {
+            // something we care about within a directory was replaced, and needs a full refresh
+            context.report(replacement(previous, current), &child);
+            return Ok(());
+        } else if node.target {
+            context.report(kind(events), path);
+            // directories may have appeared or disappeared
+            if let WatchMode::Tree(_) = context.mode {
+                return Ok(());
+            }
+        }
+    }
+    Err(io::Error::other("event port thread exited").into())
+}
+
+/// What an event was, for the event filter.
+fn kind(events: i32) -> ChangeKind {
+    if events & FILE_DELETE != 0 {
+        ChangeKind::Removed
+    } else if events & (FILE_RENAME_TO | FILE_RENAME_FROM) != 0 {
+        ChangeKind::Renamed
+    } else if events & (FILE_MODIFIED | FILE_TRUNC) != 0 {
+        ChangeKind::Modified
+    } else if events & FILE_ATTRIB != 0 {
+        ChangeKind::Attributes
+    } else {
+        ChangeKind::Other
+    }
+}
+
+/// Whether an event port can be created at all.
+pub(crate) fn probe() -> io::Result<()> {
+    let port = unsafe { libc::port_create() };
+    if port < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    drop(unsafe { OwnedFd::from_raw_fd(port) });
+    Ok(())
+}
+
+/// Associate the path `name` with `port` for `mask`, reporting events with `index`. Associations only report a single event,
+/// and are renewed after each.
+fn associate(port: RawFd, name: &CStr, index: usize, mask: i32) -> io::Result<()> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::stat(name.as_ptr(), &mut stat) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // changes since these times are reported right away, so none are missed between events
+    let object = FileObj {
+        fo_atime: libc::timespec {
+            tv_sec: stat.st_atime,
+            tv_nsec: stat.st_atime_nsec,
+        },
+        fo_mtime: libc::timespec {
+            tv_sec: stat.st_mtime,
+            tv_nsec: stat.st_mtime_nsec,
+        },
+        fo_ctime: libc::timespec {
+            tv_sec: stat.st_ctime,
+            tv_nsec: stat.st_ctime_nsec,
+        },
+        fo_pad: [0; 3],
+        fo_name: name.as_ptr(),
+    };
+    let result = unsafe {
+        libc::port_associate(
+            port,
+            libc::PORT_SOURCE_FILE,
+            &object as *const FileObj as libc::uintptr_t,
+            mask,
+            index as *mut libc::c_void,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Events as (index of the associated path, events).
+type Receiver = mpsc::UnboundedReceiver<io::Result<(usize, i32)>>;
+
+/// Stops the thread waiting on the port when dropped.
+struct Port(Arc<OwnedFd>);
+
+impl Port {
+    /// Waits for events on its own thread, renewing associations of `names` as they fire.
+    fn start(&self, names: Vec<CString>, mask: i32) -> io::Result<Receiver> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let port = self.0.clone();
+        crate::thread::spawn("ports", move || loop {
+            let mut event: libc::port_event = unsafe { std::mem::zeroed() };
+            if unsafe { libc::port_get(port.as_raw_fd(), &mut event, std::ptr::null_mut()) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                sender.send(Err(e)).ok();
+                return;
+            }
+            if event.portev_source as i32 != libc::PORT_SOURCE_FILE {
+                return;
+            }
+            let index = event.portev_user as usize;
+            if sender.send(Ok((index, event.portev_events))).is_err() {
+                return;
+            }
+            if event.portev_events & EXCEPTION == 0 {
+                match associate(port.as_raw_fd(), &names[index], index, mask) {
+                    Ok(()) => (),
+                    // removed since the event, which needs a full refresh all the same
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        sender.send(Ok((index, FILE_DELETE))).ok();
+                        return;
+                    }
+                    Err(e) => {
+                        sender.send(Err(e)).ok();
+                        return;
+                    }
+                }
+            }
+        })?;
+        Ok(receiver)
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        unsafe { libc::port_send(self.0.as_raw_fd(), 0, std::ptr::null_mut()) };
+    }
+}
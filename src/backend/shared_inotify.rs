@@ -0,0 +1,206 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    sync::{Arc, Mutex, OnceLock, Weak},
+};
+
+use crate::logging::error;
+use futures::{pin_mut, StreamExt};
+use tokio::sync::mpsc;
+
+use crate::{
+    inotify::{INotify, INotifyEvent, INotifyMask, WatchHandle},
+    rt::{self, AbortHandle},
+};
+
+/// Reported regardless of the mask a watch was registered with.
+const ALWAYS: INotifyMask = INotifyMask::Ignored
+    .or(INotifyMask::Unmount)
+    .or(INotifyMask::QueueOverflow);
+
+/// One inotify instance for every watcher that opted in, handing events out by watch descriptor.
+/// Stops reading events once the last subscription is dropped.
+pub(crate) struct SharedINotify {
+    state: Arc<State>,
+    dispatcher: AbortHandle,
+}
+
+impl Drop for SharedINotify {
+    fn drop(&mut self) {
+        self.dispatcher.abort();
+    }
+}
+
+struct State {
+    notify: INotify,
+    registry: Mutex<Registry>,
+}
+
+#[derive(Default)]
+struct Registry {
+    /// Which subscribers are interested in each watch, and in which events.
+    watches: HashMap<WatchHandle, HashMap<u64, INotifyMask>>,
+    subscribers: HashMap<u64, mpsc::UnboundedSender<INotifyEvent>>,
+    next_id: u64,
+}
+
+impl Registry {
+    fn dispatch(&mut self, event: INotifyEvent) {
+        if event.mask.contains(INotifyMask::QueueOverflow) {
+            // not tied to a watch, and anyone may have missed events
+            for sender in self.subscribers.values() {
+                sender.send(event.clone()).ok();
+            }
+            return;
+        }
+        let Some(interested) = self.watches.get(&event.watch_descriptor) else {
+            // left over from a watch removed since
+            return;
+        };
+        for (id, mask) in interested {
+            if !event.mask.intersects(*mask | ALWAYS) {
+                // asked for by another subscriber of the same inode
+                continue;
+            }
+            if let Some(sender) = self.subscribers.get(id) {
+                sender.send(event.clone()).ok();
+            }
+        }
+        if event.mask.contains(INotifyMask::Ignored) {
+            self.watches.remove(&event.watch_descriptor);
+        }
+    }
+}
+
+/// The running instance, if any subscription is left.
+fn instance() -> &'static Mutex<Weak<SharedINotify>> {
+    static INSTANCE: OnceLock<Mutex<Weak<SharedINotify>>> = OnceLock::new();
+    INSTANCE.get_or_init(Default::default)
+}
+
+/// Forgets the running instance, whose dispatcher doesn't survive a fork, so the next subscriber starts another.
+pub(crate) fn reset() {
+    *instance().lock().unwrap() = Weak::new();
+}
+
+impl SharedINotify {
+    /// Subscribes to the shared instance, starting it on the current runtime if none is running.
+    pub(crate) fn subscribe() -> io::Result<(Subscription, mpsc::UnboundedReceiver<INotifyEvent>)> {
+        let mut current = instance().lock().unwrap();
+        let shared = match current.upgrade() {
+            Some(x) => x,
+            None => {
+                let state = Arc::new(State {
+                    notify: INotify::new()?,
+                    registry: Default::default(),
+                });
+                let dispatcher = rt::spawn(dispatch(state.clone())).abort_handle();
+                let shared = Arc::new(SharedINotify { state, dispatcher });
+                *current = Arc::downgrade(&shared);
+                shared
+            }
+        };
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut registry = shared.state.registry.lock().unwrap();
+        let id = registry.next_id;
+        registry.next_id += 1;
+        registry.subscribers.insert(id, sender);
+        drop(registry);
+        Ok((Subscription { shared, id }, receiver))
+    }
+}
+
+/// Reads events for every subscriber, until the last one is gone or the runtime it was started on shuts down.
+async fn dispatch(state: Arc<State>) {
+    let _closed = Closed(state.clone());
+    let stream = state.notify.stream();
+    pin_mut!(stream);
+    while let Some(event) = stream.next().await {
+        match event {
+            Ok(event) => state.registry.lock().unwrap().dispatch(event),
+            Err(e) => {
+                error!("shared inotify instance failed: {e}");
+                return;
+            }
+        }
+    }
+}
+
+/// Once events stop being read, subscribers are told by their channel closing, and the next subscriber starts a new instance.
+struct Closed(Arc<State>);
+
+impl Drop for Closed {
+    fn drop(&mut self) {
+        let mut current = instance().lock().unwrap();
+        if current
+            .upgrade()
+            .is_some_and(|x| Arc::ptr_eq(&x.state, &self.0))
+        {
+            *current = Weak::new();
+        }
+        drop(current);
+        self.0.registry.lock().unwrap().subscribers.clear();
+    }
+}
+
+/// A watcher's share of the instance. Watches are removed once no subscriber is interested in them anymore.
+pub(crate) struct Subscription {
+    shared: Arc<SharedINotify>,
+    id: u64,
+}
+
+impl Subscription {
+    /// Watches from other subscribers of the same inode are widened to include `mask`, and events are narrowed down to it again.
+    pub(crate) fn add_watch(&self, path: &Path, mask: INotifyMask) -> io::Result<WatchHandle> {
+        // held across registration, so the first events for a new watch aren't dropped
+        let mut registry = self.shared.state.registry.lock().unwrap();
+        let handle = self
+            .shared
+            .state
+            .notify
+            .add_watch(path, mask | INotifyMask::MaskAdd)?;
+        let interest = registry
+            .watches
+            .entry(handle)
+            .or_default()
+            .entry(self.id)
+            .or_insert(mask);
+        *interest |= mask;
+        Ok(handle)
+    }
+
+    pub(crate) fn rm_watch(&self, handle: WatchHandle) -> io::Result<()> {
+        let mut registry = self.shared.state.registry.lock().unwrap();
+        self.release(&mut registry, handle)
+    }
+
+    fn release(&self, registry: &mut Registry, handle: WatchHandle) -> io::Result<()> {
+        let Some(interested) = registry.watches.get_mut(&handle) else {
+            return Ok(());
+        };
+        interested.remove(&self.id);
+        if interested.is_empty() {
+            registry.watches.remove(&handle);
+            self.shared.state.notify.rm_watch(handle)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut registry = self.shared.state.registry.lock().unwrap();
+        registry.subscribers.remove(&self.id);
+        let handles: Vec<WatchHandle> = registry
+            .watches
+            .iter()
+            .filter(|(_, interested)| interested.contains_key(&self.id))
+            .map(|(handle, _)| *handle)
+            .collect();
+        for handle in handles {
+            // already gone if what it watched was deleted
+            self.release(&mut registry, handle).ok();
+        }
+    }
+}
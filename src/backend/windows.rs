@@ -0,0 +1,471 @@
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt::Display,
+    io,
+    os::windows::{
+        ffi::{OsStrExt, OsStringExt},
+        io::{AsRawHandle, FromRawHandle, OwnedHandle},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::Level;
+use tokio::sync::mpsc;
+use windows_sys::Win32::{
+    Foundation::{HANDLE, INVALID_HANDLE_VALUE},
+    Storage::FileSystem::{
+        CreateFileW, ReadDirectoryChangesW, FILE_ACTION_ADDED, FILE_ACTION_MODIFIED,
+        FILE_ACTION_REMOVED, FILE_FLAG_BACKUP_SEMANTICS, FILE_FLAG_OVERLAPPED, FILE_LIST_DIRECTORY,
+        FILE_NOTIFY_CHANGE_ATTRIBUTES, FILE_NOTIFY_CHANGE_CREATION, FILE_NOTIFY_CHANGE_DIR_NAME,
+        FILE_NOTIFY_CHANGE_FILE_NAME, FILE_NOTIFY_CHANGE_LAST_WRITE, FILE_NOTIFY_CHANGE_SECURITY,
+        FILE_NOTIFY_CHANGE_SIZE, FILE_NOTIFY_INFORMATION, FILE_SHARE_DELETE, FILE_SHARE_READ,
+        FILE_SHARE_WRITE, OPEN_EXISTING,
+    },
+    System::IO::{
+        CancelIoEx, CreateIoCompletionPort, GetQueuedCompletionStatus, PostQueuedCompletionStatus,
+        OVERLAPPED,
+    },
+};
+
+use super::Links;
+use crate::{
+    directory::WatchMode,
+    k8s::DATA_LINK,
+    normalize,
+    rt::{self, AbortHandle},
+    ChangeKind, ErrorHook, FileWatcherError, WatcherContext,
+};
+
+/// Open files are only watched on unix.
+pub(crate) const WATCHES_DESCRIPTORS: bool = false;
+
+/// Stops the backend when dropped.
+pub(crate) struct BackendHandle(AbortHandle);
+
+impl Drop for BackendHandle {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub(crate) async fn start_backend<E: Display + Send + 'static>(
+    watcher_context: WatcherContext,
+    on_error: ErrorHook<E>,
+) -> BackendHandle {
+    let task = rt::spawn(async move {
+        let watcher_context = Arc::new(watcher_context);
+        let mut failures = 0;
+        loop {
+            match load_config::<E>(watcher_context.clone()).await {
+                Ok(()) => failures = 0,
+                Err(e) => {
+                    failures += 1;
+                    watcher_context.watch_error(&e, failures);
+                    on_error(&e);
+                    rt::sleep(watcher_context.retry_interval).await;
+                }
+            }
+        }
+    });
+    BackendHandle(task.abort_handle())
+}
+
+/// A watched directory.
+#[derive(Default)]
+struct Node {
+    /// Any change within is a change to the target.
+    target: bool,
+    /// Changes anywhere below count, not just direct children.
+    recursive: bool,
+    /// Entries we care about, and whether modifying them (rather than only replacing them) is a change.
+    children: Vec<(OsString, bool)>,
+}
+
+impl Node {
+    fn child(&mut self, name: &OsStr, contents: bool) {
+        match self.children.iter_mut().find(|(x, _)| same_name(x, name)) {
+            Some((_, x)) => *x |= contents,
+            None => self.children.push((name.to_os_string(), contents)),
+        }
+    }
+}
+
+/// Names are case-insensitive on Windows.
+fn same_name(left: &OsStr, right: &OsStr) -> bool {
+    left.to_string_lossy().to_lowercase() == right.to_string_lossy().to_lowercase()
+}
+
+/// Watch every ancestor of `path` for `path` (or the path to it) being replaced, following symlinked and junctioned ancestors to where they point.
+/// Symlinked ancestors are followed at most `links` deep.
+fn watch_ancestors(nodes: &mut HashMap<PathBuf, Node>, path: &Path, links: usize) {
+    let mut child = path;
+    while let Some(parent) = child.parent() {
+        if let Some(name) = child.file_name() {
+            nodes
+                .entry(parent.to_path_buf())
+                .or_default()
+                .child(name, false);
+        }
+        if links > 0 && std::fs::symlink_metadata(parent).is_ok_and(|x| x.is_symlink()) {
+            // the watch on `parent` follows the link, but the real directory can be replaced on its own
+            if let Ok(real) = std::fs::canonicalize(parent).and_then(|x| normalize(&x)) {
+                watch_ancestors(nodes, &real, links - 1);
+            }
+        }
+        child = parent;
+    }
+}
+
+pub(crate) async fn load_config<E: Display + Send + 'static>(
+    context: Arc<WatcherContext>,
+) -> Result<(), FileWatcherError<E>> {
+    let mut nodes: HashMap<PathBuf, Node> = HashMap::new();
+    let mut current_main_file = normalize(&context.file)?;
+    let mut links = Links::new(context.max_symlink_depth);
+    loop {
+        let missing = match context.mode {
+            WatchMode::Sentinel => true,
+            _ => {
+                context.optional
+                    && matches!(
+                        std::fs::symlink_metadata(&current_main_file),
+                        Err(e) if e.kind() == io::ErrorKind::NotFound
+                    )
+            }
+        };
+        watch_ancestors(&mut nodes, &current_main_file, context.max_symlink_depth);
+        if missing {
+            // the ancestors are watched for the target (or the path to it) coming and going
+            break;
+        }
+        let metadata = std::fs::symlink_metadata(&current_main_file)?;
+        if metadata.is_symlink() {
+            // symlinks and junctions are replaced rather than modified, which the parent directory sees
+            links.follow(&current_main_file)?;
+            let link = std::fs::read_link(&current_main_file)?;
+            current_main_file = normalize(&match current_main_file.parent() {
+                Some(parent) if link.is_relative() => parent.join(link),
+                _ => link,
+            })?;
+            continue;
+        }
+        context.logger.log(
+            Level::Debug,
+            0,
+            format_args!("watching main target {}", current_main_file.display()),
+        );
+        match &context.mode {
+            // only the atomic swap matters, everything else is kubelet staging the next version
+            WatchMode::ConfigMap => nodes
+                .entry(current_main_file.clone())
+                .or_default()
+                .child(OsStr::new(DATA_LINK), false),
+            WatchMode::Directory { .. } | WatchMode::Tree(_) => {
+                let node = nodes.entry(current_main_file.clone()).or_default();
+                node.target = true;
+                node.recursive = matches!(context.mode, WatchMode::Tree(_));
+            }
+            _ => {
+                if let (Some(parent), Some(name)) =
+                    (current_main_file.parent(), current_main_file.file_name())
+                {
+                    nodes
+                        .entry(parent.to_path_buf())
+                        .or_default()
+                        .child(name, true);
+                }
+            }
+        }
+        break;
+    }
+
+    let mut watched = vec![];
+    let mut handles = vec![];
+    for (path, node) in nodes {
+        let handle = match open(&path) {
+            Ok(x) => x,
+            // an ancestor of a missing target
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !node.target => continue,
+            Err(e) => return Err(e.into()),
+        };
+        handles.push((handle, node.recursive));
+        watched.push((path, node));
+    }
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let filter = if context.attribute_changes {
+        FILTER | FILE_NOTIFY_CHANGE_ATTRIBUTES | FILE_NOTIFY_CHANGE_SECURITY
+    } else {
+        FILTER
+    };
+    let _watches = Watches::start(handles, filter, sender)?;
+    context.established(watched.iter().map(|(path, node)| {
+        let subtree = if node.recursive { ", subtree" } else { "" };
+        (path.clone(), format!("{filter:#x}{subtree}"))
+    }));
+
+    while let Some(event) = receiver.recv().await {
+        let (index, changes) = match event {
+            Event::Changes(index, changes) => (index, changes),
+            Event::Overflow(index) => {
+                context.logger.log(
+                    Level::Debug,
+                    0,
+                    format_args!("event buffer overflowed for {}", watched[index].0.display()),
+                );
+                context.notify.notify_one();
+                return Ok(());
+            }
+            Event::Failed(e) => return Err(e.into()),
+        };
+        let (path, node) = &watched[index];
+        let mut rebuild = false;
+        for (action, name) in changes {
+            let changed = path.join(&name);
+            context.logger.log(
+                Level::Debug,
+                0,
+                format_args!("received event {action} for {}", changed.display()),
+            );
+            if let Some((_, contents)) = node.children.iter().find(|(x, _)| same_name(x, &name)) {
+                if action != FILE_ACTION_MODIFIED {
+                    // something along the way was replaced, and needs a full refresh
+                    context.report(kind(action), &changed);
+                    rebuild = true;
+                } else if *contents {
+                    context.report(kind(action), &changed);
+                }
+            } else if node.target {
+                context.report(kind(action), &changed);
+            }
+        }
+        if rebuild {
+            return Ok(());
+        }
+    }
+    Err(io::Error::other("directory watch thread stopped").into())
+}
+
+/// What an action was, for the event filter. Attribute changes are reported as modifications too.
+fn kind(action: u32) -> ChangeKind {
+    match action {
+        FILE_ACTION_ADDED => ChangeKind::Created,
+        FILE_ACTION_REMOVED => ChangeKind::Removed,
+        FILE_ACTION_MODIFIED => ChangeKind::Modified,
+        _ => ChangeKind::Renamed,
+    }
+}
+
+fn open(path: &Path) -> io::Result<OwnedHandle> {
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let handle = unsafe {
+        CreateFileW(
+            wide.as_ptr(),
+            FILE_LIST_DIRECTORY,
+            FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OVERLAPPED,
+            0,
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedHandle::from_raw_handle(handle as _) })
+}
+
+enum Event {
+    /// (index of the watched directory, [(action, name relative to the directory)])
+    Changes(usize, Vec<(u32, OsString)>),
+    /// Too much changed at once to be reported, anything may have changed.
+    Overflow(usize),
+    Failed(io::Error),
+}
+
+/// Completion key telling the thread to stop, directories are keyed by their index + 1.
+const STOP: usize = 0;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+const FILTER: u32 = FILE_NOTIFY_CHANGE_FILE_NAME
+    | FILE_NOTIFY_CHANGE_DIR_NAME
+    | FILE_NOTIFY_CHANGE_SIZE
+    | FILE_NOTIFY_CHANGE_LAST_WRITE
+    | FILE_NOTIFY_CHANGE_CREATION;
+
+/// Watches directories on a thread waiting on an I/O completion port, stopped when dropped.
+struct Watches(Arc<OwnedHandle>);
+
+impl Drop for Watches {
+    fn drop(&mut self) {
+        unsafe {
+            PostQueuedCompletionStatus(self.0.as_raw_handle() as HANDLE, 0, STOP, std::ptr::null())
+        };
+    }
+}
+
+struct Watch {
+    handle: OwnedHandle,
+    recursive: bool,
+    filter: u32,
+    overlapped: Box<OVERLAPPED>,
+    // `FILE_NOTIFY_INFORMATION` needs to be aligned
+    buffer: Vec<u32>,
+}
+
+// the pointer in `OVERLAPPED` is never used, and the read it belongs to is only touched by the watching thread
+unsafe impl Send for Watch {}
+
+impl Watch {
+    fn read(&mut self) -> io::Result<()> {
+        *self.overlapped = unsafe { std::mem::zeroed() };
+        let result = unsafe {
+            ReadDirectoryChangesW(
+                self.handle.as_raw_handle() as HANDLE,
+                self.buffer.as_mut_ptr().cast(),
+                BUFFER_SIZE as u32,
+                self.recursive as i32,
+                self.filter,
+                std::ptr::null_mut(),
+                &mut *self.overlapped,
+                None,
+            )
+        };
+        if result == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn changes(&self, length: usize) -> Vec<(u32, OsString)> {
+        let buffer = self.buffer.as_ptr().cast::<u8>();
+        let mut out = vec![];
+        let mut offset = 0;
+        while offset < length {
+            let info = unsafe { &*buffer.add(offset).cast::<FILE_NOTIFY_INFORMATION>() };
+            let name = unsafe {
+                std::slice::from_raw_parts(info.FileName.as_ptr(), info.FileNameLength as usize / 2)
+            };
+            out.push((info.Action, OsString::from_wide(name)));
+            if info.NextEntryOffset == 0 {
+                break;
+            }
+            offset += info.NextEntryOffset as usize;
+        }
+        out
+    }
+}
+
+impl Watches {
+    fn start(
+        handles: Vec<(OwnedHandle, bool)>,
+        filter: u32,
+        sender: mpsc::UnboundedSender<Event>,
+    ) -> io::Result<Self> {
+        let port = unsafe { CreateIoCompletionPort(INVALID_HANDLE_VALUE, 0, 0, 1) };
+        if port == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let port = Arc::new(unsafe { OwnedHandle::from_raw_handle(port as _) });
+        let mut watches = vec![];
+        for (index, (handle, recursive)) in handles.into_iter().enumerate() {
+            let associated = unsafe {
+                CreateIoCompletionPort(
+                    handle.as_raw_handle() as HANDLE,
+                    port.as_raw_handle() as HANDLE,
+                    index + 1,
+                    0,
+                )
+            };
+            if associated == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            watches.push(Watch {
+                handle,
+                recursive,
+                filter,
+                overlapped: Box::new(unsafe { std::mem::zeroed() }),
+                buffer: vec![0; BUFFER_SIZE / 4],
+            });
+        }
+        for pending in 0..watches.len() {
+            if let Err(e) = watches[pending].read() {
+                cancel(&port, &watches, pending);
+                return Err(e);
+            }
+        }
+        let thread_port = port.clone();
+        crate::thread::spawn("windows", move || run(thread_port, watches, sender))?;
+        Ok(Self(port))
+    }
+}
+
+fn run(port: Arc<OwnedHandle>, mut watches: Vec<Watch>, sender: mpsc::UnboundedSender<Event>) {
+    let mut pending = watches.len();
+    loop {
+        let mut length = 0u32;
+        let mut key = 0usize;
+        let mut overlapped = std::ptr::null_mut();
+        let result = unsafe {
+            GetQueuedCompletionStatus(
+                port.as_raw_handle() as HANDLE,
+                &mut length,
+                &mut key,
+                &mut overlapped,
+                u32::MAX,
+            )
+        };
+        if key == STOP {
+            break;
+        }
+        pending -= 1;
+        let index = key - 1;
+        let event = if result == 0 {
+            Event::Failed(io::Error::last_os_error())
+        } else if length == 0 {
+            Event::Overflow(index)
+        } else {
+            Event::Changes(index, watches[index].changes(length as usize))
+        };
+        let failed = matches!(event, Event::Failed(_));
+        if sender.send(event).is_err() || failed {
+            break;
+        }
+        if let Err(e) = watches[index].read() {
+            sender.send(Event::Failed(e)).ok();
+            break;
+        }
+        pending += 1;
+    }
+    cancel(&port, &watches, pending);
+}
+
+/// Cancels outstanding reads, waiting for them to finish before their buffers are freed.
+fn cancel(port: &OwnedHandle, watches: &[Watch], mut pending: usize) {
+    for watch in watches {
+        unsafe { CancelIoEx(watch.handle.as_raw_handle() as HANDLE, std::ptr::null()) };
+    }
+    while pending > 0 {
+        let mut length = 0u32;
+        let mut key = 0usize;
+        let mut overlapped = std::ptr::null_mut();
+        let result = unsafe {
+            GetQueuedCompletionStatus(
+                port.as_raw_handle() as HANDLE,
+                &mut length,
+                &mut key,
+                &mut overlapped,
+                u32::MAX,
+            )
+        };
+        if result == 0 && overlapped.is_null() {
+            // the port itself failed, nothing more will complete
+            break;
+        }
+        if key != STOP {
+            pending -= 1;
+        }
+    }
+}
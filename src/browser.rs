@@ -0,0 +1,166 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use crate::logging::debug;
+use futures::future::BoxFuture;
+use js_sys::{ArrayBuffer, Function, Promise, Uint8Array};
+use tokio::{select, sync::Notify};
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+
+use crate::{rt, Source};
+
+#[cfg(target_feature = "atomics")]
+compile_error!("the `wasm` feature relies on browser builds having a single thread");
+
+/// Reads a file through JavaScript in a browser, i.e. from the origin private file system (OPFS) or a handle granted
+/// through the File System Access API, for the same config reload code to run in a browser-hosted build.
+/// Neither reports changes, so they are polled for with [`BrowserSource::with_poll_interval`], and can be reported
+/// by calling [`BrowserSource::change_callback`], i.e. from a `FileSystemObserver` or a message from the worker that
+/// wrote the file.
+pub struct BrowserSource {
+    name: String,
+    read: Local<Function>,
+    poll_interval: Option<Duration>,
+    changed: Arc<Notify>,
+    state: Mutex<BrowserState>,
+}
+
+#[derive(Default)]
+struct BrowserState {
+    contents: Option<Vec<u8>>,
+    /// `contents` were read while polling and not read yet.
+    fresh: bool,
+}
+
+impl BrowserSource {
+    /// `read` is called without arguments and returns the contents, or a promise of them, as a string, `ArrayBuffer`, or
+    /// `Uint8Array`, i.e. `async () => (await handle.getFile()).arrayBuffer()`. A thrown error or rejected promise is a
+    /// failed read. `name` identifies the file in logs.
+    pub fn new(name: impl AsRef<str>, read: Function) -> Self {
+        Self {
+            name: name.as_ref().to_string(),
+            read: Local(read),
+            poll_interval: None,
+            changed: Default::default(),
+            state: Default::default(),
+        }
+    }
+
+    /// Reads every `poll_interval`, emitting when the contents changed.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = Some(poll_interval);
+        self
+    }
+
+    /// A JavaScript function that makes the watcher read the file again, for whatever knows it changed to call. Get it
+    /// before handing the source to [`crate::FileWatcherConfig::from_source`].
+    pub fn change_callback(&self) -> Function {
+        let changed = self.changed.clone();
+        Closure::<dyn Fn()>::new(move || changed.notify_one())
+            .into_js_value()
+            .unchecked_into()
+    }
+
+    /// Returns whether the contents changed.
+    async fn fetch(&self) -> io::Result<bool> {
+        let out = self.read.0.call0(&JsValue::UNDEFINED).map_err(js_error)?;
+        let out = JsFuture::from(Promise::resolve(&out))
+            .await
+            .map_err(js_error)?;
+        let contents = if let Some(text) = out.as_string() {
+            text.into_bytes()
+        } else if let Some(bytes) = out.dyn_ref::<Uint8Array>() {
+            bytes.to_vec()
+        } else if out.is_instance_of::<ArrayBuffer>() {
+            Uint8Array::new(&out).to_vec()
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected a string, ArrayBuffer, or Uint8Array",
+            ));
+        };
+        let mut state = self.state.lock().unwrap();
+        let changed = state.contents.as_ref() != Some(&contents);
+        state.contents = Some(contents);
+        state.fresh = true;
+        Ok(changed)
+    }
+}
+
+impl Source for BrowserSource {
+    type Input = Vec<u8>;
+
+    fn describe(&self) -> String {
+        self.name.clone()
+    }
+
+    fn read(&self) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(Local(async move {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.fresh {
+                    state.fresh = false;
+                    return Ok(state.contents.clone().unwrap_or_default());
+                }
+            }
+            self.fetch().await?;
+            let mut state = self.state.lock().unwrap();
+            state.fresh = false;
+            Ok(state.contents.clone().unwrap_or_default())
+        }))
+    }
+
+    fn changed(&self) -> BoxFuture<'_, ()> {
+        Box::pin(Local(async move {
+            let Some(poll_interval) = self.poll_interval else {
+                return self.changed.notified().await;
+            };
+            loop {
+                select! {
+                    _ = self.changed.notified() => return,
+                    _ = rt::sleep(poll_interval) => (),
+                }
+                match self.fetch().await {
+                    Ok(true) => return,
+                    Ok(false) => continue,
+                    Err(e) => {
+                        // the read reports it and handles retrying
+                        debug!("failed to poll '{}': {e}", self.name);
+                        return;
+                    }
+                }
+            }
+        }))
+    }
+}
+
+/// Carries JavaScript values, and futures holding them, across `Send` bounds. Sound since they never leave the single
+/// thread of a browser build.
+struct Local<T>(T);
+
+unsafe impl<T> Send for Local<T> {}
+unsafe impl<T> Sync for Local<T> {}
+
+impl<F: Future> Future for Local<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // structural pinning of the only field
+        unsafe { self.map_unchecked_mut(|x| &mut x.0) }.poll(cx)
+    }
+}
+
+fn js_error(e: JsValue) -> io::Error {
+    let message = match e.dyn_ref::<js_sys::Error>() {
+        Some(e) => String::from(e.message()),
+        None => e.as_string().unwrap_or_else(|| format!("{e:?}")),
+    };
+    io::Error::other(message)
+}
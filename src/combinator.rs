@@ -0,0 +1,160 @@
+use tokio::{select, sync::mpsc};
+
+use crate::{group::Runner, rt, WatcherId};
+
+/// Merges the output of several started watchers into a single stream of tagged updates.
+/// Each source is mapped into the shared output type, e.g. an enum with a variant per config, or a `Box<dyn Any + Send>`.
+pub struct Merge<T> {
+    sources: Vec<Runner<T>>,
+}
+
+impl<T: Send + 'static> Default for Merge<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Send + 'static> Merge<T> {
+    pub fn new() -> Self {
+        Self { sources: vec![] }
+    }
+
+    /// Add the receiver of a started watcher. Returns the id its updates are tagged with.
+    pub fn add<S: Send + 'static>(
+        &mut self,
+        receiver: mpsc::Receiver<S>,
+        map: impl Fn(S) -> T + Send + 'static,
+    ) -> WatcherId {
+        let id = WatcherId(self.sources.len());
+        self.sources.push(Box::new(move |sender| {
+            Box::pin(forward(receiver, sender, move |x| (id, map(x))))
+        }));
+        id
+    }
+
+    /// Start merging. Dropping/closing this receiver drops every source receiver, stopping their watchers.
+    /// The stream ends once every source has ended.
+    pub fn start(self) -> mpsc::Receiver<(WatcherId, T)> {
+        let (sender, receiver) = mpsc::channel(3 * self.sources.len().max(1));
+        for source in self.sources {
+            rt::spawn(source(sender.clone()));
+        }
+        receiver
+    }
+}
+
+/// Forwards `receiver` into `sender` through `map`, until either side closes.
+async fn forward<S, T>(
+    mut receiver: mpsc::Receiver<S>,
+    sender: mpsc::Sender<T>,
+    map: impl Fn(S) -> T,
+) {
+    loop {
+        select! {
+            item = receiver.recv() => {
+                let Some(item) = item else {
+                    return;
+                };
+                if sender.send(map(item)).await.is_err() {
+                    return;
+                }
+            },
+            _ = sender.closed() => {
+                return;
+            }
+        }
+    }
+}
+
+/// Returns `None` if `receiver` ended or `sender` was closed.
+async fn recv_until_closed<S, T>(
+    receiver: &mut mpsc::Receiver<S>,
+    sender: &mpsc::Sender<T>,
+) -> Option<S> {
+    select! {
+        item = receiver.recv() => item,
+        _ = sender.closed() => None,
+    }
+}
+
+enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+/// Combines two started watchers, emitting both latest values whenever either updates (e.g. configs that must be applied together).
+/// Nothing is emitted until both have produced a value. Dropping/closing this receiver stops both watchers.
+pub fn zip_latest<A: Clone + Send + 'static, B: Clone + Send + 'static>(
+    left: mpsc::Receiver<A>,
+    right: mpsc::Receiver<B>,
+) -> mpsc::Receiver<(A, B)> {
+    let mut merge = Merge::new();
+    merge.add(left, Either::Left);
+    merge.add(right, Either::Right);
+    let mut updates = merge.start();
+    let (sender, receiver) = mpsc::channel(3);
+    rt::spawn(async move {
+        let mut latest_left = None;
+        let mut latest_right = None;
+        while let Some((_, update)) = recv_until_closed(&mut updates, &sender).await {
+            match update {
+                Either::Left(x) => latest_left = Some(x),
+                Either::Right(x) => latest_right = Some(x),
+            }
+            if let (Some(left), Some(right)) = (&latest_left, &latest_right) {
+                if sender.send((left.clone(), right.clone())).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+/// Like [`zip_latest`], for any number of watchers of the same type. Values are emitted in the order of `receivers`.
+pub fn zip_latest_all<T: Clone + Send + 'static>(
+    receivers: impl IntoIterator<Item = mpsc::Receiver<T>>,
+) -> mpsc::Receiver<Vec<T>> {
+    let mut merge = Merge::new();
+    for receiver in receivers {
+        merge.add(receiver, |x| x);
+    }
+    let mut latest: Vec<Option<T>> = (0..merge.sources.len()).map(|_| None).collect();
+    let mut updates = merge.start();
+    let (sender, receiver) = mpsc::channel(3);
+    rt::spawn(async move {
+        while let Some((id, update)) = recv_until_closed(&mut updates, &sender).await {
+            latest[id.0] = Some(update);
+            let Some(values) = latest.iter().cloned().collect::<Option<Vec<T>>>() else {
+                continue;
+            };
+            if sender.send(values).await.is_err() {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_zip_latest() {
+        let (tls, tls_receiver) = mpsc::channel(3);
+        let (limits, limits_receiver) = mpsc::channel(3);
+        let mut receiver = zip_latest(tls_receiver, limits_receiver);
+        tls.send("cert1").await.unwrap();
+        limits.send(10).await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), ("cert1", 10));
+        limits.send(20).await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), ("cert1", 20));
+        tls.send("cert2").await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), ("cert2", 20));
+        drop(receiver);
+        tokio::time::timeout(std::time::Duration::from_secs(5), tls.closed())
+            .await
+            .unwrap();
+    }
+}
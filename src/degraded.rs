@@ -0,0 +1,144 @@
+use std::{
+    fmt::Display,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::Level;
+
+use crate::{
+    logging::Logger,
+    rt::{self, Instant},
+};
+
+/// A watcher that has been failing for longer than the threshold set with
+/// [`crate::FileWatcherConfig::with_degraded_hook`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Degraded {
+    /// Path of the target.
+    pub path: PathBuf,
+    /// How long the watcher has been failing for.
+    pub duration: Duration,
+    /// The last error it failed with.
+    pub last_error: String,
+}
+
+pub(crate) type DegradedCallback = Arc<dyn Fn(&Degraded) + Send + Sync>;
+
+/// Calls its hook once per run of failures lasting longer than `threshold`.
+pub(crate) struct DegradedHook {
+    threshold: Duration,
+    /// Watch errors further apart than this are separate runs, since the watch was established in between.
+    gap: Duration,
+    path: PathBuf,
+    log_name: String,
+    logger: Logger,
+    hook: DegradedCallback,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// When the current run of failures started, `None` without one.
+    since: Option<Instant>,
+    /// Reads are failing, rather than only watches, so only a successful read ends the run.
+    reads_failing: bool,
+    last: Option<Instant>,
+    last_error: String,
+    fired: bool,
+    /// Counts the runs of failures, so the timer of an earlier one does nothing.
+    run: u64,
+}
+
+impl DegradedHook {
+    pub(crate) fn new(
+        threshold: Duration,
+        retry_interval: Duration,
+        path: PathBuf,
+        log_name: String,
+        logger: Logger,
+        hook: DegradedCallback,
+    ) -> Self {
+        Self {
+            threshold,
+            gap: retry_interval * 2,
+            path,
+            log_name,
+            logger,
+            hook,
+            state: Default::default(),
+        }
+    }
+
+    /// Records a failure to read or parse the target, or with `read` unset, to establish a watch. Backends keep
+    /// retrying failed watches every retry interval, so those only continue a run while they keep coming.
+    pub(crate) fn failed(self: &Arc<Self>, e: &impl Display, read: bool) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if !state.reads_failing && state.last.is_some_and(|x| now - x > self.gap) {
+            state.since = None;
+        }
+        if state.since.is_none() {
+            state.since = Some(now);
+            state.fired = false;
+            state.run += 1;
+        }
+        state.last = Some(now);
+        state.last_error = e.to_string();
+        // reads aren't retried at all with `Retry::OnChange`, so nothing else would notice the threshold passing
+        if read && !state.reads_failing {
+            state.reads_failing = true;
+            if rt::available() {
+                let (hook, run) = (self.clone(), state.run);
+                rt::spawn(async move {
+                    rt::sleep(hook.threshold).await;
+                    let mut state = hook.state.lock().unwrap();
+                    if state.run == run {
+                        hook.fire(&mut state);
+                    }
+                });
+            }
+        }
+        self.fire(&mut state);
+    }
+
+    /// Ends the current run of failures, if any.
+    pub(crate) fn recovered(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.since.take().is_some() {
+            state.run += 1;
+        }
+        state.reads_failing = false;
+        state.last = None;
+    }
+
+    /// Calls the hook if the current run of failures lasted long enough, and it wasn't called for it yet.
+    fn fire(&self, state: &mut State) {
+        let Some(since) = state.since else {
+            return;
+        };
+        let duration = since.elapsed();
+        if state.fired || duration < self.threshold {
+            return;
+        }
+        state.fired = true;
+        self.logger.log(
+            Level::Warn,
+            0,
+            format_args!(
+                "{} has been failing for {:.1} second(s), last with: {} @ '{}'",
+                self.log_name,
+                duration.as_secs_f64(),
+                state.last_error,
+                self.path.display()
+            ),
+        );
+        (self.hook)(&Degraded {
+            path: self.path.clone(),
+            duration,
+            last_error: state.last_error.clone(),
+        });
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    ffi::OsStr,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{fs, k8s::DATA_LINK, tail::TailOptions};
+
+/// Contents of every regular file in a watched directory, sorted by path.
+pub type DirectoryContents = Vec<(PathBuf, Vec<u8>)>;
+
+/// Contents of a recursively watched directory tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeContents {
+    /// Every regular file within the tree that isn't ignored, sorted by path.
+    pub files: DirectoryContents,
+    /// Files that were created, modified, or removed since the last read that parsed. On the initial read, this is every file.
+    pub changed: Vec<PathBuf>,
+}
+
+/// A change to a single file in a watched directory. The parsed value is `None` if the file was removed.
+pub type FileChange<T> = (PathBuf, Option<T>);
+
+/// What kind of filesystem object a watcher is pointed at.
+#[derive(Clone, Debug)]
+pub(crate) enum WatchMode {
+    File,
+    /// Editor swap, backup, and lock files in it are ignored unless `editor_artifacts` is set.
+    Directory {
+        editor_artifacts: bool,
+    },
+    Tree(TreeOptions),
+    /// Only the existence of the file matters, it may be missing.
+    Sentinel,
+    /// A Kubernetes ConfigMap or Secret volume mount, where only the atomic `..data` symlink swap matters.
+    ConfigMap,
+    /// A file that is only appended to, watched the same as [`WatchMode::File`].
+    Tail(TailOptions),
+    /// An open file, by its `/proc/self/fd` (or `/dev/fd`) path. Only the file itself is watched, not wherever it is linked.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Descriptor,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TreeOptions {
+    pub(crate) max_depth: Option<usize>,
+    pub(crate) ignore: Vec<glob::Pattern>,
+    /// Keep editor swap, backup, and lock files rather than ignoring them.
+    pub(crate) editor_artifacts: bool,
+}
+
+/// Whether `name` is a file editors leave next to the one being edited: vim swap files and its `4913` write test,
+/// and backup (`~`), lock (`.#`), and autosave (`#...#`) files of emacs and others.
+pub(crate) fn is_editor_artifact(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name == "4913"
+        || name.ends_with('~')
+        || name.starts_with(".#")
+        || (name.len() > 1 && name.starts_with('#') && name.ends_with('#'))
+        || [".swp", ".swo", ".swx"]
+            .iter()
+            .any(|x| name.len() > x.len() && name.ends_with(x))
+}
+
+impl WatchMode {
+    #[allow(dead_code)] // unused by the kqueue backend
+    pub(crate) fn is_directory(&self) -> bool {
+        matches!(
+            self,
+            WatchMode::Directory { .. } | WatchMode::Tree(_) | WatchMode::ConfigMap
+        )
+    }
+
+    /// Whether editor artifacts within the watched directory are ignored.
+    pub(crate) fn ignores_editor_artifacts(&self) -> bool {
+        match self {
+            WatchMode::Directory { editor_artifacts } => !editor_artifacts,
+            WatchMode::Tree(options) => !options.editor_artifacts,
+            _ => false,
+        }
+    }
+
+    /// Whether `path` is a child of the watched directory `root` that we care about.
+    #[allow(dead_code)] // only used by the notify backend
+    pub(crate) fn is_relevant_child(&self, root: &Path, path: &Path) -> bool {
+        match self {
+            WatchMode::File | WatchMode::Sentinel | WatchMode::Tail(_) | WatchMode::Descriptor => {
+                false
+            }
+            WatchMode::Directory { editor_artifacts } => {
+                path.parent() == Some(root)
+                    && (*editor_artifacts || !path.file_name().is_some_and(is_editor_artifact))
+            }
+            WatchMode::ConfigMap => path == root.join(DATA_LINK),
+            WatchMode::Tree(options) => {
+                let Ok(relative) = path.strip_prefix(root) else {
+                    return false;
+                };
+                let depth = relative.components().count();
+                depth > 0
+                    && options.max_depth.map(|x| depth <= x + 1).unwrap_or(true)
+                    && !options.is_ignored(relative)
+            }
+        }
+    }
+}
+
+impl TreeOptions {
+    /// `relative` is relative to the tree root. Patterns are matched against the whole relative path and the file name.
+    pub(crate) fn is_ignored(&self, relative: &Path) -> bool {
+        let name = relative.file_name();
+        (!self.editor_artifacts && name.is_some_and(is_editor_artifact))
+            || self.ignore.iter().any(|pattern| {
+                pattern.matches_path(relative)
+                    || name.is_some_and(|name| pattern.matches_path(Path::new(name)))
+            })
+    }
+
+    /// Only the directory itself, without any subdirectories.
+    pub(crate) fn flat(editor_artifacts: bool) -> Self {
+        TreeOptions {
+            max_depth: Some(0),
+            ignore: vec![],
+            editor_artifacts,
+        }
+    }
+}
+
+pub(crate) async fn read_directory(
+    dir: PathBuf,
+    editor_artifacts: bool,
+) -> io::Result<DirectoryContents> {
+    let (_, files) = walk(&dir, &TreeOptions::flat(editor_artifacts)).await?;
+    read_files(files).await
+}
+
+/// Hashes of the files in a tree, as of the last read that parsed, to compute [`TreeContents::changed`] against.
+#[derive(Default)]
+pub(crate) struct TreeHashes {
+    committed: Option<HashMap<PathBuf, u64>>,
+    /// Of the latest read, until its parse succeeds.
+    pending: Option<HashMap<PathBuf, u64>>,
+}
+
+impl TreeHashes {
+    /// The latest read parsed, so later reads are compared against it.
+    pub(crate) fn commit(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            self.committed = Some(pending);
+        }
+    }
+}
+
+/// Reads a tree, tracking a hash of each file between calls to compute [`TreeContents::changed`].
+/// The hashes are only committed with [`TreeHashes::commit`], so a failed parse is retried with the same changes.
+pub(crate) async fn read_tree(
+    root: PathBuf,
+    options: TreeOptions,
+    previous: Arc<Mutex<TreeHashes>>,
+) -> io::Result<TreeContents> {
+    let (_, files) = walk(&root, &options).await?;
+    let files = read_files(files).await?;
+    let hashes: HashMap<PathBuf, u64> = files
+        .iter()
+        .map(|(path, raw)| (path.clone(), hash_bytes(raw)))
+        .collect();
+    let mut previous = previous.lock().unwrap();
+    let mut changed: Vec<PathBuf> = match &previous.committed {
+        None => hashes.keys().cloned().collect(),
+        Some(previous) => hashes
+            .iter()
+            .filter(|(path, hash)| previous.get(*path) != Some(*hash))
+            .map(|(path, _)| path.clone())
+            .chain(
+                previous
+                    .keys()
+                    .filter(|path| !hashes.contains_key(*path))
+                    .cloned(),
+            )
+            .collect(),
+    };
+    changed.sort();
+    previous.pending = Some(hashes);
+    Ok(TreeContents { files, changed })
+}
+
+/// Walks `root` up to the configured depth, following symlinks, returning all directories (including `root`) and regular files.
+pub(crate) async fn walk(
+    root: &Path,
+    options: &TreeOptions,
+) -> io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    walk_within(root, root, options).await
+}
+
+/// Like [`walk`], but only the subdirectory `start` of `root`, with depth and ignore patterns still relative to `root`.
+pub(crate) async fn walk_within(
+    root: &Path,
+    start: &Path,
+    options: &TreeOptions,
+) -> io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut dirs = vec![];
+    let mut files = vec![];
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    let depth = start
+        .strip_prefix(root)
+        .map(|x| x.components().count())
+        .unwrap_or(0);
+    if options.max_depth.is_some_and(|x| depth > x) {
+        return Ok((dirs, files));
+    }
+    let mut pending = vec![(start.to_path_buf(), depth)];
+    while let Some((dir, depth)) = pending.pop() {
+        let canonical = match fs::canonicalize(&dir).await {
+            Ok(x) => x,
+            // raced deletion of a subdirectory
+            Err(e) if e.kind() == io::ErrorKind::NotFound && depth > 0 => continue,
+            Err(e) => return Err(e),
+        };
+        // symlinked directories can form cycles
+        if !seen.insert(canonical) {
+            continue;
+        }
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && depth > 0 => continue,
+            Err(e) => return Err(e),
+        };
+        dirs.push(dir);
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if let Ok(relative) = path.strip_prefix(root) {
+                if options.is_ignored(relative) {
+                    continue;
+                }
+            }
+            // follows symlinks, so linked files (i.e. k8s projected keys) are included
+            let metadata = match fs::metadata(&path).await {
+                Ok(x) => x,
+                // dangling symlink or raced deletion
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if metadata.is_file() {
+                files.push(path);
+            } else if metadata.is_dir() && options.max_depth.map(|x| depth < x).unwrap_or(true) {
+                pending.push((path, depth + 1));
+            }
+        }
+    }
+    files.sort();
+    Ok((dirs, files))
+}
+
+async fn read_files(files: Vec<PathBuf>) -> io::Result<DirectoryContents> {
+    let mut out = vec![];
+    for path in files {
+        let raw = match fs::read(&path).await {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        out.push((path, raw));
+    }
+    Ok(out)
+}
+
+fn hash_bytes(raw: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    raw.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Parses files of a directory individually, tracking which have changed since the last successful parse.
+pub(crate) struct PerFile<T, E> {
+    parser: Box<dyn Fn(Vec<u8>) -> Result<T, E> + Send + Sync>,
+    previous: Mutex<HashMap<PathBuf, u64>>,
+}
+
+impl<T, E> PerFile<T, E> {
+    pub(crate) fn new(parser: impl Fn(Vec<u8>) -> Result<T, E> + Send + Sync + 'static) -> Self {
+        Self {
+            parser: Box::new(parser),
+            previous: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn is_unchanged(&self, files: &DirectoryContents) -> bool {
+        let previous = self.previous.lock().unwrap();
+        files.len() == previous.len()
+            && files
+                .iter()
+                .all(|(path, raw)| previous.get(path) == Some(&hash_bytes(raw)))
+    }
+
+    /// Nothing is recorded as seen unless every changed file parses.
+    pub(crate) fn parse(&self, files: DirectoryContents) -> Result<Vec<FileChange<T>>, E> {
+        let mut previous = self.previous.lock().unwrap();
+        let mut hashes = HashMap::new();
+        let mut out = vec![];
+        for (path, raw) in files {
+            let hash = hash_bytes(&raw);
+            if previous.get(&path) != Some(&hash) {
+                out.push((path.clone(), Some((self.parser)(raw)?)));
+            }
+            hashes.insert(path, hash);
+        }
+        let mut removed: Vec<PathBuf> = previous
+            .keys()
+            .filter(|path| !hashes.contains_key(*path))
+            .cloned()
+            .collect();
+        removed.sort();
+        out.extend(removed.into_iter().map(|path| (path, None)));
+        *previous = hashes;
+        Ok(out)
+    }
+}
@@ -0,0 +1,230 @@
+use std::{collections::HashMap, iter::Peekable, str::Chars};
+
+use thiserror::Error;
+
+/// Error from parsing a `.env` file.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("line {line}: {message}")]
+pub struct DotenvError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Parses `.env` style `KEY=value` assignments.
+///
+/// * Blank lines and lines starting with `#` are skipped, and an optional leading `export ` is allowed.
+/// * Unquoted values are trimmed, and end at a ` #` comment.
+/// * Single quoted values are taken literally.
+/// * Double quoted values support `\n`, `\r`, `\t`, `\"`, `\\`, and `\$` escapes.
+/// * Quoted values may span multiple lines.
+///
+/// No variable expansion is performed. Later assignments of the same key win.
+pub fn parse_dotenv(raw: &[u8]) -> Result<HashMap<String, String>, DotenvError> {
+    let raw = std::str::from_utf8(raw).map_err(|e| DotenvError {
+        line: 1 + raw[..e.valid_up_to()]
+            .iter()
+            .filter(|x| **x == b'\n')
+            .count(),
+        message: "invalid UTF-8".to_string(),
+    })?;
+    let mut parser = Parser {
+        chars: raw.chars().peekable(),
+        line: 1,
+    };
+    let mut out = HashMap::new();
+    while let Some((key, value)) = parser.next_assignment()? {
+        out.insert(key, value);
+    }
+    Ok(out)
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: impl Into<String>) -> DotenvError {
+        DotenvError {
+            line: self.line,
+            message: message.into(),
+        }
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn skip_inline_whitespace(&mut self) {
+        while matches!(self.chars.peek(), Some(' ' | '\t')) {
+            self.next();
+        }
+    }
+
+    fn skip_line(&mut self) {
+        while let Some(c) = self.next() {
+            if c == '\n' {
+                break;
+            }
+        }
+    }
+
+    fn next_assignment(&mut self) -> Result<Option<(String, String)>, DotenvError> {
+        loop {
+            self.skip_inline_whitespace();
+            match self.chars.peek() {
+                None => return Ok(None),
+                Some('\n' | '\r') => {
+                    self.next();
+                }
+                Some('#') => self.skip_line(),
+                Some(_) => break,
+            }
+        }
+        let mut key = self.read_key();
+        if key == "export" && matches!(self.chars.peek(), Some(' ' | '\t')) {
+            self.skip_inline_whitespace();
+            key = self.read_key();
+        }
+        if key.is_empty() || key.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(self.error("expected a variable name"));
+        }
+        self.skip_inline_whitespace();
+        if self.next() != Some('=') {
+            return Err(self.error(format!("expected '=' after '{key}'")));
+        }
+        self.skip_inline_whitespace();
+        let value = match self.chars.peek() {
+            Some('\'') => {
+                self.next();
+                let value = self.read_quoted('\'')?;
+                self.finish_line()?;
+                value
+            }
+            Some('"') => {
+                self.next();
+                let value = self.read_quoted('"')?;
+                self.finish_line()?;
+                value
+            }
+            _ => self.read_unquoted(),
+        };
+        Ok(Some((key, value)))
+    }
+
+    fn read_key(&mut self) -> String {
+        let mut key = String::new();
+        while let Some(c) = self.chars.peek() {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '.') {
+                key.push(*c);
+                self.next();
+            } else {
+                break;
+            }
+        }
+        key
+    }
+
+    fn read_quoted(&mut self, quote: char) -> Result<String, DotenvError> {
+        let start = self.line;
+        let mut value = String::new();
+        loop {
+            match self.next() {
+                None => {
+                    return Err(DotenvError {
+                        line: start,
+                        message: format!("unterminated {quote} quote"),
+                    })
+                }
+                Some(c) if c == quote => return Ok(value),
+                Some('\\') if quote == '"' => match self.next() {
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some(c @ ('"' | '\\' | '$')) => value.push(c),
+                    Some(c) => {
+                        value.push('\\');
+                        value.push(c);
+                    }
+                    None => continue,
+                },
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    /// After a quoted value, only whitespace or a comment may follow.
+    fn finish_line(&mut self) -> Result<(), DotenvError> {
+        self.skip_inline_whitespace();
+        match self.chars.peek() {
+            None => Ok(()),
+            Some('\n' | '\r' | '#') => {
+                self.skip_line();
+                Ok(())
+            }
+            Some(&c) => Err(self.error(format!("unexpected '{c}' after quoted value"))),
+        }
+    }
+
+    fn read_unquoted(&mut self) -> String {
+        let mut value = String::new();
+        while let Some(c) = self.chars.peek() {
+            match c {
+                '\n' => break,
+                '#' if value.is_empty() || value.ends_with([' ', '\t']) => {
+                    self.skip_line();
+                    return value.trim_end().to_string();
+                }
+                _ => {
+                    value.push(*c);
+                    self.next();
+                }
+            }
+        }
+        self.next();
+        value.trim_end().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dotenv() {
+        let parsed = parse_dotenv(
+            br#"
+# feature flags
+export FLAG_A=true
+FLAG_B = on # inline comment
+URL=http://example.com/#anchor
+EMPTY=
+SINGLE='literal \n $HOME'
+DOUBLE="line\nnext \"quoted\" \$HOME"
+MULTI="first
+second"
+FLAG_A=false
+"#,
+        )
+        .unwrap();
+        assert_eq!(parsed.len(), 7);
+        assert_eq!(parsed["FLAG_A"], "false");
+        assert_eq!(parsed["FLAG_B"], "on");
+        assert_eq!(parsed["URL"], "http://example.com/#anchor");
+        assert_eq!(parsed["EMPTY"], "");
+        assert_eq!(parsed["SINGLE"], "literal \\n $HOME");
+        assert_eq!(parsed["DOUBLE"], "line\nnext \"quoted\" $HOME");
+        assert_eq!(parsed["MULTI"], "first\nsecond");
+    }
+
+    #[test]
+    fn test_parse_dotenv_errors() {
+        assert_eq!(parse_dotenv(b"A=1\nB 2").unwrap_err().line, 2);
+        assert_eq!(parse_dotenv(b"A=1\nB=\"open\n\n").unwrap_err().line, 2);
+        assert_eq!(parse_dotenv(b"A='x' y").unwrap_err().line, 1);
+    }
+}
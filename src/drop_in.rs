@@ -0,0 +1,142 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    directory::{walk, TreeOptions},
+    fs, DirectoryContents,
+};
+
+/// Standard configuration search directories, highest precedence first:
+/// `$XDG_CONFIG_HOME` (or `~/.config`), `$XDG_CONFIG_DIRS` (or `/etc/xdg`), `/etc`, `/run`, `/usr/local/lib`, `/usr/lib`.
+pub fn default_search_dirs() -> Vec<PathBuf> {
+    let mut out = vec![];
+    match std::env::var_os("XDG_CONFIG_HOME").filter(|x| !x.is_empty()) {
+        Some(config_home) => out.push(PathBuf::from(config_home)),
+        None => {
+            if let Some(home) = std::env::var_os("HOME").filter(|x| !x.is_empty()) {
+                out.push(Path::new(&home).join(".config"));
+            }
+        }
+    }
+    match std::env::var("XDG_CONFIG_DIRS")
+        .ok()
+        .filter(|x| !x.is_empty())
+    {
+        Some(config_dirs) => out.extend(
+            config_dirs
+                .split(':')
+                .filter(|x| !x.is_empty())
+                .map(PathBuf::from),
+        ),
+        None => out.push(PathBuf::from("/etc/xdg")),
+    }
+    out.extend(
+        ["/etc", "/run", "/usr/local/lib", "/usr/lib"]
+            .into_iter()
+            .map(PathBuf::from),
+    );
+    out
+}
+
+/// `name` with `.d` appended, i.e. `app/app.conf` -> `app/app.conf.d`
+pub(crate) fn drop_in_dir(name: &Path) -> PathBuf {
+    let mut out: OsString = name.as_os_str().to_os_string();
+    out.push(".d");
+    out.into()
+}
+
+/// Resolves `name` across `dirs` with systemd-style precedence.
+/// The first main file found wins. Drop-ins are collected from every `<name>.d` directory, where a drop-in masks any drop-in
+/// of the same file name in a lower precedence directory, then are ordered by file name and follow the main file.
+pub(crate) async fn read_drop_ins(
+    dirs: Vec<PathBuf>,
+    name: PathBuf,
+) -> io::Result<DirectoryContents> {
+    let mut out = vec![];
+    for dir in &dirs {
+        let path = dir.join(&name);
+        match fs::read(&path).await {
+            Ok(raw) => {
+                out.push((path, raw));
+                break;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    let extension = name.extension();
+    let mut drop_ins: BTreeMap<OsString, PathBuf> = BTreeMap::new();
+    for dir in &dirs {
+        let options = TreeOptions::flat(false);
+        let files = match walk(&dir.join(drop_in_dir(&name)), &options).await {
+            Ok((_, files)) => files,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for file in files {
+            if extension.is_some() && file.extension() != extension {
+                continue;
+            }
+            if let Some(file_name) = file.file_name() {
+                drop_ins.entry(file_name.to_os_string()).or_insert(file);
+            }
+        }
+    }
+    for (_, path) in drop_ins {
+        match fs::read(&path).await {
+            Ok(raw) => out.push((path, raw)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    if out.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("'{}' not found in any search directory", name.display()),
+        ));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_drop_ins() {
+        let root =
+            std::env::temp_dir().join(format!("really-notify-{}-drop-in", std::process::id()));
+        std::fs::remove_dir_all(&root).ok();
+        let etc = root.join("etc");
+        let vendor = root.join("usr/lib");
+        std::fs::create_dir_all(etc.join("app/app.conf.d")).unwrap();
+        std::fs::create_dir_all(vendor.join("app/app.conf.d")).unwrap();
+        std::fs::write(vendor.join("app/app.conf"), "vendor").unwrap();
+        std::fs::write(etc.join("app/app.conf.d/10-a.conf"), "etc a").unwrap();
+        std::fs::write(etc.join("app/app.conf.d/ignored.txt"), "").unwrap();
+        std::fs::write(vendor.join("app/app.conf.d/10-a.conf"), "vendor a").unwrap();
+        std::fs::write(vendor.join("app/app.conf.d/20-b.conf"), "vendor b").unwrap();
+        let contents = read_drop_ins(
+            vec![root.join("missing"), etc.clone(), vendor.clone()],
+            PathBuf::from("app/app.conf"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            contents,
+            vec![
+                (vendor.join("app/app.conf"), b"vendor".to_vec()),
+                (etc.join("app/app.conf.d/10-a.conf"), b"etc a".to_vec()),
+                (
+                    vendor.join("app/app.conf.d/20-b.conf"),
+                    b"vendor b".to_vec()
+                ),
+            ]
+        );
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
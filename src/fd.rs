@@ -0,0 +1,135 @@
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fs::File,
+    io::{self, Read},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::fs::{FileExt, MetadataExt},
+    },
+    path::{Component, Path, PathBuf},
+};
+
+use crate::logging::info;
+
+use crate::{backend::Links, fs};
+
+/// Path that refers to the open file behind `file`, regardless of where (or whether) it is linked on the filesystem.
+pub(crate) fn fd_path(file: &File) -> PathBuf {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let dir = "/proc/self/fd";
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let dir = "/dev/fd";
+    PathBuf::from(dir).join(file.as_raw_fd().to_string())
+}
+
+/// Reads all of `file` with positioned reads, leaving its offset alone.
+pub(crate) fn read_fd(file: &File) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    let mut buf = vec![0u8; 8192];
+    loop {
+        match file.read_at(&mut buf, out.len() as u64) {
+            Ok(0) => return Ok(out),
+            Ok(read) => out.extend_from_slice(&buf[..read]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Reads `path` without following any symlink the reader didn't see and log first: symlinks are resolved one at a time,
+/// at most `max_depth` of them, and the result is opened a component at a time with `O_NOFOLLOW`, so a symlink swapped
+/// in anywhere along the way fails the read. The opened file must be the same regular file that was resolved.
+pub(crate) fn read_nofollow(path: &Path, max_depth: usize) -> io::Result<Vec<u8>> {
+    let resolved = resolve(path, max_depth)?;
+    let expected = std::fs::symlink_metadata(&resolved)?;
+    let mut file = open_nofollow(&resolved)?;
+    let opened = file.metadata()?;
+    if (opened.dev(), opened.ino()) != (expected.dev(), expected.ino()) {
+        return Err(io::Error::other(format!(
+            "'{}' was replaced between resolving and opening it",
+            resolved.display()
+        )));
+    }
+    if !opened.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' is not a regular file", resolved.display()),
+        ));
+    }
+    let mut out = vec![];
+    file.read_to_end(&mut out)?;
+    Ok(out)
+}
+
+/// Resolves every symlink in `path`, logging each.
+fn resolve(path: &Path, max_depth: usize) -> io::Result<PathBuf> {
+    let mut links = Links::new(max_depth);
+    let mut remaining: VecDeque<OsString> = VecDeque::new();
+    let mut out = PathBuf::from("/");
+    push_front(&mut remaining, &mut out, &std::path::absolute(path)?);
+    while let Some(name) = remaining.pop_front() {
+        if name == ".." {
+            out.pop();
+            continue;
+        }
+        let next = out.join(&name);
+        if !std::fs::symlink_metadata(&next)?.is_symlink() {
+            out = next;
+            continue;
+        }
+        links.follow(&next)?;
+        let link = std::fs::read_link(&next)?;
+        info!(
+            "following symlink '{}' to '{}'",
+            next.display(),
+            link.display()
+        );
+        push_front(&mut remaining, &mut out, &link);
+    }
+    Ok(out)
+}
+
+/// Queues the components of `path` to be resolved next, starting over from the root if it is absolute.
+fn push_front(remaining: &mut VecDeque<OsString>, out: &mut PathBuf, path: &Path) {
+    for component in path.components().rev() {
+        match component {
+            Component::RootDir => *out = PathBuf::from("/"),
+            Component::ParentDir => remaining.push_front("..".into()),
+            Component::Normal(name) => remaining.push_front(name.to_os_string()),
+            Component::CurDir | Component::Prefix(_) => (),
+        }
+    }
+}
+
+/// Opens `path`, which has no symlinks left in it, one component at a time, failing if any of them is a symlink by now.
+fn open_nofollow(path: &Path) -> io::Result<File> {
+    let mut out = File::open("/")?;
+    let mut components = path
+        .components()
+        .filter_map(|x| match x {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .peekable();
+    while let Some(name) = components.next() {
+        let flags = match components.peek() {
+            // doesn't block on a FIFO, which is rejected as not a regular file afterwards
+            None => libc::O_NONBLOCK,
+            Some(_) => libc::O_DIRECTORY,
+        };
+        let name = fs::c_path(Path::new(name))?;
+        let fd = unsafe {
+            libc::openat(
+                out.as_raw_fd(),
+                name.as_ptr(),
+                libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC | flags,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        out = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+    Ok(out)
+}
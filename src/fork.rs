@@ -0,0 +1,16 @@
+use crate::{backend, shared};
+
+/// Tears down and re-creates the descriptors, threads, and tasks watchers share across the process, i.e. in the child
+/// after `fork()` or daemonizing, where threads of the parent (like the one watching the mount table) are gone and the
+/// shared inotify instance is still shared with the parent.
+///
+/// Every backend still running is stopped and started again with new descriptors, reading its target again for changes
+/// missed in between. Tokio runtimes don't survive a fork themselves, so if the watchers were started on one that
+/// didn't, build a new runtime in the child and start them again there, after calling this.
+pub fn reinit_after_fork() {
+    shared::reset();
+    crate::rt::reset_shared_task();
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    crate::fs::reset_uring();
+    backend::restart_all();
+}
@@ -0,0 +1,384 @@
+//! Filesystem access for reads and polling. WASI has no threads to hand blocking calls off to,
+//! so they are made inline there, which is fine for the small files we read. With the `async-std` or `smol`
+//! feature, they are handed to its blocking thread pool instead of tokio's.
+//! With the `uring` feature on Linux, whole-file reads are submitted to io_uring instead.
+
+use std::{fs::Metadata, future::Future, io, path::Path, time::SystemTime};
+
+use crate::logging::debug;
+
+#[cfg(unix)]
+use crate::PathError;
+
+#[cfg(not(any(target_family = "wasm", feature = "async-std", feature = "smol")))]
+pub(crate) use tokio::fs::{canonicalize, metadata, read_dir, read_link, try_exists, File};
+
+#[cfg(not(any(target_family = "wasm", feature = "async-std", feature = "smol")))]
+#[allow(unused_imports)] // unused without inotify
+pub(crate) use tokio::fs::symlink_metadata;
+
+#[cfg(all(
+    not(any(target_family = "wasm", feature = "async-std", feature = "smol")),
+    not(all(feature = "uring", target_os = "linux"))
+))]
+pub(crate) use tokio::fs::read;
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub(crate) use uring::{read, reset as reset_uring};
+
+#[cfg(any(target_family = "wasm", feature = "async-std", feature = "smol"))]
+pub(crate) use blocking::*;
+
+#[cfg(any(target_family = "wasm", feature = "async-std", feature = "smol"))]
+mod blocking {
+    use std::{
+        fs::{DirEntry, Metadata},
+        io::{self, Read, Seek, SeekFrom},
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+    };
+
+    use crate::rt;
+
+    /// Runs `f` where blocking is fine, or inline without threads.
+    async fn blocking<T: Send + 'static>(
+        f: impl FnOnce() -> io::Result<T> + Send + 'static,
+    ) -> io::Result<T> {
+        rt::spawn_blocking(f).await?
+    }
+
+    pub(crate) async fn canonicalize(path: impl AsRef<Path>) -> io::Result<PathBuf> {
+        let path = path.as_ref().to_path_buf();
+        blocking(move || std::fs::canonicalize(path)).await
+    }
+
+    pub(crate) async fn metadata(path: impl AsRef<Path>) -> io::Result<Metadata> {
+        let path = path.as_ref().to_path_buf();
+        blocking(move || std::fs::metadata(path)).await
+    }
+
+    #[allow(dead_code)] // unused without inotify
+    pub(crate) async fn symlink_metadata(path: impl AsRef<Path>) -> io::Result<Metadata> {
+        let path = path.as_ref().to_path_buf();
+        blocking(move || std::fs::symlink_metadata(path)).await
+    }
+
+    #[allow(dead_code)] // replaced by io_uring reads
+    pub(crate) async fn read(path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+        let path = path.as_ref().to_path_buf();
+        blocking(move || std::fs::read(path)).await
+    }
+
+    pub(crate) async fn read_link(path: impl AsRef<Path>) -> io::Result<PathBuf> {
+        let path = path.as_ref().to_path_buf();
+        blocking(move || std::fs::read_link(path)).await
+    }
+
+    pub(crate) async fn try_exists(path: impl AsRef<Path>) -> io::Result<bool> {
+        let path = path.as_ref().to_path_buf();
+        blocking(move || path.try_exists()).await
+    }
+
+    pub(crate) async fn read_dir(path: impl AsRef<Path>) -> io::Result<ReadDir> {
+        let path = path.as_ref().to_path_buf();
+        blocking(move || std::fs::read_dir(path))
+            .await
+            .map(|x| ReadDir(Arc::new(Mutex::new(x))))
+    }
+
+    pub(crate) struct ReadDir(Arc<Mutex<std::fs::ReadDir>>);
+
+    impl ReadDir {
+        pub(crate) async fn next_entry(&mut self) -> io::Result<Option<DirEntry>> {
+            let entries = self.0.clone();
+            blocking(move || entries.lock().unwrap().next().transpose()).await
+        }
+    }
+
+    pub(crate) struct File(Arc<std::fs::File>);
+
+    impl File {
+        pub(crate) async fn open(path: impl AsRef<Path>) -> io::Result<File> {
+            let path = path.as_ref().to_path_buf();
+            blocking(move || std::fs::File::open(path))
+                .await
+                .map(|x| File(Arc::new(x)))
+        }
+
+        pub(crate) async fn metadata(&self) -> io::Result<Metadata> {
+            let file = self.0.clone();
+            blocking(move || file.metadata()).await
+        }
+
+        pub(crate) async fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+            let file = self.0.clone();
+            blocking(move || (&*file).seek(position)).await
+        }
+
+        pub(crate) async fn read_to_end(&mut self, out: &mut Vec<u8>) -> io::Result<usize> {
+            let file = self.0.clone();
+            let read = blocking(move || {
+                let mut read = vec![];
+                (&*file).read_to_end(&mut read)?;
+                Ok(read)
+            })
+            .await?;
+            out.extend_from_slice(&read);
+            Ok(read.len())
+        }
+    }
+}
+
+/// `path` as handed to the OS, failing with [`PathError::Nul`] rather than panicking if it has a NUL byte in it.
+#[cfg(unix)]
+pub(crate) fn c_path(path: &Path) -> io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| PathError::Nul(path.to_path_buf()).into())
+}
+
+/// Writes `contents` to a temporary file next to `path`, flushes it to disk, and renames it over `path`, so readers only
+/// ever see the old or the new contents. A symlinked `path` keeps pointing where it did, with the file it points to
+/// replaced instead. The permissions of the file replaced are kept, and on unix its ownership too where this process may
+/// change it (as root, or to another of its own groups), otherwise the new file is owned by this process.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::{
+        io::Write,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' is not a file", path.display()),
+        ));
+    };
+    // looks like an editor lock file, so watchers of the directory ignore it
+    let temp = dir.join(format!(
+        ".#{}.{}.{}",
+        name.to_string_lossy(),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let metadata = std::fs::metadata(&path).ok();
+    let mut options = std::fs::OpenOptions::new();
+    // rather than truncating, or following a symlink, already at the temporary path
+    options.write(true).create_new(true);
+    // never readable by more than the file it replaces, or only by its owner if there is none, before it is written
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+        options.mode(metadata.as_ref().map_or(0o600, |x| x.mode() & 0o777));
+    }
+    let mut file = options.open(&temp)?;
+    let mut write = || {
+        if let Some(metadata) = &metadata {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                match std::os::unix::fs::fchown(&file, Some(metadata.uid()), Some(metadata.gid())) {
+                    Err(e) if e.kind() != io::ErrorKind::PermissionDenied => return Err(e),
+                    _ => (),
+                }
+            }
+            // after changing the owner, which clears setuid and setgid bits
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.write_all(contents)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, &path)?;
+        // the rename itself is only durable once the directory is
+        #[cfg(unix)]
+        std::fs::File::open(dir)?.sync_all()?;
+        Ok(())
+    };
+    let out = write();
+    if out.is_err() {
+        std::fs::remove_file(&temp).ok();
+    }
+    out
+}
+
+/// Enough of a file's metadata to tell that it hasn't been written to since it was hashed.
+#[derive(PartialEq)]
+pub(crate) struct Stat {
+    len: u64,
+    modified: Option<SystemTime>,
+    inode: Option<u64>,
+    /// Permissions and ownership, or only whether it is read-only where there are none.
+    pub(crate) attributes: (u32, u32, u32),
+}
+
+impl Stat {
+    pub(crate) fn new(metadata: &Metadata) -> Self {
+        #[cfg(unix)]
+        let inode = {
+            use std::os::unix::fs::MetadataExt;
+            Some(metadata.ino())
+        };
+        #[cfg(not(unix))]
+        let inode = None;
+        #[cfg(unix)]
+        let attributes = {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.mode(), metadata.uid(), metadata.gid())
+        };
+        #[cfg(not(unix))]
+        let attributes = (metadata.permissions().readonly() as u32, 0, 0);
+        Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            inode,
+            attributes,
+        }
+    }
+}
+
+/// Device and inode of a file, which stay the same across renames, `None` where there are none.
+pub(crate) fn identity(metadata: &Metadata) -> Option<(u64, u64)> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some((metadata.dev(), metadata.ino()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// How many times a read is attempted before giving up on the target ever holding still.
+const VERIFY_ATTEMPTS: usize = 3;
+
+/// Runs `read`, checking that `path`'s metadata is the same before and after, so a writer modifying the file in place
+/// can't hand us a mix of old and new contents. Retries a few times, then fails so the read is retried later.
+pub(crate) async fn read_verified<T, F: Future<Output = io::Result<T>>>(
+    path: &Path,
+    read: impl Fn() -> F,
+) -> io::Result<T> {
+    for _ in 0..VERIFY_ATTEMPTS {
+        let Ok(before) = metadata(path).await else {
+            // leave the error to the read
+            return read().await;
+        };
+        let out = read().await?;
+        if metadata(path)
+            .await
+            .is_ok_and(|after| Stat::new(&before) == Stat::new(&after))
+        {
+            return Ok(out);
+        }
+        debug!(
+            "'{}' changed while being read, reading again",
+            path.display()
+        );
+    }
+    Err(io::Error::other(format!(
+        "'{}' kept changing while being read",
+        path.display()
+    )))
+}
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring {
+    use std::{
+        io,
+        path::{Path, PathBuf},
+        sync::Mutex,
+    };
+
+    use crate::logging::info;
+    use tokio::sync::{mpsc, oneshot};
+    use tokio_uring::buf::IoBuf;
+
+    /// How much is read at a time, and how much the buffer grows by for larger files.
+    const CHUNK: usize = 64 * 1024;
+
+    type Request = (PathBuf, oneshot::Sender<io::Result<Vec<u8>>>);
+
+    /// Reads `path` on the io_uring thread, or with a regular read if io_uring couldn't be set up.
+    pub(crate) async fn read(path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+        let path = path.as_ref().to_path_buf();
+        let Some(reader) = reader() else {
+            return fallback(path).await;
+        };
+        let (sender, receiver) = oneshot::channel();
+        if reader.send((path.clone(), sender)).is_err() {
+            return fallback(path).await;
+        }
+        match receiver.await {
+            Ok(x) => x,
+            Err(_) => fallback(path).await,
+        }
+    }
+
+    async fn fallback(path: PathBuf) -> io::Result<Vec<u8>> {
+        crate::rt::spawn_blocking(move || std::fs::read(path)).await?
+    }
+
+    /// io_uring submissions can't be made from a regular tokio runtime, so reads are handed to a thread running a
+    /// tokio-uring runtime, started on first use. `None` if io_uring is unavailable, i.e. an older kernel or seccomp.
+    fn reader() -> Option<mpsc::UnboundedSender<Request>> {
+        READER.lock().unwrap().get_or_insert_with(start).clone()
+    }
+
+    static READER: Mutex<Option<Option<mpsc::UnboundedSender<Request>>>> = Mutex::new(None);
+
+    /// Forgets the io_uring thread, which doesn't survive a fork, so the next read starts another.
+    pub(crate) fn reset() {
+        *READER.lock().unwrap() = None;
+    }
+
+    fn start() -> Option<mpsc::UnboundedSender<Request>> {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Request>();
+        let (started, result) = std::sync::mpsc::channel();
+        let spawned = crate::thread::spawn("uring", move || {
+            let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                Ok(x) => x,
+                Err(e) => {
+                    started.send(Err(e)).ok();
+                    return;
+                }
+            };
+            started.send(Ok(())).ok();
+            runtime.block_on(async move {
+                while let Some((path, reply)) = receiver.recv().await {
+                    tokio_uring::spawn(async move {
+                        reply.send(read_file(&path).await).ok();
+                    });
+                }
+            });
+        });
+        let e = match spawned.map(|_| result.recv()) {
+            Ok(Ok(Ok(()))) => return Some(sender),
+            Ok(Ok(Err(e))) => e,
+            Ok(Err(e)) => io::Error::other(e),
+            Err(e) => e,
+        };
+        info!("io_uring is unavailable, reading files normally: {e}");
+        None
+    }
+
+    async fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+        let file = tokio_uring::fs::File::open(path).await?;
+        let mut out = Vec::with_capacity(CHUNK);
+        let read = loop {
+            if out.len() == out.capacity() {
+                out.reserve(CHUNK);
+            }
+            let position = out.len();
+            let (read, slice) = file.read_at(out.slice(position..), position as u64).await;
+            out = slice.into_inner();
+            match read {
+                Ok(0) => break Ok(()),
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => break Err(e),
+            }
+        };
+        file.close().await?;
+        read.map(|()| out)
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::logging::info;
+use futures::future::BoxFuture;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+
+use crate::{rt, FileWatcherConfig, FileWatcherError, DEFAULT_RETRY_INTERVAL};
+
+/// Identifies a watcher within a [`WatcherGroup`], in the order they were added.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct WatcherId(pub usize);
+
+impl Display for WatcherId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+type GroupErrorHook<E> = Arc<dyn Fn(WatcherId, &FileWatcherError<E>) + Send + Sync>;
+
+type StartupHook = Arc<dyn Fn(&StartupProgress) + Send + Sync>;
+
+pub(crate) type Runner<T> =
+    Box<dyn FnOnce(mpsc::Sender<(WatcherId, T)>) -> BoxFuture<'static, ()> + Send>;
+
+/// Like [`Runner`], also handed the group's startup, if tracked.
+type GroupRunner<T> = Box<
+    dyn FnOnce(mpsc::Sender<(WatcherId, T)>, Option<Arc<Startup>>) -> BoxFuture<'static, ()> + Send,
+>;
+
+/// How far a [`WatcherGroup`] is through the initial reads of its watchers, reported to hooks set with
+/// [`WatcherGroup::with_startup_progress`] as each one finishes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StartupProgress {
+    /// Watchers whose initial read was emitted.
+    pub ready: usize,
+    /// Watchers whose initial read failed. They keep retrying in the background as usual.
+    pub failed: usize,
+    /// Watchers in the group.
+    pub total: usize,
+}
+
+impl StartupProgress {
+    /// Whether every watcher finished its initial read, successfully or not.
+    pub fn is_done(&self) -> bool {
+        self.ready + self.failed == self.total
+    }
+}
+
+/// Initial reads of a group in progress.
+struct Startup {
+    /// Limits initial reads in flight.
+    permits: Option<Arc<Semaphore>>,
+    progress: Mutex<StartupProgress>,
+    hooks: Vec<StartupHook>,
+}
+
+/// The initial read of a single watcher of a group.
+struct StartupSlot {
+    startup: Arc<Startup>,
+    permit: Mutex<Option<OwnedSemaphorePermit>>,
+    done: AtomicBool,
+}
+
+impl StartupSlot {
+    /// Waits for a turn to do the initial read.
+    async fn acquire(&self) {
+        if let Some(permits) = &self.startup.permits {
+            let permit = permits.clone().acquire_owned().await.ok();
+            *self.permit.lock().unwrap() = permit;
+        }
+    }
+
+    /// Ends the initial read, letting the next watcher start.
+    fn finish(&self, ready: bool) {
+        if self.done.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.permit.lock().unwrap().take();
+        let progress = {
+            let mut progress = self.startup.progress.lock().unwrap();
+            if ready {
+                progress.ready += 1;
+            } else {
+                progress.failed += 1;
+            }
+            *progress
+        };
+        if progress.is_done() {
+            info!(
+                "group started, {} of {} watcher(s) ready",
+                progress.ready, progress.total
+            );
+        }
+        for hook in &self.startup.hooks {
+            hook(&progress);
+        }
+    }
+}
+
+/// Owns many watchers with shared defaults, emitting their updates on one stream. All watchers share the output type `T`
+/// and error type `E`, use an enum in your parsers to combine different configs.
+pub struct WatcherGroup<T, E> {
+    retry_interval: Option<Duration>,
+    error_hooks: Vec<GroupErrorHook<E>>,
+    /// Initial reads allowed in flight at once.
+    startup_concurrency: Option<usize>,
+    startup_hooks: Vec<StartupHook>,
+    /// Along with whether each runs within the shared task.
+    watchers: Vec<(GroupRunner<T>, bool)>,
+}
+
+impl<T: Send + 'static, E: Display + Send + 'static> Default for WatcherGroup<T, E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Send + 'static, E: Display + Send + 'static> WatcherGroup<T, E> {
+    pub fn new() -> Self {
+        Self {
+            retry_interval: None,
+            error_hooks: vec![],
+            startup_concurrency: None,
+            startup_hooks: vec![],
+            watchers: vec![],
+        }
+    }
+
+    /// Retry interval for watchers added afterwards that don't set their own.
+    pub fn with_retry_interval(mut self, retry_interval: Duration) -> Self {
+        self.retry_interval = Some(retry_interval);
+        self
+    }
+
+    /// Observe errors from every watcher added afterwards, in addition to their own error hooks.
+    pub fn with_error_hook(
+        mut self,
+        hook: impl Fn(WatcherId, &FileWatcherError<E>) + Send + Sync + 'static,
+    ) -> Self {
+        self.error_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Read at most `limit` watchers initially at once, rather than all of them, so starting dozens doesn't spike IO.
+    /// A watcher makes way for the next once its initial read is emitted or fails.
+    pub fn with_startup_concurrency(mut self, limit: usize) -> Self {
+        self.startup_concurrency = Some(limit.max(1));
+        self
+    }
+
+    /// Observe the progress of initial reads when the group starts, called as each watcher's finishes. May be called
+    /// multiple times to add more hooks.
+    pub fn with_startup_progress(
+        mut self,
+        hook: impl Fn(&StartupProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.startup_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Add a watcher, applying the group defaults. Returns the id its updates are tagged with.
+    pub fn add<I: Send + 'static>(&mut self, mut config: FileWatcherConfig<T, E, I>) -> WatcherId {
+        let id = WatcherId(self.watchers.len());
+        if let Some(retry_interval) = self.retry_interval {
+            if config.retry_interval == DEFAULT_RETRY_INTERVAL {
+                config.retry_interval = retry_interval;
+            }
+        }
+        for hook in &self.error_hooks {
+            let hook = hook.clone();
+            config = config.with_error_hook(move |e| hook(id, e));
+        }
+        let shared_task = config.shared_task;
+        self.watchers.push((
+            Box::new(move |sender, startup| {
+                let Some(startup) = startup else {
+                    return Box::pin(config.supervise(sender, move |x| (id, x)));
+                };
+                let slot = Arc::new(StartupSlot {
+                    startup,
+                    permit: Default::default(),
+                    done: Default::default(),
+                });
+                let config = config.with_error_hook({
+                    let slot = slot.clone();
+                    move |_| slot.finish(false)
+                });
+                Box::pin(async move {
+                    slot.acquire().await;
+                    let map = {
+                        let slot = slot.clone();
+                        move |x| {
+                            slot.finish(true);
+                            (id, x)
+                        }
+                    };
+                    config.supervise(sender, map).await
+                })
+            }),
+            shared_task,
+        ));
+        id
+    }
+
+    /// Number of watchers in the group.
+    pub fn len(&self) -> usize {
+        self.watchers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watchers.is_empty()
+    }
+
+    /// Run every watcher. Dropping/closing this receiver will cause an immediate cleanup of all of them.
+    pub fn start(self) -> mpsc::Receiver<(WatcherId, T)> {
+        let (sender, receiver) = mpsc::channel(3 * self.watchers.len().max(1));
+        let startup =
+            (self.startup_concurrency.is_some() || !self.startup_hooks.is_empty()).then(|| {
+                Arc::new(Startup {
+                    permits: self
+                        .startup_concurrency
+                        .map(|limit| Arc::new(Semaphore::new(limit))),
+                    progress: Mutex::new(StartupProgress {
+                        ready: 0,
+                        failed: 0,
+                        total: self.watchers.len(),
+                    }),
+                    hooks: self.startup_hooks,
+                })
+            });
+        for (watcher, shared_task) in self.watchers {
+            let watcher = watcher(sender.clone(), startup.clone());
+            if shared_task {
+                rt::spawn_shared(watcher);
+            } else {
+                rt::spawn(watcher);
+            }
+        }
+        receiver
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::{rt::SystemTime, ChangeKind};
+
+/// The most recent events and reload decisions of the watchers set up with
+/// [`crate::FileWatcherConfig::with_history`], to tell after the fact why a target did or didn't reload. Clones share the
+/// same history.
+#[derive(Clone)]
+pub struct EventHistory(Arc<Mutex<History>>);
+
+struct History {
+    capacity: usize,
+    entries: VecDeque<HistoryEntry>,
+}
+
+/// Something that happened to a watcher, from [`EventHistory::entries`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct HistoryEntry {
+    pub at: SystemTime,
+    /// The log name of the watcher.
+    pub watcher: String,
+    pub event: HistoryEvent,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HistoryEvent {
+    /// The backend reported a change, which triggers a reload.
+    Received { kind: ChangeKind, path: PathBuf },
+    /// The backend reported a change, which the event filter, or the editor artifact check, rejected.
+    Filtered { kind: ChangeKind, path: PathBuf },
+    /// The target is being read, `initial`ly or after a change.
+    Reload { initial: bool },
+    /// The target parsed to an update.
+    Reloaded,
+    /// The target was unchanged since the last parse, written by this process, or skipped as empty.
+    Unchanged,
+    /// Reading or parsing the target failed.
+    Failed { error: String },
+}
+
+impl EventHistory {
+    /// Keeps the last `capacity` entries.
+    pub fn new(capacity: usize) -> Self {
+        Self(Arc::new(Mutex::new(History {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        })))
+    }
+
+    /// The entries kept, oldest first.
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.0.lock().unwrap().entries.iter().cloned().collect()
+    }
+
+    pub(crate) fn record(&self, watcher: &str, event: HistoryEvent) {
+        let mut history = self.0.lock().unwrap();
+        if history.capacity == 0 {
+            return;
+        }
+        if history.entries.len() == history.capacity {
+            history.entries.pop_front();
+        }
+        history.entries.push_back(HistoryEntry {
+            at: SystemTime::now(),
+            watcher: watcher.to_string(),
+            event,
+        });
+    }
+}
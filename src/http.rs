@@ -0,0 +1,119 @@
+use std::{io, sync::Mutex, time::Duration};
+
+use crate::logging::debug;
+use futures::future::BoxFuture;
+use reqwest::{
+    header::{HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    StatusCode,
+};
+
+use crate::{rt, Source};
+
+/// Polls a URL, using `If-None-Match`/`If-Modified-Since` so unchanged bodies aren't transferred or emitted.
+/// Failed requests surface as read errors, and are retried on the watcher's retry interval.
+pub struct HttpSource {
+    client: reqwest::Client,
+    url: String,
+    poll_interval: Duration,
+    state: Mutex<HttpState>,
+}
+
+#[derive(Default)]
+struct HttpState {
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    body: Option<Vec<u8>>,
+    /// `body` was fetched while polling and not read yet.
+    fresh: bool,
+}
+
+impl HttpSource {
+    /// Polls every `poll_interval`.
+    pub fn new(url: impl AsRef<str>, poll_interval: Duration) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.as_ref().to_string(),
+            poll_interval,
+            state: Default::default(),
+        }
+    }
+
+    /// Use a preconfigured client, i.e. with authentication headers, timeouts, or custom TLS roots.
+    pub fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Returns whether the body changed.
+    async fn fetch(&self) -> io::Result<bool> {
+        let mut request = self.client.get(&self.url);
+        {
+            let state = self.state.lock().unwrap();
+            if state.body.is_some() {
+                if let Some(etag) = &state.etag {
+                    request = request.header(IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &state.last_modified {
+                    request = request.header(IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+        }
+        let response = request.send().await.map_err(io::Error::other)?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(false);
+        }
+        let response = response.error_for_status().map_err(io::Error::other)?;
+        let etag = response.headers().get(ETAG).cloned();
+        let last_modified = response.headers().get(LAST_MODIFIED).cloned();
+        let body = response.bytes().await.map_err(io::Error::other)?.to_vec();
+        let mut state = self.state.lock().unwrap();
+        // servers without validators always respond with the full body
+        let changed = state.body.as_ref() != Some(&body);
+        state.etag = etag;
+        state.last_modified = last_modified;
+        state.body = Some(body);
+        state.fresh = true;
+        Ok(changed)
+    }
+}
+
+impl Source for HttpSource {
+    type Input = Vec<u8>;
+
+    fn describe(&self) -> String {
+        self.url.clone()
+    }
+
+    fn read(&self) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.fresh {
+                    state.fresh = false;
+                    return Ok(state.body.clone().unwrap_or_default());
+                }
+            }
+            self.fetch().await?;
+            let mut state = self.state.lock().unwrap();
+            state.fresh = false;
+            Ok(state.body.clone().unwrap_or_default())
+        })
+    }
+
+    fn changed(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            loop {
+                rt::sleep(self.poll_interval).await;
+                match self.fetch().await {
+                    Ok(true) => return,
+                    Ok(false) => continue,
+                    Err(e) => {
+                        // the read reports it and handles retrying
+                        debug!("failed to poll '{}': {e}", self.url);
+                        return;
+                    }
+                }
+            }
+        })
+    }
+}
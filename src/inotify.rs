@@ -1,24 +1,32 @@
+//! An async inotify wrapper, reading events through the runtime's reactor rather than a blocking thread.
+//! This is what the inotify backend is built on, for uses that don't fit a config watcher.
+
 use std::{
-    ffi::{CString, OsString},
+    ffi::OsString,
     fs::File,
-    io::Error as IoError,
+    io::{Error as IoError, Read},
     os::{
         fd::{AsRawFd, FromRawFd},
-        unix::prelude::{OsStrExt, OsStringExt},
+        unix::prelude::OsStringExt,
     },
-    path::{Component, Path, PathBuf},
+    path::Path,
 };
 
+use crate::logging::debug;
 use async_stream::stream;
 use bitmask_enum::bitmask;
 use futures::Stream;
-use log::debug;
-use tokio::{io::AsyncReadExt, net::unix::pipe::Receiver};
 
+use crate::rt::AsyncFd;
+
+/// An inotify instance. Must be created within a Tokio runtime (or with the `async-std` or `smol` feature, anywhere), whose
+/// reactor its events are read through.
 pub struct INotify {
-    stream: Receiver,
+    stream: AsyncFd<File>,
+    buffer_size: usize,
 }
 
+/// Events to watch for, and flags reported with events. See `inotify(7)`.
 #[bitmask(u32)]
 pub enum INotifyMask {
     Access = libc::IN_ACCESS,
@@ -59,57 +67,71 @@ struct RawINotifyEvent {
 
 const EVENT_SIZE: usize = std::mem::size_of::<RawINotifyEvent>();
 
-#[derive(Debug)]
+/// An event read from an [`INotify`].
+#[derive(Debug, Clone)]
 pub struct INotifyEvent {
+    /// The watch the event is for, or -1 for [`INotifyMask::QueueOverflow`].
     pub watch_descriptor: WatchHandle,
     pub mask: INotifyMask,
+    /// Pairs [`INotifyMask::MovedFrom`] and [`INotifyMask::MovedTo`] events of the same rename, otherwise 0.
     pub cookie: u32,
+    /// The name of the child in a watched directory the event is for, empty for the directory (or file) itself.
     pub name: OsString,
 }
 
+/// A watch, as returned by [`INotify::add_watch`]. Adding a watch for an inode already watched returns the same handle.
 #[derive(PartialEq, Eq, Debug, Clone, Copy, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct WatchHandle(i32);
 
-pub(crate) fn normalize(path: &Path) -> PathBuf {
-    if !path.is_absolute() {
-        panic!("attempted to normalize a relative path");
-    }
-    let mut out = PathBuf::new();
-    for component in path.components() {
-        match component {
-            Component::Prefix(_) => unreachable!(),
-            Component::RootDir => out.push("/"),
-            Component::CurDir => out.push("."),
-            Component::ParentDir => out.push(".."),
-            Component::Normal(component) => out.push(component),
-        }
+impl WatchHandle {
+    /// The watch descriptor as the kernel reports it.
+    pub fn as_raw(self) -> i32 {
+        self.0
     }
-    out
 }
 
 const NAME_MAX: usize = 255;
 
+/// Large enough for a few hundred events per read, grown to whatever is queued on busy directories.
+const DEFAULT_BUFFER_SIZE: usize = 16 * 1024;
+
 impl INotify {
+    /// An instance with the default buffer size.
     pub fn new() -> Result<Self, IoError> {
+        Self::with_buffer_size(DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Reads at least `buffer_size` bytes of events at a time, or everything queued if more.
+    /// Never less than a single event with the longest possible name.
+    pub fn with_buffer_size(buffer_size: usize) -> Result<Self, IoError> {
         let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
         if fd < 0 {
             return Err(IoError::last_os_error());
         }
         let file = unsafe { File::from_raw_fd(fd) };
-        let stream = Receiver::from_file_unchecked(file)?;
-        Ok(Self { stream })
+        let stream = AsyncFd::new(file)?;
+        Ok(Self {
+            stream,
+            buffer_size: buffer_size.max(EVENT_SIZE + NAME_MAX + 1),
+        })
     }
 
+    /// Watch `path` for `mask`, replacing the mask of an existing watch of the same inode unless [`INotifyMask::MaskAdd`] is set.
     pub fn add_watch(
         &self,
         path: impl AsRef<Path>,
         mask: INotifyMask,
     ) -> Result<WatchHandle, IoError> {
         let pathd = path.as_ref();
-        let path = CString::new(path.as_ref().as_os_str().as_bytes()).expect("NUL byte in path");
-        let descriptor =
-            unsafe { libc::inotify_add_watch(self.stream.as_raw_fd(), path.as_ptr(), mask.bits()) };
+        let path = crate::fs::c_path(pathd)?;
+        let descriptor = unsafe {
+            libc::inotify_add_watch(
+                self.stream.get_ref().as_raw_fd(),
+                path.as_ptr(),
+                mask.bits(),
+            )
+        };
         if descriptor < 0 {
             return Err(IoError::last_os_error());
         }
@@ -117,44 +139,79 @@ impl INotify {
         Ok(WatchHandle(descriptor))
     }
 
-    #[allow(dead_code)]
+    /// Stop watching. An [`INotifyMask::Ignored`] event follows for the handle.
     pub fn rm_watch(&self, handle: WatchHandle) -> Result<(), IoError> {
-        let out = unsafe { libc::inotify_rm_watch(self.stream.as_raw_fd(), handle.0) };
+        let out = unsafe { libc::inotify_rm_watch(self.stream.get_ref().as_raw_fd(), handle.0) };
         if out < 0 {
             return Err(IoError::last_os_error());
         }
         Ok(())
     }
 
-    pub fn stream<'a>(&'a mut self) -> impl Stream<Item = Result<INotifyEvent, IoError>> + 'a {
+    /// Events as they are read. Only one stream should be read at a time, since each read hands out whatever is queued.
+    pub fn stream<'a>(&'a self) -> impl Stream<Item = Result<INotifyEvent, IoError>> + 'a {
         stream! {
-            let mut buf = [0u8; EVENT_SIZE + NAME_MAX + 1];
+            let mut buf = vec![];
             loop {
-                let read_len = self.stream.read(&mut buf[..EVENT_SIZE + NAME_MAX + 1]).await?;
-                let mut buf_ref = &mut buf[..read_len];
-                while !buf_ref.is_empty() {
-                    if buf_ref.len() < EVENT_SIZE {
-                        continue;
-                    }
-                    let raw_event: RawINotifyEvent = *unsafe { (buf_ref.as_ptr() as *const RawINotifyEvent).as_ref().unwrap() };
-                    buf_ref = &mut buf_ref[EVENT_SIZE..];
-                    if read_len - EVENT_SIZE < raw_event.len as usize {
-                        continue;
+                let read_len = self.stream.read_with(|mut file| {
+                    // the kernel only hands out whole events, so make room for everything queued to drain it in one read
+                    let mut queued: libc::c_int = 0;
+                    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FIONREAD, &mut queued) } < 0 {
+                        return Err(IoError::last_os_error());
                     }
-                    let mut path = &buf_ref[..raw_event.len as usize];
+                    buf.resize((queued.max(0) as usize).max(self.buffer_size), 0);
+                    file.read(&mut buf)
+                }).await?;
+                let mut buf_ref = &buf[..read_len];
+                while buf_ref.len() >= EVENT_SIZE {
+                    let raw_event: RawINotifyEvent = unsafe { std::ptr::read_unaligned(buf_ref.as_ptr().cast()) };
+                    buf_ref = &buf_ref[EVENT_SIZE..];
+                    let Some(mut path) = buf_ref.get(..raw_event.len as usize) else {
+                        // truncated, which the kernel shouldn't do
+                        break;
+                    };
+                    buf_ref = &buf_ref[raw_event.len as usize..];
                     while !path.is_empty() && path[path.len() - 1] == 0 {
                         path = &path[..path.len() - 1];
                     }
-                    let path = OsString::from_vec(path.to_vec());
-                    buf_ref = &mut buf_ref[raw_event.len as usize..];
                     yield Ok(INotifyEvent {
                         watch_descriptor: raw_event.watch_descriptor,
                         mask: raw_event.mask,
                         cookie: raw_event.cookie,
-                        name: path,
+                        name: OsString::from_vec(path.to_vec()),
                     });
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_batched_events() {
+        let dir = std::env::temp_dir().join(format!(
+            "really-notify-{}-inotify-batch",
+            std::process::id()
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let notify = INotify::with_buffer_size(0).unwrap();
+        notify.add_watch(&dir, INotifyMask::Create).unwrap();
+        // queued before the first read, more than fit in the smallest buffer
+        for i in 0..100 {
+            std::fs::write(dir.join(format!("{i:0>200}")), "").unwrap();
+        }
+        let stream = notify.stream();
+        futures::pin_mut!(stream);
+        for i in 0..100 {
+            let event = stream.next().await.unwrap().unwrap();
+            assert_eq!(event.name, OsString::from(format!("{i:0>200}")));
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,45 @@
+use std::{io, path::PathBuf};
+
+use crate::logging::debug;
+
+use crate::{
+    directory::{walk, TreeOptions},
+    fs, DirectoryContents,
+};
+
+/// Kubelet projects every key through this symlink, and atomically swaps it to point at a new timestamped directory on update.
+pub(crate) const DATA_LINK: &str = "..data";
+
+/// Reads every projected key through a single resolution of `..data`, so all keys come from the same version.
+/// If `..data` was swapped during the read, it is retried. Keys are reported as paths within `dir`.
+pub(crate) async fn read_config_map(dir: PathBuf) -> io::Result<DirectoryContents> {
+    let data_link = dir.join(DATA_LINK);
+    'outer: loop {
+        let version = fs::read_link(&data_link).await?;
+        let version_dir = dir.join(&version);
+        let options = TreeOptions::default();
+        let files = match walk(&version_dir, &options).await {
+            Ok((_, files)) => files,
+            // swapped and cleaned up before we got to it
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let mut out = vec![];
+        for file in files {
+            let raw = match fs::read(&file).await {
+                Ok(x) => x,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue 'outer,
+                Err(e) => return Err(e),
+            };
+            let key = file.strip_prefix(&version_dir).unwrap();
+            out.push((dir.join(key), raw));
+        }
+        if fs::read_link(&data_link).await? == version {
+            return Ok(out);
+        }
+        debug!(
+            "'{}' was swapped during read, rereading",
+            data_link.display()
+        );
+    }
+}
@@ -1,12 +1,28 @@
 use std::{
+    any::Any,
+    collections::{hash_map::Entry, HashMap, HashSet},
     fmt::{self, Display},
-    path::{Path, PathBuf},
-    sync::Arc,
+    future::Future,
+    io,
+    panic::AssertUnwindSafe,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
-use backend::start_backend;
-use log::{error, info};
+use backend::{start_backend, start_stat_check, BackendHandle};
+use degraded::{DegradedCallback, DegradedHook};
+use directory::{
+    is_editor_artifact, read_directory, read_tree, PerFile, TreeHashes, TreeOptions, WatchMode,
+};
+use drop_in::{drop_in_dir, read_drop_ins};
+use futures::{future::BoxFuture, FutureExt};
+use k8s::read_config_map;
+use log::Level;
+use logging::{LevelMap, Logger};
+use rt::Instant;
+use source::{forward_changes, Changes};
+use tail::{read_tail, TailOptions, TailState};
 use thiserror::Error;
 use tokio::{
     select,
@@ -14,42 +30,582 @@ use tokio::{
 };
 
 mod backend;
-#[cfg(all(feature = "inotify", target_family = "unix"))]
-mod inotify;
+#[cfg(all(feature = "wasm", target_family = "wasm", target_os = "unknown"))]
+mod browser;
+mod combinator;
+mod degraded;
+mod directory;
+mod dotenv;
+mod drop_in;
+#[cfg(unix)]
+mod fd;
+mod fork;
+mod fs;
+mod group;
+mod history;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(all(feature = "inotify", any(target_os = "linux", target_os = "android")))]
+pub mod inotify;
+mod k8s;
+mod lock;
+mod logging;
+#[cfg(feature = "merge")]
+mod merge;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "opentelemetry")]
+mod otel;
+mod policy;
+mod retry;
+mod rt;
+mod self_writes;
+mod shared;
+mod source;
+mod stats;
+mod tail;
+mod thread;
+mod writer;
+
+#[cfg(feature = "mock")]
+pub use backend::MockBackend;
+pub use backend::{Backend, ChangeKind, TargetKind, WatchBackend};
+#[cfg(all(feature = "wasm", target_family = "wasm", target_os = "unknown"))]
+pub use browser::BrowserSource;
+pub use combinator::{zip_latest, zip_latest_all, Merge};
+pub use degraded::Degraded;
+pub use directory::{DirectoryContents, FileChange, TreeContents};
+pub use dotenv::{parse_dotenv, DotenvError};
+pub use drop_in::default_search_dirs;
+pub use fork::reinit_after_fork;
+pub use group::{StartupProgress, WatcherGroup, WatcherId};
+pub use history::{EventHistory, HistoryEntry, HistoryEvent};
+#[cfg(feature = "http")]
+pub use http::HttpSource;
+pub use lock::ReadLock;
+pub use logging::set_log_callback;
+#[cfg(feature = "merge")]
+use merge::apply_env_overlay;
+#[cfg(feature = "merge")]
+pub use merge::{deep_merge, MergeError};
+pub use policy::{Ownership, PermissionPolicy, PolicyViolation};
+pub use retry::{Backoff, Retry};
+pub use self_writes::{SelfWriteGuard, SelfWrites};
+pub use source::Source;
+pub use stats::{Watch, WatcherStats, WatcherStatus};
+pub use thread::{set_thread_options, ThreadOptions};
+pub use writer::{ConfigWriter, WriteError};
 
 /// `really-notify` primary input.
 /// [`T`] is the target parse type, i.e. your serde-deserializable `Config` struct.
 /// [`E`] is the generic error type that your parser can fail with.
-pub struct FileWatcherConfig<T, E> {
+/// [`I`] is the raw input handed to the parser, `Vec<u8>` for single files, [`DirectoryContents`] in directory and layered modes, or [`TreeContents`] in tree mode.
+pub struct FileWatcherConfig<T, E, I = Vec<u8>> {
     /// Cosmetic, used for logs to be consistent with application terminology
     pub log_name: String,
-    /// Path to the file you are interested in changes of. Do your worse with symlinks here.
+    /// Path to the file (or directory) you are interested in changes of. Do your worse with symlinks here.
     pub file: PathBuf,
     /// Parser function to transform a modified target file into our desired output. If you just want raw bytes, you can pass it through, or not set this at all.
-    pub parser: Arc<dyn Fn(Vec<u8>) -> Result<T, E> + Send + Sync>,
+    pub parser: Arc<dyn Fn(I) -> Result<T, E> + Send + Sync>,
     /// Defaults to one second, how often to attempt reparsing/error recovery.
     pub retry_interval: Duration,
+    /// How long to wait between attempts to read the target again after it failed, instead of `retry_interval`.
+    retry_backoff: Option<Backoff>,
+    /// When to read the target again after failing to read it, instead of after `retry_backoff`.
+    read_retry: Option<Retry>,
+    /// When to read the target again after failing to parse it, instead of after `retry_backoff`.
+    parse_retry: Option<Retry>,
+    /// How many times in a row a failed read or parse is retried before giving up.
+    max_retries: Option<u32>,
+    /// How long the first valid target may take before giving up.
+    initial_timeout: Option<Duration>,
+    /// Warn about reads and parses of the target taking longer than this.
+    slow_reload: Option<Duration>,
+    reader: Reader<I>,
+    mode: WatchMode,
+    unchanged: Option<Unchanged<I>>,
+    /// Called once the latest read has parsed, for readers tracking what changed since the last one.
+    parsed: Option<Parsed>,
+    /// Returns true if a read after the first is of a write this process made, and shouldn't be emitted.
+    own_writes: Option<Unchanged<I>>,
+    /// What to do with an empty target, as told apart by `is_empty`.
+    empty_files: EmptyFiles,
+    is_empty: Option<Unchanged<I>>,
+    /// Whether the target itself may not exist, i.e. a fallback candidate.
+    optional: bool,
+    /// Additional files or directories that trigger a reload when changed, which may not exist.
+    extra_watches: Vec<(PathBuf, WatchMode)>,
+    /// Latest set of additional files reported by a dependency parser.
+    dependencies: Option<Arc<Mutex<Vec<PathBuf>>>>,
+    error_hooks: Vec<ErrorHook<E>>,
+    /// Set if the input is the plain contents of `file`, so the watch and read can be shared with other watchers of the same file.
+    shared: Option<Shared<I>>,
+    /// Share the watch and read of `file` with other watchers of it that set this, see `shared_watch`.
+    shared_watch: bool,
+    /// Set for custom sources, which signal their own changes instead of being watched on the filesystem.
+    source_changes: Option<Changes>,
+    /// Poll instead of using filesystem events.
+    poll_interval: Option<Duration>,
+    backend: Backend,
+    custom_backend: Option<Arc<dyn WatchBackend>>,
+    shared_inotify: bool,
+    /// Run within the task shared by every watcher that sets this.
+    shared_task: bool,
+    /// Permission, ownership, and other attribute changes also trigger a reload.
+    attribute_changes: bool,
+    event_filter: Option<EventFilter>,
+    /// Backends to try in order if the chosen one can't be set up.
+    fallback_backends: Vec<Backend>,
+    lifecycle_hooks: Vec<LifecycleHook>,
+    /// Called once the watcher has been failing for longer than the threshold paired with each.
+    degraded_hooks: Vec<(Duration, DegradedCallback)>,
+    /// Tracks failures for `degraded_hooks`, set up once the watcher starts.
+    degraded: Vec<Arc<DegradedHook>>,
+    /// How long to wait for events from a test file before deciding they are never delivered.
+    self_test: Option<Duration>,
+    /// Mounts appearing or disappearing along the path to the target trigger a reload.
+    mount_changes: bool,
+    /// How long the target must go without changes before it is read.
+    write_quiescence: Option<Duration>,
+    /// How long after a change to keep folding further ones into the same read.
+    burst_window: Option<Duration>,
+    /// How long to wait after emitting a target before emitting the next.
+    min_emit_interval: Option<Duration>,
+    /// How often to stat the target for changes the backend missed.
+    stat_interval: Option<Duration>,
+    /// Reads are checked against the target's metadata before and after, and repeated if it changed in between.
+    verify_reads: bool,
+    /// How long a deleted target has to be recreated in before its absence is reported.
+    deletion_grace: Option<Duration>,
+    /// How many symlinks may be followed on the way to the target.
+    max_symlink_depth: usize,
+    /// Mode bits the target is checked for before every read.
+    permission_policy: Option<PermissionPolicy>,
+    /// Who the target must be owned by to be read.
+    ownership: Option<Ownership>,
+    /// Advisory lock taken on the target before every read, and how long to wait for it.
+    read_lock: Option<(ReadLock, Duration)>,
+    /// Set to follow the target across renames within its directory.
+    follow: Option<Mutex<Followed>>,
+    /// Reads don't follow symlinks that could be swapped in.
+    hardened_reads: bool,
+    stats: Option<WatcherStats>,
+    history: Option<EventHistory>,
+    /// Key-value pairs identifying the watcher in metrics and traces, beyond its log name.
+    #[cfg_attr(
+        not(any(feature = "tracing", feature = "metrics", feature = "opentelemetry")),
+        allow(dead_code)
+    )]
+    labels: Vec<(String, String)>,
+    logger: Logger,
+    /// Stops the watcher once cancelled.
+    #[cfg(feature = "tokio-util")]
+    cancel: Option<tokio_util::sync::CancellationToken>,
+    /// The reload attempt that parsed to an update, until the update is emitted.
+    #[cfg(feature = "opentelemetry")]
+    reload: Mutex<Option<otel::Reload>>,
+}
+
+/// Where a target followed across renames is now.
+struct Followed {
+    path: PathBuf,
+    /// Identity of the file last read, to find it by once it is renamed.
+    identity: Option<(u64, u64)>,
+}
+
+/// Converts the shared contents of a file into the parser input.
+type Shared<I> = Arc<dyn Fn(Vec<u8>) -> I + Send + Sync>;
+
+type Reader<I> =
+    Arc<dyn Fn(PathBuf, WatchMode, ReadContext) -> BoxFuture<'static, io::Result<I>> + Send + Sync>;
+
+/// What a [`Reader`] is handed along with the target and mode, for settings that apply at read time.
+#[derive(Clone)]
+struct ReadContext {
+    logger: Logger,
+    /// Set with [`FileWatcherConfig::with_hardened_reads`], to the most symlinks to resolve on the way to the target.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    hardened: Option<usize>,
+}
+
+impl ReadContext {
+    /// Reads a single file, without following symlinks if reads are hardened.
+    async fn read(&self, file: PathBuf) -> io::Result<Vec<u8>> {
+        #[cfg(unix)]
+        if let Some(max_depth) = self.hardened {
+            return rt::spawn_blocking(move || fd::read_nofollow(&file, max_depth)).await?;
+        }
+        fs::read(file).await
+    }
+}
+
+/// Returns true if a freshly read input is identical to the previously read one and should not be emitted.
+type Unchanged<I> = Arc<dyn Fn(&I) -> bool + Send + Sync>;
+
+/// Tells the reader that the input it last returned was parsed.
+type Parsed = Arc<dyn Fn() + Send + Sync>;
+
+/// Decides whether a change reported by the backend triggers a reload.
+type EventFilter = Arc<dyn Fn(ChangeKind, &Path) -> bool + Send + Sync>;
+
+/// Called with every error a watcher encounters, after it is logged. The watcher keeps retrying regardless.
+type ErrorHook<E> = Arc<dyn Fn(&FileWatcherError<E>) + Send + Sync>;
+
+/// Called with every lifecycle event of a watcher.
+type LifecycleHook = Arc<dyn Fn(&LifecycleEvent) + Send + Sync>;
+
+/// Something that happened to a watcher, handed to the hooks set with [`FileWatcherConfig::with_lifecycle_hook`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LifecycleEvent {
+    /// `backend` started watching `path`, the target or an additional file. Reported again whenever it falls back to another backend.
+    BackendStarted { path: PathBuf, backend: Backend },
+    /// The target at `path` was read empty, and skipped as set with [`FileWatcherConfig::with_empty_files`].
+    Empty { path: PathBuf },
+    /// The target was renamed from `from` to `to` within its directory, and is watched there from now on, as set with
+    /// [`FileWatcherConfig::with_follow_renames`].
+    PathChanged { from: PathBuf, to: PathBuf },
+}
+
+/// What to do with an empty target, set with [`FileWatcherConfig::with_empty_files`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EmptyFiles {
+    /// Hand it to the parser like any other contents.
+    #[default]
+    Parse,
+    /// Treat it as not written yet, and wait for the next change (or on startup, the retry interval) to read it again.
+    Wait,
+    /// Like [`EmptyFiles::Wait`], also reporting [`LifecycleEvent::Empty`] to lifecycle hooks.
+    Report,
 }
 
+/// An error encountered while reading, parsing, or watching a target.
 #[derive(Error, Debug)]
-enum FileWatcherError<E: Display> {
+#[non_exhaustive]
+pub enum FileWatcherError<E> {
+    /// Reading the target or establishing a watch failed.
+    #[error("{0}")]
+    Io(std::io::Error),
+    /// The path to the target has a symlink cycle, or more symlinks than [`FileWatcherConfig::with_max_symlink_depth`].
+    #[error("{0}")]
+    Symlink(#[from] SymlinkError),
+    /// The path to the target, or a symlink on the way to it, can't be handed to the OS.
+    #[error("{0}")]
+    Path(#[from] PathError),
+    /// The target failed a policy set on the watcher, i.e. [`FileWatcherConfig::with_permission_policy`], and wasn't read.
     #[error("{0}")]
-    Io(#[from] std::io::Error),
+    Policy(#[from] PolicyViolation),
+    /// The `notify` backend failed.
     #[cfg(feature = "notify")]
     #[error("{0}")]
     Notify(#[from] notify::Error),
+    /// The parser rejected the target.
     #[error("{0}")]
     Parse(E),
+    /// The target failed this many times in a row, exhausting [`FileWatcherConfig::with_max_retries`], and the watcher
+    /// stopped.
+    #[error("gave up after {0} failed attempt(s)")]
+    GaveUp(u32),
+    /// The target couldn't be read and parsed within [`FileWatcherConfig::with_initial_timeout`], and the watcher stopped.
+    #[error("no valid target within {:.1} second(s)", .0.as_secs_f64())]
+    InitialTimeout(Duration),
+    /// The read loop or the backend of the watcher panicked or stopped unexpectedly, and is restarted after a backoff.
+    #[error("{0}, restarting")]
+    Crashed(String),
+    /// The backend ran out of watches (i.e. `fs.inotify.max_user_watches`), so the target is polled instead.
+    #[error("{0}, polling instead")]
+    WatchLimit(std::io::Error),
+}
+
+impl<E> From<io::Error> for FileWatcherError<E> {
+    fn from(e: io::Error) -> Self {
+        // backends resolve symlinks and paths alongside other IO, and pass these along as IO errors
+        if e.get_ref().is_some_and(|x| x.is::<SymlinkError>()) {
+            if let Ok(e) = e.into_inner().unwrap().downcast::<SymlinkError>() {
+                return FileWatcherError::Symlink(*e);
+            }
+            unreachable!()
+        }
+        if e.get_ref().is_some_and(|x| x.is::<PathError>()) {
+            if let Ok(e) = e.into_inner().unwrap().downcast::<PathError>() {
+                return FileWatcherError::Path(*e);
+            }
+            unreachable!()
+        }
+        FileWatcherError::Io(e)
+    }
+}
+
+/// Why the chain of symlinks to a target couldn't be resolved.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SymlinkError {
+    /// Following symlinks led back to this one.
+    #[error("symlink cycle through '{}'", .0.display())]
+    Cycle(PathBuf),
+    /// More than `depth` symlinks had to be followed to reach the target, the last being `path`.
+    #[error("more than {depth} symlinks to follow, up to '{}'", path.display())]
+    TooDeep { path: PathBuf, depth: usize },
+}
+
+impl From<SymlinkError> for io::Error {
+    fn from(e: SymlinkError) -> Self {
+        io::Error::other(e)
+    }
+}
+
+/// Why a path can't be handed to the OS.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PathError {
+    /// The path has a NUL byte in it.
+    #[error("'{}' contains a NUL byte", .0.display())]
+    Nul(PathBuf),
+    /// The path is relative, and couldn't be resolved against the current directory, i.e. because it was removed.
+    #[error("'{}' is relative, and can't be resolved against the current directory", .0.display())]
+    Relative(PathBuf),
+    /// A watcher of a list of paths, i.e. [`FileWatcherConfig::new_fallback`], was given none.
+    #[error("no paths given to watch")]
+    Empty,
+}
+
+impl From<PathError> for io::Error {
+    fn from(e: PathError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/// Waits out `window` after a notification, folding any notifications in the meantime into it.
+pub(crate) async fn coalesce(notify: &Notify, window: Option<Duration>) {
+    let Some(window) = window else {
+        return;
+    };
+    rt::sleep(window).await;
+    // the read that follows covers them
+    discard_pending(notify);
+}
+
+/// Consumes a notification `notify` holds on to, if any.
+fn discard_pending(notify: &Notify) {
+    let notified = notify.notified();
+    futures::pin_mut!(notified);
+    notified.enable();
+}
+
+/// Waits until `notify` has gone `window` without a notification.
+pub(crate) async fn quiesce(notify: &Notify, window: Option<Duration>) {
+    let Some(window) = window else {
+        return;
+    };
+    while rt::timeout(window, notify.notified()).await.is_some() {}
+}
+
+/// How much is left of the grace period for a deleted target to be recreated in, starting it if `deadline` isn't set yet.
+pub(crate) fn grace_left(
+    grace: Option<Duration>,
+    deadline: &mut Option<Instant>,
+) -> Option<Duration> {
+    let deadline = *deadline.get_or_insert(Instant::now() + grace?);
+    Some(deadline.saturating_duration_since(Instant::now())).filter(|x| !x.is_zero())
+}
+
+/// How long to wait before restarting a task of a watcher that failed, backing off from `retry_interval` for as long as
+/// it keeps failing. `failures` counts the failures in a row, starting over once the task ran for a while before failing.
+pub(crate) fn restart_delay(
+    retry_interval: Duration,
+    failures: &mut u32,
+    started: Instant,
+) -> Duration {
+    if started.elapsed() >= MAX_RESTART_DELAY {
+        *failures = 0;
+    }
+    *failures += 1;
+    Backoff::exponential(retry_interval, MAX_RESTART_DELAY).delay(*failures)
+}
+
+/// The message a task panicked with.
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map_or("unknown cause", String::as_str),
+    }
+}
+
+/// Whether a read failed because the target (or a directory on the way to it) doesn't exist.
+fn is_missing<E>(e: &FileWatcherError<E>) -> bool {
+    matches!(e, FileWatcherError::Io(e) if e.kind() == io::ErrorKind::NotFound)
 }
 
-pub(crate) struct WatcherContext {
+/// A target being watched, handed to a [`WatchBackend`].
+#[derive(Clone)]
+pub struct WatcherContext {
     pub(crate) file: PathBuf,
     pub(crate) log_name: String,
     pub(crate) retry_interval: Duration,
+    /// When to read the target again after failing to.
+    pub(crate) read_retry: Retry,
     pub(crate) notify: Arc<Notify>,
+    pub(crate) mode: WatchMode,
+    /// If the target doesn't exist, wait for it to be created rather than failing.
+    #[allow(dead_code)] // unused when only polling
+    pub(crate) optional: bool,
+    /// Poll the contents instead of using filesystem events.
+    pub(crate) poll_interval: Option<Duration>,
+    pub(crate) backend: Backend,
+    pub(crate) custom: Option<Arc<dyn WatchBackend>>,
+    /// Register inotify watches with the instance shared between watchers, rather than one of its own.
+    #[allow(dead_code)] // unused without inotify
+    pub(crate) shared_inotify: bool,
+    /// Permission, ownership, and other attribute changes are changes too.
+    pub(crate) attribute_changes: bool,
+    /// Changes it rejects don't trigger a reload.
+    pub(crate) event_filter: Option<EventFilter>,
+    /// Backends to try in order if the chosen one can't be set up, before polling.
+    pub(crate) fallback_backends: Vec<Backend>,
+    pub(crate) lifecycle_hooks: Vec<LifecycleHook>,
+    /// Check that events are delivered before relying on them, waiting this long for them.
+    #[allow(dead_code)] // unused without inotify
+    pub(crate) self_test: Option<Duration>,
+    /// Resolve the path to the target again whenever the mount table changes.
+    #[allow(dead_code)] // unused without inotify
+    pub(crate) mount_changes: bool,
+    /// Wait for this long without changes before reading.
+    pub(crate) write_quiescence: Option<Duration>,
+    /// Wait this long after a change before reading, to read once for all the changes in between.
+    pub(crate) burst_window: Option<Duration>,
+    /// Check that the target didn't change while it was being read.
+    pub(crate) verify_reads: bool,
+    /// Wait this long for a deleted target to be recreated before reporting it.
+    pub(crate) deletion_grace: Option<Duration>,
+    /// Fail once more symlinks than this need following to reach the target.
+    #[allow(dead_code)] // unused by the notify and poll backends
+    pub(crate) max_symlink_depth: usize,
+    /// Held for as long as the backend started with this context runs, so its supervisor notices it stopping once every
+    /// clone is dropped.
+    pub(crate) _running: Option<mpsc::Sender<()>>,
+    /// Where the watches of the backend are listed, set once it is chosen.
+    pub(crate) stats: Option<WatcherStats>,
+    pub(crate) watch_set: Option<Arc<stats::WatchSet>>,
+    /// Where the changes reported, and whether they were accepted, are recorded.
+    pub(crate) history: Option<EventHistory>,
+    /// Logs messages about the target.
+    pub(crate) logger: Logger,
+}
+
+impl WatcherContext {
+    /// Absolute path of the target. Open files are watched through their `/proc/self/fd` (or `/dev/fd`) path.
+    pub fn path(&self) -> &Path {
+        &self.file
+    }
+
+    /// Cosmetic, used for logs to be consistent with application terminology.
+    pub fn log_name(&self) -> &str {
+        &self.log_name
+    }
+
+    /// What kind of filesystem object the target is.
+    pub fn kind(&self) -> TargetKind {
+        match self.mode {
+            WatchMode::File | WatchMode::Tail(_) | WatchMode::Descriptor => TargetKind::File,
+            WatchMode::Directory { .. } | WatchMode::ConfigMap => TargetKind::Directory,
+            WatchMode::Tree(_) => TargetKind::Tree,
+            WatchMode::Sentinel => TargetKind::Sentinel,
+        }
+    }
+
+    /// Whether the target may not exist, in which case it being created is a change.
+    pub fn optional(&self) -> bool {
+        self.optional || matches!(self.mode, WatchMode::Sentinel)
+    }
+
+    /// Lists what the backend watches for [`WatcherStats::watches`], as paths along with the events watched for there, i.e.
+    /// the target and the directories on the way to it, replacing what it listed before.
+    pub fn established(&self, watches: impl IntoIterator<Item = (PathBuf, String)>) {
+        if let Some(watch_set) = &self.watch_set {
+            watch_set.set(watches);
+        }
+    }
+
+    /// How often the watcher retries after errors, i.e. as a default interval for backends that poll.
+    pub fn retry_interval(&self) -> Duration {
+        self.retry_interval
+    }
+
+    /// Whether changes to permissions, ownership, and other attributes of the target count as changes.
+    pub fn attribute_changes(&self) -> bool {
+        self.attribute_changes
+    }
+
+    /// Signal that the target may have changed, so the watcher reads it again. Signals before the read starts are coalesced.
+    pub fn changed(&self) {
+        self.notify.notify_one();
+    }
+
+    /// Signal a change of `kind` to `path`, which is the target or within or on the way to it.
+    /// Like [`WatcherContext::changed`], unless the watcher's event filter rejects it.
+    pub fn report(&self, kind: ChangeKind, path: &Path) {
+        if self.accepts(kind, path) {
+            self.notify.notify_one();
+        }
+    }
+
+    /// Logs that watching the target failed with `e`, `failures` times in a row.
+    pub(crate) fn watch_error(&self, e: &dyn Display, failures: u32) {
+        self.logger.log(
+            Level::Error,
+            failures,
+            format_args!(
+                "{} watch error: {e} @ '{}'",
+                self.log_name,
+                self.file.display()
+            ),
+        );
+    }
+
+    /// Hands `event` to the watcher's lifecycle hooks.
+    pub(crate) fn lifecycle(&self, event: LifecycleEvent) {
+        for hook in &self.lifecycle_hooks {
+            hook(&event);
+        }
+    }
+
+    /// Whether a change of `kind` to `path` triggers a reload.
+    pub(crate) fn accepts(&self, kind: ChangeKind, path: &Path) -> bool {
+        let artifact = self.mode.ignores_editor_artifacts()
+            && path != self.file
+            && path.file_name().is_some_and(is_editor_artifact);
+        let accepted = !artifact
+            && match &self.event_filter {
+                Some(filter) => filter(kind, path),
+                None => true,
+            };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(kind = ?kind, path = %path.display(), accepted, "change reported");
+        if let Some(history) = &self.history {
+            let path = path.to_path_buf();
+            let event = if accepted {
+                HistoryEvent::Received { kind, path }
+            } else {
+                HistoryEvent::Filtered { kind, path }
+            };
+            history.record(&self.log_name, event);
+        }
+        accepted
+    }
 }
 
+pub(crate) const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub(crate) const DEFAULT_MAX_SYMLINK_DEPTH: usize = 16;
+
+/// Longest wait before restarting a task that keeps failing.
+pub(crate) const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
 /// Impossible to fail converting a Vec<u8> to a Vec<u8>
+#[derive(Debug)]
 pub enum Infallible {}
 
 impl fmt::Display for Infallible {
@@ -58,126 +614,5265 @@ impl fmt::Display for Infallible {
     }
 }
 
-impl FileWatcherConfig<Vec<u8>, Infallible> {
-    pub fn new(file: impl AsRef<Path>, log_name: impl AsRef<str>) -> Self {
+impl<I: 'static> FileWatcherConfig<I, Infallible, I> {
+    fn from_reader(
+        file: impl AsRef<Path>,
+        log_name: impl AsRef<str>,
+        reader: Reader<I>,
+        mode: WatchMode,
+    ) -> Self {
         Self {
             file: file.as_ref().to_path_buf(),
             log_name: log_name.as_ref().to_string(),
-            parser: Arc::new(|x| Ok(x)),
-            retry_interval: Duration::from_secs(1),
+            parser: Arc::new(Ok),
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+            retry_backoff: None,
+            read_retry: None,
+            parse_retry: None,
+            max_retries: None,
+            initial_timeout: None,
+            slow_reload: None,
+            reader,
+            mode,
+            unchanged: None,
+            parsed: None,
+            own_writes: None,
+            empty_files: EmptyFiles::Parse,
+            is_empty: None,
+            optional: false,
+            extra_watches: vec![],
+            dependencies: None,
+            error_hooks: vec![],
+            shared: None,
+            source_changes: None,
+            poll_interval: None,
+            backend: Backend::Auto,
+            custom_backend: None,
+            shared_inotify: false,
+            shared_task: false,
+            shared_watch: false,
+            attribute_changes: false,
+            event_filter: None,
+            fallback_backends: vec![Backend::Notify],
+            lifecycle_hooks: vec![],
+            degraded_hooks: vec![],
+            degraded: vec![],
+            #[cfg(feature = "opentelemetry")]
+            reload: Default::default(),
+            self_test: None,
+            mount_changes: false,
+            write_quiescence: None,
+            burst_window: None,
+            min_emit_interval: None,
+            stat_interval: None,
+            verify_reads: false,
+            deletion_grace: None,
+            max_symlink_depth: DEFAULT_MAX_SYMLINK_DEPTH,
+            permission_policy: None,
+            ownership: None,
+            read_lock: None,
+            follow: None,
+            hardened_reads: false,
+            stats: None,
+            history: None,
+            labels: vec![],
+            logger: Logger::default(),
+            #[cfg(feature = "tokio-util")]
+            cancel: None,
         }
     }
+
+    /// Watch a custom [`Source`]. [`Source::describe`] stands in for the file path in logs.
+    pub fn from_source<S: Source<Input = I>>(source: S, log_name: impl AsRef<str>) -> Self {
+        let source = Arc::new(source);
+        let source2 = source.clone();
+        let mut out = Self::from_reader(
+            source.describe(),
+            log_name,
+            Arc::new(move |_, _, _| {
+                let source = source2.clone();
+                Box::pin(async move { source.read().await })
+            }),
+            WatchMode::File,
+        );
+        out.source_changes = Some(Arc::new(move || {
+            let source = source.clone();
+            Box::pin(async move { source.changed().await })
+        }));
+        out
+    }
 }
 
-impl<T: Send + 'static, E: Display + Send + 'static> FileWatcherConfig<T, E> {
-    /// Set a new parser and adjust the FileWatcherConfig type parameters as needed.
-    pub fn with_parser<T2: Send + 'static, E2: Display + Send + 'static>(
+#[cfg(not(any(
+    feature = "async-std",
+    feature = "smol",
+    all(feature = "wasm", target_family = "wasm", target_os = "unknown")
+)))]
+impl<I: Send + 'static> FileWatcherConfig<I, Infallible, I> {
+    /// Like [`FileWatcherConfig::start`], but parses with `parser` on the current `LocalSet`, so it doesn't need to be
+    /// `Send`, i.e. to use `Rc`-based state. Only the parser runs there, the watcher runs on the runtime as usual.
+    /// Reads it rejects are logged and skipped, waiting for the next change. Must be called within a `LocalSet`.
+    pub fn start_local<T2: 'static, E2: Display + 'static>(
         self,
-        func: impl Fn(Vec<u8>) -> Result<T2, E2> + Send + Sync + 'static,
-    ) -> FileWatcherConfig<T2, E2> {
-        FileWatcherConfig {
-            log_name: self.log_name,
-            file: self.file,
-            parser: Arc::new(func),
-            retry_interval: self.retry_interval,
-        }
+        parser: impl Fn(I) -> Result<T2, E2> + 'static,
+    ) -> mpsc::Receiver<T2> {
+        let (file, log_name) = (self.file.clone(), self.log_name.clone());
+        let logger = self.logger.clone();
+        let mut reads = self.start();
+        let (sender, receiver) = mpsc::channel(3);
+        tokio::task::spawn_local(async move {
+            let mut failures = 0;
+            loop {
+                let raw = select! {
+                    raw = reads.recv() => raw,
+                    _ = sender.closed() => return,
+                };
+                let Some(raw) = raw else {
+                    return;
+                };
+                match parser(raw) {
+                    Ok(parsed) => {
+                        failures = 0;
+                        if sender.send(parsed).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        failures += 1;
+                        logger.log(
+                            Level::Error,
+                            failures,
+                            format_args!(
+                                "failed to parse {log_name} update: {e} @ '{}', waiting for next change",
+                                file.display()
+                            ),
+                        );
+                    }
+                }
+            }
+        });
+        receiver
     }
+}
 
-    /// Set an alternative retry_interval
-    pub fn with_retry_interval(mut self, retry_interval: Duration) -> Self {
-        self.retry_interval = retry_interval;
-        self
+impl FileWatcherConfig<Vec<u8>, Infallible> {
+    /// Watch a single file.
+    pub fn new(file: impl AsRef<Path>, log_name: impl AsRef<str>) -> Self {
+        let mut out = Self::from_reader(
+            file,
+            log_name,
+            Arc::new(|file, _, context| Box::pin(async move { context.read(file).await })),
+            WatchMode::File,
+        );
+        out.shared = Some(Arc::new(|raw| raw));
+        out
     }
+}
 
-    /// Run the watcher. Dropping/closing this receiver will cause an immediate cleanup.
-    pub fn start(self) -> mpsc::Receiver<T> {
-        let (sender, receiver) = mpsc::channel(3);
-        tokio::spawn(self.run(sender));
-        receiver
+impl FileWatcherConfig<DirectoryContents, Infallible, DirectoryContents> {
+    /// Watch a drop-in style directory (i.e. `conf.d`). Any file created, modified, or deleted inside of it triggers a reload,
+    /// and the parser receives the contents of every regular file in the directory, sorted by path.
+    pub fn new_directory(dir: impl AsRef<Path>, log_name: impl AsRef<str>) -> Self {
+        Self::from_reader(
+            dir,
+            log_name,
+            Arc::new(|dir, mode, _| {
+                let WatchMode::Directory { editor_artifacts } = mode else {
+                    unreachable!()
+                };
+                Box::pin(read_directory(dir, editor_artifacts))
+            }),
+            WatchMode::Directory {
+                editor_artifacts: false,
+            },
+        )
     }
+}
 
-    async fn run(self, sender: mpsc::Sender<T>) {
-        let target = loop {
-            match self.read_target().await {
-                Ok(x) => break x,
-                Err(e) => {
-                    error!(
-                        "failed to read initial {}: {e} @ '{}', retrying in {:.1} second(s)",
-                        self.log_name,
-                        self.file.display(),
-                        self.retry_interval.as_secs_f64(),
+impl FileWatcherConfig<TreeContents, Infallible, TreeContents> {
+    /// Recursively watch a directory tree (i.e. `templates/`). Any file created, modified, or deleted within it triggers a reload,
+    /// and the parser receives every file in the tree along with which of them changed since the last read.
+    pub fn new_tree(dir: impl AsRef<Path>, log_name: impl AsRef<str>) -> Self {
+        let previous = Arc::new(Mutex::new(TreeHashes::default()));
+        let parsed = previous.clone();
+        let mut out = Self::from_reader(
+            dir,
+            log_name,
+            Arc::new(move |dir, mode, _| {
+                let WatchMode::Tree(options) = mode else {
+                    unreachable!()
+                };
+                Box::pin(read_tree(dir, options, previous.clone()))
+            }),
+            WatchMode::Tree(TreeOptions::default()),
+        );
+        out.parsed = Some(Arc::new(move || parsed.lock().unwrap().commit()));
+        out
+    }
+}
+
+impl FileWatcherConfig<HashMap<String, String>, DotenvError> {
+    /// Watch a `.env` style file, emitting its variables. See [`parse_dotenv`] for the supported syntax.
+    pub fn new_dotenv(file: impl AsRef<Path>, log_name: impl AsRef<str>) -> Self {
+        FileWatcherConfig::new(file, log_name).with_parser(|raw| parse_dotenv(&raw))
+    }
+}
+
+impl FileWatcherConfig<Vec<u8>, Infallible> {
+    /// Poll a URL (i.e. a config service) every `poll_interval`, emitting the body whenever it changes. See [`HttpSource`].
+    #[cfg(feature = "http")]
+    pub fn new_http(
+        url: impl AsRef<str>,
+        poll_interval: Duration,
+        log_name: impl AsRef<str>,
+    ) -> Self {
+        Self::from_source(HttpSource::new(url, poll_interval), log_name)
+    }
+
+    /// Watch an ordered list of candidate paths (i.e. `/etc/app/config.yaml`, `./config.yaml`), where the first one that exists is used.
+    /// All candidates are watched, so if a higher priority candidate appears (or the current one disappears), the watcher switches over.
+    /// Fails with [`PathError::Empty`] if there are no candidates.
+    pub fn new_fallback(
+        candidates: impl IntoIterator<Item = impl AsRef<Path>>,
+        log_name: impl AsRef<str>,
+    ) -> Result<Self, PathError> {
+        let candidates: Vec<PathBuf> = candidates
+            .into_iter()
+            .map(|x| x.as_ref().to_path_buf())
+            .collect();
+        let first = candidates.first().cloned().ok_or(PathError::Empty)?;
+        let log_name = log_name.as_ref().to_string();
+        let candidates2 = candidates.clone();
+        let log_name2 = log_name.clone();
+        let current = Arc::new(Mutex::new(None));
+        let mut out = Self::from_reader(
+            first,
+            log_name,
+            Arc::new(move |_, _, context| {
+                Box::pin(read_fallback(
+                    candidates2.clone(),
+                    log_name2.clone(),
+                    current.clone(),
+                    context,
+                ))
+            }),
+            WatchMode::File,
+        );
+        out.optional = true;
+        out.extra_watches = candidates
+            .into_iter()
+            .skip(1)
+            .map(|x| (x, WatchMode::File))
+            .collect();
+        Ok(out)
+    }
+}
+
+async fn read_fallback(
+    candidates: Vec<PathBuf>,
+    log_name: String,
+    current: Arc<Mutex<Option<PathBuf>>>,
+    context: ReadContext,
+) -> io::Result<Vec<u8>> {
+    for candidate in candidates {
+        match context.read(candidate.clone()).await {
+            Ok(raw) => {
+                let mut current = current.lock().unwrap();
+                if current.as_ref() != Some(&candidate) {
+                    context.logger.log(
+                        Level::Info,
+                        0,
+                        format_args!("using {log_name} candidate '{}'", candidate.display()),
                     );
-                    tokio::time::sleep(self.retry_interval).await;
+                    *current = Some(candidate);
                 }
+                return Ok(raw);
             }
-        };
-        if sender.send(target).await.is_err() {
-            return;
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "none of the candidate paths exist",
+    ))
+}
+
+impl FileWatcherConfig<DirectoryContents, Infallible, DirectoryContents> {
+    /// Watch an ordered list of layered files (i.e. base, environment override, local override). A change to any layer triggers a reload,
+    /// and the parser receives the contents of each layer in order. The first layer must exist, later layers are skipped while missing.
+    /// See [`FileWatcherConfig::with_layer_parser`] to parse and merge layers individually. Fails with [`PathError::Empty`] if
+    /// there are no layers.
+    pub fn new_layered(
+        layers: impl IntoIterator<Item = impl AsRef<Path>>,
+        log_name: impl AsRef<str>,
+    ) -> Result<Self, PathError> {
+        let layers: Vec<PathBuf> = layers
+            .into_iter()
+            .map(|x| x.as_ref().to_path_buf())
+            .collect();
+        let base = layers.first().cloned().ok_or(PathError::Empty)?;
+        let layers2 = layers.clone();
+        let mut out = Self::from_reader(
+            base,
+            log_name,
+            Arc::new(move |_, _, _| Box::pin(read_layers(layers2.clone()))),
+            WatchMode::File,
+        );
+        out.extra_watches = layers
+            .into_iter()
+            .skip(1)
+            .map(|x| (x, WatchMode::File))
+            .collect();
+        Ok(out)
+    }
+}
+
+async fn read_layers(layers: Vec<PathBuf>) -> io::Result<DirectoryContents> {
+    let mut out = vec![];
+    for (i, layer) in layers.into_iter().enumerate() {
+        match fs::read(&layer).await {
+            Ok(raw) => out.push((layer, raw)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && i > 0 => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(out)
+}
+
+impl FileWatcherConfig<DirectoryContents, Infallible, DirectoryContents> {
+    /// Resolve a relative config path (i.e. `myapp/myapp.conf`) across [`default_search_dirs`] with systemd-style precedence.
+    /// See [`FileWatcherConfig::new_drop_in_with_dirs`].
+    pub fn new_drop_in(name: impl AsRef<Path>, log_name: impl AsRef<str>) -> Self {
+        Self::drop_in(default_search_dirs(), name, log_name)
+    }
+
+    /// Resolve a relative config path (i.e. `myapp/myapp.conf`) across `search_dirs`, highest precedence first.
+    /// The parser receives the first main file found, followed by drop-ins (i.e. `myapp/myapp.conf.d/*.conf`) from every search directory,
+    /// ordered by file name. A drop-in masks drop-ins of the same file name in lower precedence directories.
+    /// Every location is watched, so any layer appearing, changing, or disappearing triggers a reload.
+    /// See [`FileWatcherConfig::with_layer_parser`] to parse and merge them. Fails with [`PathError::Empty`] if there are no
+    /// search directories.
+    pub fn new_drop_in_with_dirs(
+        search_dirs: impl IntoIterator<Item = impl AsRef<Path>>,
+        name: impl AsRef<Path>,
+        log_name: impl AsRef<str>,
+    ) -> Result<Self, PathError> {
+        let search_dirs: Vec<PathBuf> = search_dirs
+            .into_iter()
+            .map(|x| x.as_ref().to_path_buf())
+            .collect();
+        if search_dirs.is_empty() {
+            return Err(PathError::Empty);
         }
-        let mut file = self.file.clone();
-        if file.is_relative() {
-            if let Ok(cwd) = std::env::current_dir() {
-                file = cwd.join(file);
+        Ok(Self::drop_in(search_dirs, name, log_name))
+    }
+
+    /// Watches `name` across `search_dirs`, of which there is at least one.
+    fn drop_in(
+        search_dirs: Vec<PathBuf>,
+        name: impl AsRef<Path>,
+        log_name: impl AsRef<str>,
+    ) -> Self {
+        let name = name.as_ref().to_path_buf();
+        let first = search_dirs[0].join(&name);
+        let search_dirs2 = search_dirs.clone();
+        let name2 = name.clone();
+        let mut out = Self::from_reader(
+            first,
+            log_name,
+            Arc::new(move |_, _, _| Box::pin(read_drop_ins(search_dirs2.clone(), name2.clone()))),
+            WatchMode::File,
+        );
+        out.optional = true;
+        for (i, dir) in search_dirs.iter().enumerate() {
+            if i > 0 {
+                out.extra_watches.push((dir.join(&name), WatchMode::File));
             }
+            out.extra_watches.push((
+                dir.join(drop_in_dir(&name)),
+                WatchMode::Directory {
+                    editor_artifacts: false,
+                },
+            ));
         }
-        let notify = Arc::new(Notify::new());
-        let watcher_context = WatcherContext {
+        out
+    }
+}
+
+impl FileWatcherConfig<DirectoryContents, Infallible, DirectoryContents> {
+    /// Watch a Kubernetes ConfigMap or Secret volume mount. Only the atomic `..data` symlink swap kubelet performs on update triggers a reload,
+    /// and every projected key is read from the same version. The parser receives each key as a path within `dir`, sorted by path.
+    pub fn new_config_map(dir: impl AsRef<Path>, log_name: impl AsRef<str>) -> Self {
+        Self::from_reader(
+            dir,
+            log_name,
+            Arc::new(|dir, _, _| Box::pin(read_config_map(dir))),
+            WatchMode::ConfigMap,
+        )
+    }
+}
+
+impl FileWatcherConfig<HashMap<String, Vec<u8>>, Infallible, DirectoryContents> {
+    /// Like [`FileWatcherConfig::new_config_map`], but emits a map of key name (relative path within `dir`) to contents.
+    pub fn new_key_map(dir: impl AsRef<Path>, log_name: impl AsRef<str>) -> Self {
+        let dir = dir.as_ref().to_path_buf();
+        FileWatcherConfig::new_config_map(&dir, log_name).with_parser(move |files| {
+            Ok(files
+                .into_iter()
+                .map(|(path, raw)| {
+                    let key = path.strip_prefix(&dir).unwrap_or(&path);
+                    (key.to_string_lossy().into_owned(), raw)
+                })
+                .collect())
+        })
+    }
+}
+
+impl FileWatcherConfig<Vec<u8>, Infallible> {
+    /// Follow a file that is only appended to (i.e. a command or event log). The initial emission is the existing contents,
+    /// after which only newly appended bytes are emitted. See [`FileWatcherConfig::with_line_parser`] to parse line by line.
+    /// Truncation and logrotate-style rotation are followed, continuing from the start of the new file.
+    pub fn new_tail(file: impl AsRef<Path>, log_name: impl AsRef<str>) -> Self {
+        let state = Arc::new(tokio::sync::Mutex::new(TailState::default()));
+        let mut out = Self::from_reader(
             file,
-            log_name: self.log_name.clone(),
-            retry_interval: self.retry_interval,
-            notify: notify.clone(),
-        };
-        start_backend::<E>(watcher_context).await;
-        loop {
-            select! {
-                _ = notify.notified() => {
-                    let target = loop {
-                        match self.read_target().await {
-                            Ok(x) => break x,
-                            Err(e) => {
-                                error!("failed to read {} update: {e} @ {}, retrying in {:.1} second(s)", self.log_name, self.file.display(), self.retry_interval.as_secs_f64());
-                                tokio::time::sleep(self.retry_interval).await;
-                                // toss out any pending notification, since we will already try again
-                                let notify = notify.notified();
-                                futures::pin_mut!(notify);
-                                notify.enable();
-                            }
-                        }
-                    };
-                    if sender.send(target).await.is_err() {
-                        return;
-                    }
-                },
-                _ = sender.closed() => {
-                    return;
-                }
-            }
+            log_name,
+            Arc::new(move |file, mode, _| {
+                let WatchMode::Tail(options) = mode else {
+                    unreachable!()
+                };
+                Box::pin(read_tail(file, options, state.clone()))
+            }),
+            WatchMode::Tail(TailOptions::default()),
+        );
+        let initial = Mutex::new(true);
+        out.unchanged = Some(Arc::new(move |appended| {
+            !std::mem::replace(&mut *initial.lock().unwrap(), false) && appended.is_empty()
+        }));
+        out
+    }
+}
+
+impl<T: Send + 'static, E: Display + Send + 'static> FileWatcherConfig<T, E, Vec<u8>> {
+    /// Parse each line on its own, emitting every line parsed from one read together.
+    /// In tail mode, a partially written final line is left for the next read.
+    pub fn with_line_parser<T2: Send + 'static, E2: Display + Send + 'static>(
+        mut self,
+        func: impl Fn(String) -> Result<T2, E2> + Send + Sync + 'static,
+    ) -> FileWatcherConfig<Vec<T2>, E2, Vec<u8>> {
+        if let WatchMode::Tail(options) = &mut self.mode {
+            options.lines = true;
         }
+        self.with_parser(move |raw| {
+            String::from_utf8_lossy(&raw)
+                .lines()
+                .map(|line| func(line.to_string()))
+                .collect()
+        })
+    }
+
+    /// Don't reload the target after this process changes it, as marked with guards from `writes`, i.e. an application
+    /// saving its own settings. Changes made by anyone else are still reloaded.
+    pub fn with_self_writes(mut self, writes: &SelfWrites) -> Self {
+        writes.watch(absolute(&self.file));
+        let writes = writes.clone();
+        self.own_writes = Some(Arc::new(move |raw| writes.is_own(raw)));
+        self
+    }
+
+    /// When the target is renamed within its directory, rather than replaced, follow it to its new name and keep watching
+    /// it there, reporting [`LifecycleEvent::PathChanged`] to lifecycle hooks, i.e. for tools rotating configs by renaming
+    /// the active file. Once the target goes missing, its directory is searched for the file last read. Reads aren't
+    /// shared with other watchers.
+    #[cfg(unix)]
+    pub fn with_follow_renames(mut self, follow: bool) -> Self {
+        self.follow = follow.then(|| {
+            Mutex::new(Followed {
+                path: absolute(&self.file),
+                identity: None,
+            })
+        });
+        self
+    }
+
+    /// Choose what happens when the target is read empty, which atomic writers briefly expose, instead of handing it to
+    /// the parser as usual.
+    pub fn with_empty_files(mut self, empty_files: EmptyFiles) -> Self {
+        self.empty_files = empty_files;
+        self.is_empty = Some(Arc::new(|raw: &Vec<u8>| raw.is_empty()));
+        self
+    }
+}
+
+#[cfg(unix)]
+impl FileWatcherConfig<Vec<u8>, Infallible> {
+    /// Watch an already open file (i.e. received through fd passing), reading through the descriptor.
+    /// The open file is watched rather than a path, so it can't be swapped out between opening and watching.
+    pub fn from_fd(fd: impl Into<std::os::fd::OwnedFd>, log_name: impl AsRef<str>) -> Self {
+        let file = Arc::new(std::fs::File::from(fd.into()));
+        let path = fd::fd_path(&file);
+        Self::from_reader(
+            path,
+            log_name,
+            Arc::new(move |_, _, _| {
+                let file = file.clone();
+                Box::pin(async move { rt::spawn_blocking(move || fd::read_fd(&file)).await? })
+            }),
+            WatchMode::Descriptor,
+        )
+    }
+
+    /// Read the target without following symlinks anyone could swap in between watching and reading, for files in
+    /// directories writable by less trusted users. Symlinks on the way to the target are resolved one at a time and
+    /// logged, up to the depth set with [`FileWatcherConfig::with_max_symlink_depth`], and the result is opened
+    /// a component at a time with `O_NOFOLLOW`, so a symlink appearing anywhere along it fails the read. The opened file
+    /// must be the regular file that was resolved. Only applies to `new` and `new_fallback`.
+    pub fn with_hardened_reads(mut self) -> Self {
+        self.hardened_reads = true;
+        self
     }
+}
 
-    async fn read_target(&self) -> Result<T, FileWatcherError<E>> {
-        info!(
-            "reading updated {} '{}'",
-            self.log_name,
-            self.file.display()
+impl FileWatcherConfig<bool, Infallible, bool> {
+    /// Watch for a marker file (i.e. a maintenance-mode flag or lock file) coming into existence or disappearing.
+    /// The file is never read, and the parser receives whether it currently exists. Only transitions are emitted.
+    pub fn new_sentinel(file: impl AsRef<Path>, log_name: impl AsRef<str>) -> Self {
+        let mut out = Self::from_reader(
+            file,
+            log_name,
+            Arc::new(|file, _, _| Box::pin(async move { fs::try_exists(file).await })),
+            WatchMode::Sentinel,
         );
-        let raw = tokio::fs::read(&self.file).await?;
-        (self.parser)(raw).map_err(FileWatcherError::Parse)
+        let previous = Mutex::new(None);
+        out.unchanged = Some(Arc::new(move |exists| {
+            previous.lock().unwrap().replace(*exists) == Some(*exists)
+        }));
+        out
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl<T: Send + 'static, E: Display + Send + 'static> FileWatcherConfig<T, E, TreeContents> {
+    /// Limit how many directory levels below the root are watched. `0` only watches the root itself. Defaults to unlimited.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        if let WatchMode::Tree(options) = &mut self.mode {
+            options.max_depth = Some(max_depth);
+        }
+        self
+    }
 
-    #[tokio::test]
-    async fn test_file_zone() {
-        env_logger::Builder::new()
-            .parse_env(env_logger::Env::default().default_filter_or("info"))
-            .init();
-        let mut receiver = FileWatcherConfig::new("./test.yaml", "config").start();
-        while let Some(_update) = receiver.recv().await {
-            println!("updated!");
+    /// Ignore any file or directory matching a glob pattern, against either its path relative to the root or its file name.
+    /// i.e. `*.swp`, `.git`, `cache/**`
+    pub fn with_ignore_pattern(mut self, pattern: &str) -> Result<Self, glob::PatternError> {
+        if let WatchMode::Tree(options) = &mut self.mode {
+            options.ignore.push(glob::Pattern::new(pattern)?);
+        }
+        Ok(self)
+    }
+
+    /// Parse each changed file on its own and emit only what changed since the last emission, instead of the whole tree.
+    pub fn with_file_parser<T2: Send + 'static, E2: Display + Send + 'static>(
+        self,
+        func: impl Fn(Vec<u8>) -> Result<T2, E2> + Send + Sync + 'static,
+    ) -> FileWatcherConfig<Vec<FileChange<T2>>, E2, TreeContents> {
+        let per_file = Arc::new(PerFile::new(func));
+        let per_file2 = per_file.clone();
+        let mut out =
+            self.with_parser(move |contents: TreeContents| per_file.parse(contents.files));
+        out.unchanged = Some(Arc::new(move |contents| {
+            per_file2.is_unchanged(&contents.files)
+        }));
+        out
+    }
+}
+
+impl<T: Send + 'static, E: Display + Send + 'static> FileWatcherConfig<T, E, DirectoryContents> {
+    /// Parse each file (or layer) on its own, then combine them in order with `merge`.
+    pub fn with_layer_parser<T2: Send + 'static, E2: Display + Send + 'static>(
+        self,
+        parser: impl Fn(Vec<u8>) -> Result<T2, E2> + Send + Sync + 'static,
+        merge: impl Fn(Vec<T2>) -> T2 + Send + Sync + 'static,
+    ) -> FileWatcherConfig<T2, E2, DirectoryContents> {
+        self.with_parser(move |layers| {
+            let parsed = layers
+                .into_iter()
+                .map(|(_, raw)| parser(raw))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(merge(parsed))
+        })
+    }
+
+    /// Parse each file (or layer) into a `serde_json::Value`, deep merge them in order (later layers win), then deserialize the result.
+    /// Objects are merged key by key, anything else is replaced. YAML/TOML users can deserialize straight to a `serde_json::Value`.
+    #[cfg(feature = "merge")]
+    pub fn with_deep_merge<
+        T2: serde::de::DeserializeOwned + Send + 'static,
+        E2: Display + Send + 'static,
+    >(
+        self,
+        parser: impl Fn(Vec<u8>) -> Result<serde_json::Value, E2> + Send + Sync + 'static,
+    ) -> FileWatcherConfig<T2, MergeError<E2>, DirectoryContents> {
+        self.with_parser(move |layers| {
+            let mut merged = serde_json::Value::Null;
+            for (_, raw) in layers {
+                deep_merge(&mut merged, parser(raw).map_err(MergeError::Parse)?);
+            }
+            serde_json::from_value(merged).map_err(MergeError::Deserialize)
+        })
+    }
+
+    /// Parse each changed file on its own and emit only what changed since the last emission, instead of the whole directory.
+    pub fn with_file_parser<T2: Send + 'static, E2: Display + Send + 'static>(
+        self,
+        func: impl Fn(Vec<u8>) -> Result<T2, E2> + Send + Sync + 'static,
+    ) -> FileWatcherConfig<Vec<FileChange<T2>>, E2, DirectoryContents> {
+        let per_file = Arc::new(PerFile::new(func));
+        let per_file2 = per_file.clone();
+        let mut out = self.with_parser(move |files| per_file.parse(files));
+        out.unchanged = Some(Arc::new(move |files| per_file2.is_unchanged(files)));
+        out
+    }
+}
+
+impl<T: Send + 'static, E: Display + Send + 'static, I: Send + 'static> FileWatcherConfig<T, E, I> {
+    /// Set a new parser and adjust the FileWatcherConfig type parameters as needed.
+    /// Error hooks are typed by the parser error, so any previously set are discarded.
+    pub fn with_parser<T2: Send + 'static, E2: Display + Send + 'static>(
+        mut self,
+        func: impl Fn(I) -> Result<T2, E2> + Send + Sync + 'static,
+    ) -> FileWatcherConfig<T2, E2, I> {
+        let unchanged = self.unchanged.take();
+        let own_writes = self.own_writes.take();
+        let is_empty = self.is_empty.take();
+        let shared = self.shared.take();
+        let reader = self.reader.clone();
+        FileWatcherConfig {
+            unchanged,
+            own_writes,
+            is_empty,
+            shared,
+            ..self.map_parser(Arc::new(func), reader)
+        }
+    }
+
+    /// Emit `()` whenever the target changes, without reading or parsing it, for consumers that do their own IO
+    /// (i.e. re-exec, or read through a privileged helper). The first emission is right away, as watching starts.
+    /// Discards any parser and error hooks set so far.
+    pub fn with_notify_only(self) -> FileWatcherConfig<(), Infallible, ()> {
+        FileWatcherConfig {
+            parsed: None,
+            empty_files: EmptyFiles::Parse,
+            dependencies: None,
+            ..self.map_parser(Arc::new(Ok), Arc::new(|_, _, _| Box::pin(async { Ok(()) })))
+        }
+    }
+
+    /// Moves the settings that don't depend on the parser or what is read over to a config with `parser` and `reader`.
+    /// Error hooks, and whatever was set up for the previous read type, are dropped.
+    fn map_parser<T2, E2, I2>(
+        self,
+        parser: Arc<dyn Fn(I2) -> Result<T2, E2> + Send + Sync>,
+        reader: Reader<I2>,
+    ) -> FileWatcherConfig<T2, E2, I2> {
+        FileWatcherConfig {
+            log_name: self.log_name,
+            file: self.file,
+            parser,
+            retry_interval: self.retry_interval,
+            retry_backoff: self.retry_backoff,
+            read_retry: self.read_retry,
+            parse_retry: self.parse_retry,
+            max_retries: self.max_retries,
+            initial_timeout: self.initial_timeout,
+            slow_reload: self.slow_reload,
+            reader,
+            mode: self.mode,
+            unchanged: None,
+            parsed: self.parsed,
+            own_writes: None,
+            empty_files: self.empty_files,
+            is_empty: None,
+            optional: self.optional,
+            extra_watches: self.extra_watches,
+            dependencies: self.dependencies,
+            error_hooks: vec![],
+            shared: None,
+            source_changes: self.source_changes,
+            poll_interval: self.poll_interval,
+            backend: self.backend,
+            custom_backend: self.custom_backend,
+            shared_inotify: self.shared_inotify,
+            shared_task: self.shared_task,
+            shared_watch: self.shared_watch,
+            attribute_changes: self.attribute_changes,
+            event_filter: self.event_filter,
+            fallback_backends: self.fallback_backends,
+            lifecycle_hooks: self.lifecycle_hooks,
+            degraded_hooks: self.degraded_hooks,
+            degraded: vec![],
+            #[cfg(feature = "opentelemetry")]
+            reload: Default::default(),
+            self_test: self.self_test,
+            mount_changes: self.mount_changes,
+            write_quiescence: self.write_quiescence,
+            burst_window: self.burst_window,
+            min_emit_interval: self.min_emit_interval,
+            stat_interval: self.stat_interval,
+            verify_reads: self.verify_reads,
+            deletion_grace: self.deletion_grace,
+            max_symlink_depth: self.max_symlink_depth,
+            permission_policy: self.permission_policy,
+            ownership: self.ownership,
+            read_lock: self.read_lock,
+            follow: self.follow,
+            hardened_reads: self.hardened_reads,
+            stats: self.stats,
+            history: self.history,
+            labels: self.labels,
+            logger: self.logger,
+            #[cfg(feature = "tokio-util")]
+            cancel: self.cancel,
+        }
+    }
+
+    /// Set a parser that also reports additional files the output depends on (i.e. files pulled in by `include` directives).
+    /// These are watched alongside the target, and the set is replaced after every successful parse.
+    /// Relative paths are relative to the parent directory of the target.
+    pub fn with_dependency_parser<T2: Send + 'static, E2: Display + Send + 'static>(
+        self,
+        func: impl Fn(I) -> Result<(T2, Vec<PathBuf>), E2> + Send + Sync + 'static,
+    ) -> FileWatcherConfig<T2, E2, I> {
+        let dependencies = Arc::new(Mutex::new(vec![]));
+        let dependencies2 = dependencies.clone();
+        let mut out = self.with_parser(move |raw| {
+            let (parsed, dependencies) = func(raw)?;
+            *dependencies2.lock().unwrap() = dependencies;
+            Ok(parsed)
+        });
+        out.dependencies = Some(dependencies);
+        out
+    }
+
+    /// Apply environment variable overrides (i.e. `APP__SERVER__PORT=8080` with prefix `APP`) on top of every parsed value.
+    /// Nested keys are separated by `__` and matched case-insensitively, and values are parsed as JSON when possible.
+    /// The environment is read again on every emission.
+    #[cfg(feature = "merge")]
+    pub fn with_env_overlay(self, prefix: impl AsRef<str>) -> FileWatcherConfig<T, MergeError<E>, I>
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        let prefix = prefix.as_ref().to_string();
+        let parser = self.parser.clone();
+        self.with_parser(move |raw| {
+            let parsed = parser(raw).map_err(MergeError::Parse)?;
+            apply_env_overlay(&prefix, parsed).map_err(MergeError::Deserialize)
+        })
+    }
+
+    /// Compare the contents of the target every `poll_interval` instead of relying on filesystem events,
+    /// i.e. for filesystems that don't generate them. Paths under `/proc` and `/sys` are always polled, every retry interval by default.
+    /// Ignored if another backend is chosen with `with_backend`, except [`Backend::Notify`], which polls with notify's `PollWatcher` at this interval.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = Some(poll_interval);
+        self
+    }
+
+    /// Choose how changes are detected, rather than the best backend available for the target. See [`Backend`].
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Backends to fall back to in order if the chosen one can't be set up on this host, i.e. inotify blocked by seccomp
+    /// or out of instances, with polling as the last resort. Defaults to [`Backend::Notify`]. The backend in use is reported
+    /// to lifecycle hooks, so a single binary can be shipped to different hosts without a matrix of features.
+    pub fn with_fallback_backends(mut self, backends: impl IntoIterator<Item = Backend>) -> Self {
+        self.fallback_backends = backends.into_iter().collect();
+        self
+    }
+
+    /// Check that the filesystem holding the target delivers events before relying on them, by creating and removing a
+    /// hidden file next to the target (or in the target directory) and waiting up to `timeout` for the events. Some
+    /// filesystems (i.e. certain FUSE and overlay setups) accept inotify watches but never report changes, in which case
+    /// the target is polled instead, with a warning. Skipped if the directory isn't writable. Only applies to the inotify backend.
+    pub fn with_self_test(mut self, timeout: Duration) -> Self {
+        self.self_test = Some(timeout);
+        self
+    }
+
+    /// Watch the mount table (`/proc/self/mountinfo`) too, and reload when a mount along the path to the target changes,
+    /// i.e. a config bind-mounted over the target, or an overlayfs upper layer swapped, neither of which generate inotify
+    /// events on the inodes watched before. Only applies to the inotify backend.
+    pub fn with_mount_changes(mut self, mount_changes: bool) -> Self {
+        self.mount_changes = mount_changes;
+        self
+    }
+
+    /// Wait until no changes have been reported for `window` before reading, restarting the wait on every change, to
+    /// avoid reading a file a slow writer is still streaming out incrementally. Delays every reload by at least `window`.
+    pub fn with_write_quiescence(mut self, window: Duration) -> Self {
+        self.write_quiescence = Some(window);
+        self
+    }
+
+    /// Once a change is reported, wait `window` before reading, folding every change in the meantime into that read, so
+    /// bursts of changes (i.e. rsync of a whole directory) are read and parsed once rather than once per change. Unlike
+    /// [`FileWatcherConfig::with_write_quiescence`], changes don't restart the wait, bounding how late a reload can be.
+    pub fn with_burst_coalescing(mut self, window: Duration) -> Self {
+        self.burst_window = Some(window);
+        self
+    }
+
+    /// Emit a new target at most once per `interval`, i.e. when reloading downstream is disruptive. Changes in the meantime
+    /// are held back until the interval is over, and then only the latest is read and emitted.
+    pub fn with_min_emit_interval(mut self, interval: Duration) -> Self {
+        self.min_emit_interval = Some(interval);
+        self
+    }
+
+    /// Also stat the target every `interval`, and reload if its size, modification time, or inode changed without a
+    /// change being reported for a whole interval, i.e. events lost to a queue overflow or never generated for writes
+    /// from another NFS client. The target itself is only checked, not the files within a directory.
+    pub fn with_stat_check(mut self, interval: Duration) -> Self {
+        self.stat_interval = Some(interval);
+        self
+    }
+
+    /// Compare the target's size, modification time, and inode before and after every read, and read it again if they
+    /// changed in between, i.e. a writer modifying the file in place (rather than renaming a new file over it) was caught
+    /// half done. After a few attempts, the read fails and is retried after the retry interval.
+    pub fn with_read_verification(mut self, verify_reads: bool) -> Self {
+        self.verify_reads = verify_reads;
+        self
+    }
+
+    /// When the target disappears after having been read, wait up to `grace` for it to be recreated before logging and
+    /// reporting the failed read and retrying every retry interval, i.e. for writers that delete and then recreate it.
+    /// Nothing is emitted in the meantime, and the target is read again as soon as it reappears.
+    pub fn with_deletion_grace(mut self, grace: Duration) -> Self {
+        self.deletion_grace = Some(grace);
+        self
+    }
+
+    /// Keep files editors leave next to the one being edited, which directory and tree watches ignore by default: vim
+    /// swap files (`.swp`, `.swo`, `.swx`) and its `4913` write test, backups (`~`), and emacs lock (`.#`) and autosave
+    /// (`#...#`) files. Ignored files neither trigger a reload nor are read.
+    pub fn with_editor_artifacts(mut self, editor_artifacts: bool) -> Self {
+        match &mut self.mode {
+            WatchMode::Directory {
+                editor_artifacts: keep,
+            } => *keep = editor_artifacts,
+            WatchMode::Tree(options) => options.editor_artifacts = editor_artifacts,
+            _ => (),
+        }
+        self
+    }
+
+    /// Detect changes with a [`WatchBackend`] of your own, instead of any built-in backend.
+    pub fn with_custom_backend(mut self, backend: impl WatchBackend) -> Self {
+        self.custom_backend = Some(Arc::new(backend));
+        self
+    }
+
+    /// Register inotify watches with a single inotify instance shared by every watcher in the process that sets this,
+    /// rather than one instance (and file descriptor) per watcher. Watches of the same directory are shared too.
+    /// The instance is driven by the runtime of the first watcher to use it, and is started again on another if that runtime shuts down.
+    /// Only applies to the inotify backend.
+    pub fn with_shared_inotify(mut self, shared: bool) -> Self {
+        self.shared_inotify = shared;
+        self
+    }
+
+    /// Share the watch and reads of the target with the other watchers of the same file in this process that set this and
+    /// watch and read it with the same settings, each parsing the contents on its own. Cuts the events and reads of a
+    /// file parsed into several types. Only applies to `new`, and not along with settings that need a watch or read of
+    /// its own: following renames, hardened reads, dependencies, stat checks, event filters, permission and ownership
+    /// checks, read locks, stats, history, log level mapping, lifecycle hooks, or a custom backend.
+    pub fn with_shared_watch(mut self, shared: bool) -> Self {
+        self.shared_watch = shared;
+        self
+    }
+
+    /// Run the watcher, and the tasks of its backend, within a single task shared by every watcher in the process that
+    /// sets this, rather than a few tasks each, along with sharing an inotify instance as with
+    /// [`FileWatcherConfig::with_shared_inotify`]. Cuts the overhead of hundreds of watchers, which then don't run in
+    /// parallel with each other. The task is driven by the runtime of the first watcher to use it.
+    pub fn with_shared_task(mut self, shared: bool) -> Self {
+        self.shared_task = shared;
+        self.shared_inotify = shared;
+        self
+    }
+
+    /// Also reload when permissions, ownership, or other attributes of the target change (i.e. `chmod` and `chown`),
+    /// not only its contents. i.e. for secrets that only become readable once an init container fixes their permissions.
+    pub fn with_attribute_changes(mut self, attribute_changes: bool) -> Self {
+        self.attribute_changes = attribute_changes;
+        self
+    }
+
+    /// Only reload for changes `filter` returns true for, given what kind of change it was and the path it happened to.
+    /// i.e. ignore [`ChangeKind::Modified`] and only react to [`ChangeKind::ClosedWrite`] and [`ChangeKind::Renamed`], for writers that
+    /// close the file or rename it into place once done. Watches are kept up to date regardless, and changes that may hide
+    /// others (i.e. an inotify queue overflow) always reload. Not every backend can tell every kind apart, see [`ChangeKind`].
+    pub fn with_event_filter(
+        mut self,
+        filter: impl Fn(ChangeKind, &Path) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.event_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Limit how many symlinks are followed on the way to the target, including through its parent directories. Defaults
+    /// to 16. A longer chain, or a cycle, is reported to the error hooks as [`FileWatcherError::Symlink`] and retried.
+    pub fn with_max_symlink_depth(mut self, max_symlink_depth: usize) -> Self {
+        self.max_symlink_depth = max_symlink_depth;
+        self
+    }
+
+    /// Refuse to read the target while its permissions (after following symlinks) have any bits the policy forbids,
+    /// i.e. [`PermissionPolicy::NOT_WORLD_WRITABLE`] for credentials. Violations are reported to the error hooks as
+    /// [`FileWatcherError::Policy`] and retried like failed reads.
+    #[cfg(unix)]
+    pub fn with_permission_policy(mut self, policy: PermissionPolicy) -> Self {
+        self.permission_policy = Some(policy);
+        self
+    }
+
+    /// Refuse to read the target unless it (after following symlinks) is owned by the required user and group, i.e.
+    /// [`Ownership::current_user`] for secrets directories shared with other workloads. Violations are reported to the
+    /// error hooks as [`FileWatcherError::Policy`] and retried like failed reads.
+    #[cfg(unix)]
+    pub fn with_required_owner(mut self, ownership: Ownership) -> Self {
+        self.ownership = Some(ownership);
+        self
+    }
+
+    /// Take a shared advisory `lock` on the target before every read, holding it until the read is done, so writers
+    /// holding an exclusive one while updating the target in place are never read half done. Waits up to `timeout` for
+    /// writers to release it, after which the read fails with a timeout, and is retried like other failed reads.
+    /// In directory modes, the directory itself is locked.
+    #[cfg(unix)]
+    pub fn with_read_lock(mut self, lock: ReadLock, timeout: Duration) -> Self {
+        self.read_lock = Some((lock, timeout));
+        self
+    }
+
+    /// Set an alternative retry_interval
+    pub fn with_retry_interval(mut self, retry_interval: Duration) -> Self {
+        self.retry_interval = retry_interval;
+        self
+    }
+
+    /// Back off between attempts to read the target again after failing to, rather than retrying every retry interval,
+    /// i.e. `Backoff::exponential(Duration::from_secs(1), Duration::from_secs(60)).with_jitter(0.5)` so a persistently
+    /// broken target isn't retried in lockstep across a fleet. The backoff starts over after a successful read.
+    pub fn with_retry_backoff(mut self, backoff: Backoff) -> Self {
+        self.retry_backoff = Some(backoff);
+        self
+    }
+
+    /// When to try again after failing to read the target, i.e. `Retry::After(Backoff::fixed(Duration::from_secs(30)))`
+    /// to retry a missing file slowly, since it is also read as soon as it is created. Defaults to the retry backoff.
+    pub fn with_read_retry(mut self, retry: Retry) -> Self {
+        self.read_retry = Some(retry);
+        self
+    }
+
+    /// When to try again after failing to parse the target, i.e. `Retry::OnChange` to leave an invalid target alone until
+    /// it is written again, rather than parsing the same contents every retry interval. Defaults to the retry backoff.
+    pub fn with_parse_retry(mut self, retry: Retry) -> Self {
+        self.parse_retry = Some(retry);
+        self
+    }
+
+    /// Give up after the target failed to be read or parsed `retries` more times in a row after the first failure,
+    /// reporting [`FileWatcherError::GaveUp`] to error hooks and ending the stream, for callers that would rather fail fast
+    /// than keep retrying in the background.
+    pub fn with_max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = Some(retries);
+        self
+    }
+
+    /// Give up if the target couldn't be read and parsed within `timeout` of starting, reporting
+    /// [`FileWatcherError::InitialTimeout`] to error hooks and ending the stream before anything was emitted, i.e. so a
+    /// process with a broken config mount exits rather than waiting on it forever.
+    pub fn with_initial_timeout(mut self, timeout: Duration) -> Self {
+        self.initial_timeout = Some(timeout);
+        self
+    }
+
+    /// Warn whenever reading and parsing the target takes longer than `threshold`, i.e. as it slowly grows, and count it
+    /// in the `really_notify_slow_reloads_total` metric with the `metrics` feature. Reads of a watch shared with
+    /// [`FileWatcherConfig::with_shared_watch`] aren't timed, only parsing is.
+    pub fn with_slow_reload_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_reload = Some(threshold);
+        self
+    }
+
+    /// Observe errors (i.e. to surface them in metrics or health checks). May be called multiple times to add more hooks.
+    /// Set this after any `with_*parser`.
+    pub fn with_error_hook(
+        mut self,
+        hook: impl Fn(&FileWatcherError<E>) + Send + Sync + 'static,
+    ) -> Self {
+        self.error_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Call `hook` once the watcher has been unable to read and parse the target, or to establish a watch, for longer than
+    /// `threshold`, with the last error it failed with. Fires once per run of failures, ended by the next valid target, so
+    /// alerts can page on sustained breakage rather than every transient error. May be called multiple times, i.e. to
+    /// warn and page at different thresholds.
+    pub fn with_degraded_hook(
+        mut self,
+        threshold: Duration,
+        hook: impl Fn(&Degraded) + Send + Sync + 'static,
+    ) -> Self {
+        self.degraded_hooks.push((threshold, Arc::new(hook)));
+        self
+    }
+
+    /// Count reloads and failures of the target in `stats`, kept to query them from, i.e. for an admin endpoint, along with
+    /// the watches established for it.
+    pub fn with_stats(mut self, stats: &WatcherStats) -> Self {
+        self.stats = Some(stats.clone());
+        self
+    }
+
+    /// Label the watcher's metrics, its `tracing` span, and its OpenTelemetry spans and metrics with `key` set to `value`,
+    /// i.e. the team owning the target or the tenant it configures, alongside its log name as `watcher`. May be called
+    /// multiple times to add more labels.
+    pub fn with_label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.push((key.into(), value.into()));
+        self
+    }
+
+    /// Log the messages of the watcher about the target, i.e. failing to read or parse it, with `target` rather than
+    /// `really_notify`, to route or filter them apart from those of other watchers. With the `tracing` feature, whose
+    /// event targets are fixed, the `watcher` span tells them apart instead.
+    pub fn with_log_target(mut self, target: impl Into<String>) -> Self {
+        self.logger.target = Some(target.into());
+        self
+    }
+
+    /// Log the messages of the watcher about the target at the level `levels` maps them to, or drop them if it returns
+    /// `None`. It is handed the level a message would be logged at, and for messages about a failure, how many times in
+    /// a row it happened, or 0 otherwise, i.e. to log the first failure to read the target as a warning and the retries
+    /// after it as debug messages, rather than errors tripping alerts during an expected startup race.
+    pub fn with_log_levels(
+        mut self,
+        levels: impl Fn(Level, u32) -> Option<Level> + Send + Sync + 'static,
+    ) -> Self {
+        self.logger.levels = Some(Arc::new(levels) as LevelMap);
+        self
+    }
+
+    /// Record the changes reported for the target, whether they triggered a reload, and how each reload went in `history`,
+    /// kept to tell from after the fact why the target did or didn't reload.
+    pub fn with_history(mut self, history: &EventHistory) -> Self {
+        self.history = Some(history.clone());
+        self
+    }
+
+    /// Observe what happens to the watcher, i.e. which backend ended up watching the target. May be called multiple times to add more hooks.
+    pub fn with_lifecycle_hook(
+        mut self,
+        hook: impl Fn(&LifecycleEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.lifecycle_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Run the watcher. Dropping/closing this receiver will cause an immediate cleanup.
+    /// If it panics, i.e. in the parser, it is restarted after a backoff, reading and emitting the target again.
+    pub fn start(self) -> mpsc::Receiver<T> {
+        let shared_task = self.shared_task;
+        let (watcher, receiver) = self.run();
+        if shared_task {
+            rt::spawn_shared(watcher);
+        } else {
+            rt::spawn(watcher);
+        }
+        receiver
+    }
+
+    /// Like [`FileWatcherConfig::start`], but spawns the watcher, and so its backends, on the runtime of `handle` rather
+    /// than the current one, i.e. a dedicated IO runtime. Doesn't need to be called from within a runtime.
+    #[cfg(not(any(
+        feature = "async-std",
+        feature = "smol",
+        all(feature = "wasm", target_family = "wasm", target_os = "unknown")
+    )))]
+    pub fn start_on(self, handle: &tokio::runtime::Handle) -> mpsc::Receiver<T> {
+        let _runtime = handle.enter();
+        self.start()
+    }
+
+    /// Like [`FileWatcherConfig::start`], but runs the watcher on a `really-notify-runtime` thread hosting a current-thread
+    /// runtime, shared by every watcher started this way and created on first use, so applications without tokio can
+    /// watch too. Receive updates with [`mpsc::Receiver::blocking_recv`]. Doesn't survive a fork.
+    #[cfg(not(any(feature = "async-std", feature = "smol", target_family = "wasm")))]
+    pub fn start_detached(self) -> mpsc::Receiver<T> {
+        self.start_on(rt::dedicated())
+    }
+
+    /// Like [`FileWatcherConfig::start`], but spawns the watcher into `tasks`, where it completes once stopped. The tasks
+    /// of its backend are stopped along with it, without being in `tasks` themselves.
+    #[cfg(not(any(
+        feature = "async-std",
+        feature = "smol",
+        all(feature = "wasm", target_family = "wasm", target_os = "unknown")
+    )))]
+    pub fn start_in(self, tasks: &mut tokio::task::JoinSet<()>) -> mpsc::Receiver<T> {
+        let (watcher, receiver) = self.run();
+        tasks.spawn(watcher);
+        receiver
+    }
+
+    /// Like [`FileWatcherConfig::start`], but tracks the watcher in `tracker`, along with every task it spawns, i.e. to
+    /// wait for all of them to finish with [`tokio_util::task::TaskTracker::wait`] before exiting.
+    #[cfg(feature = "tokio-util")]
+    pub fn start_tracked(self, tracker: &tokio_util::task::TaskTracker) -> mpsc::Receiver<T> {
+        let shared_task = self.shared_task;
+        let (watcher, receiver) = self.run();
+        let watcher = rt::tracked(tracker.clone(), watcher);
+        if shared_task {
+            rt::spawn_shared(watcher);
+        } else {
+            rt::spawn(watcher);
+        }
+        receiver
+    }
+
+    /// Like [`FileWatcherConfig::start`], but returns the watcher for the caller to drive wherever they like, i.e. on a
+    /// `LocalSet` or in a `select!` in main, rather than spawning it. It stops once either it or the receiver is dropped.
+    /// Backends still spawn their event loops on the current runtime.
+    pub fn run(self) -> (impl Future<Output = ()> + Send + 'static, mpsc::Receiver<T>) {
+        let (sender, receiver) = mpsc::channel(3);
+        (self.supervise(sender, |x| x), receiver)
+    }
+
+    /// Stop the watcher once `token` is cancelled, closing its channel, like dropping the receiver would.
+    #[cfg(feature = "tokio-util")]
+    pub fn with_cancellation(mut self, token: tokio_util::sync::CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Runs the watcher, within a `watcher` span with the `tracing` feature, which tasks it spawns inherit.
+    async fn supervise<U: Send + 'static>(
+        self,
+        sender: mpsc::Sender<U>,
+        map: impl Fn(T) -> U + Clone + Send + Sync + 'static,
+    ) {
+        #[cfg(feature = "metrics")]
+        let _active = metrics::Active::new(&self.log_name, &self.labels);
+        let _running = self.stats.as_ref().map(WatcherStats::running);
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::info_span!(
+                "watcher",
+                watcher = %self.log_name,
+                path = %self.file.display(),
+                labels = tracing::field::Empty,
+            );
+            if !self.labels.is_empty() {
+                let labels: Vec<String> = self
+                    .labels
+                    .iter()
+                    .map(|(k, v)| format!("{k}={v}"))
+                    .collect();
+                span.record("labels", labels.join(","));
+            }
+            tracing::Instrument::instrument(self.until_cancelled(sender, map), span).await
+        }
+        #[cfg(not(feature = "tracing"))]
+        self.until_cancelled(sender, map).await
+    }
+
+    /// Runs `restart_on_panic` until cancelled.
+    async fn until_cancelled<U: Send + 'static>(
+        self,
+        sender: mpsc::Sender<U>,
+        map: impl Fn(T) -> U + Clone + Send + Sync + 'static,
+    ) {
+        #[cfg(feature = "tokio-util")]
+        if let Some(cancel) = self.cancel.clone() {
+            let (log_name, file, logger) = (
+                self.log_name.clone(),
+                self.file.clone(),
+                self.logger.clone(),
+            );
+            select! {
+                _ = self.restart_on_panic(sender, map) => (),
+                _ = cancel.cancelled() => logger.log(Level::Info, 0, format_args!("stopped watching {log_name} '{}', cancelled", file.display())),
+            }
+            return;
+        }
+        self.restart_on_panic(sender, map).await
+    }
+
+    /// Like `read_loop`, restarting it after a backoff whenever it panics, and reporting that it did.
+    async fn restart_on_panic<U: Send + 'static>(
+        self,
+        sender: mpsc::Sender<U>,
+        map: impl Fn(T) -> U + Clone + Send + Sync + 'static,
+    ) {
+        let mut config = self;
+        config.degraded = config
+            .degraded_hooks
+            .iter()
+            .map(|(threshold, hook)| {
+                Arc::new(DegradedHook::new(
+                    *threshold,
+                    config.retry_interval,
+                    absolute(&config.file),
+                    config.log_name.clone(),
+                    config.logger.clone(),
+                    hook.clone(),
+                ))
+            })
+            .collect();
+        let config = Arc::new(config);
+        let mut failures = 0;
+        loop {
+            let started = Instant::now();
+            let run = config.read_loop(sender.clone(), map.clone());
+            let Err(panic) = AssertUnwindSafe(run).catch_unwind().await else {
+                return;
+            };
+            let delay = restart_delay(config.retry_interval, &mut failures, started);
+            let e = FileWatcherError::Crashed(format!(
+                "read loop panicked: {}",
+                panic_message(&*panic)
+            ));
+            config.logger.log(
+                Level::Error,
+                failures,
+                format_args!(
+                    "{} {e} in {:.1} second(s) @ '{}'",
+                    config.log_name,
+                    delay.as_secs_f64(),
+                    config.file.display()
+                ),
+            );
+            config.report(&e);
+            select! {
+                _ = rt::sleep(delay) => (),
+                _ = sender.closed() => return,
+            }
+        }
+    }
+
+    /// Emits each update through `map` into `sender`, until `sender` is closed.
+    async fn read_loop<U: Send + 'static>(
+        &self,
+        sender: mpsc::Sender<U>,
+        map: impl Fn(T) -> U + Send + 'static,
+    ) {
+        if let Some(shared) = self.shared_watch() {
+            return self.run_shared(shared, sender, map).await;
+        }
+        let read_stat = Arc::new(Mutex::new(None));
+        let initial = self.read_initial(&read_stat);
+        let target = match self.initial_timeout {
+            Some(timeout) => match rt::timeout(timeout, initial).await {
+                Some(target) => target,
+                None => {
+                    self.times_out(timeout);
+                    None
+                }
+            },
+            None => initial.await,
+        };
+        let Some(target) = target else {
+            return;
+        };
+        let mut failures = 0;
+        if !self.emit(&sender, map(target)).await {
+            return;
+        }
+        let mut last_emit = Instant::now();
+        let notify = Arc::new(Notify::new());
+        let mut _backends = vec![];
+        let _source_changes = self
+            .source_changes
+            .as_ref()
+            .map(|changes| forward_changes(changes.clone(), notify.clone()));
+        // started again once the target is followed to a new name
+        let mut _target = self.watch_target(&notify, &read_stat).await;
+        for (file, mode) in &self.extra_watches {
+            _backends.push(self.watch(file, mode.clone(), true, &notify).await);
+        }
+        let mut dependency_backends = HashMap::new();
+        self.update_dependencies(&mut dependency_backends, &notify)
+            .await;
+        loop {
+            select! {
+                _ = notify.notified() => {
+                    coalesce(&notify, self.burst_window).await;
+                    quiesce(&notify, self.write_quiescence).await;
+                    if self.throttle(Some(last_emit)).await {
+                        // the read that follows covers them
+                        discard_pending(&notify);
+                    }
+                    let mut creation = None;
+                    // when the grace period for a deleted target to be recreated in ends
+                    let mut deadline = None;
+                    let target = loop {
+                        self.note_read(&read_stat).await;
+                        match self.read_target(false).await {
+                            Ok(x) => {
+                                failures = 0;
+                                self.recovered();
+                                break x;
+                            }
+                            Err(e) => {
+                                if is_missing(&e) && self.follow_rename().await {
+                                    _target = self.watch_target(&notify, &read_stat).await;
+                                    continue;
+                                }
+                                if let Some(left) = is_missing(&e).then(|| grace_left(self.deletion_grace, &mut deadline)).flatten() {
+                                    self.logger.log(Level::Info, 0, format_args!("{} '{}' is gone, waiting up to {:.1} second(s) for it to be recreated", self.log_name, self.target().display(), left.as_secs_f64()));
+                                    self.wait_to_retry(true, Some(left), &mut creation).await;
+                                    continue;
+                                }
+                                failures += 1;
+                                let Some(delay) = self.retry_for(&e).delay(failures) else {
+                                    self.logger.log(Level::Error, failures, format_args!("failed to read {} update: {e} @ {}, waiting for next change", self.log_name, self.target().display()));
+                                    self.report(&e);
+                                    if self.gives_up(failures) {
+                                        return;
+                                    }
+                                    break None;
+                                };
+                                self.logger.log(Level::Error, failures, format_args!("failed to read {} update: {e} @ {}, retrying in {:.1} second(s)", self.log_name, self.target().display(), delay.as_secs_f64()));
+                                self.report(&e);
+                                if self.gives_up(failures) {
+                                    return;
+                                }
+                                self.wait_to_retry(is_missing(&e), Some(delay), &mut creation).await;
+                                // toss out any pending notification, since we will already try again
+                                let notify = notify.notified();
+                                futures::pin_mut!(notify);
+                                notify.enable();
+                            }
+                        }
+                    };
+                    let Some(target) = target else {
+                        continue;
+                    };
+                    self.update_dependencies(&mut dependency_backends, &notify).await;
+                    if !self.emit(&sender, map(target)).await {
+                        return;
+                    }
+                    last_emit = Instant::now();
+                },
+                _ = sender.closed() => {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Waits out `interval` after a failed read, or less if the target was `missing` and is created in the meantime, or
+    /// until it changes without an interval. It is watched from the nearest existing ancestor down, with the watch kept in
+    /// `creation` until dropped.
+    async fn wait_to_retry(
+        &self,
+        missing: bool,
+        interval: Option<Duration>,
+        creation: &mut Option<(BackendHandle, Arc<Notify>)>,
+    ) {
+        if (missing || interval.is_none()) && creation.is_none() && self.source_changes.is_none() {
+            let notify = Arc::new(Notify::new());
+            let backend = self
+                .watch(&self.target(), self.mode.clone(), true, &notify)
+                .await;
+            // created before the watch was in place
+            if missing && fs::try_exists(&self.target()).await.unwrap_or(false) {
+                notify.notify_one();
+            }
+            *creation = Some((backend, notify));
+        }
+        match creation {
+            Some((_, notify)) => {
+                select! {
+                    _ = rt::sleep(interval.unwrap_or_default()), if interval.is_some() => (),
+                    _ = notify.notified() => (),
+                }
+            }
+            // there's nothing to tell us about changes
+            None => rt::sleep(interval.unwrap_or(self.retry_interval)).await,
+        }
+    }
+
+    /// Reads the target until it parses, or `None` once the watcher gives up on it.
+    async fn read_initial(&self, read_stat: &Mutex<Option<fs::Stat>>) -> Option<T> {
+        let mut creation = None;
+        let mut failures = 0;
+        loop {
+            self.note_read(read_stat).await;
+            match self.read_target(true).await {
+                Ok(Some(x)) => {
+                    self.recovered();
+                    return Some(x);
+                }
+                // nothing to compare against yet, so only an empty target that is skipped, and not written yet
+                Ok(None) => {
+                    self.wait_to_retry(false, Some(self.retry_interval), &mut creation)
+                        .await
+                }
+                Err(e) => {
+                    failures += 1;
+                    let delay = self.retry_for(&e).delay(failures);
+                    match delay {
+                        Some(delay) => self.logger.log(
+                            Level::Error,
+                            failures,
+                            format_args!(
+                                "failed to read initial {}: {e} @ '{}', retrying in {:.1} second(s)",
+                                self.log_name,
+                                self.target().display(),
+                                delay.as_secs_f64(),
+                            ),
+                        ),
+                        None => self.logger.log(
+                            Level::Error,
+                            failures,
+                            format_args!(
+                                "failed to read initial {}: {e} @ '{}', retrying once it changes",
+                                self.log_name,
+                                self.target().display(),
+                            ),
+                        ),
+                    }
+                    self.report(&e);
+                    if self.gives_up(failures) {
+                        return None;
+                    }
+                    self.wait_to_retry(is_missing(&e), delay, &mut creation)
+                        .await;
+                }
+            }
+        }
+    }
+
+    /// How the contents of a watch shared with other watchers of the same file are converted into the parser input, unless
+    /// reads aren't shared, or need a watch or read of their own for a setting the shared watch doesn't apply.
+    fn shared_watch(&self) -> Option<Shared<I>> {
+        if !self.shared_watch
+            || self.follow.is_some()
+            || self.hardened_reads
+            || self.dependencies.is_some()
+            || self.stat_interval.is_some()
+            || self.event_filter.is_some()
+            || self.permission_policy.is_some()
+            || self.ownership.is_some()
+            || self.read_lock.is_some()
+            || self.stats.is_some()
+            || self.history.is_some()
+            || self.logger.levels.is_some()
+            || !self.lifecycle_hooks.is_empty()
+            || self.custom_backend.is_some()
+        {
+            return None;
+        }
+        self.shared.clone()
+    }
+
+    /// Like `read_loop`, but parses reads of a watch shared with other watchers of the same file.
+    async fn run_shared<U: Send + 'static>(
+        &self,
+        shared: Shared<I>,
+        sender: mpsc::Sender<U>,
+        map: impl Fn(T) -> U + Send + 'static,
+    ) {
+        let mut receiver = shared::subscribe(self.context(
+            &self.file,
+            WatchMode::File,
+            false,
+            &Arc::new(Notify::new()),
+        ));
+        let mut last_emit = None;
+        let mut failures = 0;
+        let started = Instant::now();
+        loop {
+            self.throttle(last_emit).await;
+            let read = receiver.borrow_and_update().clone();
+            // the same contents are parsed again after this long, as with a parse retry set
+            let mut retry = None;
+            match read {
+                // not read yet
+                None => (),
+                // already logged by the shared watch
+                Some(Err(e)) => {
+                    failures += 1;
+                    self.report(&FileWatcherError::Io(io::Error::new(
+                        e.kind(),
+                        e.to_string(),
+                    )));
+                }
+                Some(Ok(raw)) => match self
+                    .attempt(last_emit.is_none(), async {
+                        phase("parse", async {
+                            self.parse(shared((*raw).clone()), last_emit.is_none())
+                        })
+                        .await
+                    })
+                    .await
+                {
+                    Ok(Some(target)) => {
+                        failures = 0;
+                        self.recovered();
+                        if !self.emit(&sender, map(target)).await {
+                            return;
+                        }
+                        last_emit = Some(Instant::now());
+                    }
+                    Ok(None) => {
+                        failures = 0;
+                        self.recovered();
+                    }
+                    Err(e) => {
+                        failures += 1;
+                        retry = self.retry_for(&e).delay(failures);
+                        match retry {
+                            Some(delay) => self.logger.log(
+                                Level::Error,
+                                failures,
+                                format_args!(
+                                    "failed to parse {} update: {e} @ '{}', retrying in {:.1} second(s)",
+                                    self.log_name,
+                                    self.file.display(),
+                                    delay.as_secs_f64()
+                                ),
+                            ),
+                            None => self.logger.log(
+                                Level::Error,
+                                failures,
+                                format_args!(
+                                    "failed to parse {} update: {e} @ '{}', waiting for next change",
+                                    self.log_name,
+                                    self.file.display()
+                                ),
+                            ),
+                        }
+                        self.report(&e);
+                    }
+                },
+            }
+            if self.gives_up(failures) {
+                return;
+            }
+            select! {
+                changed = receiver.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                },
+                _ = rt::sleep(retry.unwrap_or_default()), if retry.is_some() => (),
+                _ = rt::sleep_until(started + self.initial_timeout.unwrap_or_default()), if last_emit.is_none() && self.initial_timeout.is_some() => {
+                    self.times_out(self.initial_timeout.unwrap());
+                    return;
+                },
+                _ = sender.closed() => {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Watches the target where it is now, along with checking its metadata if set.
+    async fn watch_target(
+        &self,
+        notify: &Arc<Notify>,
+        read_stat: &Arc<Mutex<Option<fs::Stat>>>,
+    ) -> Vec<BackendHandle> {
+        let mut out = vec![];
+        if self.source_changes.is_none() {
+            out.push(
+                self.watch(&self.target(), self.mode.clone(), self.optional, notify)
+                    .await,
+            );
+        }
+        if let Some(interval) = self.stat_interval {
+            out.push(start_stat_check(
+                self.context(&self.target(), self.mode.clone(), self.optional, notify),
+                interval,
+                read_stat.clone(),
+            ));
+        }
+        out
+    }
+
+    async fn watch(
+        &self,
+        file: &Path,
+        mode: WatchMode,
+        optional: bool,
+        notify: &Arc<Notify>,
+    ) -> BackendHandle {
+        let hooks = self.error_hooks.clone();
+        let degraded = self.degraded.clone();
+        start_backend::<E>(
+            self.context(file, mode, optional, notify),
+            Arc::new(move |e| {
+                hooks.iter().for_each(|hook| hook(e));
+                degraded.iter().for_each(|x| x.failed(e, false));
+            }),
+        )
+        .await
+    }
+
+    /// What a backend needs to watch `file` on behalf of this watcher.
+    fn context(
+        &self,
+        file: &Path,
+        mode: WatchMode,
+        optional: bool,
+        notify: &Arc<Notify>,
+    ) -> WatcherContext {
+        WatcherContext {
+            file: absolute(file),
+            log_name: self.log_name.clone(),
+            retry_interval: self.retry_interval,
+            read_retry: self.read_retry(),
+            notify: notify.clone(),
+            mode,
+            optional,
+            poll_interval: self.poll_interval,
+            backend: self.backend,
+            custom: self.custom_backend.clone(),
+            shared_inotify: self.shared_inotify,
+            attribute_changes: self.attribute_changes,
+            event_filter: self.event_filter.clone(),
+            fallback_backends: self.fallback_backends.clone(),
+            lifecycle_hooks: self.lifecycle_hooks.clone(),
+            self_test: self.self_test,
+            mount_changes: self.mount_changes,
+            write_quiescence: self.write_quiescence,
+            burst_window: self.burst_window,
+            verify_reads: self.verify_reads,
+            deletion_grace: self.deletion_grace,
+            max_symlink_depth: self.max_symlink_depth,
+            _running: None,
+            stats: self.stats.clone(),
+            watch_set: None,
+            history: self.history.clone(),
+            logger: self.logger.clone(),
+        }
+    }
+
+    /// Whether `failures` in a row exhaust the retry budget, reporting that the watcher gives up if so.
+    fn gives_up(&self, failures: u32) -> bool {
+        if self.max_retries.is_none_or(|max| failures <= max) {
+            return false;
+        }
+        self.logger.log(
+            Level::Error,
+            failures,
+            format_args!(
+                "giving up on {} after {failures} failed attempt(s) @ '{}'",
+                self.log_name,
+                self.target().display()
+            ),
+        );
+        self.report(&FileWatcherError::GaveUp(failures));
+        true
+    }
+
+    /// Reports giving up on the first valid target after `timeout`.
+    fn times_out(&self, timeout: Duration) {
+        self.logger.log(
+            Level::Error,
+            0,
+            format_args!(
+                "giving up on {}, which couldn't be read within {:.1} second(s) @ '{}'",
+                self.log_name,
+                timeout.as_secs_f64(),
+                self.target().display()
+            ),
+        );
+        self.report(&FileWatcherError::InitialTimeout(timeout));
+    }
+
+    /// When to read the target again after failing to read it.
+    fn read_retry(&self) -> Retry {
+        self.read_retry.unwrap_or(Retry::After(
+            self.retry_backoff
+                .unwrap_or(Backoff::fixed(self.retry_interval)),
+        ))
+    }
+
+    /// When to read the target again after failing with `e`.
+    fn retry_for(&self, e: &FileWatcherError<E>) -> Retry {
+        match (e, self.parse_retry) {
+            (FileWatcherError::Parse(_), Some(retry)) => retry,
+            _ => self.read_retry(),
+        }
+    }
+
+    /// Notes the target's metadata before it is read, for the stat check to compare against.
+    async fn note_read(&self, read_stat: &Mutex<Option<fs::Stat>>) {
+        if self.stat_interval.is_some() {
+            *read_stat.lock().unwrap() =
+                backend::stat(&self.target(), self.attribute_changes).await;
+        }
+    }
+
+    /// Waits out the rest of the minimum emit interval since `last_emit`, returning whether it had to.
+    async fn throttle(&self, last_emit: Option<Instant>) -> bool {
+        let Some(wait) = self
+            .min_emit_interval
+            .zip(last_emit)
+            .map(|(interval, last_emit)| {
+                (last_emit + interval).saturating_duration_since(Instant::now())
+            })
+            .filter(|x| !x.is_zero())
+        else {
+            return false;
+        };
+        self.logger.log(
+            Level::Debug,
+            0,
+            format_args!(
+                "holding back {} update for {:.1} second(s) @ '{}'",
+                self.log_name,
+                wait.as_secs_f64(),
+                self.target().display()
+            ),
+        );
+        rt::sleep(wait).await;
+        true
+    }
+
+    fn report(&self, e: &FileWatcherError<E>) {
+        // rather than the watcher giving up, or failing to watch
+        let failed_reload = matches!(
+            e,
+            FileWatcherError::Io(_)
+                | FileWatcherError::Symlink(_)
+                | FileWatcherError::Path(_)
+                | FileWatcherError::Policy(_)
+                | FileWatcherError::Parse(_)
+        );
+        if failed_reload {
+            #[cfg(feature = "metrics")]
+            metrics::failed(&self.log_name, &self.labels);
+            if let Some(stats) = &self.stats {
+                stats.failed(e.to_string());
+            }
+        }
+        for hook in &self.error_hooks {
+            hook(e);
+        }
+        for degraded in &self.degraded {
+            degraded.failed(e, true);
+        }
+    }
+
+    /// Ends the run of failures degraded hooks are timing, after a valid target.
+    fn recovered(&self) {
+        if let Some(stats) = &self.stats {
+            stats.recovered();
+        }
+        for degraded in &self.degraded {
+            degraded.recovered();
+        }
+    }
+
+    /// Start and stop watching dependencies reported by the parser to match the latest set.
+    async fn update_dependencies(
+        &self,
+        backends: &mut HashMap<PathBuf, BackendHandle>,
+        notify: &Arc<Notify>,
+    ) {
+        let Some(dependencies) = &self.dependencies else {
+            return;
+        };
+        let base = self.file.parent().unwrap_or(Path::new(""));
+        let dependencies: HashSet<PathBuf> = dependencies
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|x| base.join(x))
+            .collect();
+        backends.retain(|file, _| dependencies.contains(file));
+        for file in dependencies {
+            if let Entry::Vacant(entry) = backends.entry(file) {
+                self.logger.log(
+                    Level::Info,
+                    0,
+                    format_args!(
+                        "watching {} dependency '{}'",
+                        self.log_name,
+                        entry.key().display()
+                    ),
+                );
+                let backend = self.watch(entry.key(), WatchMode::File, true, notify).await;
+                entry.insert(backend);
+            }
+        }
+    }
+
+    /// Returns `None` if the input was unchanged since the last read, or skipped as empty, and should not be emitted.
+    /// Reads and parses the target, which is the `initial` read when nothing was emitted yet.
+    async fn read_target(&self, initial: bool) -> Result<Option<T>, FileWatcherError<E>> {
+        self.attempt(initial, self.read_and_parse(initial)).await
+    }
+
+    /// Runs `attempt`, which reads or parses the target, as a reload attempt, traced with the `opentelemetry` feature and
+    /// recorded in the history. Attempts that parse to an update end once it is emitted.
+    async fn attempt(
+        &self,
+        initial: bool,
+        attempt: impl Future<Output = Result<Option<T>, FileWatcherError<E>>>,
+    ) -> Result<Option<T>, FileWatcherError<E>> {
+        if let Some(history) = &self.history {
+            history.record(&self.log_name, HistoryEvent::Reload { initial });
+        }
+        let started = Instant::now();
+        #[cfg(feature = "opentelemetry")]
+        let out = {
+            let reload = otel::Reload::start(&self.log_name, &self.labels, &self.target(), initial);
+            let out =
+                opentelemetry::context::FutureExt::with_context(attempt, reload.context()).await;
+            match &out {
+                Ok(Some(_)) => *self.reload.lock().unwrap() = Some(reload),
+                Ok(None) => reload.finish("unchanged", None),
+                Err(e) => reload.finish("failure", Some(e)),
+            }
+            out
+        };
+        #[cfg(not(feature = "opentelemetry"))]
+        let out = attempt.await;
+        self.timed(started.elapsed());
+        if let Some(history) = &self.history {
+            let event = match &out {
+                Ok(Some(_)) => HistoryEvent::Reloaded,
+                Ok(None) => HistoryEvent::Unchanged,
+                Err(e) => HistoryEvent::Failed {
+                    error: e.to_string(),
+                },
+            };
+            history.record(&self.log_name, event);
+        }
+        out
+    }
+
+    /// Records that reading and parsing the target took `duration`, warning if it was slow.
+    fn timed(&self, duration: Duration) {
+        let slow = self
+            .slow_reload
+            .is_some_and(|threshold| duration > threshold);
+        if slow {
+            self.logger.log(
+                Level::Warn,
+                0,
+                format_args!(
+                    "reading and parsing {} '{}' took {:.3} second(s), over the {:.3} second(s) threshold",
+                    self.log_name,
+                    self.target().display(),
+                    duration.as_secs_f64(),
+                    self.slow_reload.unwrap_or_default().as_secs_f64()
+                ),
+            );
+        }
+        if let Some(stats) = &self.stats {
+            stats.timed(duration);
+        }
+        #[cfg(feature = "metrics")]
+        metrics::reload_duration(&self.log_name, &self.labels, duration, slow);
+    }
+
+    /// Hands `target` to the receiver, ending the reload attempt it came from, and returns whether the receiver is still
+    /// listening.
+    async fn emit<U>(&self, sender: &mpsc::Sender<U>, target: U) -> bool {
+        #[cfg(feature = "opentelemetry")]
+        {
+            let reload = self.reload.lock().unwrap().take();
+            if let Some(reload) = reload {
+                let sent = opentelemetry::context::FutureExt::with_context(
+                    phase("emit", sender.send(target)),
+                    reload.context(),
+                )
+                .await;
+                reload.finish("success", None);
+                return sent.is_ok();
+            }
+        }
+        sender.send(target).await.is_ok()
+    }
+
+    async fn read_and_parse(&self, initial: bool) -> Result<Option<T>, FileWatcherError<E>> {
+        let file = self.target();
+        self.logger.log(
+            Level::Info,
+            0,
+            format_args!("reading updated {} '{}'", self.log_name, file.display()),
+        );
+        #[cfg(unix)]
+        policy::check(
+            &file,
+            self.permission_policy.as_ref(),
+            self.ownership.as_ref(),
+        )
+        .await?;
+        #[cfg(unix)]
+        let _lock = match self.read_lock {
+            Some((lock, timeout)) => Some(lock::lock_shared(&file, lock, timeout).await?),
+            None => None,
+        };
+        let context = ReadContext {
+            logger: self.logger.clone(),
+            hardened: self.hardened_reads.then_some(self.max_symlink_depth),
+        };
+        let read = || (self.reader)(file.clone(), self.mode.clone(), context.clone());
+        #[cfg(feature = "tracing")]
+        let started = Instant::now();
+        let raw = phase("read", async {
+            if self.verify_reads {
+                fs::read_verified(&file, read).await
+            } else {
+                read().await
+            }
+        })
+        .await?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(path = %file.display(), duration = ?started.elapsed(), "read target");
+        if let Some(follow) = &self.follow {
+            // a rename in between leaves the identity of the file read before
+            if let Ok(metadata) = fs::metadata(&file).await {
+                follow.lock().unwrap().identity = fs::identity(&metadata);
+            }
+        }
+        phase("parse", async { self.parse(raw, initial) }).await
+    }
+
+    /// Where the target is now, which is `file` unless it was followed across a rename.
+    fn target(&self) -> PathBuf {
+        match &self.follow {
+            Some(follow) => follow.lock().unwrap().path.clone(),
+            None => self.file.clone(),
+        }
+    }
+
+    /// Looks for the file last read in the directory of the missing target, and if it was renamed within it, follows it
+    /// to its new name, returning whether it did.
+    async fn follow_rename(&self) -> bool {
+        let Some(follow) = &self.follow else {
+            return false;
+        };
+        let (from, identity) = {
+            let follow = follow.lock().unwrap();
+            (follow.path.clone(), follow.identity)
+        };
+        let (Some(identity), Some(dir)) = (identity, from.parent()) else {
+            return false;
+        };
+        let Ok(mut entries) = fs::read_dir(dir).await else {
+            return false;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let to = entry.path();
+            if to == from
+                || !fs::metadata(&to)
+                    .await
+                    .is_ok_and(|x| fs::identity(&x) == Some(identity))
+            {
+                continue;
+            }
+            self.logger.log(
+                Level::Info,
+                0,
+                format_args!(
+                    "{} '{}' was renamed to '{}', following it",
+                    self.log_name,
+                    from.display(),
+                    to.display()
+                ),
+            );
+            follow.lock().unwrap().path = to.clone();
+            let event = LifecycleEvent::PathChanged { from, to };
+            self.lifecycle_hooks.iter().for_each(|hook| hook(&event));
+            return true;
+        }
+        false
+    }
+
+    /// Returns `None` if the input was unchanged since the last parse, written by this process after the `initial` read,
+    /// or skipped as empty, and should not be emitted.
+    fn parse(&self, raw: I, initial: bool) -> Result<Option<T>, FileWatcherError<E>> {
+        #[cfg(any(feature = "tracing", feature = "metrics"))]
+        let started = Instant::now();
+        let out = self.parse_input(raw, initial);
+        if let (Some(stats), Ok(Some(_))) = (&self.stats, &out) {
+            stats.reloaded();
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            duration = ?started.elapsed(),
+            parsed = out.is_ok(),
+            emitted = matches!(out, Ok(Some(_))),
+            "parsed target"
+        );
+        #[cfg(feature = "metrics")]
+        metrics::parsed(
+            &self.log_name,
+            &self.labels,
+            started.elapsed(),
+            matches!(out, Ok(Some(_))),
+        );
+        out
+    }
+
+    fn parse_input(&self, raw: I, initial: bool) -> Result<Option<T>, FileWatcherError<E>> {
+        if self
+            .is_empty
+            .as_ref()
+            .is_some_and(|is_empty| is_empty(&raw))
+        {
+            match self.empty_files {
+                EmptyFiles::Parse => (),
+                EmptyFiles::Wait => {
+                    self.logger.log(
+                        Level::Info,
+                        0,
+                        format_args!(
+                            "{} '{}' is empty, waiting for it to be written",
+                            self.log_name,
+                            self.target().display()
+                        ),
+                    );
+                    return Ok(None);
+                }
+                EmptyFiles::Report => {
+                    let event = LifecycleEvent::Empty {
+                        path: self.target(),
+                    };
+                    self.lifecycle_hooks.iter().for_each(|hook| hook(&event));
+                    return Ok(None);
+                }
+            }
+        }
+        if let Some(unchanged) = &self.unchanged {
+            if unchanged(&raw) {
+                return Ok(None);
+            }
+        }
+        if !initial && self.own_writes.as_ref().is_some_and(|own| own(&raw)) {
+            self.logger.log(
+                Level::Info,
+                0,
+                format_args!(
+                    "{} '{}' was written by this process, not reloading",
+                    self.log_name,
+                    self.target().display()
+                ),
+            );
+            return Ok(None);
+        }
+        let out = (self.parser)(raw).map_err(FileWatcherError::Parse)?;
+        if let Some(parsed) = &self.parsed {
+            parsed();
+        }
+        Ok(Some(out))
+    }
+}
+
+/// Runs `future` within a span for `name`, a phase of the current reload attempt, with the `opentelemetry` feature.
+#[cfg_attr(not(feature = "opentelemetry"), allow(unused_variables))]
+async fn phase<F: Future>(name: &'static str, future: F) -> F::Output {
+    #[cfg(feature = "opentelemetry")]
+    return otel::phase(name, future).await;
+    #[cfg(not(feature = "opentelemetry"))]
+    future.await
+}
+
+/// Relative paths are relative to the current directory, or on Windows, the current directory of their drive.
+fn absolute(file: &Path) -> PathBuf {
+    std::path::absolute(file).unwrap_or_else(|_| file.to_path_buf())
+}
+
+/// Cleans up an absolute path without touching the filesystem.
+/// On unix `..` is kept, since it applies to wherever a symlink before it points.
+/// Windows resolves `..` lexically, except in `\\?\` paths which are passed through untouched, so it is resolved here.
+#[allow(dead_code)] // unused when only polling
+pub(crate) fn normalize(path: &Path) -> io::Result<PathBuf> {
+    if !path.is_absolute() {
+        return Err(PathError::Relative(path.to_path_buf()).into());
+    }
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            // drive letters, UNC shares, and their `\\?\` forms
+            Component::Prefix(prefix) => out.push(prefix.as_os_str()),
+            Component::RootDir => out.push(Component::RootDir),
+            Component::CurDir => out.push("."),
+            Component::ParentDir if cfg!(windows) => {
+                out.pop();
+            }
+            Component::ParentDir => out.push(".."),
+            Component::Normal(component) => out.push(component),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_file_zone() {
+        env_logger::Builder::new()
+            .parse_env(env_logger::Env::default().default_filter_or("info"))
+            .init();
+        let mut receiver = FileWatcherConfig::new("./test.yaml", "config").start();
+        while let Some(_update) = receiver.recv().await {
+            println!("updated!");
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("really-notify-{}-{name}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_directory() {
+        let dir = test_dir("directory");
+        std::fs::write(dir.join("b.conf"), "b").unwrap();
+        std::fs::write(dir.join("a.conf"), "a").unwrap();
+        std::fs::create_dir(dir.join("nested")).unwrap();
+        let mut receiver = FileWatcherConfig::new_directory(&dir, "conf.d").start();
+        let initial = receiver.recv().await.unwrap();
+        assert_eq!(
+            initial,
+            vec![
+                (dir.join("a.conf"), b"a".to_vec()),
+                (dir.join("b.conf"), b"b".to_vec())
+            ]
+        );
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("c.conf"), "c").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let update = receiver.recv().await.unwrap();
+                if update.len() == 3 {
+                    break update;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(update[2], (dir.join("c.conf"), b"c".to_vec()));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_editor_artifacts() {
+        let dir = test_dir("editor_artifacts");
+        std::fs::write(dir.join("a.conf"), "a").unwrap();
+        std::fs::write(dir.join(".a.conf.swp"), "swap").unwrap();
+        let mut receiver = FileWatcherConfig::new_directory(&dir, "conf.d").start();
+        let mut kept = FileWatcherConfig::new_directory(&dir, "conf.d")
+            .with_editor_artifacts(true)
+            .start();
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            vec![(dir.join("a.conf"), b"a".to_vec())]
+        );
+        assert_eq!(kept.recv().await.unwrap().len(), 2);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // what saving with vim or emacs leaves around
+        for name in ["4913", "a.conf~", ".#a.conf", "#a.conf#", ".a.conf.swx"] {
+            std::fs::write(dir.join(name), "").unwrap();
+            std::fs::remove_file(dir.join(name)).unwrap();
+        }
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), receiver.recv())
+                .await
+                .is_err()
+        );
+        std::fs::write(dir.join("b.conf"), "b").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            update,
+            vec![
+                (dir.join("a.conf"), b"a".to_vec()),
+                (dir.join("b.conf"), b"b".to_vec())
+            ]
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_directory_per_file() {
+        let dir = test_dir("directory_per_file");
+        std::fs::write(dir.join("tenant-a"), "1").unwrap();
+        std::fs::write(dir.join("tenant-b"), "2").unwrap();
+        let mut receiver = FileWatcherConfig::new_directory(&dir, "tenants")
+            .with_file_parser(|raw| String::from_utf8(raw).map(|x| x.parse::<u32>().unwrap()))
+            .start();
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            vec![
+                (dir.join("tenant-a"), Some(1)),
+                (dir.join("tenant-b"), Some(2))
+            ]
+        );
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("tenant-b"), "3").unwrap();
+        let timeout = Duration::from_secs(5);
+        let update = tokio::time::timeout(timeout, receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, vec![(dir.join("tenant-b"), Some(3))]);
+        std::fs::remove_file(dir.join("tenant-a")).unwrap();
+        let update = tokio::time::timeout(timeout, receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, vec![(dir.join("tenant-a"), None)]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_tree() {
+        let dir = test_dir("tree");
+        std::fs::create_dir_all(dir.join("a/b")).unwrap();
+        std::fs::write(dir.join("a/b/deep.txt"), "deep").unwrap();
+        std::fs::write(dir.join("a/.shallow.swp"), "swap").unwrap();
+        let mut receiver = FileWatcherConfig::new_tree(&dir, "templates")
+            .with_ignore_pattern("*.swp")
+            .unwrap()
+            .start();
+        let initial = receiver.recv().await.unwrap();
+        assert_eq!(
+            initial.files,
+            vec![(dir.join("a/b/deep.txt"), b"deep".to_vec())]
+        );
+        assert_eq!(initial.changed, vec![dir.join("a/b/deep.txt")]);
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("a/b/.deep.swp"), "swap").unwrap();
+        std::fs::create_dir(dir.join("c")).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("c/new.txt"), "new").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let update = receiver.recv().await.unwrap();
+                if update.files.len() == 2 {
+                    break update;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(update.changed, vec![dir.join("c/new.txt")]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_tree_parse_failure() {
+        let dir = test_dir("tree_parse_failure");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "1").unwrap();
+        let mut receiver = FileWatcherConfig::new_tree(&dir, "tree_parse_failure")
+            .with_parser(|contents: TreeContents| {
+                if contents.files.iter().any(|(_, raw)| raw == b"bad") {
+                    return Err("bad file");
+                }
+                Ok(contents.changed)
+            })
+            .with_parse_retry(Retry::OnChange)
+            .start();
+        let timeout = Duration::from_secs(5);
+        let initial = tokio::time::timeout(timeout, receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(initial, vec![dir.join("a.txt")]);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("b.txt"), "bad").unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        std::fs::write(dir.join("a.txt"), "2").unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        std::fs::write(dir.join("b.txt"), "ok").unwrap();
+        // the change to a.txt was only read while b.txt failed to parse, and is still reported
+        let update = tokio::time::timeout(timeout, receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, vec![dir.join("a.txt"), dir.join("b.txt")]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(any(
+        feature = "notify",
+        all(feature = "inotify", any(target_os = "linux", target_os = "android"))
+    ))]
+    #[tokio::test]
+    async fn test_tree_rename() {
+        let dir = test_dir("tree_rename");
+        std::fs::create_dir_all(dir.join("a/b")).unwrap();
+        std::fs::write(dir.join("a/b/deep.txt"), "deep").unwrap();
+        let mut receiver = FileWatcherConfig::new_tree(&dir, "templates").start();
+        assert_eq!(receiver.recv().await.unwrap().files.len(), 1);
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // still watched after being renamed within the tree
+        std::fs::rename(dir.join("a"), dir.join("c")).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("c/b/new.txt"), "new").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let update = receiver.recv().await.unwrap();
+                if update.files.len() == 2 {
+                    break update;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(update.changed, vec![dir.join("c/b/new.txt")]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(all(feature = "inotify", any(target_os = "linux", target_os = "android")))]
+    #[tokio::test]
+    async fn test_shared_inotify() {
+        let dir = test_dir("shared_inotify");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a"), "a1").unwrap();
+        std::fs::write(dir.join("b"), "b1").unwrap();
+        let start = |name: &str| {
+            FileWatcherConfig::new(dir.join(name), name)
+                .with_backend(Backend::Inotify)
+                .with_shared_inotify(true)
+                .start()
+        };
+        let mut a = start("a");
+        let mut b = start("b");
+        assert_eq!(a.recv().await.unwrap(), b"a1");
+        assert_eq!(b.recv().await.unwrap(), b"b1");
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // the directory is watched once for both, but each only sees its own file change
+        std::fs::write(dir.join("a"), "a2").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), a.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"a2");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(b.try_recv().is_err());
+        // the other watcher keeps its watches once one stops
+        drop(a);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("b"), "b2").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), b.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"b2");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(feature = "notify")]
+    #[tokio::test]
+    async fn test_notify_debounce() {
+        let dir = test_dir("notify_debounce");
+        let file = dir.join("config");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&file, "0").unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_backend(Backend::Notify)
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"0");
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // a burst of saves is a single reload
+        for i in 1..=20 {
+            std::fs::write(&file, i.to_string()).unwrap();
+        }
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"20");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(receiver.try_recv().is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(feature = "notify")]
+    #[tokio::test]
+    async fn test_notify_poll() {
+        let dir = test_dir("notify_poll");
+        let file = dir.join("config");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&file, "1").unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_backend(Backend::Notify)
+            .with_poll_interval(Duration::from_millis(100))
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        // PollWatcher compares modification times in whole seconds
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        std::fs::write(&file, "2").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"2");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_notify_only() {
+        let dir = test_dir("notify_only");
+        let file = dir.join("config");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&file, "1").unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_notify_only()
+            .start();
+        receiver.recv().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // unreadable, which doesn't matter since we never read it
+        std::fs::remove_file(&file).unwrap();
+        std::fs::create_dir(&file).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_attribute_changes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir("attribute_changes");
+        let file = dir.join("config");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&file, "1").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_attribute_changes(true)
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"1");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(any(
+        feature = "notify",
+        all(feature = "inotify", any(target_os = "linux", target_os = "android"))
+    ))]
+    #[tokio::test]
+    async fn test_event_filter() {
+        let dir = test_dir("event_filter");
+        let file = dir.join("config");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&file, "1").unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_event_filter(|kind, _| kind == ChangeKind::Renamed)
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // written in place, which we ignore
+        std::fs::write(&file, "2").unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), receiver.recv())
+                .await
+                .is_err()
+        );
+        std::fs::write(dir.join("tmp"), "3").unwrap();
+        std::fs::rename(dir.join("tmp"), &file).unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"3");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_replace() {
+        let dir = test_dir("replace");
+        let file = dir.join("a/b/c/config");
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(&file, "1").unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "config").start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // each replacement moves the watch to the new file, and the one after is still seen
+        for i in 2..5 {
+            std::fs::write(dir.join("tmp"), i.to_string()).unwrap();
+            std::fs::rename(dir.join("tmp"), &file).unwrap();
+            let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(update, i.to_string().as_bytes());
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(all(feature = "inotify", any(target_os = "linux", target_os = "android")))]
+    #[tokio::test]
+    async fn test_rename_over() {
+        let dir = test_dir("rename_over");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let history = EventHistory::new(64);
+        let stats = WatcherStats::new();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_backend(Backend::Inotify)
+            .with_history(&history)
+            .with_stats(&stats)
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let watched = |stats: &WatcherStats| {
+            stats
+                .watches()
+                .into_iter()
+                .map(|x| x.path)
+                .collect::<Vec<_>>()
+        };
+        let before = watched(&stats);
+        std::fs::write(dir.join("config.tmp"), "2").unwrap();
+        std::fs::rename(dir.join("config.tmp"), &file).unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap();
+        assert_eq!(update.unwrap(), b"2");
+        let replaced = HistoryEvent::Received {
+            kind: ChangeKind::Renamed,
+            path: file.clone(),
+        };
+        assert!(history.entries().iter().any(|x| x.event == replaced));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(watched(&stats), before);
+        // the file renamed over it is watched in its place
+        std::fs::write(&file, "3").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let update = receiver.recv().await.unwrap();
+                if update == b"3" {
+                    break update;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(update, b"3");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(all(feature = "inotify", any(target_os = "linux", target_os = "android")))]
+    #[tokio::test]
+    async fn test_rename_away() {
+        let dir = test_dir("rename_away");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let history = EventHistory::new(64);
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_retry_interval(Duration::from_millis(100))
+            .with_backend(Backend::Inotify)
+            .with_history(&history)
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::rename(&file, dir.join("config.old")).unwrap();
+        let removed = HistoryEvent::Received {
+            kind: ChangeKind::Removed,
+            path: file.clone(),
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !history.entries().iter().any(|x| x.event == removed) {
+            assert!(Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let received = |history: &EventHistory| {
+            history
+                .entries()
+                .iter()
+                .filter(|x| matches!(x.event, HistoryEvent::Received { .. }))
+                .count()
+        };
+        let before = received(&history);
+        // no longer watched where it was renamed to
+        std::fs::write(dir.join("config.old"), "old").unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(received(&history), before);
+        // but watched for being created again, and watched once it is
+        std::fs::write(&file, "2").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap();
+        assert_eq!(update.unwrap(), b"2");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(&file, "3").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let update = receiver.recv().await.unwrap();
+                if update == b"3" {
+                    break update;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(update, b"3");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_fallback() {
+        let dir = test_dir("fallback");
+        std::fs::write(dir.join("local"), "local").unwrap();
+        let mut receiver =
+            FileWatcherConfig::new_fallback([dir.join("system"), dir.join("local")], "config")
+                .unwrap()
+                .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"local");
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // the higher priority candidate is switched to once it appears
+        std::fs::write(dir.join("system"), "system").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let update = receiver.recv().await.unwrap();
+                if update == b"system" {
+                    break update;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(update, b"system");
+        assert!(matches!(
+            FileWatcherConfig::new_fallback(Vec::<PathBuf>::new(), "config"),
+            Err(PathError::Empty)
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_fallback_hardened_reads() {
+        let dir = test_dir("fallback_hardened_reads");
+        std::fs::create_dir(dir.join("real")).unwrap();
+        std::fs::write(dir.join("real/local"), "local").unwrap();
+        std::os::unix::fs::symlink("real/local", dir.join("local")).unwrap();
+        let mut receiver =
+            FileWatcherConfig::new_fallback([dir.join("system"), dir.join("local")], "config")
+                .unwrap()
+                .with_hardened_reads()
+                .start();
+        // the candidate in use is read, through its symlink, rather than only the first one
+        let initial = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(initial, b"local");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("system"), "system").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let update = receiver.recv().await.unwrap();
+                if update == b"system" {
+                    break update;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(update, b"system");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_layered() {
+        let dir = test_dir("layered");
+        std::fs::write(dir.join("base"), "base").unwrap();
+        let mut receiver =
+            FileWatcherConfig::new_layered([dir.join("base"), dir.join("local")], "config")
+                .unwrap()
+                .with_layer_parser(String::from_utf8, |layers| layers.join("+"))
+                .start();
+        assert_eq!(receiver.recv().await.unwrap(), "base");
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("local"), "local").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let update = receiver.recv().await.unwrap();
+                if update != "base" {
+                    break update;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(update, "base+local");
+        assert!(matches!(
+            FileWatcherConfig::new_layered(Vec::<PathBuf>::new(), "config"),
+            Err(PathError::Empty)
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_drop_in() {
+        let dir = test_dir("drop_in");
+        let (high, low) = (dir.join("high"), dir.join("low"));
+        std::fs::create_dir_all(low.join("app/app.conf.d")).unwrap();
+        std::fs::write(low.join("app/app.conf"), "low").unwrap();
+        std::fs::write(low.join("app/app.conf.d/10-extra.conf"), "extra").unwrap();
+        let mut receiver =
+            FileWatcherConfig::new_drop_in_with_dirs([&high, &low], "app/app.conf", "config")
+                .unwrap()
+                .with_layer_parser(String::from_utf8, |layers| layers.join("+"))
+                .start();
+        assert_eq!(receiver.recv().await.unwrap(), "low+extra");
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // the main file of a higher precedence directory is used once it appears
+        std::fs::create_dir_all(high.join("app")).unwrap();
+        std::fs::write(high.join("app/app.conf"), "high").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let update = receiver.recv().await.unwrap();
+                if update == "high+extra" {
+                    break update;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(update, "high+extra");
+        assert!(matches!(
+            FileWatcherConfig::new_drop_in_with_dirs(
+                Vec::<PathBuf>::new(),
+                "app/app.conf",
+                "config"
+            ),
+            Err(PathError::Empty)
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_dependencies() {
+        let dir = test_dir("dependencies");
+        std::fs::write(dir.join("main.conf"), "include.conf").unwrap();
+        std::fs::write(dir.join("include.conf"), "1").unwrap();
+        let dir2 = dir.clone();
+        let mut receiver = FileWatcherConfig::new(dir.join("main.conf"), "config")
+            .with_dependency_parser(move |raw| {
+                let include = String::from_utf8(raw)?;
+                let included = std::fs::read_to_string(dir2.join(&include)).unwrap();
+                Ok::<_, std::string::FromUtf8Error>((included, vec![PathBuf::from(include)]))
+            })
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), "1");
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("include.conf"), "2").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, "2");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_config_map() {
+        let dir = test_dir("config_map");
+        let project = |version: &str, value: &str| {
+            std::fs::create_dir(dir.join(version)).unwrap();
+            std::fs::write(dir.join(version).join("key"), value).unwrap();
+            std::os::unix::fs::symlink(version, dir.join("..data_tmp")).unwrap();
+            std::fs::rename(dir.join("..data_tmp"), dir.join("..data")).unwrap();
+        };
+        project("..v1", "1");
+        std::os::unix::fs::symlink("..data/key", dir.join("key")).unwrap();
+        let mut receiver = FileWatcherConfig::new_config_map(&dir, "config map").start();
+        assert_eq!(
+            receiver.recv().await.unwrap(),
+            vec![(dir.join("key"), b"1".to_vec())]
+        );
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        project("..v2", "2");
+        std::fs::remove_dir_all(dir.join("..v1")).unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, vec![(dir.join("key"), b"2".to_vec())]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_sentinel() {
+        let dir = test_dir("sentinel");
+        let flag = dir.join("maintenance");
+        let mut receiver = FileWatcherConfig::new_sentinel(&flag, "maintenance flag").start();
+        assert!(!receiver.recv().await.unwrap());
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(&flag, "").unwrap();
+        let timeout = Duration::from_secs(5);
+        assert!(tokio::time::timeout(timeout, receiver.recv())
+            .await
+            .unwrap()
+            .unwrap());
+        std::fs::write(&flag, "still here").unwrap();
+        std::fs::remove_file(&flag).unwrap();
+        assert!(!tokio::time::timeout(timeout, receiver.recv())
+            .await
+            .unwrap()
+            .unwrap());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_group() {
+        let dir = test_dir("group");
+        std::fs::write(dir.join("a"), "a").unwrap();
+        std::fs::write(dir.join("b"), "b").unwrap();
+        let mut group = WatcherGroup::new();
+        let a = group.add(FileWatcherConfig::new(dir.join("a"), "a"));
+        let b = group.add(FileWatcherConfig::new(dir.join("b"), "b"));
+        let mut receiver = group.start();
+        let mut initial = vec![
+            receiver.recv().await.unwrap(),
+            receiver.recv().await.unwrap(),
+        ];
+        initial.sort();
+        assert_eq!(initial, vec![(a, b"a".to_vec()), (b, b"b".to_vec())]);
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("b"), "b2").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, (b, b"b2".to_vec()));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_group_startup() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let dir = test_dir("group_startup");
+        let reading = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        let progress = Arc::new(Mutex::new(vec![]));
+        let mut group = WatcherGroup::new()
+            .with_startup_concurrency(1)
+            .with_startup_progress({
+                let progress = progress.clone();
+                move |x| progress.lock().unwrap().push(*x)
+            });
+        for name in ["a", "b", "c"] {
+            std::fs::write(dir.join(name), name).unwrap();
+            let reading = reading.clone();
+            group.add(
+                FileWatcherConfig::new(dir.join(name), name).with_parser(move |raw| {
+                    let (current, most) = &*reading;
+                    most.fetch_max(current.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(50));
+                    current.fetch_sub(1, Ordering::SeqCst);
+                    Ok::<_, Infallible>(raw)
+                }),
+            );
+        }
+        group.add(FileWatcherConfig::new(dir.join("missing"), "missing").with_parser(Ok));
+        let mut receiver = group.start();
+        for _ in 0..3 {
+            tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        // one at a time
+        assert_eq!(reading.1.load(Ordering::SeqCst), 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let progress = progress.lock().unwrap();
+        let last = progress.last().unwrap();
+        assert_eq!((last.ready, last.failed, last.total), (3, 1, 4));
+        assert!(last.is_done());
+        assert_eq!(progress.len(), 4);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_shared() {
+        let dir = test_dir("shared");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let mut raw = FileWatcherConfig::new(&file, "raw")
+            .with_shared_watch(true)
+            .start();
+        let mut parsed = FileWatcherConfig::new(&file, "parsed")
+            .with_shared_watch(true)
+            .with_parser(|raw| String::from_utf8(raw).map(|x| x.parse::<u32>().unwrap()))
+            .start();
+        assert_eq!(raw.recv().await.unwrap(), b"1");
+        assert_eq!(parsed.recv().await.unwrap(), 1);
+        assert_eq!(shared_watches(&file), 1);
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(&file, "2").unwrap();
+        let timeout = Duration::from_secs(5);
+        let update = tokio::time::timeout(timeout, raw.recv()).await.unwrap();
+        assert_eq!(update.unwrap(), b"2");
+        let update = tokio::time::timeout(timeout, parsed.recv()).await.unwrap();
+        assert_eq!(update.unwrap(), 2);
+        drop(raw);
+        drop(parsed);
+        tokio::time::timeout(timeout, async {
+            while shared_watches(&file) > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    fn shared_watches(file: &Path) -> usize {
+        shared::registry()
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| key.file == file)
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_shared_settings() {
+        let dir = test_dir("shared_settings");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let mut quiet = FileWatcherConfig::new(&file, "quiet")
+            .with_shared_watch(true)
+            .with_write_quiescence(Duration::from_secs(30))
+            .start();
+        let mut eager = FileWatcherConfig::new(&file, "eager")
+            .with_shared_watch(true)
+            .start();
+        let mut unshared = FileWatcherConfig::new(&file, "unshared").start();
+        assert_eq!(quiet.recv().await.unwrap(), b"1");
+        assert_eq!(eager.recv().await.unwrap(), b"1");
+        assert_eq!(unshared.recv().await.unwrap(), b"1");
+        // the second watcher waits for no quiescence, so it can't share the watch of the first
+        assert_eq!(shared_watches(&file), 2);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(&file, "2").unwrap();
+        let timeout = Duration::from_secs(5);
+        let update = tokio::time::timeout(timeout, eager.recv()).await.unwrap();
+        assert_eq!(update.unwrap(), b"2");
+        let update = tokio::time::timeout(timeout, unshared.recv())
+            .await
+            .unwrap();
+        assert_eq!(update.unwrap(), b"2");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), quiet.recv())
+                .await
+                .is_err()
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    struct MemorySource {
+        value: Mutex<String>,
+        changed: Notify,
+    }
+
+    impl Source for MemorySource {
+        type Input = String;
+
+        fn describe(&self) -> String {
+            "memory".to_string()
+        }
+
+        fn read(&self) -> BoxFuture<'_, io::Result<String>> {
+            Box::pin(async move { Ok(self.value.lock().unwrap().clone()) })
+        }
+
+        fn changed(&self) -> BoxFuture<'_, ()> {
+            Box::pin(self.changed.notified())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_source() {
+        let source = Arc::new(MemorySource {
+            value: Mutex::new("1".to_string()),
+            changed: Notify::new(),
+        });
+        let mut receiver = FileWatcherConfig::from_source(source.clone(), "memory")
+            .with_parser(|x: String| x.parse::<u32>())
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), 1);
+        *source.value.lock().unwrap() = "2".to_string();
+        source.changed.notify_one();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, 2);
+    }
+
+    #[tokio::test]
+    async fn test_tail() {
+        let dir = test_dir("tail");
+        let file = dir.join("commands");
+        std::fs::write(&file, "start\n").unwrap();
+        let mut receiver = FileWatcherConfig::new_tail(&file, "commands")
+            .with_line_parser(Ok::<_, Infallible>)
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), vec!["start"]);
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut handle = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&file)
+            .unwrap();
+        std::io::Write::write_all(&mut handle, b"stop\nrest").unwrap();
+        let timeout = Duration::from_secs(5);
+        let update = tokio::time::timeout(timeout, receiver.recv())
+            .await
+            .unwrap();
+        assert_eq!(update.unwrap(), vec!["stop"]);
+        std::io::Write::write_all(&mut handle, b"art\n").unwrap();
+        let update = tokio::time::timeout(timeout, receiver.recv())
+            .await
+            .unwrap();
+        assert_eq!(update.unwrap(), vec!["restart"]);
+        std::fs::rename(&file, dir.join("commands.1")).unwrap();
+        std::io::Write::write_all(&mut handle, b"late\n").unwrap();
+        std::fs::write(&file, "rotated\n").unwrap();
+        let update = tokio::time::timeout(timeout, async {
+            let mut lines = vec![];
+            while lines.len() < 2 {
+                lines.extend(receiver.recv().await.unwrap());
+            }
+            lines
+        })
+        .await
+        .unwrap();
+        assert_eq!(update, vec!["late", "rotated"]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_poll() {
+        let dir = test_dir("poll");
+        let file = dir.join("limit");
+        std::fs::write(&file, "1").unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "limit")
+            .with_poll_interval(Duration::from_millis(20))
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        // the poller takes its baseline after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(&file, "2").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"2");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_backend() {
+        let dir = test_dir("backend");
+        std::fs::write(dir.join("a.conf"), "a").unwrap();
+        let unavailable = [Backend::Inotify, Backend::Kqueue, Backend::Windows]
+            .into_iter()
+            .find(|x| !x.is_available())
+            .unwrap();
+        let errors = Arc::new(Mutex::new(0));
+        let errors2 = errors.clone();
+        let mut receiver = FileWatcherConfig::new_directory(&dir, "conf.d")
+            .with_backend(unavailable)
+            .with_error_hook(move |_| *errors2.lock().unwrap() += 1)
+            .start();
+        assert_eq!(receiver.recv().await.unwrap().len(), 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // reported, then watched by whatever is available instead
+        assert_eq!(*errors.lock().unwrap(), 1);
+        std::fs::write(dir.join("b.conf"), "b").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update.len(), 2);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_backend() {
+        let dir = test_dir("lifecycle_backend");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let events = Arc::new(Mutex::new(vec![]));
+        let events2 = events.clone();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_backend(Backend::Poll)
+            .with_lifecycle_hook(move |event| events2.lock().unwrap().push(event.clone()))
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![LifecycleEvent::BackendStarted {
+                path: absolute(&file),
+                backend: Backend::Poll
+            }]
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(all(feature = "inotify", any(target_os = "linux", target_os = "android")))]
+    #[tokio::test]
+    async fn test_self_test() {
+        let dir = test_dir("self_test");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let events = Arc::new(Mutex::new(vec![]));
+        let events2 = events.clone();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_backend(Backend::Inotify)
+            .with_self_test(Duration::from_secs(1))
+            .with_lifecycle_hook(move |event| events2.lock().unwrap().push(event.clone()))
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(&file, "2").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"2");
+        // events are delivered here, so inotify is kept
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![LifecycleEvent::BackendStarted {
+                path: absolute(&file),
+                backend: Backend::Inotify
+            }]
+        );
+        // and the test file is gone
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    #[tokio::test]
+    async fn test_uring_read() {
+        let dir = test_dir("uring_read");
+        let file = dir.join("config");
+        // larger than a single read
+        let contents: Vec<u8> = (0..200_000u32).map(|x| x as u8).collect();
+        std::fs::write(&file, &contents).unwrap();
+        assert_eq!(fs::read(&file).await.unwrap(), contents);
+        assert_eq!(
+            fs::read(dir.join("missing")).await.unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(all(feature = "inotify", target_os = "linux"))]
+    #[tokio::test]
+    async fn test_mount_changes() {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+        let dir = test_dir("mount_changes");
+        let mounted = dir.join("mounted");
+        let over = dir.join("over");
+        std::fs::create_dir_all(&mounted).unwrap();
+        std::fs::create_dir_all(&over).unwrap();
+        std::fs::write(mounted.join("config"), "1").unwrap();
+        std::fs::write(over.join("config"), "2").unwrap();
+        let mut receiver = FileWatcherConfig::new(mounted.join("config"), "config")
+            .with_backend(Backend::Inotify)
+            .with_mount_changes(true)
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let source = CString::new(over.as_os_str().as_bytes()).unwrap();
+        let target = CString::new(mounted.as_os_str().as_bytes()).unwrap();
+        let result = unsafe {
+            libc::mount(
+                source.as_ptr(),
+                target.as_ptr(),
+                std::ptr::null(),
+                libc::MS_BIND,
+                std::ptr::null(),
+            )
+        };
+        if result < 0 {
+            // unprivileged
+            std::fs::remove_dir_all(&dir).ok();
+            return;
+        }
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await;
+        unsafe { libc::umount(target.as_ptr()) };
+        assert_eq!(update.unwrap().unwrap(), b"2");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(all(feature = "inotify", target_os = "linux"))]
+    #[tokio::test]
+    async fn test_remount() {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+        let dir = test_dir("remount");
+        let mount_point = dir.join("mnt");
+        std::fs::create_dir_all(&mount_point).unwrap();
+        let target = CString::new(mount_point.as_os_str().as_bytes()).unwrap();
+        let mount = || unsafe {
+            libc::mount(
+                c"tmpfs".as_ptr(),
+                target.as_ptr(),
+                c"tmpfs".as_ptr(),
+                0,
+                std::ptr::null(),
+            )
+        };
+        if mount() < 0 {
+            // unprivileged
+            std::fs::remove_dir_all(&dir).ok();
+            return;
+        }
+        let file = mount_point.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_backend(Backend::Inotify)
+            .with_retry_interval(Duration::from_millis(100))
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(unsafe { libc::umount(target.as_ptr()) }, 0);
+        // the last read value is kept while unmounted, rather than failing reads
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), receiver.recv())
+                .await
+                .is_err()
+        );
+        assert_eq!(mount(), 0);
+        std::fs::write(&file, "2").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let update = receiver.recv().await.unwrap();
+                if update == b"2" {
+                    break update;
+                }
+            }
+        })
+        .await;
+        unsafe { libc::umount(target.as_ptr()) };
+        assert_eq!(update.unwrap(), b"2");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_created_through_missing_ancestors() {
+        let dir = test_dir("created_through_missing_ancestors");
+        let file = dir.join("a/b/config");
+        let conf_d = dir.join("c/d/conf.d");
+        // far longer than the test may take, so only watching for creation gets the reads in time
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_retry_interval(Duration::from_secs(60))
+            .start();
+        let mut directory = FileWatcherConfig::new_directory(&conf_d, "conf.d")
+            .with_retry_interval(Duration::from_secs(60))
+            .start();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(&file, "1").unwrap();
+        std::fs::create_dir_all(&conf_d).unwrap();
+        std::fs::write(conf_d.join("a.conf"), "a").unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read, b"1");
+        let read = tokio::time::timeout(Duration::from_secs(5), directory.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(read.len() <= 1);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_write_quiescence() {
+        use std::io::Write;
+        let dir = test_dir("write_quiescence");
+        let file = dir.join("config");
+        std::fs::write(&file, "").unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_write_quiescence(Duration::from_millis(500))
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // a slow writer streaming the file out in chunks
+        let mut writer = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&file)
+            .unwrap();
+        for chunk in ["a: 1\n", "b: 2\n", "c: 3\n", "d: 4\n"] {
+            writer.write_all(chunk.as_bytes()).unwrap();
+            tokio::time::sleep(Duration::from_millis(150)).await;
+        }
+        let read = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read, b"a: 1\nb: 2\nc: 3\nd: 4\n");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_burst_coalescing() {
+        let dir = test_dir("burst_coalescing");
+        let file = dir.join("config");
+        std::fs::write(&file, "0").unwrap();
+        let parses = Arc::new(AtomicUsize::new(0));
+        let parses2 = parses.clone();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_burst_coalescing(Duration::from_millis(800))
+            .with_parser(move |raw| {
+                parses2.fetch_add(1, Ordering::SeqCst);
+                String::from_utf8(raw)
+            })
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), "0");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // a bulk copy, slower than reading the file
+        for i in 1..=10 {
+            std::fs::write(&file, i.to_string()).unwrap();
+            tokio::time::sleep(Duration::from_millis(30)).await;
+        }
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, "10");
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(parses.load(Ordering::SeqCst), 2);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_min_emit_interval() {
+        let dir = test_dir("min_emit_interval");
+        let file = dir.join("config");
+        std::fs::write(&file, "0").unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_min_emit_interval(Duration::from_secs(1))
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"0");
+        let emitted = Instant::now();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for i in 1..=3 {
+            std::fs::write(&file, i.to_string()).unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"3");
+        assert!(emitted.elapsed() >= Duration::from_secs(1));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(receiver.try_recv().is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff::exponential(Duration::from_millis(100), Duration::from_secs(1));
+        let delays: Vec<_> = (1..=6).map(|x| backoff.delay(x).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(1));
+        let jittered = backoff.with_jitter(0.5);
+        for _ in 0..100 {
+            let delay = jittered.delay(4);
+            assert!(delay >= Duration::from_millis(400) && delay <= Duration::from_millis(800));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_backoff() {
+        let dir = test_dir("retry_backoff");
+        // a directory can't be read as a file
+        let file = dir.join("config");
+        std::fs::create_dir(&file).unwrap();
+        let errors = Arc::new(AtomicUsize::new(0));
+        let errors2 = errors.clone();
+        let _receiver = FileWatcherConfig::new(&file, "config")
+            .with_retry_backoff(Backoff::exponential(
+                Duration::from_millis(100),
+                Duration::from_secs(10),
+            ))
+            .with_error_hook(move |_| {
+                errors2.fetch_add(1, Ordering::SeqCst);
+            })
+            .start();
+        // failing at 0, 100, 300, 700ms, and then only at 1.5s
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert_eq!(errors.load(Ordering::SeqCst), 4);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_shared_parse_retry() {
+        let dir = test_dir("shared_parse_retry");
+        let file = dir.join("config");
+        std::fs::write(&file, "x").unwrap();
+        let valid = Arc::new(AtomicBool::new(false));
+        let valid2 = valid.clone();
+        let mut retried = FileWatcherConfig::new(&file, "retried")
+            .with_shared_watch(true)
+            .with_parse_retry(Retry::After(Backoff::fixed(Duration::from_millis(50))))
+            .with_parser(move |raw| match valid2.load(Ordering::SeqCst) {
+                true => Ok(raw.len()),
+                false => Err("invalid"),
+            })
+            .start();
+        let errors = Arc::new(AtomicUsize::new(0));
+        let errors2 = errors.clone();
+        let _waiting = FileWatcherConfig::new(&file, "waiting")
+            .with_shared_watch(true)
+            .with_parse_retry(Retry::OnChange)
+            .with_parser(|raw| String::from_utf8_lossy(&raw).parse::<u32>())
+            .with_error_hook(move |_| {
+                errors2.fetch_add(1, Ordering::SeqCst);
+            })
+            .start();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(shared_watches(&file), 1);
+        // the same contents are parsed again, without changing
+        valid.store(true, Ordering::SeqCst);
+        let read = tokio::time::timeout(Duration::from_secs(5), retried.recv())
+            .await
+            .unwrap();
+        assert_eq!(read.unwrap(), 1);
+        assert_eq!(errors.load(Ordering::SeqCst), 1);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_parse_retry() {
+        let dir = test_dir("parse_retry");
+        let file = dir.join("config");
+        std::fs::write(&file, "x").unwrap();
+        let errors = Arc::new(AtomicUsize::new(0));
+        let errors2 = errors.clone();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_retry_interval(Duration::from_millis(100))
+            .with_parse_retry(Retry::OnChange)
+            .with_parser(|raw| String::from_utf8_lossy(&raw).parse::<u32>())
+            .with_error_hook(move |_| {
+                errors2.fetch_add(1, Ordering::SeqCst);
+            })
+            .start();
+        // the same invalid contents aren't parsed again until they change
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(errors.load(Ordering::SeqCst), 1);
+        std::fs::write(&file, "1").unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read, 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // writing it may be reported as more than one change
+        std::fs::write(&file, "y").unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let errors_after_write = errors.load(Ordering::SeqCst);
+        assert!(errors_after_write > 1);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(errors.load(Ordering::SeqCst), errors_after_write);
+        std::fs::write(&file, "2").unwrap();
+        // skipping over any repeats of the first
+        let read = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let read = receiver.recv().await.unwrap();
+                if read != 1 {
+                    break read;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(read, 2);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_max_retries() {
+        let dir = test_dir("max_retries");
+        let file = dir.join("config");
+        std::fs::create_dir(&file).unwrap();
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors2 = errors.clone();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_retry_interval(Duration::from_millis(50))
+            .with_max_retries(2)
+            .with_error_hook(move |e| {
+                errors2
+                    .lock()
+                    .unwrap()
+                    .push(matches!(e, FileWatcherError::GaveUp(3)));
+            })
+            .start();
+        // the stream ends once the watcher gives up
+        assert!(
+            tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(*errors.lock().unwrap(), [false, false, false, true]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_follow_renames() {
+        let dir = test_dir("follow_renames");
+        let file = dir.join("config");
+        let rotated = dir.join("config.1");
+        std::fs::write(&file, "1").unwrap();
+        let events = Arc::new(Mutex::new(vec![]));
+        let events2 = events.clone();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_follow_renames(true)
+            .with_lifecycle_hook(move |event| {
+                if let LifecycleEvent::PathChanged { .. } = event {
+                    events2.lock().unwrap().push(event.clone());
+                }
+            })
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::rename(&file, &rotated).unwrap();
+        // read again where it went
+        let read = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read, b"1");
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![LifecycleEvent::PathChanged {
+                from: absolute(&file),
+                to: rotated.clone()
+            }]
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(&rotated, "2").unwrap();
+        loop {
+            let read = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if read == b"2" {
+                break;
+            }
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_async_std() {
+        // no tokio runtime anywhere
+        async_std::task::block_on(async {
+            let dir = test_dir("async_std");
+            let file = dir.join("config");
+            std::fs::write(&file, "1").unwrap();
+            let mut receiver = FileWatcherConfig::new(&file, "config").start();
+            assert_eq!(receiver.recv().await.unwrap(), b"1");
+            async_std::task::sleep(Duration::from_millis(100)).await;
+            std::fs::write(&file, "2").unwrap();
+            loop {
+                let read = async_std::future::timeout(Duration::from_secs(5), receiver.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                if read == b"2" {
+                    break;
+                }
+            }
+            std::fs::remove_dir_all(&dir).ok();
+        });
+    }
+
+    #[cfg(feature = "smol")]
+    #[test]
+    fn test_smol() {
+        smol::block_on(async {
+            let dir = test_dir("smol");
+            let file = dir.join("config");
+            std::fs::write(&file, "1").unwrap();
+            let mut receiver = FileWatcherConfig::new(&file, "config").start();
+            assert_eq!(receiver.recv().await.unwrap(), b"1");
+            smol::Timer::after(Duration::from_millis(100)).await;
+            std::fs::write(&file, "2").unwrap();
+            let updated = async {
+                while receiver.recv().await.unwrap() != b"2" {}
+                true
+            };
+            let timed_out = async {
+                smol::Timer::after(Duration::from_secs(5)).await;
+                false
+            };
+            assert!(smol::future::or(updated, timed_out).await);
+            std::fs::remove_dir_all(&dir).ok();
+        });
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let dir = test_dir("run");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let (watcher, mut receiver) = FileWatcherConfig::new(&file, "config").run();
+        let mut watcher = Box::pin(watcher);
+        // nothing happens until it is driven
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(receiver.try_recv().is_err());
+        select! {
+            _ = &mut watcher => unreachable!(),
+            read = receiver.recv() => assert_eq!(read.unwrap(), b"1"),
+        }
+        // watches are established after the initial read, while it is driven
+        select! {
+            _ = &mut watcher => unreachable!(),
+            _ = tokio::time::sleep(Duration::from_millis(100)) => (),
+        }
+        std::fs::write(&file, "2").unwrap();
+        select! {
+            _ = &mut watcher => unreachable!(),
+            _ = tokio::time::sleep(Duration::from_secs(5)) => panic!("no update"),
+            read = async {
+                loop {
+                    let read = receiver.recv().await.unwrap();
+                    if read == b"2" {
+                        break read;
+                    }
+                }
+            } => assert_eq!(read, b"2"),
+        }
+        // dropping it stops the watcher, closing the receiver once updates already sent are received
+        drop(watcher);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while receiver.recv().await.is_some() {}
+        })
+        .await
+        .unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(not(any(feature = "async-std", feature = "smol", target_family = "wasm")))]
+    #[tokio::test]
+    async fn test_start_on() {
+        let dir = test_dir("start_on");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "config").start_on(runtime.handle());
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        // the watcher went down with the runtime it was started on
+        runtime.shutdown_background();
+        let closed = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await;
+        assert!(closed.unwrap().is_none());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(not(any(
+        feature = "async-std",
+        feature = "smol",
+        all(feature = "wasm", target_family = "wasm", target_os = "unknown")
+    )))]
+    #[tokio::test]
+    async fn test_start_local() {
+        let dir = test_dir("start_local");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        tokio::task::LocalSet::new()
+            .run_until(async {
+                // not Send
+                let parsed = std::rc::Rc::new(std::cell::Cell::new(0));
+                let mut receiver = FileWatcherConfig::new(&file, "config").start_local({
+                    let parsed = parsed.clone();
+                    move |raw| {
+                        parsed.set(parsed.get() + 1);
+                        String::from_utf8(raw).unwrap().parse::<u32>()
+                    }
+                });
+                assert_eq!(receiver.recv().await.unwrap(), 1);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                // skipped
+                std::fs::write(&file, "x").unwrap();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                std::fs::write(&file, "2").unwrap();
+                let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(update, 2);
+                assert!(parsed.get() >= 3);
+            })
+            .await;
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(not(any(feature = "async-std", feature = "smol", target_family = "wasm")))]
+    #[test]
+    fn test_start_detached() {
+        // no runtime here
+        let dir = test_dir("start_detached");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "config").start_detached();
+        assert_eq!(receiver.blocking_recv().unwrap(), b"1");
+        std::thread::sleep(Duration::from_millis(100));
+        std::fs::write(&file, "2").unwrap();
+        let (updated, update) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            // may read it truncated first, since it runs on another thread
+            while receiver.blocking_recv().unwrap() != b"2" {}
+            updated.send(()).unwrap();
+        });
+        update.recv_timeout(Duration::from_secs(5)).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(feature = "tokio-util")]
+    #[tokio::test]
+    async fn test_cancellation() {
+        let dir = test_dir("cancellation");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let token = tokio_util::sync::CancellationToken::new();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_cancellation(token.child_token())
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        token.cancel();
+        let closed = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await;
+        assert!(closed.unwrap().is_none());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Messages of events, with the name of the span they were in.
+    #[cfg(feature = "tracing")]
+    type Events = Arc<Mutex<Vec<(Option<&'static str>, String)>>>;
+
+    /// Records the messages of events, with the name of the span they were in.
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct Recorder {
+        spans: Mutex<Vec<&'static tracing::Metadata<'static>>>,
+        entered: Mutex<Vec<u64>>,
+        events: Events,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(span.metadata());
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            struct Message(String);
+            impl tracing::field::Visit for Message {
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    if field.name() == "message" {
+                        self.0 = format!("{value:?}");
+                    }
+                }
+            }
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            let span = self.entered.lock().unwrap().last().copied();
+            let span = span.map(|id| self.spans.lock().unwrap()[id as usize - 1].name());
+            self.events.lock().unwrap().push((span, message.0));
+        }
+
+        fn enter(&self, span: &tracing::span::Id) {
+            self.entered.lock().unwrap().push(span.into_u64());
+        }
+
+        fn exit(&self, _: &tracing::span::Id) {
+            self.entered.lock().unwrap().pop();
+        }
+
+        // for tasks spawned within a span to inherit it
+        fn current_span(&self) -> tracing_core::span::Current {
+            match self.entered.lock().unwrap().last() {
+                Some(&id) => tracing_core::span::Current::new(
+                    tracing::span::Id::from_u64(id),
+                    self.spans.lock().unwrap()[id as usize - 1],
+                ),
+                None => tracing_core::span::Current::none(),
+            }
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_tracing() {
+        let recorder = Recorder::default();
+        let events = recorder.events.clone();
+        let _guard = tracing::subscriber::set_default(recorder);
+        let dir = test_dir("tracing");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_shared_watch(true)
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        let events = events.lock().unwrap();
+        assert!(events
+            .iter()
+            .any(|(span, message)| *span == Some("shared_read") && message == "read target"));
+        assert!(events
+            .iter()
+            .any(|(span, message)| *span == Some("watcher") && message == "parsed target"));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(all(feature = "metrics", not(target_family = "wasm")))]
+    #[tokio::test]
+    async fn test_metrics() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        type Snapshot = Vec<(
+            metrics_util::CompositeKey,
+            Option<::metrics::Unit>,
+            Option<::metrics::SharedString>,
+            DebugValue,
+        )>;
+
+        /// The value of `name` for the watcher and its label, with `result` if set.
+        fn value<'a>(
+            snapshot: &'a Snapshot,
+            name: &str,
+            result: Option<&str>,
+        ) -> Option<&'a DebugValue> {
+            snapshot
+                .iter()
+                .find(|(key, ..)| {
+                    let key = key.key();
+                    key.name() == name
+                        && key
+                            .labels()
+                            .any(|x| x.key() == "watcher" && x.value() == "metrics")
+                        && key
+                            .labels()
+                            .any(|x| x.key() == "team" && x.value() == "infra")
+                        && result.is_none_or(|result| {
+                            key.labels()
+                                .any(|x| x.key() == "result" && x.value() == result)
+                        })
+                })
+                .map(|(.., value)| value)
+        }
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        recorder.install().unwrap();
+        let dir = test_dir("metrics");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "metrics")
+            .with_parser(|raw| match &*raw {
+                b"bad" => Err("bad"),
+                _ => {
+                    std::thread::sleep(Duration::from_millis(20));
+                    Ok(raw)
+                }
+            })
+            .with_label("team", "infra")
+            .with_slow_reload_threshold(Duration::from_millis(10))
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        // histograms are drained by taking a snapshot
+        let snapshot = snapshotter.snapshot().into_vec();
+        assert_eq!(
+            value(&snapshot, "really_notify_reloads_total", Some("success")),
+            Some(&DebugValue::Counter(1))
+        );
+        assert_eq!(
+            value(&snapshot, "really_notify_active_watches", None),
+            Some(&DebugValue::Gauge(1.0.into()))
+        );
+        assert!(matches!(
+            value(&snapshot, "really_notify_parse_duration_seconds", None),
+            Some(DebugValue::Histogram(x)) if x.len() == 1
+        ));
+        assert!(matches!(
+            value(&snapshot, "really_notify_last_reload_timestamp_seconds", None),
+            Some(DebugValue::Gauge(x)) if x.0 > 0.0
+        ));
+        assert!(matches!(
+            value(&snapshot, "really_notify_reload_duration_seconds", None),
+            Some(DebugValue::Histogram(x)) if x.len() == 1 && x[0].0 >= 0.02
+        ));
+        assert_eq!(
+            value(&snapshot, "really_notify_slow_reloads_total", None),
+            Some(&DebugValue::Counter(1))
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(&file, "bad").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while value(
+            &snapshotter.snapshot().into_vec(),
+            "really_notify_reloads_total",
+            Some("failure"),
+        )
+        .is_none()
+        {
+            assert!(Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop(receiver);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(all(feature = "opentelemetry", not(target_family = "wasm")))]
+    #[tokio::test]
+    async fn test_opentelemetry() {
+        use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+
+        let exporter = InMemorySpanExporter::default();
+        opentelemetry::global::set_tracer_provider(
+            SdkTracerProvider::builder()
+                .with_simple_exporter(exporter.clone())
+                .build(),
+        );
+        let dir = test_dir("opentelemetry");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "otel")
+            .with_label("tenant", "a")
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let spans = loop {
+            let spans = exporter.get_finished_spans().unwrap();
+            if spans.iter().any(|x| x.name == "reload") {
+                break spans;
+            }
+            assert!(Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let reload = spans.iter().find(|x| x.name == "reload").unwrap();
+        assert!(reload
+            .attributes
+            .contains(&opentelemetry::KeyValue::new("watcher", "otel")));
+        assert!(reload
+            .attributes
+            .contains(&opentelemetry::KeyValue::new("tenant", "a")));
+        assert!(reload
+            .attributes
+            .contains(&opentelemetry::KeyValue::new("result", "success")));
+        for phase in ["parse", "emit"] {
+            let phase = spans.iter().find(|x| x.name == phase).unwrap();
+            assert_eq!(phase.parent_span_id, reload.span_context.span_id());
+        }
+        drop(receiver);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let dir = test_dir("stats");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let stats = WatcherStats::new();
+        assert_eq!(stats.status(), WatcherStatus::Starting);
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_parser(|raw| match &*raw {
+                b"bad" => Err("bad"),
+                _ => Ok(raw),
+            })
+            .with_stats(&stats)
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        assert_eq!(stats.total_reloads(), 1);
+        assert!(stats.last_success().is_some());
+        assert!(stats.last_failure().is_none());
+        assert_eq!(stats.status(), WatcherStatus::Healthy);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(&file, "bad").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while stats.consecutive_failures() == 0 {
+            assert!(Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(stats.last_error().as_deref(), Some("bad"));
+        assert!(stats.last_failure().is_some());
+        assert!(matches!(
+            stats.status(),
+            WatcherStatus::Degraded { last_error, .. } if last_error == "bad"
+        ));
+        std::fs::write(&file, "2").unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), b"2");
+        // the write may be seen, and the target reloaded, more than once
+        assert!(stats.total_reloads() >= 2);
+        assert_eq!(stats.consecutive_failures(), 0);
+        // kept for after it recovers
+        assert_eq!(stats.last_error().as_deref(), Some("bad"));
+        assert_eq!(stats.status(), WatcherStatus::Healthy);
+        drop(receiver);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while stats.status() != WatcherStatus::Stopped {
+            assert!(Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_stats_status() {
+        let stats = WatcherStats::new();
+        let first = stats.running();
+        let second = stats.running();
+        assert_eq!(stats.status(), WatcherStatus::Starting);
+        // failing before the target ever parsed
+        stats.failed("missing".to_string());
+        let WatcherStatus::Degraded { since, last_error } = stats.status() else {
+            panic!("{:?}", stats.status());
+        };
+        assert_eq!(last_error, "missing");
+        // still failing since the first failure, with the latest error
+        stats.failed("bad".to_string());
+        assert_eq!(
+            stats.status(),
+            WatcherStatus::Degraded {
+                since,
+                last_error: "bad".to_string()
+            }
+        );
+        stats.reloaded();
+        assert_eq!(stats.status(), WatcherStatus::Healthy);
+        stats.failed("bad".to_string());
+        assert!(matches!(
+            stats.status(),
+            WatcherStatus::Degraded { since: x, .. } if x >= since
+        ));
+        // unchanged after failing, without parsing to an update
+        stats.recovered();
+        assert_eq!(stats.status(), WatcherStatus::Healthy);
+        // only once every watcher sharing the stats is
+        drop(first);
+        assert_eq!(stats.status(), WatcherStatus::Healthy);
+        drop(second);
+        assert_eq!(stats.status(), WatcherStatus::Stopped);
+    }
+
+    /// Records of `log`, as (target, level, message).
+    #[cfg(not(feature = "tracing"))]
+    fn captured_logs() -> &'static Mutex<Vec<(String, Level, String)>> {
+        struct Capture;
+        impl log::Log for Capture {
+            fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record<'_>) {
+                captured_logs().lock().unwrap().push((
+                    record.target().to_string(),
+                    record.level(),
+                    record.args().to_string(),
+                ));
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGS: std::sync::OnceLock<Mutex<Vec<(String, Level, String)>>> =
+            std::sync::OnceLock::new();
+        LOGS.get_or_init(|| {
+            log::set_boxed_logger(Box::new(Capture)).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+            Mutex::default()
+        })
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    #[tokio::test]
+    async fn test_log_levels() {
+        let logs = captured_logs();
+        let dir = test_dir("log_levels");
+        let _receiver = FileWatcherConfig::new(dir.join("missing"), "config")
+            .with_retry_interval(Duration::from_millis(20))
+            .with_log_target("app::config")
+            .with_log_levels(|level, failures| match failures {
+                0 => Some(level),
+                1 => Some(Level::Warn),
+                _ => Some(Level::Debug),
+            })
+            .start();
+        let failed = || -> Vec<Level> {
+            logs.lock()
+                .unwrap()
+                .iter()
+                .filter(|(target, _, message)| {
+                    target == "app::config" && message.starts_with("failed to read")
+                })
+                .map(|(_, level, _)| *level)
+                .collect()
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while failed().len() < 3 {
+            assert!(Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let failed = failed();
+        assert_eq!(failed[0], Level::Warn);
+        assert!(failed[1..].iter().all(|x| *x == Level::Debug));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_log_callback() {
+        static MESSAGES: Mutex<Vec<(Level, String, String)>> = Mutex::new(vec![]);
+        set_log_callback(|level, target, message| {
+            if target == "callback::config" || message.to_string().contains("log_callback") {
+                MESSAGES
+                    .lock()
+                    .unwrap()
+                    .push((level, target.to_string(), message.to_string()));
+            } else {
+                // for the messages of other tests
+                #[cfg(not(feature = "tracing"))]
+                log::logger().log(
+                    &log::Record::builder()
+                        .level(level)
+                        .target(target)
+                        .args(*message)
+                        .build(),
+                );
+                #[cfg(feature = "tracing")]
+                match level {
+                    Level::Error => tracing::error!("{message}"),
+                    Level::Warn => tracing::warn!("{message}"),
+                    Level::Info => tracing::info!("{message}"),
+                    Level::Debug => tracing::debug!("{message}"),
+                    Level::Trace => tracing::trace!("{message}"),
+                }
+            }
+        });
+        let dir = test_dir("log_callback");
+        let start = || {
+            FileWatcherConfig::new(dir.join("missing"), "config")
+                .with_retry_interval(Duration::from_millis(20))
+                .with_log_target("callback::config")
+                .with_shared_watch(true)
+                .start()
+        };
+        let _receivers = (start(), start());
+        // messages that aren't about a watcher use the module's target
+        logging::info!("log_callback outside of a watcher");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let messages = MESSAGES.lock().unwrap().clone();
+            let failed = messages.iter().any(|(level, target, message)| {
+                *level == Level::Error
+                    && target == "callback::config"
+                    && message.starts_with("failed to read")
+            });
+            // from sharing the watch, on behalf of the watcher
+            let shared = messages.iter().any(|(_, target, message)| {
+                target == "callback::config" && message.starts_with("sharing existing watch")
+            });
+            let outside = messages.iter().any(|(level, target, message)| {
+                *level == Level::Info
+                    && target == "really_notify::tests"
+                    && message == "log_callback outside of a watcher"
+            });
+            if failed && shared && outside {
+                break;
+            }
+            assert!(Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_slow_reload() {
+        #[cfg(not(feature = "tracing"))]
+        let logs = captured_logs();
+        #[cfg(feature = "tracing")]
+        let recorder = Recorder::default();
+        #[cfg(feature = "tracing")]
+        let events = recorder.events.clone();
+        #[cfg(feature = "tracing")]
+        let _guard = tracing::subscriber::set_default(recorder);
+        let dir = test_dir("slow_reload");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let stats = WatcherStats::new();
+        let mut receiver = FileWatcherConfig::new(&file, "slow_reload")
+            .with_parser(|raw| {
+                std::thread::sleep(Duration::from_millis(50));
+                Ok::<_, Infallible>(raw)
+            })
+            .with_slow_reload_threshold(Duration::from_millis(10))
+            .with_stats(&stats)
+            .start();
+        let mut fast = FileWatcherConfig::new(&file, "fast_reload")
+            .with_slow_reload_threshold(Duration::from_secs(10))
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        assert_eq!(fast.recv().await.unwrap(), b"1");
+        assert!(stats.last_reload_duration().unwrap() >= Duration::from_millis(50));
+        #[cfg(not(feature = "tracing"))]
+        let warnings: Vec<String> = logs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, level, _)| *level == Level::Warn)
+            .map(|(.., message)| message.clone())
+            .collect();
+        #[cfg(feature = "tracing")]
+        let warnings: Vec<String> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, message)| message.clone())
+            .collect();
+        assert!(warnings
+            .iter()
+            .any(|x| x.starts_with("reading and parsing slow_reload")
+                && x.ends_with("over the 0.010 second(s) threshold")));
+        assert!(!warnings
+            .iter()
+            .any(|x| x.starts_with("reading and parsing fast_reload")));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let dir = test_dir("history");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let history = EventHistory::new(64);
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_parser(|raw| match &*raw {
+                b"bad" => Err("bad"),
+                _ => Ok(raw),
+            })
+            .with_history(&history)
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        let events = |history: &EventHistory| {
+            history
+                .entries()
+                .into_iter()
+                .map(|x| x.event)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            events(&history),
+            [
+                HistoryEvent::Reload { initial: true },
+                HistoryEvent::Reloaded
+            ]
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(&file, "bad").unwrap();
+        let failed = HistoryEvent::Failed {
+            error: "bad".to_string(),
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !events(&history).contains(&failed) {
+            assert!(Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let events = events(&history);
+        assert!(events.iter().any(|x| matches!(
+            x,
+            HistoryEvent::Received { path, .. } if *path == file
+        )));
+        assert!(events.contains(&HistoryEvent::Reload { initial: false }));
+        assert!(history.entries().iter().all(|x| x.watcher == "config"));
+        drop(receiver);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_history_capacity() {
+        let history = EventHistory::new(2);
+        for initial in [true, false, false] {
+            history.record("config", HistoryEvent::Reload { initial });
+        }
+        let events: Vec<_> = history.entries().into_iter().map(|x| x.event).collect();
+        assert_eq!(
+            events,
+            [
+                HistoryEvent::Reload { initial: false },
+                HistoryEvent::Reload { initial: false }
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watches() {
+        let dir = test_dir("watches");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let stats = WatcherStats::new();
+        let receiver = FileWatcherConfig::new(&file, "config")
+            .with_stats(&stats)
+            .start();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !stats
+            .watches()
+            .iter()
+            .any(|x| x.path == dir || x.path == file)
+        {
+            assert!(Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(stats.watches().iter().all(|x| x.backend.is_some()));
+        drop(receiver);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !stats.watches().is_empty() {
+            assert!(Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[test]
+    fn test_thread_options() {
+        let started = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        set_thread_options(ThreadOptions::new().with_name_prefix("cfg").with_on_start({
+            let started = started.clone();
+            move || {
+                started.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }));
+        let name = thread::spawn("test", || std::thread::current().name().map(str::to_string))
+            .unwrap()
+            .join()
+            .unwrap();
+        set_thread_options(ThreadOptions::new());
+        assert_eq!(name.as_deref(), Some("cfg-test"));
+        // other tests may have spawned threads in the meantime
+        assert!(started.load(std::sync::atomic::Ordering::SeqCst) >= 1);
+    }
+
+    #[tokio::test]
+    async fn test_shared_task() {
+        let dir = test_dir("shared_task");
+        std::fs::write(dir.join("a"), "a1").unwrap();
+        std::fs::write(dir.join("b"), "b1").unwrap();
+        let start = |name: &str| {
+            FileWatcherConfig::new(dir.join(name), name)
+                .with_shared_task(true)
+                .start()
+        };
+        let mut a = start("a");
+        let mut b = start("b");
+        assert_eq!(a.recv().await.unwrap(), b"a1");
+        assert_eq!(b.recv().await.unwrap(), b"b1");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("a"), "a2").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), a.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"a2");
+        // the others keep running once one stops
+        drop(a);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("b"), "b2").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let update = b.recv().await.unwrap();
+                if update == b"b2" {
+                    break update;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(update, b"b2");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(not(any(
+        feature = "async-std",
+        feature = "smol",
+        all(feature = "wasm", target_family = "wasm", target_os = "unknown")
+    )))]
+    #[tokio::test]
+    async fn test_start_in() {
+        let dir = test_dir("start_in");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let mut tasks = tokio::task::JoinSet::new();
+        let mut receiver = FileWatcherConfig::new(&file, "config").start_in(&mut tasks);
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        drop(receiver);
+        let finished = tokio::time::timeout(Duration::from_secs(5), tasks.join_next()).await;
+        assert!(finished.unwrap().unwrap().is_ok());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(feature = "tokio-util")]
+    #[tokio::test]
+    async fn test_start_tracked() {
+        let dir = test_dir("start_tracked");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let tracker = tokio_util::task::TaskTracker::new();
+        let mut receiver = FileWatcherConfig::new(&file, "config").start_tracked(&tracker);
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // the backend's tasks along with the watcher
+        assert!(tracker.len() > 1);
+        drop(receiver);
+        tracker.close();
+        tokio::time::timeout(Duration::from_secs(5), tracker.wait())
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_self_writes() {
+        let dir = test_dir("self_writes");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let writes = SelfWrites::new();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_self_writes(&writes)
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        {
+            let _guard = writes.guard();
+            std::fs::write(&file, "2").unwrap();
+        }
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), receiver.recv())
+                .await
+                .is_err()
+        );
+        // someone else's write
+        std::fs::write(&file, "3").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"3");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_config_writer() {
+        let dir = test_dir("config_writer");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        // only possible as root
+        #[cfg(unix)]
+        let owned = std::os::unix::fs::chown(&file, Some(1234), Some(1234)).is_ok();
+        let writes = SelfWrites::new();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_parser(|raw| String::from_utf8_lossy(&raw).parse::<u32>())
+            .with_self_writes(&writes)
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let writer = ConfigWriter::new(&file)
+            .with_serializer(|x: &u32| Ok::<_, Infallible>(x.to_string().into_bytes()))
+            .with_self_writes(&writes);
+        writer.write(&2).await.unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), b"2");
+        // the temporary file was renamed over the config
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{MetadataExt, PermissionsExt};
+            let metadata = std::fs::metadata(&file).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+            if owned {
+                assert_eq!((metadata.uid(), metadata.gid()), (1234, 1234));
+            }
+        }
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), receiver.recv())
+                .await
+                .is_err()
+        );
+        // not marked as written by this process
+        ConfigWriter::new(&file).write_raw("3").await.unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, 3);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_degraded_hook() {
+        let dir = test_dir("degraded_hook");
+        let file = dir.join("config");
+        std::fs::write(&file, "x").unwrap();
+        let degraded = Arc::new(Mutex::new(vec![]));
+        let degraded2 = degraded.clone();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_parser(|raw| String::from_utf8_lossy(&raw).parse::<u32>())
+            // nothing is retried, so only the threshold passing can fire the hook
+            .with_parse_retry(Retry::OnChange)
+            .with_degraded_hook(Duration::from_millis(400), move |x| {
+                degraded2.lock().unwrap().push(x.clone())
+            })
+            .start();
+        // fixed before the threshold
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(&file, "1").unwrap();
+        let first = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first, 1);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(degraded.lock().unwrap().is_empty());
+        // broken for longer than the threshold
+        std::fs::write(&file, "y").unwrap();
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        let degraded = degraded.lock().unwrap();
+        assert_eq!(degraded.len(), 1);
+        assert!(degraded[0].duration >= Duration::from_millis(400));
+        assert_eq!(degraded[0].last_error, "invalid digit found in string");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_initial_timeout() {
+        let dir = test_dir("initial_timeout");
+        let file = dir.join("config");
+        std::fs::write(&file, "x").unwrap();
+        let timed_out = Arc::new(AtomicUsize::new(0));
+        let timed_out2 = timed_out.clone();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_parser(|raw| String::from_utf8_lossy(&raw).parse::<u32>())
+            .with_initial_timeout(Duration::from_millis(300))
+            .with_error_hook(move |e| {
+                if matches!(e, FileWatcherError::InitialTimeout(_)) {
+                    timed_out2.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            .start();
+        // the stream ends without ever producing a valid target
+        assert!(
+            tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(timed_out.load(Ordering::SeqCst), 1);
+        // a valid target in time isn't affected
+        std::fs::write(&file, "1").unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_parser(|raw| String::from_utf8_lossy(&raw).parse::<u32>())
+            .with_initial_timeout(Duration::from_millis(300))
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), 1);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        std::fs::write(&file, "2").unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read, 2);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_read_verification() {
+        let dir = test_dir("read_verification");
+        let file = dir.join("config");
+        std::fs::write(&file, "a: 1").unwrap();
+        let reads = AtomicUsize::new(0);
+        // a writer appending to the file while the first read is underway
+        let read = fs::read_verified(&file, || {
+            let first = reads.fetch_add(1, Ordering::SeqCst) == 0;
+            let file = file.clone();
+            async move {
+                let out = std::fs::read(&file);
+                if first {
+                    std::fs::write(&file, "a: 1\nb: 2").unwrap();
+                }
+                out
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(read, b"a: 1\nb: 2");
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        // one that never stops
+        let error = fs::read_verified(&file, || {
+            let file = file.clone();
+            async move {
+                let out = std::fs::read(&file);
+                std::fs::write(&file, [out.as_ref().unwrap().as_slice(), b"x"].concat()).unwrap();
+                out
+            }
+        })
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("kept changing"));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(all(feature = "inotify", any(target_os = "linux", target_os = "android")))]
+    #[tokio::test]
+    async fn test_max_symlink_depth() {
+        let dir = test_dir("max_symlink_depth");
+        std::fs::create_dir(dir.join("conf.d")).unwrap();
+        std::os::unix::fs::symlink("conf.d", dir.join("link1")).unwrap();
+        std::os::unix::fs::symlink("link1", dir.join("link2")).unwrap();
+        std::os::unix::fs::symlink("link2", dir.join("link3")).unwrap();
+        let errors = Arc::new(Mutex::new(vec![]));
+        let errors2 = errors.clone();
+        let mut receiver = FileWatcherConfig::new_directory(dir.join("link3"), "conf.d")
+            .with_backend(Backend::Inotify)
+            .with_max_symlink_depth(2)
+            .with_error_hook(move |e| {
+                if let FileWatcherError::Symlink(e) = e {
+                    errors2.lock().unwrap().push(e.clone());
+                }
+            })
+            .start();
+        // reading is left to the OS, only the watches give up
+        assert!(receiver.recv().await.unwrap().is_empty());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while errors.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            errors.lock().unwrap()[0],
+            SymlinkError::TooDeep {
+                path: absolute(&dir.join("link1")),
+                depth: 2
+            }
+        );
+        // and cycles surface the same way, through the IO errors backends return
+        let cycle = io::Error::from(SymlinkError::Cycle(dir.join("link1")));
+        assert!(matches!(
+            FileWatcherError::<Infallible>::from(cycle),
+            FileWatcherError::Symlink(SymlinkError::Cycle(_))
+        ));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hardened_reads() {
+        let dir = test_dir("hardened_reads");
+        std::fs::create_dir(dir.join("real")).unwrap();
+        std::fs::write(dir.join("real/config"), "1").unwrap();
+        std::os::unix::fs::symlink("real", dir.join("current")).unwrap();
+        std::os::unix::fs::symlink("current/config", dir.join("config")).unwrap();
+        let mut receiver = FileWatcherConfig::new(dir.join("config"), "config")
+            .with_hardened_reads()
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("real/config"), "2").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"2");
+        // cycles are caught while resolving, not left to the OS
+        std::os::unix::fs::symlink("b", dir.join("a")).unwrap();
+        std::os::unix::fs::symlink("a", dir.join("b")).unwrap();
+        let error = fd::read_nofollow(&dir.join("a"), 16).unwrap_err();
+        assert!(matches!(
+            FileWatcherError::<Infallible>::from(error),
+            FileWatcherError::Symlink(SymlinkError::Cycle(_))
+        ));
+        let error = fd::read_nofollow(&dir.join("real"), 16).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_permission_policy() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = test_dir("permission_policy");
+        let file = dir.join("credentials");
+        std::fs::write(&file, "secret").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o666)).unwrap();
+        let violations = Arc::new(Mutex::new(vec![]));
+        let violations2 = violations.clone();
+        let mut receiver = FileWatcherConfig::new(&file, "credentials")
+            .with_permission_policy(PermissionPolicy::NOT_WORLD_WRITABLE)
+            .with_retry_interval(Duration::from_millis(100))
+            .with_error_hook(move |e| {
+                if let FileWatcherError::Policy(e) = e {
+                    violations2.lock().unwrap().push(e.clone());
+                }
+            })
+            .start();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), receiver.recv())
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            violations.lock().unwrap()[0],
+            PolicyViolation::Mode {
+                path: file.clone(),
+                mode: 0o666,
+                forbidden: 0o002
+            }
+        );
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read, b"secret");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_read_lock() {
+        use std::os::fd::AsRawFd;
+        let dir = test_dir("read_lock");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let writer = std::fs::File::open(&file).unwrap();
+        assert_eq!(
+            unsafe { libc::flock(writer.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) },
+            0
+        );
+        let timeouts = Arc::new(Mutex::new(0));
+        let timeouts2 = timeouts.clone();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_read_lock(ReadLock::Flock, Duration::from_millis(100))
+            .with_retry_interval(Duration::from_millis(100))
+            .with_error_hook(move |e| {
+                if let FileWatcherError::Io(e) = e {
+                    if e.kind() == io::ErrorKind::TimedOut {
+                        *timeouts2.lock().unwrap() += 1;
+                    }
+                }
+            })
+            .start();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), receiver.recv())
+                .await
+                .is_err()
+        );
+        assert!(*timeouts.lock().unwrap() > 0);
+        // closing the only descriptor holding it releases the lock
+        drop(writer);
+        let read = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read, b"1");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_required_owner() {
+        let dir = test_dir("required_owner");
+        let file = dir.join("credentials");
+        std::fs::write(&file, "secret").unwrap();
+        let current = Ownership::current_user().uid().unwrap();
+        let other = current + 1000;
+        let violations = Arc::new(Mutex::new(vec![]));
+        let violations2 = violations.clone();
+        let mut receiver = FileWatcherConfig::new(&file, "credentials")
+            .with_required_owner(Ownership::user(other))
+            .with_retry_interval(Duration::from_millis(100))
+            .with_error_hook(move |e| {
+                if let FileWatcherError::Policy(e) = e {
+                    violations2.lock().unwrap().push(e.clone());
+                }
+            })
+            .start();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), receiver.recv())
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            violations.lock().unwrap()[0],
+            PolicyViolation::Owner {
+                path: file.clone(),
+                uid: current,
+                required: other
+            }
+        );
+        // handing the file over needs root
+        if std::os::unix::fs::chown(&file, Some(other), None).is_ok() {
+            let read = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(read, b"secret");
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_empty_files() {
+        let dir = test_dir("empty_files");
+        let file = dir.join("config");
+        std::fs::write(&file, "a").unwrap();
+        let events = Arc::new(Mutex::new(vec![]));
+        let events2 = events.clone();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_empty_files(EmptyFiles::Report)
+            .with_lifecycle_hook(move |event| {
+                if let LifecycleEvent::Empty { .. } = event {
+                    events2.lock().unwrap().push(event.clone());
+                }
+            })
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"a");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // an atomic writer that truncates before writing
+        std::fs::write(&file, "").unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), receiver.recv())
+                .await
+                .is_err()
+        );
+        // truncating and closing may each be read
+        let events = events.lock().unwrap().clone();
+        assert!(!events.is_empty());
+        assert!(events
+            .iter()
+            .all(|x| *x == LifecycleEvent::Empty { path: file.clone() }));
+        std::fs::write(&file, "b").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"b");
+
+        // empty on startup is waited out too
+        let file = dir.join("other");
+        std::fs::write(&file, "").unwrap();
+        let mut receiver = FileWatcherConfig::new(&file, "other")
+            .with_empty_files(EmptyFiles::Wait)
+            .with_retry_interval(Duration::from_millis(100))
+            .start();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        std::fs::write(&file, "c").unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read, b"c");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_deletion_grace() {
+        let dir = test_dir("deletion_grace");
+        let file = dir.join("config");
+        let conf_d = dir.join("conf.d");
+        std::fs::write(&file, "1").unwrap();
+        std::fs::create_dir(&conf_d).unwrap();
+        let errors = Arc::new(AtomicUsize::new(0));
+        let errors2 = errors.clone();
+        let errors3 = errors.clone();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_deletion_grace(Duration::from_secs(5))
+            .with_error_hook(move |_| {
+                errors2.fetch_add(1, Ordering::SeqCst);
+            })
+            .start();
+        let mut directory = FileWatcherConfig::new_directory(&conf_d, "conf.d")
+            .with_deletion_grace(Duration::from_secs(5))
+            .with_error_hook(move |_| {
+                errors3.fetch_add(1, Ordering::SeqCst);
+            })
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        assert!(directory.recv().await.unwrap().is_empty());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::remove_file(&file).unwrap();
+        std::fs::remove_dir(&conf_d).unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        std::fs::write(&file, "2").unwrap();
+        std::fs::create_dir(&conf_d).unwrap();
+        std::fs::write(conf_d.join("a.conf"), "a").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(3), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"2");
+        let update = tokio::time::timeout(Duration::from_secs(3), async {
+            loop {
+                let update = directory.recv().await.unwrap();
+                if !update.is_empty() {
+                    break update;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(update, vec![(conf_d.join("a.conf"), b"a".to_vec())]);
+        assert_eq!(errors.load(Ordering::SeqCst), 0);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Reports a change whenever told to, and nothing else.
+    struct ManualBackend(Notify);
+
+    impl WatchBackend for ManualBackend {
+        fn watch<'a>(&'a self, context: &'a WatcherContext) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(async move {
+                loop {
+                    self.0.notified().await;
+                    context.changed();
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_backend() {
+        let dir = test_dir("custom_backend");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let backend = Arc::new(ManualBackend(Notify::new()));
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_custom_backend(backend.clone())
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        std::fs::write(&file, "2").unwrap();
+        // nothing else is watching the file
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), receiver.recv())
+                .await
+                .is_err()
+        );
+        backend.0.notify_one();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"2");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Panics the first time it watches, then behaves like [`ManualBackend`].
+    struct PanickingBackend(AtomicUsize, Notify);
+
+    impl WatchBackend for PanickingBackend {
+        fn watch<'a>(&'a self, context: &'a WatcherContext) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(async move {
+                if self.0.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("backend test panic");
+                }
+                loop {
+                    self.1.notified().await;
+                    context.changed();
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_supervised_backend() {
+        let dir = test_dir("supervised_backend");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let backend = Arc::new(PanickingBackend(AtomicUsize::new(0), Notify::new()));
+        let crashes = Arc::new(AtomicUsize::new(0));
+        let crashes2 = crashes.clone();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_retry_interval(Duration::from_millis(50))
+            .with_custom_backend(backend.clone())
+            .with_error_hook(move |e| {
+                if matches!(e, FileWatcherError::Crashed(_)) {
+                    crashes2.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            // not shared, which would leave backend errors to the shared watch
+            .with_event_filter(|_, _| true)
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        while backend.0.load(Ordering::SeqCst) < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(crashes.load(Ordering::SeqCst), 1);
+        // the restarted backend reports changes again, after reading the target again for any missed in between
+        std::fs::write(&file, "2").unwrap();
+        backend.1.notify_one();
+        let update = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let update = receiver.recv().await.unwrap();
+                if update != b"1" {
+                    break update;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(update, b"2");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_supervised_run() {
+        let dir = test_dir("supervised_run");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let parses = AtomicUsize::new(0);
+        let crashes = Arc::new(Mutex::new(vec![]));
+        let crashes2 = crashes.clone();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_retry_interval(Duration::from_millis(50))
+            .with_parser(move |raw| {
+                if parses.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("parser test panic");
+                }
+                Ok::<_, Infallible>(raw)
+            })
+            .with_error_hook(move |e| {
+                if let FileWatcherError::Crashed(message) = e {
+                    crashes2.lock().unwrap().push(message.clone());
+                }
+            })
+            .start();
+        // read again once restarted
+        let first = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first, b"1");
+        assert_eq!(
+            *crashes.lock().unwrap(),
+            ["read loop panicked: parser test panic"]
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_stat_check() {
+        let dir = test_dir("stat_check");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let backend = Arc::new(ManualBackend(Notify::new()));
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_custom_backend(backend.clone())
+            .with_stat_check(Duration::from_millis(200))
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        // a change the backend never reports
+        std::fs::write(&file, "22").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"22");
+        // one it does isn't read twice
+        std::fs::write(&file, "333").unwrap();
+        backend.0.notify_one();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"333");
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(receiver.try_recv().is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn test_mock_backend() {
+        let dir = test_dir("mock_backend");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let mock = MockBackend::new();
+        let mut receiver = FileWatcherConfig::new(&file, "config")
+            .with_custom_backend(mock.clone())
+            .start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        std::fs::write(&file, "2").unwrap();
+        // delivered even if the watch hasn't started yet
+        mock.trigger();
+        assert_eq!(receiver.recv().await.unwrap(), b"2");
+        assert_eq!(mock.watching(), 1);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_fd() {
+        let dir = test_dir("fd");
+        let file = dir.join("config");
+        std::fs::write(&file, "1").unwrap();
+        let mut receiver =
+            FileWatcherConfig::from_fd(std::fs::File::open(&file).unwrap(), "config").start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        // watches are established after the initial read
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // the open file is followed, not the path
+        std::fs::rename(&file, dir.join("moved")).unwrap();
+        std::fs::write(&file, "unrelated").unwrap();
+        std::fs::write(dir.join("moved"), "2").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let update = receiver.recv().await.unwrap();
+                if update != b"1" {
+                    break update;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(update, b"2");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_unusual_paths() {
+        let dir = test_dir("unusual_paths");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("config"), "1").unwrap();
+        // watches above the `..` aren't on the way to the target
+        let mut receiver = FileWatcherConfig::new(dir.join("sub/../config"), "config").start();
+        assert_eq!(receiver.recv().await.unwrap(), b"1");
+        let mut directory = FileWatcherConfig::new_directory(dir.join("sub/.."), "dir").start();
+        assert_eq!(directory.recv().await.unwrap().len(), 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("config"), "2").unwrap();
+        let update = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update, b"2");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_normalize() {
+        #[cfg(unix)]
+        {
+            assert_eq!(
+                normalize(Path::new("/etc//app/../x")).unwrap(),
+                Path::new("/etc/app/../x")
+            );
+            // a NUL byte is an error, rather than a panic
+            assert!(matches!(
+                FileWatcherError::<Infallible>::from(
+                    fs::c_path(Path::new("/etc/a\0b")).unwrap_err()
+                ),
+                FileWatcherError::Path(PathError::Nul(_))
+            ));
+        }
+        #[cfg(windows)]
+        {
+            assert_eq!(
+                normalize(Path::new(r"C:\etc\app\..\x")).unwrap(),
+                Path::new(r"C:\etc\x")
+            );
+            assert_eq!(
+                normalize(Path::new(r"\\?\C:\etc\app\..\x")).unwrap(),
+                Path::new(r"\\?\C:\etc\x")
+            );
+            assert_eq!(
+                normalize(Path::new(r"\\server\share\app\..\..\x")).unwrap(),
+                Path::new(r"\\server\share\x")
+            );
         }
+        assert!(matches!(
+            FileWatcherError::<Infallible>::from(normalize(Path::new("app/x")).unwrap_err()),
+            FileWatcherError::Path(PathError::Relative(_))
+        ));
     }
 }
@@ -0,0 +1,90 @@
+#[cfg(unix)]
+use std::{
+    fs::File,
+    io,
+    os::fd::AsRawFd,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+use crate::logging::debug;
+
+#[cfg(unix)]
+use crate::rt;
+
+/// How often a held lock is tried again while waiting for it.
+#[cfg(unix)]
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Kind of advisory lock taken on the target before reading it, set with [`crate::FileWatcherConfig::with_read_lock`].
+/// Use whichever the writers take; the two don't see each other's locks everywhere.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadLock {
+    /// `flock(2)`, held per open file.
+    Flock,
+    /// POSIX record locks with `fcntl(2)` over the whole file, which also work over NFS. These are held per process, so
+    /// writers in the same process aren't waited for.
+    Fcntl,
+}
+
+/// Shared lock on a file, released once dropped.
+#[cfg(unix)]
+pub(crate) struct LockGuard(#[allow(dead_code)] File);
+
+/// Takes a shared `lock` on `path`, waiting up to `timeout` for writers holding an exclusive one to release it.
+#[cfg(unix)]
+pub(crate) async fn lock_shared(
+    path: &Path,
+    lock: ReadLock,
+    timeout: Duration,
+) -> io::Result<LockGuard> {
+    let file = {
+        let path = path.to_path_buf();
+        rt::spawn_blocking(move || File::open(path)).await??
+    };
+    let started = Instant::now();
+    let mut logged = false;
+    while !try_lock(&file, lock)? {
+        if started.elapsed() >= timeout {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "'{}' stayed locked for {:.1} second(s)",
+                    path.display(),
+                    timeout.as_secs_f64()
+                ),
+            ));
+        }
+        if !logged {
+            debug!("'{}' is locked, waiting to read it", path.display());
+            logged = true;
+        }
+        rt::sleep(LOCK_POLL_INTERVAL).await;
+    }
+    Ok(LockGuard(file))
+}
+
+/// Returns false if someone else holds an exclusive lock.
+#[cfg(unix)]
+fn try_lock(file: &File, lock: ReadLock) -> io::Result<bool> {
+    let out = match lock {
+        ReadLock::Flock => unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) },
+        ReadLock::Fcntl => {
+            // zeroed, as the fields and their order differ between platforms
+            let mut range: libc::flock = unsafe { std::mem::zeroed() };
+            range.l_type = libc::F_RDLCK as _;
+            range.l_whence = libc::SEEK_SET as _;
+            unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &range) }
+        }
+    };
+    if out == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(code) if [libc::EWOULDBLOCK, libc::EAGAIN, libc::EACCES].contains(&code) => Ok(false),
+        Some(libc::EINTR) => try_lock(file, lock),
+        _ => Err(e),
+    }
+}
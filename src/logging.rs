@@ -0,0 +1,122 @@
+//! Log macros, logging through `log`, or with the `tracing` feature, through `tracing`, so watchers show up in span-based
+//! observability along with the spans and structured fields they add, unless a callback is set with
+//! [`set_log_callback`]. And the logger watchers log messages about their target with.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use log::Level;
+
+/// Hands a message to the callback if one is set, or to the `log` or `tracing` macro `$macro` otherwise.
+macro_rules! dispatch {
+    ($level:ident, $macro:ident, $($arg:tt)+) => {
+        match $crate::logging::callback() {
+            Some(callback) => callback(::log::Level::$level, module_path!(), &format_args!($($arg)+)),
+            #[cfg(not(feature = "tracing"))]
+            None => ::log::$macro!($($arg)+),
+            #[cfg(feature = "tracing")]
+            None => ::tracing::$macro!($($arg)+),
+        }
+    };
+}
+
+#[allow(unused_macros)] // some go unused depending on features
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::logging::dispatch!(Error, error, $($arg)+) };
+}
+
+// named apart from the builtin `warn` attribute, which a plain `warn` would be ambiguous with
+#[allow(unused_macros)] // some go unused depending on features
+macro_rules! warning {
+    ($($arg:tt)+) => { $crate::logging::dispatch!(Warn, warn, $($arg)+) };
+}
+
+#[allow(unused_macros)] // some go unused depending on features
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::logging::dispatch!(Info, info, $($arg)+) };
+}
+
+#[allow(unused_macros)] // some go unused depending on features
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::logging::dispatch!(Debug, debug, $($arg)+) };
+}
+
+#[allow(unused_imports)] // some go unused depending on features
+pub(crate) use {debug, dispatch, error, info, warning as warn};
+
+/// Receives every message of the crate instead of `log` or `tracing`, set with [`set_log_callback`].
+type LogCallback = Arc<dyn Fn(Level, &str, &fmt::Arguments<'_>) + Send + Sync>;
+
+static CALLBACK: Mutex<Option<LogCallback>> = Mutex::new(None);
+
+/// Whether [`CALLBACK`] is set, to check without taking the lock.
+static CALLBACK_SET: AtomicBool = AtomicBool::new(false);
+
+/// Delivers the messages of the crate, from every watcher, to `callback` instead of `log`, or `tracing` with the
+/// `tracing` feature, i.e. for an FFI host or plugin system with logging of its own and no `log` logger. It is handed
+/// the level of each message, its target, i.e. the module it comes from or the one set with
+/// [`crate::FileWatcherConfig::with_log_target`], and the message. Set before starting watchers to cover all their
+/// messages. The debug events the `tracing` feature adds for its structured fields still go to `tracing`.
+pub fn set_log_callback(
+    callback: impl Fn(Level, &str, &fmt::Arguments<'_>) + Send + Sync + 'static,
+) {
+    *CALLBACK.lock().unwrap() = Some(Arc::new(callback));
+    CALLBACK_SET.store(true, Ordering::Release);
+}
+
+/// The callback set with [`set_log_callback`], if any.
+pub(crate) fn callback() -> Option<LogCallback> {
+    if !CALLBACK_SET.load(Ordering::Acquire) {
+        return None;
+    }
+    CALLBACK.lock().unwrap().clone()
+}
+
+/// Picks the level of a message at `level`, about `failures` in a row, or drops it, see
+/// [`crate::FileWatcherConfig::with_log_levels`].
+pub(crate) type LevelMap = Arc<dyn Fn(Level, u32) -> Option<Level> + Send + Sync>;
+
+/// Target of the messages of watchers without one of their own.
+const DEFAULT_TARGET: &str = "really_notify";
+
+/// Logs the messages of a watcher about its target, with its own target and levels.
+#[derive(Clone, Default)]
+pub(crate) struct Logger {
+    pub(crate) target: Option<String>,
+    pub(crate) levels: Option<LevelMap>,
+}
+
+impl Logger {
+    /// Logs `message` at `level`, or the level it is mapped to. `failures` is how many times in a row what the message
+    /// is about failed, or 0 if it isn't about a failure.
+    pub(crate) fn log(&self, level: Level, failures: u32, message: fmt::Arguments<'_>) {
+        let level = match &self.levels {
+            Some(levels) => match levels(level, failures) {
+                Some(level) => level,
+                None => return,
+            },
+            None => level,
+        };
+        let target = self.target.as_deref().unwrap_or(DEFAULT_TARGET);
+        if let Some(callback) = callback() {
+            callback(level, target, &message);
+            return;
+        }
+        #[cfg(not(feature = "tracing"))]
+        log::log!(target: target, level, "{message}");
+        // targets of `tracing` events are static, the watcher span tells watchers apart instead
+        #[cfg(feature = "tracing")]
+        match level {
+            Level::Error => tracing::error!(target: DEFAULT_TARGET, "{message}"),
+            Level::Warn => tracing::warn!(target: DEFAULT_TARGET, "{message}"),
+            Level::Info => tracing::info!(target: DEFAULT_TARGET, "{message}"),
+            Level::Debug => tracing::debug!(target: DEFAULT_TARGET, "{message}"),
+            Level::Trace => tracing::trace!(target: DEFAULT_TARGET, "{message}"),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt::Display;
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+/// Error from [`crate::FileWatcherConfig::with_deep_merge`] or [`crate::FileWatcherConfig::with_env_overlay`].
+#[derive(Error, Debug)]
+pub enum MergeError<E: Display> {
+    #[error("{0}")]
+    Parse(E),
+    #[error("failed to deserialize merged config: {0}")]
+    Deserialize(serde_json::Error),
+}
+
+/// Recursively merges `overlay` into `base`. Objects are merged key by key, anything else in `overlay` replaces `base`.
+pub fn deep_merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                deep_merge(base.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Applies environment variable overrides named `{prefix}__SECTION__KEY` on top of `value`.
+pub(crate) fn apply_env_overlay<T: Serialize + DeserializeOwned>(
+    prefix: &str,
+    value: T,
+) -> Result<T, serde_json::Error> {
+    let mut value = serde_json::to_value(value)?;
+    env_overlay(&mut value, prefix, std::env::vars());
+    serde_json::from_value(value)
+}
+
+/// Path segments are matched to existing keys case-insensitively, and lowercased otherwise.
+/// Values are parsed as JSON (i.e. numbers, booleans, arrays) unless they replace a string, falling back to a string.
+/// Less nested overrides are applied first, so more specific ones win.
+fn env_overlay(value: &mut Value, prefix: &str, vars: impl IntoIterator<Item = (String, String)>) {
+    let prefix = format!("{prefix}__");
+    let mut overrides: Vec<(Vec<String>, String)> = vars
+        .into_iter()
+        .filter_map(|(name, raw)| {
+            let path = name.strip_prefix(&prefix)?;
+            Some((
+                path.split("__").map(str::to_string).collect::<Vec<_>>(),
+                raw,
+            ))
+        })
+        .filter(|(path, _)| path.iter().all(|x| !x.is_empty()))
+        .collect();
+    overrides.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+    for (path, raw) in overrides {
+        let mut current = &mut *value;
+        for segment in path {
+            if !current.is_object() {
+                *current = Value::Object(Default::default());
+            }
+            let Value::Object(object) = current else {
+                unreachable!()
+            };
+            let key = object
+                .keys()
+                .find(|key| key.eq_ignore_ascii_case(&segment))
+                .cloned()
+                .unwrap_or_else(|| segment.to_lowercase());
+            current = object.entry(key).or_insert(Value::Null);
+        }
+        *current = match current {
+            Value::String(_) => Value::String(raw),
+            _ => serde_json::from_str(&raw).unwrap_or(Value::String(raw)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_deep_merge() {
+        let mut base = json!({
+            "server": { "port": 80, "host": "0.0.0.0" },
+            "features": ["a", "b"],
+        });
+        deep_merge(
+            &mut base,
+            json!({
+                "server": { "port": 8080 },
+                "features": ["c"],
+                "debug": true,
+            }),
+        );
+        assert_eq!(
+            base,
+            json!({
+                "server": { "port": 8080, "host": "0.0.0.0" },
+                "features": ["c"],
+                "debug": true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_env_overlay() {
+        let mut value = json!({
+            "server": { "port": 80, "hostName": "0.0.0.0", "tag": "1" },
+        });
+        env_overlay(
+            &mut value,
+            "APP",
+            [
+                ("APP__SERVER__PORT", "8080"),
+                ("APP__SERVER__HOSTNAME", "localhost"),
+                ("APP__SERVER__TAG", "2"),
+                ("APP__DEBUG", "true"),
+                ("APP__LIMITS__RPS", "[1, 2]"),
+                ("OTHER__DEBUG", "false"),
+            ]
+            .map(|(name, raw)| (name.to_string(), raw.to_string())),
+        );
+        assert_eq!(
+            value,
+            json!({
+                "server": { "port": 8080, "hostName": "localhost", "tag": "2" },
+                "debug": true,
+                "limits": { "rps": [1, 2] },
+            })
+        );
+    }
+}
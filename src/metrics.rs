@@ -0,0 +1,128 @@
+//! Metrics recorded through the `metrics` facade, for whichever exporter the application installed, i.e.
+//! `metrics-exporter-prometheus`, to track config rollout health. All are labelled with the watcher's log name, as
+//! `watcher`, and the labels set with [`crate::FileWatcherConfig::with_label`].
+
+use std::{sync::Once, time::Duration};
+
+use ::metrics::{
+    counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Label, Unit,
+};
+
+use crate::rt::SystemTime;
+
+const RELOADS: &str = "really_notify_reloads_total";
+const PARSE_DURATION: &str = "really_notify_parse_duration_seconds";
+const LAST_RELOAD: &str = "really_notify_last_reload_timestamp_seconds";
+const ACTIVE_WATCHES: &str = "really_notify_active_watches";
+const RELOAD_DURATION: &str = "really_notify_reload_duration_seconds";
+const SLOW_RELOADS: &str = "really_notify_slow_reloads_total";
+
+fn describe() {
+    static DESCRIBED: Once = Once::new();
+    DESCRIBED.call_once(|| {
+        describe_counter!(
+            RELOADS,
+            "Reads of the target that parsed to an update, with result \"success\", or failed, with result \"failure\""
+        );
+        describe_histogram!(
+            PARSE_DURATION,
+            Unit::Seconds,
+            "How long parsing the target took"
+        );
+        describe_gauge!(
+            LAST_RELOAD,
+            Unit::Seconds,
+            "When the target last parsed to an update, as a unix timestamp, to alert on the time since"
+        );
+        describe_gauge!(ACTIVE_WATCHES, "Watchers running");
+        describe_histogram!(
+            RELOAD_DURATION,
+            Unit::Seconds,
+            "How long reading and parsing the target took, whether it failed or not"
+        );
+        describe_counter!(
+            SLOW_RELOADS,
+            "Reads and parses of the target over the threshold set with with_slow_reload_threshold"
+        );
+    });
+}
+
+/// The labels of a watcher, followed by `extra`.
+fn labels(
+    watcher: &str,
+    labels: &[(String, String)],
+    extra: Option<(&'static str, &'static str)>,
+) -> Vec<Label> {
+    let mut out = vec![Label::new("watcher", watcher.to_string())];
+    out.extend(
+        labels
+            .iter()
+            .map(|(key, value)| Label::new(key.clone(), value.clone())),
+    );
+    out.extend(extra.map(|(key, value)| Label::new(key, value)));
+    out
+}
+
+/// The target was parsed, to an update if `reloaded`, in `duration`.
+pub(crate) fn parsed(
+    watcher: &str,
+    watcher_labels: &[(String, String)],
+    duration: Duration,
+    reloaded: bool,
+) {
+    describe();
+    histogram!(PARSE_DURATION, labels(watcher, watcher_labels, None)).record(duration);
+    if reloaded {
+        counter!(
+            RELOADS,
+            labels(watcher, watcher_labels, Some(("result", "success")))
+        )
+        .increment(1);
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        gauge!(LAST_RELOAD, labels(watcher, watcher_labels, None)).set(now.as_secs_f64());
+    }
+}
+
+/// Reading and parsing the target took `duration`, over the slow reload threshold if `slow`.
+pub(crate) fn reload_duration(
+    watcher: &str,
+    watcher_labels: &[(String, String)],
+    duration: Duration,
+    slow: bool,
+) {
+    describe();
+    histogram!(RELOAD_DURATION, labels(watcher, watcher_labels, None)).record(duration);
+    if slow {
+        counter!(SLOW_RELOADS, labels(watcher, watcher_labels, None)).increment(1);
+    }
+}
+
+/// Reading or parsing the target failed.
+pub(crate) fn failed(watcher: &str, watcher_labels: &[(String, String)]) {
+    describe();
+    counter!(
+        RELOADS,
+        labels(watcher, watcher_labels, Some(("result", "failure")))
+    )
+    .increment(1);
+}
+
+/// Counts a watcher as active until dropped.
+pub(crate) struct Active(Vec<Label>);
+
+impl Active {
+    pub(crate) fn new(watcher: &str, watcher_labels: &[(String, String)]) -> Self {
+        describe();
+        let labels = labels(watcher, watcher_labels, None);
+        gauge!(ACTIVE_WATCHES, labels.clone()).increment(1.0);
+        Self(labels)
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        gauge!(ACTIVE_WATCHES, self.0.clone()).decrement(1.0);
+    }
+}
@@ -0,0 +1,96 @@
+//! OpenTelemetry instrumentation through the global tracer and meter providers, for whichever SDK and exporters the
+//! application installed: a `reload` span per reload attempt, with `read`, `parse`, and `emit` child spans for its phases,
+//! and reload metrics. All carry the watcher's log name as the `watcher` attribute, and the labels set with
+//! [`crate::FileWatcherConfig::with_label`] as attributes of their own.
+
+use std::{fmt::Display, future::Future, path::Path};
+
+use opentelemetry::{
+    context::FutureExt,
+    global,
+    trace::{Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+
+use crate::rt::Instant;
+
+const SCOPE: &str = "really-notify";
+
+/// A reload attempt, ended by [`Reload::finish`].
+pub(crate) struct Reload {
+    cx: Context,
+    /// The watcher's log name and labels.
+    attributes: Vec<KeyValue>,
+    started: Instant,
+}
+
+impl Reload {
+    pub(crate) fn start(
+        watcher: &str,
+        labels: &[(String, String)],
+        path: &Path,
+        initial: bool,
+    ) -> Self {
+        let mut attributes = vec![KeyValue::new("watcher", watcher.to_string())];
+        attributes.extend(
+            labels
+                .iter()
+                .map(|(key, value)| KeyValue::new(key.clone(), value.clone())),
+        );
+        let tracer = global::tracer(SCOPE);
+        let span = tracer
+            .span_builder("reload")
+            .with_attributes(attributes.iter().cloned().chain([
+                KeyValue::new("file.path", path.display().to_string()),
+                KeyValue::new("initial", initial),
+            ]))
+            .start(&tracer);
+        Self {
+            cx: Context::current_with_span(span),
+            attributes,
+            started: Instant::now(),
+        }
+    }
+
+    /// For the phases of the attempt to start their spans in.
+    pub(crate) fn context(&self) -> Context {
+        self.cx.clone()
+    }
+
+    /// Ends the attempt as `result`, which is `success` if it was emitted, `unchanged` if there was nothing to emit, or
+    /// `failure` with `error`.
+    pub(crate) fn finish(self, result: &'static str, error: Option<&dyn Display>) {
+        let span = self.cx.span();
+        if let Some(error) = error {
+            span.set_status(Status::error(error.to_string()));
+        }
+        span.set_attribute(KeyValue::new("result", result));
+        span.end();
+        let mut attributes = self.attributes;
+        attributes.push(KeyValue::new("result", result));
+        let meter = global::meter(SCOPE);
+        meter
+            .f64_histogram("really_notify.reload.duration")
+            .with_unit("s")
+            .with_description(
+                "How long reload attempts took, from reading the target to emitting it",
+            )
+            .build()
+            .record(self.started.elapsed().as_secs_f64(), &attributes);
+        meter
+            .u64_counter("really_notify.reloads")
+            .with_description("Reload attempts")
+            .build()
+            .add(1, &attributes);
+    }
+}
+
+/// Runs `future` within a span for `phase` of the current reload attempt, if any.
+pub(crate) async fn phase<F: Future>(phase: &'static str, future: F) -> F::Output {
+    let cx = Context::current();
+    if !cx.has_active_span() {
+        return future.await;
+    }
+    let span = global::tracer(SCOPE).start_with_context(phase, &cx);
+    future.with_context(cx.with_span(span)).await
+}
@@ -0,0 +1,159 @@
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+#[cfg(unix)]
+use crate::{fs, FileWatcherError};
+
+/// Permission bits a target may not have, checked before every read with
+/// [`crate::FileWatcherConfig::with_permission_policy`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PermissionPolicy {
+    forbidden: u32,
+}
+
+impl PermissionPolicy {
+    /// Refuse targets anyone can write to (`o+w`).
+    pub const NOT_WORLD_WRITABLE: Self = Self::forbid(0o002);
+    /// Refuse targets anyone but their owner can read or write, as ssh does for private keys (`go+rwx`).
+    pub const OWNER_ONLY: Self = Self::forbid(0o077);
+
+    /// Refuse targets with any of the `mode` bits set, i.e. `0o022` for group or world writable targets.
+    pub const fn forbid(mode: u32) -> Self {
+        Self {
+            forbidden: mode & 0o7777,
+        }
+    }
+
+    /// Mode bits a target may not have.
+    pub fn forbidden(&self) -> u32 {
+        self.forbidden
+    }
+
+    /// Whether a target with `mode` passes.
+    pub fn allows(&self, mode: u32) -> bool {
+        mode & self.forbidden == 0
+    }
+}
+
+/// Who a target must be owned by, checked before every read with [`crate::FileWatcherConfig::with_required_owner`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Ownership {
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl Ownership {
+    /// Require the target to be owned by the user `uid`.
+    pub fn user(uid: u32) -> Self {
+        Self {
+            uid: Some(uid),
+            gid: None,
+        }
+    }
+
+    /// Require the target to belong to the group `gid`.
+    pub fn group(gid: u32) -> Self {
+        Self {
+            uid: None,
+            gid: Some(gid),
+        }
+    }
+
+    /// Require the target to be owned by the effective user of this process.
+    #[cfg(unix)]
+    pub fn current_user() -> Self {
+        Self::user(unsafe { libc::geteuid() })
+    }
+
+    /// Also require the target to belong to the group `gid`.
+    pub fn and_group(mut self, gid: u32) -> Self {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// The user the target must be owned by, if any.
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    /// The group the target must belong to, if any.
+    pub fn gid(&self) -> Option<u32> {
+        self.gid
+    }
+}
+
+/// A target that failed a policy it is required to pass before being read.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PolicyViolation {
+    /// `path` has `mode`, which has some of the `forbidden` bits set.
+    #[error("'{}' has mode {mode:o}, but {forbidden:o} is forbidden", path.display())]
+    Mode {
+        path: PathBuf,
+        mode: u32,
+        forbidden: u32,
+    },
+    /// `path` is owned by the user `uid`, rather than `required`.
+    #[error("'{}' is owned by uid {uid}, but must be owned by uid {required}", path.display())]
+    Owner {
+        path: PathBuf,
+        uid: u32,
+        required: u32,
+    },
+    /// `path` belongs to the group `gid`, rather than `required`.
+    #[error("'{}' belongs to gid {gid}, but must belong to gid {required}", path.display())]
+    Group {
+        path: PathBuf,
+        gid: u32,
+        required: u32,
+    },
+}
+
+/// Checks the target (after following symlinks) against whichever policies are set.
+#[cfg(unix)]
+pub(crate) async fn check<E>(
+    path: &Path,
+    permissions: Option<&PermissionPolicy>,
+    ownership: Option<&Ownership>,
+) -> Result<(), FileWatcherError<E>> {
+    use std::os::unix::fs::MetadataExt;
+    if permissions.is_none() && ownership.is_none() {
+        return Ok(());
+    }
+    let metadata = fs::metadata(path).await?;
+    let path = path.to_path_buf();
+    if let Some(policy) = permissions {
+        let mode = metadata.mode() & 0o7777;
+        if !policy.allows(mode) {
+            return Err(PolicyViolation::Mode {
+                path,
+                mode,
+                forbidden: policy.forbidden,
+            }
+            .into());
+        }
+    }
+    let Some(ownership) = ownership else {
+        return Ok(());
+    };
+    if let Some(required) = ownership.uid.filter(|x| *x != metadata.uid()) {
+        return Err(PolicyViolation::Owner {
+            path,
+            uid: metadata.uid(),
+            required,
+        }
+        .into());
+    }
+    if let Some(required) = ownership.gid.filter(|x| *x != metadata.gid()) {
+        return Err(PolicyViolation::Group {
+            path,
+            gid: metadata.gid(),
+            required,
+        }
+        .into());
+    }
+    Ok(())
+}
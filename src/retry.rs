@@ -0,0 +1,77 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+/// How long to wait before retrying a failed read, set with [`crate::FileWatcherConfig::with_retry_backoff`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    jitter: f64,
+}
+
+impl Backoff {
+    /// Retry every `interval`, as [`crate::FileWatcherConfig::retry_interval`] does without a backoff.
+    pub fn fixed(interval: Duration) -> Self {
+        Self {
+            initial: interval,
+            max: interval,
+            jitter: 0.0,
+        }
+    }
+
+    /// Wait `initial` after the first failure, doubling the wait after every further one, up to `max`.
+    pub fn exponential(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max: max.max(initial),
+            jitter: 0.0,
+        }
+    }
+
+    /// Shorten each wait by a random fraction of up to `jitter` (clamped to `0.0..=1.0`) of it, so watchers across a
+    /// fleet failing at the same time don't keep retrying in lockstep.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// How long to wait after `failures` consecutive failures, starting at one.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(31);
+        let delay = self
+            .initial
+            .checked_mul(1 << exponent)
+            .map_or(self.max, |x| x.min(self.max));
+        delay.mul_f64(1.0 - self.jitter * random())
+    }
+}
+
+/// A random number in `0.0..1.0`, good enough to spread retries out.
+fn random() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// When to try again after failing to read or parse the target, set per class of failure with
+/// [`crate::FileWatcherConfig::with_read_retry`] and [`crate::FileWatcherConfig::with_parse_retry`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Retry {
+    /// Once the backoff is over, or earlier if a missing target is created.
+    After(Backoff),
+    /// Only once the target changes, i.e. for parse errors, which reading the same contents again won't fix.
+    OnChange,
+}
+
+impl Retry {
+    /// How long to wait after `failures` consecutive failures, `None` to wait for a change.
+    pub(crate) fn delay(&self, failures: u32) -> Option<Duration> {
+        match self {
+            Retry::After(backoff) => Some(backoff.delay(failures)),
+            Retry::OnChange => None,
+        }
+    }
+}
@@ -0,0 +1,461 @@
+//! The async runtime watchers run their tasks, timers, and file descriptors on: tokio, unless the `async-std` or `smol`
+//! feature is enabled, in which case they run on that and never need a tokio runtime (async-std wins if both are).
+//! Only tokio's channels are used either way, which work on any runtime.
+
+use std::{
+    any::Any,
+    cell::Cell,
+    future::Future,
+    io,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+pub(crate) use futures::future::AbortHandle;
+use futures::{
+    future::{Abortable, BoxFuture},
+    stream::FuturesUnordered,
+    FutureExt, StreamExt,
+};
+use tokio::{
+    select,
+    sync::{mpsc, oneshot},
+};
+
+use crate::panic_message;
+
+#[cfg(not(all(feature = "wasm", target_family = "wasm", target_os = "unknown")))]
+pub(crate) use std::time::{Instant, SystemTime};
+/// `std`'s panics in browsers.
+#[cfg(all(feature = "wasm", target_family = "wasm", target_os = "unknown"))]
+pub(crate) use web_time::{Instant, SystemTime};
+
+/// What watchers need from an async runtime.
+pub(crate) trait Runtime {
+    /// Runs `future` in the background.
+    fn spawn(future: BoxFuture<'static, ()>);
+    /// Runs `f` on a thread where blocking is fine.
+    fn spawn_blocking(f: Box<dyn FnOnce() + Send>);
+    /// Completes once `duration` has passed.
+    fn sleep(duration: Duration) -> BoxFuture<'static, ()>;
+    /// Whether tasks can be spawned from the current thread.
+    fn available() -> bool;
+}
+
+#[cfg(not(any(
+    feature = "async-std",
+    feature = "smol",
+    all(feature = "wasm", target_family = "wasm", target_os = "unknown")
+)))]
+type Current = Tokio;
+#[cfg(feature = "async-std")]
+type Current = AsyncStd;
+#[cfg(all(feature = "smol", not(feature = "async-std")))]
+type Current = Smol;
+#[cfg(all(
+    all(feature = "wasm", target_family = "wasm", target_os = "unknown"),
+    not(any(feature = "async-std", feature = "smol"))
+))]
+type Current = Browser;
+
+#[cfg(not(any(
+    feature = "async-std",
+    feature = "smol",
+    all(feature = "wasm", target_family = "wasm", target_os = "unknown")
+)))]
+pub(crate) struct Tokio;
+
+#[cfg(not(any(
+    feature = "async-std",
+    feature = "smol",
+    all(feature = "wasm", target_family = "wasm", target_os = "unknown")
+)))]
+impl Runtime for Tokio {
+    fn spawn(future: BoxFuture<'static, ()>) {
+        tokio::spawn(future);
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    fn spawn_blocking(f: Box<dyn FnOnce() + Send>) {
+        tokio::task::spawn_blocking(f);
+    }
+
+    // there are no threads to hand it off to
+    #[cfg(target_family = "wasm")]
+    fn spawn_blocking(f: Box<dyn FnOnce() + Send>) {
+        f()
+    }
+
+    fn sleep(duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+
+    fn available() -> bool {
+        tokio::runtime::Handle::try_current().is_ok()
+    }
+}
+
+#[cfg(feature = "async-std")]
+pub(crate) struct AsyncStd;
+
+#[cfg(feature = "async-std")]
+impl Runtime for AsyncStd {
+    fn spawn(future: BoxFuture<'static, ()>) {
+        async_std::task::spawn(future);
+    }
+
+    fn spawn_blocking(f: Box<dyn FnOnce() + Send>) {
+        async_std::task::spawn_blocking(f);
+    }
+
+    fn sleep(duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(async_std::task::sleep(duration))
+    }
+
+    fn available() -> bool {
+        true
+    }
+}
+
+#[cfg(all(feature = "smol", not(feature = "async-std")))]
+pub(crate) struct Smol;
+
+#[cfg(all(feature = "smol", not(feature = "async-std")))]
+impl Runtime for Smol {
+    fn spawn(future: BoxFuture<'static, ()>) {
+        smol::spawn(future).detach();
+    }
+
+    fn spawn_blocking(f: Box<dyn FnOnce() + Send>) {
+        smol::unblock(f).detach();
+    }
+
+    fn sleep(duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            smol::Timer::after(duration).await;
+        })
+    }
+
+    fn available() -> bool {
+        true
+    }
+}
+
+/// The browser's event loop, which a tokio runtime would block.
+#[cfg(all(
+    all(feature = "wasm", target_family = "wasm", target_os = "unknown"),
+    not(any(feature = "async-std", feature = "smol"))
+))]
+pub(crate) struct Browser;
+
+#[cfg(all(
+    all(feature = "wasm", target_family = "wasm", target_os = "unknown"),
+    not(any(feature = "async-std", feature = "smol"))
+))]
+#[wasm_bindgen::prelude::wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_name = setTimeout)]
+    fn set_timeout(handler: &wasm_bindgen::JsValue, timeout: f64);
+}
+
+#[cfg(all(
+    all(feature = "wasm", target_family = "wasm", target_os = "unknown"),
+    not(any(feature = "async-std", feature = "smol"))
+))]
+impl Runtime for Browser {
+    fn spawn(future: BoxFuture<'static, ()>) {
+        wasm_bindgen_futures::spawn_local(future);
+    }
+
+    // there are no threads to hand it off to
+    fn spawn_blocking(f: Box<dyn FnOnce() + Send>) {
+        f()
+    }
+
+    fn sleep(duration: Duration) -> BoxFuture<'static, ()> {
+        let (sender, fired) = oneshot::channel();
+        let handler = wasm_bindgen::closure::Closure::once_into_js(move || {
+            sender.send(()).ok();
+        });
+        // longer timeouts overflow, and fire immediately
+        set_timeout(&handler, duration.as_millis().min(i32::MAX as u128) as f64);
+        Box::pin(async move {
+            fired.await.ok();
+        })
+    }
+
+    fn available() -> bool {
+        true
+    }
+}
+
+/// Runtime on a thread of its own, started on first use, hosting watchers of applications without one.
+#[cfg(not(any(feature = "async-std", feature = "smol", target_family = "wasm")))]
+pub(crate) fn dedicated() -> &'static tokio::runtime::Handle {
+    static RUNTIME: std::sync::OnceLock<tokio::runtime::Handle> = std::sync::OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to build watcher runtime");
+        let handle = runtime.handle().clone();
+        crate::thread::spawn("runtime", move || {
+            runtime.block_on(std::future::pending::<()>())
+        })
+        .expect("failed to spawn watcher runtime thread");
+        handle
+    })
+}
+
+/// A spawned task, which keeps running when dropped, until aborted.
+pub(crate) struct JoinHandle<T> {
+    result: oneshot::Receiver<std::thread::Result<T>>,
+    abort: AbortHandle,
+}
+
+impl<T> JoinHandle<T> {
+    pub(crate) fn abort_handle(&self) -> AbortHandle {
+        self.abort.clone()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    /// What the task returned, or what it panicked with. `None` if it was aborted, or dropped by its runtime shutting
+    /// down.
+    type Output = Option<Result<T, Box<dyn Any + Send>>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.result).poll(cx).map(Result::ok)
+    }
+}
+
+/// Runs `future` in the background on the current runtime, or within the shared task if called from it.
+pub(crate) fn spawn<T: Send + 'static>(
+    future: impl Future<Output = T> + Send + 'static,
+) -> JoinHandle<T> {
+    let (abort, registration) = AbortHandle::new_pair();
+    let (sender, result) = oneshot::channel();
+    let task = Box::pin(async move {
+        let future = AssertUnwindSafe(future).catch_unwind();
+        if let Ok(out) = Abortable::new(future, registration).await {
+            sender.send(out).ok();
+        }
+    });
+    // tasks spawned by a watcher log within its span
+    #[cfg(feature = "tracing")]
+    let task = Box::pin(tracing::Instrument::in_current_span(task));
+    #[cfg(feature = "tokio-util")]
+    let task: BoxFuture<'static, ()> = match TRACKER.with_borrow(Clone::clone) {
+        Some(tracker) => Box::pin(tracked(tracker, task)),
+        None => task,
+    };
+    if IN_SHARED_TASK.get() {
+        push_shared(task);
+    } else {
+        Current::spawn(task);
+    }
+    JoinHandle { result, abort }
+}
+
+#[cfg(feature = "tokio-util")]
+thread_local! {
+    /// Set while a tracked future is polled, so the tasks it spawns are tracked too.
+    static TRACKER: std::cell::RefCell<Option<tokio_util::task::TaskTracker>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Tracks `future` in `tracker`, along with every task it spawns, and theirs in turn.
+#[cfg(feature = "tokio-util")]
+pub(crate) fn tracked<F: Future>(
+    tracker: tokio_util::task::TaskTracker,
+    future: F,
+) -> impl Future<Output = F::Output> {
+    tracker.clone().track_future(Tracked {
+        tracker,
+        future: Box::pin(future),
+    })
+}
+
+#[cfg(feature = "tokio-util")]
+struct Tracked<F> {
+    tracker: tokio_util::task::TaskTracker,
+    future: Pin<Box<F>>,
+}
+
+#[cfg(feature = "tokio-util")]
+impl<F: Future> Future for Tracked<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        /// Restores the outer tracker, even if polling panics.
+        struct Restore(Option<tokio_util::task::TaskTracker>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                TRACKER.set(self.0.take());
+            }
+        }
+        let this = self.get_mut();
+        let _restore = Restore(TRACKER.replace(Some(this.tracker.clone())));
+        this.future.as_mut().poll(cx)
+    }
+}
+
+thread_local! {
+    /// Set while a future of the shared task is polled, so the tasks it spawns join it.
+    static IN_SHARED_TASK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Sends futures to the task polling them, once started.
+static SHARED_TASK: Mutex<Option<mpsc::UnboundedSender<BoxFuture<'static, ()>>>> = Mutex::new(None);
+
+/// Runs `future` within a single task shared with every other future run this way, along with any tasks it spawns,
+/// rather than a task of its own.
+pub(crate) fn spawn_shared(future: impl Future<Output = ()> + Send + 'static) {
+    push_shared(Box::pin(AssertUnwindSafe(future).catch_unwind().map(drop)));
+}
+
+fn push_shared(mut future: BoxFuture<'static, ()>) {
+    let mut shared = SHARED_TASK.lock().unwrap();
+    if let Some(sender) = shared.as_ref() {
+        match sender.send(future) {
+            Ok(()) => return,
+            // the runtime it was started on shut down
+            Err(e) => future = e.0,
+        }
+    }
+    let (sender, receiver) = mpsc::unbounded_channel();
+    sender.send(future).ok();
+    Current::spawn(Box::pin(drive_shared(receiver)));
+    *shared = Some(sender);
+}
+
+/// Forgets the shared task, which doesn't survive a fork, so the next future starts another.
+pub(crate) fn reset_shared_task() {
+    *SHARED_TASK.lock().unwrap() = None;
+}
+
+/// Polls every future sent to it. Each is only polled once woken.
+async fn drive_shared(mut futures: mpsc::UnboundedReceiver<BoxFuture<'static, ()>>) {
+    let mut running = FuturesUnordered::new();
+    loop {
+        select! {
+            future = futures.recv() => match future {
+                Some(future) => running.push(InSharedTask(future)),
+                None => return,
+            },
+            Some(()) = running.next(), if !running.is_empty() => (),
+        }
+    }
+}
+
+struct InSharedTask(BoxFuture<'static, ()>);
+
+impl Future for InSharedTask {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let outer = IN_SHARED_TASK.replace(true);
+        let out = self.get_mut().0.as_mut().poll(cx);
+        IN_SHARED_TASK.set(outer);
+        out
+    }
+}
+
+/// Whether [`spawn`] can be called from the current thread.
+pub(crate) fn available() -> bool {
+    Current::available()
+}
+
+/// Starts running `f` where blocking is fine, returning what it returns, or fails if it panics. Keeps running if the
+/// result is dropped.
+pub(crate) fn spawn_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> T + Send + 'static,
+) -> impl Future<Output = io::Result<T>> {
+    let (sender, result) = oneshot::channel();
+    Current::spawn_blocking(Box::new(move || {
+        sender
+            .send(std::panic::catch_unwind(AssertUnwindSafe(f)))
+            .ok();
+    }));
+    async move {
+        match result.await {
+            Ok(Ok(out)) => Ok(out),
+            Ok(Err(panic)) => Err(io::Error::other(format!(
+                "blocking task panicked: {}",
+                panic_message(&*panic)
+            ))),
+            Err(_) => Err(io::Error::other("blocking task was cancelled")),
+        }
+    }
+}
+
+pub(crate) async fn sleep(duration: Duration) {
+    Current::sleep(duration).await
+}
+
+pub(crate) async fn sleep_until(deadline: Instant) {
+    sleep(deadline.saturating_duration_since(Instant::now())).await
+}
+
+/// Runs `future` for up to `duration`, `None` if it didn't complete in time.
+pub(crate) async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    select! {
+        out = future => Some(out),
+        _ = sleep(duration) => None,
+    }
+}
+
+/// A non-blocking file descriptor, registered with the runtime's reactor.
+#[cfg(all(unix, not(any(feature = "async-std", feature = "smol"))))]
+#[allow(dead_code)] // unused without the inotify, fanotify, and kqueue backends
+pub(crate) struct AsyncFd<T: std::os::fd::AsRawFd>(tokio::io::unix::AsyncFd<T>);
+#[cfg(all(unix, any(feature = "async-std", feature = "smol")))]
+#[allow(dead_code)] // unused without the inotify, fanotify, and kqueue backends
+pub(crate) struct AsyncFd<T: std::os::fd::AsFd>(async_io::Async<T>);
+
+#[cfg(all(unix, not(any(feature = "async-std", feature = "smol"))))]
+#[allow(dead_code)]
+impl<T: std::os::fd::AsRawFd> AsyncFd<T> {
+    pub(crate) fn new(fd: T) -> io::Result<Self> {
+        tokio::io::unix::AsyncFd::new(fd).map(Self)
+    }
+
+    pub(crate) fn get_ref(&self) -> &T {
+        self.0.get_ref()
+    }
+
+    /// Runs `read` on the descriptor whenever it is readable, until it stops failing with [`io::ErrorKind::WouldBlock`].
+    pub(crate) async fn read_with<R>(
+        &self,
+        mut read: impl FnMut(&T) -> io::Result<R>,
+    ) -> io::Result<R> {
+        loop {
+            let mut guard = self.0.readable().await?;
+            if let Ok(out) = guard.try_io(|fd| read(fd.get_ref())) {
+                return out;
+            }
+        }
+    }
+}
+
+#[cfg(all(unix, any(feature = "async-std", feature = "smol")))]
+#[allow(dead_code)]
+impl<T: std::os::fd::AsFd> AsyncFd<T> {
+    pub(crate) fn new(fd: T) -> io::Result<Self> {
+        async_io::Async::new(fd).map(Self)
+    }
+
+    pub(crate) fn get_ref(&self) -> &T {
+        self.0.get_ref()
+    }
+
+    /// Runs `read` on the descriptor whenever it is readable, until it stops failing with [`io::ErrorKind::WouldBlock`].
+    pub(crate) async fn read_with<R>(
+        &self,
+        read: impl FnMut(&T) -> io::Result<R>,
+    ) -> io::Result<R> {
+        self.0.read_with(read).await
+    }
+}
@@ -0,0 +1,65 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// Writes this process makes to a target, which watchers set up with
+/// [`crate::FileWatcherConfig::with_self_writes`] don't reload. Clones share the same writes.
+#[derive(Clone, Default)]
+pub struct SelfWrites(Arc<Mutex<State>>);
+
+#[derive(Default)]
+struct State {
+    /// The target, once a watcher is set up with these writes.
+    path: Option<PathBuf>,
+    /// Guards alive, during which every read is of a write of this process still in progress.
+    writing: usize,
+    /// Contents of the target as this process last left it.
+    written: Option<Vec<u8>>,
+}
+
+impl SelfWrites {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks changes to the target until the returned guard is dropped as made by this process. Once dropped, the target
+    /// is read to remember what was written, and reads finding exactly that aren't emitted.
+    pub fn guard(&self) -> SelfWriteGuard {
+        self.0.lock().unwrap().writing += 1;
+        SelfWriteGuard(self.clone())
+    }
+
+    pub(crate) fn watch(&self, path: PathBuf) {
+        self.0.lock().unwrap().path = Some(path);
+    }
+
+    /// Whether `raw` was read from a write of this process. Once something else is read, the target was changed by
+    /// someone else since, and it no longer is, even if they change it back.
+    pub(crate) fn is_own(&self, raw: &[u8]) -> bool {
+        let mut state = self.0.lock().unwrap();
+        if state.writing > 0 {
+            return true;
+        }
+        match &state.written {
+            Some(written) if written == raw => true,
+            _ => {
+                state.written = None;
+                false
+            }
+        }
+    }
+}
+
+/// Marks changes to a target as made by this process until dropped, see [`SelfWrites::guard`].
+pub struct SelfWriteGuard(SelfWrites);
+
+impl Drop for SelfWriteGuard {
+    fn drop(&mut self) {
+        let mut state = self.0 .0.lock().unwrap();
+        state.writing -= 1;
+        if let Some(path) = &state.path {
+            state.written = std::fs::read(path).ok();
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use std::{
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use log::Level;
+use tokio::{
+    select,
+    sync::{watch, Notify},
+};
+
+use crate::{
+    backend::{start_backend, Backend, BackendHandle},
+    coalesce, fs, grace_left, panic_message, quiesce, restart_delay,
+    rt::{self, Instant},
+    Infallible, Retry, WatcherContext,
+};
+
+/// Latest read of a shared file, `None` until the first read completes.
+pub(crate) type SharedRead = Option<Result<Arc<Vec<u8>>, Arc<io::Error>>>;
+
+type Registry = Mutex<Vec<(Key, Arc<watch::Sender<SharedRead>>)>>;
+
+/// The file of a shared watch and the settings it is watched and read with, which every watcher sharing it agrees on.
+#[derive(PartialEq)]
+pub(crate) struct Key {
+    pub(crate) file: PathBuf,
+    retry_interval: Duration,
+    read_retry: Retry,
+    poll_interval: Option<Duration>,
+    backend: Backend,
+    fallback_backends: Vec<Backend>,
+    shared_inotify: bool,
+    attribute_changes: bool,
+    self_test: Option<Duration>,
+    mount_changes: bool,
+    write_quiescence: Option<Duration>,
+    burst_window: Option<Duration>,
+    verify_reads: bool,
+    deletion_grace: Option<Duration>,
+    max_symlink_depth: usize,
+    log_target: Option<String>,
+}
+
+impl Key {
+    fn of(context: &WatcherContext) -> Self {
+        Self {
+            file: context.file.clone(),
+            retry_interval: context.retry_interval,
+            read_retry: context.read_retry,
+            poll_interval: context.poll_interval,
+            backend: context.backend,
+            fallback_backends: context.fallback_backends.clone(),
+            shared_inotify: context.shared_inotify,
+            attribute_changes: context.attribute_changes,
+            self_test: context.self_test,
+            mount_changes: context.mount_changes,
+            write_quiescence: context.write_quiescence,
+            burst_window: context.burst_window,
+            verify_reads: context.verify_reads,
+            deletion_grace: context.deletion_grace,
+            max_symlink_depth: context.max_symlink_depth,
+            log_target: context.logger.target.clone(),
+        }
+    }
+}
+
+/// Every file currently watched on behalf of one or more watchers in this process.
+pub(crate) fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+/// Forgets every shared watch, whose read loops don't survive a fork, so watchers started afterwards start their own.
+pub(crate) fn reset() {
+    registry().lock().unwrap().clear();
+}
+
+/// Subscribe to reads of the file watched by `context`, starting a single backend and read loop for it if no other watcher
+/// already has with the same settings.
+pub(crate) fn subscribe(context: WatcherContext) -> watch::Receiver<SharedRead> {
+    let key = Key::of(&context);
+    let mut shared = registry().lock().unwrap();
+    if let Some((_, sender)) = shared.iter().find(|(x, _)| *x == key) {
+        context.logger.log(
+            Level::Info,
+            0,
+            format_args!(
+                "sharing existing watch of '{}' for {}",
+                context.file.display(),
+                context.log_name
+            ),
+        );
+        return sender.subscribe();
+    }
+    let (sender, receiver) = watch::channel(None);
+    let sender = Arc::new(sender);
+    shared.push((key, sender.clone()));
+    // its reads are on behalf of every subscribed watcher, not the one that started it
+    #[cfg(feature = "tracing")]
+    {
+        let span = tracing::info_span!(parent: None, "shared_read", path = %context.file.display());
+        rt::spawn(tracing::Instrument::instrument(
+            supervise(context, sender),
+            span,
+        ));
+    }
+    #[cfg(not(feature = "tracing"))]
+    rt::spawn(supervise(context, sender));
+    receiver
+}
+
+/// Runs the read loop of a shared file, restarting it after a backoff whenever it panics, as subscribers would otherwise
+/// wait for reads forever.
+async fn supervise(context: WatcherContext, sender: Arc<watch::Sender<SharedRead>>) {
+    let mut failures = 0;
+    loop {
+        let started = Instant::now();
+        let panic = match rt::spawn(run_shared(context.clone(), sender.clone())).await {
+            Some(Err(panic)) => panic,
+            _ => return,
+        };
+        let delay = restart_delay(context.retry_interval, &mut failures, started);
+        context.logger.log(
+            Level::Error,
+            failures,
+            format_args!(
+                "shared read loop of {} panicked: {}, restarting in {:.1} second(s) @ '{}'",
+                context.log_name,
+                panic_message(&*panic),
+                delay.as_secs_f64(),
+                context.file.display()
+            ),
+        );
+        rt::sleep(delay).await;
+    }
+}
+
+async fn run_shared(context: WatcherContext, sender: Arc<watch::Sender<SharedRead>>) {
+    let file = context.file.clone();
+    let log_name = context.log_name.clone();
+    let read_retry = context.read_retry;
+    let notify = context.notify.clone();
+    let write_quiescence = context.write_quiescence;
+    let burst_window = context.burst_window;
+    let verify_reads = context.verify_reads;
+    let deletion_grace = context.deletion_grace;
+    let logger = context.logger.clone();
+    // only a deletion after a successful read gets a grace period to be recreated in
+    let mut grace = None;
+    let mut deadline = None;
+    let mut failures = 0;
+    // watches the target for being created while it is missing, rather than only retrying blindly
+    let creation_context = WatcherContext {
+        optional: true,
+        ..context.clone()
+    };
+    let mut creation = None;
+    // started after the first successful read
+    let mut context = Some(context);
+    let mut _backend = None;
+    loop {
+        logger.log(
+            Level::Info,
+            0,
+            format_args!("reading updated {log_name} '{}'", file.display()),
+        );
+        #[cfg(feature = "tracing")]
+        let started = Instant::now();
+        let read = if verify_reads {
+            fs::read_verified(&file, || fs::read(&file)).await
+        } else {
+            fs::read(&file).await
+        };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(path = %file.display(), duration = ?started.elapsed(), "read target");
+        let retry = match read {
+            Ok(raw) => {
+                creation = None;
+                grace = deletion_grace;
+                deadline = None;
+                failures = 0;
+                sender.send_replace(Some(Ok(Arc::new(raw))));
+                if let Some(context) = context.take() {
+                    _backend = Some(start_backend::<Infallible>(context, Arc::new(|_| ())).await);
+                }
+                None
+            }
+            Err(e) => match (e.kind() == io::ErrorKind::NotFound)
+                .then(|| grace_left(grace, &mut deadline))
+                .flatten()
+            {
+                Some(left) => {
+                    logger.log(
+                        Level::Info,
+                        0,
+                        format_args!(
+                            "{log_name} '{}' is gone, waiting up to {:.1} second(s) for it to be recreated",
+                            file.display(),
+                            left.as_secs_f64()
+                        ),
+                    );
+                    if creation.is_none() {
+                        creation = Some(watch_creation(&creation_context).await);
+                    }
+                    Some(left)
+                }
+                None => {
+                    failures += 1;
+                    let delay = read_retry.delay(failures);
+                    match delay {
+                        Some(delay) => logger.log(
+                            Level::Error,
+                            failures,
+                            format_args!(
+                                "failed to read {log_name}: {e} @ '{}', retrying in {:.1} second(s)",
+                                file.display(),
+                                delay.as_secs_f64()
+                            ),
+                        ),
+                        None => logger.log(
+                            Level::Error,
+                            failures,
+                            format_args!(
+                                "failed to read {log_name}: {e} @ '{}', retrying once it changes",
+                                file.display(),
+                            ),
+                        ),
+                    }
+                    // watch for the target being created, or changing before a first read started the regular watch
+                    if (e.kind() == io::ErrorKind::NotFound || delay.is_none())
+                        && creation.is_none()
+                    {
+                        creation = Some(watch_creation(&creation_context).await);
+                    }
+                    sender.send_replace(Some(Err(Arc::new(e))));
+                    delay
+                }
+            },
+        };
+        if !wait(
+            &notify,
+            &sender,
+            retry,
+            creation.is_some(),
+            burst_window,
+            write_quiescence,
+        )
+        .await
+        {
+            return;
+        }
+    }
+}
+
+/// Watches for the target of `context`, which is optional, to be created.
+async fn watch_creation(context: &WatcherContext) -> BackendHandle {
+    let backend = start_backend::<Infallible>(context.clone(), Arc::new(|_| ())).await;
+    // created before the watch was in place
+    if fs::try_exists(&context.file).await.unwrap_or(false) {
+        context.notify.notify_one();
+    }
+    backend
+}
+
+/// Waits for a change, or `retry` to elapse after an error, or less if the target is `missing` and created in the meantime.
+/// Changes are followed by `burst_window`, then `write_quiescence` without any further ones. Returns false once every subscriber is gone and the watch is unregistered.
+async fn wait(
+    notify: &Notify,
+    sender: &watch::Sender<SharedRead>,
+    retry: Option<Duration>,
+    missing: bool,
+    burst_window: Option<Duration>,
+    write_quiescence: Option<Duration>,
+) -> bool {
+    loop {
+        select! {
+            _ = notify.notified(), if retry.is_none() || missing => {
+                coalesce(notify, burst_window).await;
+                quiesce(notify, write_quiescence).await;
+                return true;
+            },
+            _ = rt::sleep(retry.unwrap_or_default()), if retry.is_some() => {
+                // toss out any pending notification, since we will already try again
+                let notified = notify.notified();
+                futures::pin_mut!(notified);
+                notified.enable();
+                return true;
+            },
+            _ = sender.closed() => {
+                let mut shared = registry().lock().unwrap();
+                // someone may have subscribed since
+                if sender.receiver_count() == 0 {
+                    // unless the registry was reset, and it's another watch now
+                    shared.retain(|(_, x)| !std::ptr::eq(&**x, sender));
+                    return false;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use std::{io, sync::Arc};
+
+use futures::future::BoxFuture;
+use tokio::sync::Notify;
+
+use crate::rt::{self, AbortHandle};
+
+/// A custom input for a watcher, i.e. a database row, a message bus topic, or an in-memory value in tests.
+/// See [`crate::FileWatcherConfig::from_source`]. Reads are retried, parsed, and emitted the same as files.
+pub trait Source: Send + Sync + 'static {
+    /// Raw input handed to the parser.
+    type Input: Send + 'static;
+
+    /// Identifies the source in logs, i.e. a URL.
+    fn describe(&self) -> String;
+
+    /// Read the current input. Failures are logged and retried after the retry interval.
+    fn read(&self) -> BoxFuture<'_, io::Result<Self::Input>>;
+
+    /// Resolves once the input may have changed. Called again after each change, for as long as the watcher runs.
+    fn changed(&self) -> BoxFuture<'_, ()>;
+}
+
+/// Lets you keep a handle to the source, i.e. to update an in-memory value.
+impl<S: Source> Source for Arc<S> {
+    type Input = S::Input;
+
+    fn describe(&self) -> String {
+        (**self).describe()
+    }
+
+    fn read(&self) -> BoxFuture<'_, io::Result<Self::Input>> {
+        (**self).read()
+    }
+
+    fn changed(&self) -> BoxFuture<'_, ()> {
+        (**self).changed()
+    }
+}
+
+/// Resolves at the next change of a source.
+pub(crate) type Changes = Arc<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Stops forwarding changes when dropped.
+pub(crate) struct ChangeForwarder(AbortHandle);
+
+impl Drop for ChangeForwarder {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub(crate) fn forward_changes(changes: Changes, notify: Arc<Notify>) -> ChangeForwarder {
+    let task = rt::spawn(async move {
+        loop {
+            changes().await;
+            notify.notify_one();
+        }
+    });
+    ChangeForwarder(task.abort_handle())
+}
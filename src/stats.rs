@@ -0,0 +1,210 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{rt::SystemTime, Backend};
+
+/// Reload statistics of the watchers set up with [`crate::FileWatcherConfig::with_stats`], i.e. for an admin endpoint to
+/// report. Clones share the same statistics.
+#[derive(Clone, Default)]
+pub struct WatcherStats(Arc<Mutex<State>>);
+
+#[derive(Default)]
+struct State {
+    total_reloads: u64,
+    consecutive_failures: u32,
+    last_success: Option<SystemTime>,
+    last_failure: Option<SystemTime>,
+    last_error: Option<String>,
+    last_reload_duration: Option<Duration>,
+    /// When the current run of failures started.
+    failing_since: Option<SystemTime>,
+    /// Watchers counting in these statistics, once any started.
+    running: Option<usize>,
+    /// What each running backend watches, by [`WatchSet`].
+    watches: BTreeMap<u64, Vec<Watch>>,
+}
+
+/// A watch a backend established, from [`WatcherStats::watches`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Watch {
+    /// What is watched, i.e. the target, a directory or symlink on the way to it, or a directory within a watched tree.
+    pub path: PathBuf,
+    /// `None` for a custom backend, set with [`crate::FileWatcherConfig::with_custom_backend`].
+    pub backend: Option<Backend>,
+    /// The events watched for, as the backend names them, i.e. `INotifyMask[CloseWrite, Modify]`, or how often it is
+    /// polled.
+    pub mask: String,
+}
+
+/// What a watcher is up to, from [`WatcherStats::status`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum WatcherStatus {
+    /// The watcher hasn't started, or its target hasn't parsed yet, without failing.
+    Starting,
+    /// The target parsed, and hasn't failed since.
+    Healthy,
+    /// Reading or parsing the target has failed since `since`, including before it ever parsed, and the watcher is
+    /// retrying.
+    Degraded {
+        since: SystemTime,
+        last_error: String,
+    },
+    /// The watcher stopped, i.e. it gave up, was cancelled, or its receiver was dropped.
+    Stopped,
+}
+
+impl WatcherStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads of the target that parsed to an update, including the initial one.
+    pub fn total_reloads(&self) -> u64 {
+        self.0.lock().unwrap().total_reloads
+    }
+
+    /// Failed reads or parses of the target since it last parsed, or was unchanged.
+    pub fn consecutive_failures(&self) -> u32 {
+        self.0.lock().unwrap().consecutive_failures
+    }
+
+    /// When the target last parsed to an update.
+    pub fn last_success(&self) -> Option<SystemTime> {
+        self.0.lock().unwrap().last_success
+    }
+
+    /// When reading or parsing the target last failed.
+    pub fn last_failure(&self) -> Option<SystemTime> {
+        self.0.lock().unwrap().last_failure
+    }
+
+    /// The error reading or parsing the target last failed with, kept after it recovers.
+    pub fn last_error(&self) -> Option<String> {
+        self.0.lock().unwrap().last_error.clone()
+    }
+
+    /// How long reading and parsing the target took last time, whether it failed or not, to spot it slowing down.
+    pub fn last_reload_duration(&self) -> Option<Duration> {
+        self.0.lock().unwrap().last_reload_duration
+    }
+
+    /// For readiness and liveness probes. Shared by several watchers, it reports on them as one, which is stopped once all
+    /// are.
+    pub fn status(&self) -> WatcherStatus {
+        let state = self.0.lock().unwrap();
+        if state.running == Some(0) {
+            return WatcherStatus::Stopped;
+        }
+        match (state.failing_since, &state.last_error) {
+            (Some(since), Some(last_error)) => WatcherStatus::Degraded {
+                since,
+                last_error: last_error.clone(),
+            },
+            _ if state.last_success.is_some() => WatcherStatus::Healthy,
+            _ => WatcherStatus::Starting,
+        }
+    }
+
+    /// Every watch the backends of the watchers hold right now, to check what is watched when reloads don't fire.
+    pub fn watches(&self) -> Vec<Watch> {
+        let state = self.0.lock().unwrap();
+        let mut out: Vec<Watch> = state.watches.values().flatten().cloned().collect();
+        out.sort_by(|a, b| a.path.cmp(&b.path));
+        out
+    }
+
+    /// Counts a watcher as running until the returned guard is dropped.
+    pub(crate) fn running(&self) -> Running {
+        *self.0.lock().unwrap().running.get_or_insert(0) += 1;
+        Running(self.clone())
+    }
+
+    pub(crate) fn reloaded(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.total_reloads += 1;
+        state.consecutive_failures = 0;
+        state.failing_since = None;
+        state.last_success = Some(SystemTime::now());
+    }
+
+    pub(crate) fn timed(&self, duration: Duration) {
+        self.0.lock().unwrap().last_reload_duration = Some(duration);
+    }
+
+    pub(crate) fn recovered(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.consecutive_failures = 0;
+        state.failing_since = None;
+    }
+
+    pub(crate) fn failed(&self, error: String) {
+        let mut state = self.0.lock().unwrap();
+        let now = SystemTime::now();
+        state.consecutive_failures += 1;
+        state.last_failure = Some(now);
+        state.failing_since.get_or_insert(now);
+        state.last_error = Some(error);
+    }
+}
+
+/// What a backend started for a watcher set up with [`WatcherStats`] watches, listed until dropped.
+pub(crate) struct WatchSet {
+    stats: WatcherStats,
+    backend: Option<Backend>,
+    id: u64,
+}
+
+impl WatchSet {
+    pub(crate) fn new(stats: &WatcherStats, backend: Option<Backend>) -> Self {
+        static IDS: AtomicU64 = AtomicU64::new(0);
+        Self {
+            stats: stats.clone(),
+            backend,
+            id: IDS.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Replaces the watches listed with `watches`, as paths and the events watched for there.
+    pub(crate) fn set(&self, watches: impl IntoIterator<Item = (PathBuf, String)>) {
+        let watches = watches
+            .into_iter()
+            .map(|(path, mask)| Watch {
+                path,
+                backend: self.backend,
+                mask,
+            })
+            .collect();
+        self.stats
+            .0
+            .lock()
+            .unwrap()
+            .watches
+            .insert(self.id, watches);
+    }
+}
+
+impl Drop for WatchSet {
+    fn drop(&mut self) {
+        self.stats.0.lock().unwrap().watches.remove(&self.id);
+    }
+}
+
+/// A watcher counting in [`WatcherStats`] running, until dropped.
+pub(crate) struct Running(WatcherStats);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if let Some(running) = &mut self.0 .0.lock().unwrap().running {
+            *running -= 1;
+        }
+    }
+}
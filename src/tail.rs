@@ -0,0 +1,97 @@
+use std::{
+    fs::Metadata,
+    io::{self, SeekFrom},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use crate::logging::info;
+#[cfg(not(any(target_family = "wasm", feature = "async-std", feature = "smol")))]
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::Mutex;
+
+use crate::fs::{self, File};
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TailOptions {
+    /// Only read up to the last complete line, leaving a partially written line for the next read.
+    pub(crate) lines: bool,
+}
+
+/// The file currently being followed.
+#[derive(Default)]
+pub(crate) struct TailState {
+    handle: Option<File>,
+    offset: u64,
+}
+
+/// Reads everything appended since the previous read.
+/// If the file was rotated (renamed or replaced), the rest of the old file is read through the still open handle
+/// before continuing from the start of the new one. If it was truncated, reading continues from the start.
+pub(crate) async fn read_tail(
+    file: PathBuf,
+    options: TailOptions,
+    state: Arc<Mutex<TailState>>,
+) -> io::Result<Vec<u8>> {
+    let mut state = state.lock().await;
+    let current = match fs::metadata(&file).await {
+        Ok(x) => Some(x),
+        // rotated away, and the replacement isn't there yet
+        Err(e) if e.kind() == io::ErrorKind::NotFound && state.handle.is_some() => None,
+        Err(e) => return Err(e),
+    };
+    let mut out = vec![];
+    let offset = state.offset;
+    if let Some(handle) = &mut state.handle {
+        let open = handle.metadata().await?;
+        if current.as_ref().map(identity) == Some(identity(&open)) {
+            if open.len() < offset {
+                info!("'{}' was truncated, reading from the start", file.display());
+                state.offset = 0;
+            }
+            return read_from(&mut state, options).await;
+        }
+        // whatever was appended before the rotation
+        handle.seek(SeekFrom::Start(offset)).await?;
+        handle.read_to_end(&mut out).await?;
+        state.handle = None;
+        state.offset = 0;
+        if current.is_none() {
+            return Ok(out);
+        }
+        info!("'{}' was rotated, reading the new file", file.display());
+    }
+    state.handle = Some(File::open(&file).await?);
+    out.extend(read_from(&mut state, options).await?);
+    Ok(out)
+}
+
+async fn read_from(state: &mut TailState, options: TailOptions) -> io::Result<Vec<u8>> {
+    let offset = state.offset;
+    let handle = state.handle.as_mut().expect("no open file");
+    handle.seek(SeekFrom::Start(offset)).await?;
+    let mut appended = vec![];
+    handle.read_to_end(&mut appended).await?;
+    if options.lines {
+        let complete = appended
+            .iter()
+            .rposition(|x| *x == b'\n')
+            .map(|x| x + 1)
+            .unwrap_or(0);
+        appended.truncate(complete);
+    }
+    state.offset = offset + appended.len() as u64;
+    Ok(appended)
+}
+
+/// Identifies the underlying file, so replacing it at the same path is noticed.
+#[cfg(unix)]
+fn identity(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn identity(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
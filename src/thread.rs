@@ -0,0 +1,65 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+};
+
+/// How the threads watchers run on are set up, i.e. those of the `notify` backend or [`crate::FileWatcherConfig::start_detached`].
+/// Set with [`set_thread_options`].
+#[derive(Clone)]
+pub struct ThreadOptions {
+    prefix: String,
+    on_start: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+impl Default for ThreadOptions {
+    fn default() -> Self {
+        Self {
+            prefix: "really-notify".to_string(),
+            on_start: None,
+        }
+    }
+}
+
+impl ThreadOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Names threads `{prefix}-{purpose}`, i.e. `{prefix}-notify`, instead of `really-notify-notify`.
+    pub fn with_name_prefix(mut self, prefix: impl AsRef<str>) -> Self {
+        self.prefix = prefix.as_ref().to_string();
+        self
+    }
+
+    /// Called first thing on each new thread, i.e. to lower its priority or pin it to cores away from request serving
+    /// ones.
+    pub fn with_on_start(mut self, on_start: impl Fn() + Send + Sync + 'static) -> Self {
+        self.on_start = Some(Arc::new(on_start));
+        self
+    }
+}
+
+static OPTIONS: Mutex<Option<ThreadOptions>> = Mutex::new(None);
+
+/// Sets up threads started from now on, set before starting watchers to cover all of them.
+pub fn set_thread_options(options: ThreadOptions) {
+    *OPTIONS.lock().unwrap() = Some(options);
+}
+
+/// Spawns a thread for `purpose`, set up as configured.
+#[allow(dead_code)] // unused without the native backends and the notify, uring features
+pub(crate) fn spawn<T: Send + 'static>(
+    purpose: &str,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> io::Result<JoinHandle<T>> {
+    let options = OPTIONS.lock().unwrap().clone().unwrap_or_default();
+    std::thread::Builder::new()
+        .name(format!("{}-{purpose}", options.prefix))
+        .spawn(move || {
+            if let Some(on_start) = options.on_start {
+                on_start();
+            }
+            f()
+        })
+}
@@ -0,0 +1,104 @@
+use std::{
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::logging::info;
+use thiserror::Error;
+
+use crate::{fs, rt, Infallible, SelfWrites};
+
+type Serializer<T, E> = Arc<dyn Fn(&T) -> Result<Vec<u8>, E> + Send + Sync>;
+
+/// Writes a config file back atomically, through a temporary file that is flushed to disk and renamed over it, so
+/// watchers (and anyone else) only ever read the old or the new contents. The new file keeps the permissions of the one it
+/// replaces, and on unix its owner, unless this process isn't allowed to give it away.
+/// [`T`] is what is written, serialized with [`ConfigWriter::with_serializer`], the inverse of the watcher's parser.
+pub struct ConfigWriter<T = Vec<u8>, E = Infallible> {
+    path: PathBuf,
+    serializer: Serializer<T, E>,
+    self_writes: Option<SelfWrites>,
+}
+
+impl<T, E> Clone for ConfigWriter<T, E> {
+    fn clone(&self) -> Self {
+        Self {
+            path: self.path.clone(),
+            serializer: self.serializer.clone(),
+            self_writes: self.self_writes.clone(),
+        }
+    }
+}
+
+/// An error writing a config with a [`ConfigWriter`].
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum WriteError<E> {
+    /// Writing the temporary file, or renaming it over the config, failed. The config is left untouched.
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    /// The serializer rejected the value.
+    #[error("{0}")]
+    Serialize(E),
+}
+
+impl ConfigWriter {
+    /// Writes raw bytes to `path`.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            serializer: Arc::new(|raw: &Vec<u8>| Ok(raw.clone())),
+            self_writes: None,
+        }
+    }
+}
+
+impl<T, E: Display> ConfigWriter<T, E> {
+    /// Serializer function to transform a value into the contents of the config, i.e. `serde_json::to_vec_pretty` for
+    /// a watcher parsing it with `serde_json::from_slice`.
+    pub fn with_serializer<T2, E2: Display>(
+        self,
+        func: impl Fn(&T2) -> Result<Vec<u8>, E2> + Send + Sync + 'static,
+    ) -> ConfigWriter<T2, E2> {
+        ConfigWriter {
+            path: self.path,
+            serializer: Arc::new(func),
+            self_writes: self.self_writes,
+        }
+    }
+
+    /// Mark writes as made by this process, so watchers set up with the same `writes` don't reload them.
+    pub fn with_self_writes(mut self, writes: &SelfWrites) -> Self {
+        self.self_writes = Some(writes.clone());
+        self
+    }
+
+    /// Path of the config written.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Serializes `value` and writes it.
+    pub async fn write(&self, value: &T) -> Result<(), WriteError<E>> {
+        let raw = (self.serializer)(value).map_err(WriteError::Serialize)?;
+        self.write_raw(raw).await?;
+        Ok(())
+    }
+
+    /// Writes `raw` as is, bypassing the serializer.
+    pub async fn write_raw(&self, raw: impl Into<Vec<u8>>) -> io::Result<()> {
+        let raw = raw.into();
+        info!("writing '{}'", self.path.display());
+        // dropped once written, which reads the config back to tell later reads of it apart from other writes
+        let guard = self.self_writes.as_ref().map(SelfWrites::guard);
+        let path = self.path.clone();
+        let write = move || {
+            let out = fs::write_atomic(&path, &raw);
+            drop(guard);
+            out
+        };
+        rt::spawn_blocking(write).await?
+    }
+}
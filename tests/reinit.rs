@@ -0,0 +1,91 @@
+//! [`reinit_after_fork`] restarts every backend in the process, so this runs on its own rather than alongside the unit
+//! tests, whose backends would restart underneath them.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use really_notify::{reinit_after_fork, Backend, FileWatcherConfig, Infallible, LifecycleEvent};
+use tokio::sync::mpsc::Receiver;
+
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("really-notify-{}-{name}", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Starts a watcher of `file`, counting the backends started for it.
+fn start(
+    file: &PathBuf,
+    configure: impl FnOnce(
+        FileWatcherConfig<Vec<u8>, Infallible>,
+    ) -> FileWatcherConfig<Vec<u8>, Infallible>,
+) -> (Receiver<Vec<u8>>, Arc<AtomicUsize>) {
+    let started = Arc::new(AtomicUsize::new(0));
+    let started2 = started.clone();
+    let receiver = configure(FileWatcherConfig::new(file, "config"))
+        .with_lifecycle_hook(move |event| {
+            if matches!(event, LifecycleEvent::BackendStarted { .. }) {
+                started2.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .start();
+    (receiver, started)
+}
+
+async fn next_update(receiver: &mut Receiver<Vec<u8>>, expected: &[u8]) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while receiver.recv().await.unwrap() != expected {}
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_reinit_after_fork() {
+    let dir = test_dir("reinit_after_fork");
+    let file = dir.join("config");
+    std::fs::write(&file, "1").unwrap();
+    let mut watchers = vec![
+        start(&file, |x| x),
+        start(&file, |x| x.with_backend(Backend::Poll)),
+    ];
+    #[cfg(all(feature = "inotify", any(target_os = "linux", target_os = "android")))]
+    watchers.push(start(&file, |x| {
+        x.with_backend(Backend::Inotify).with_shared_inotify(true)
+    }));
+    for (receiver, started) in &mut watchers {
+        next_update(receiver, b"1").await;
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+    }
+
+    reinit_after_fork();
+    // each backend is stopped and started again, without waiting out a backoff
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while watchers
+            .iter()
+            .any(|(_, started)| started.load(Ordering::SeqCst) < 2)
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    for (_, started) in &watchers {
+        assert_eq!(started.load(Ordering::SeqCst), 2);
+    }
+
+    // and the new backends still report changes
+    std::fs::write(&file, "2").unwrap();
+    for (receiver, _) in &mut watchers {
+        next_update(receiver, b"2").await;
+    }
+    std::fs::remove_dir_all(&dir).ok();
+}